@@ -0,0 +1,139 @@
+// Boots a firmware image, such as BHYVE_UEFI.fd, far enough to see its
+// output on the serial console.
+//
+// This shows the pieces of the library working together: SetupGuard for
+// rollback, the bootrom, a serial port on the PioBus, and a VCPU thread
+// reporting to a supervisor. The library doesn't generate ACPI tables
+// other than the HPET table, SMBIOS tables, or emulate PCI, so firmware
+// that depends on them will stop early, and operating systems won't boot.
+//
+// The VM has a single VCPU. There is no MADT to describe application
+// processors to the firmware, and no run loop to start them in, so the
+// example stops on a SpinupAp exit instead of hanging.
+//
+//     sudo cargo run --example boot -- vmname /usr/share/bhyve/firmware/BHYVE_UEFI.fd
+
+extern crate bhyve_api;
+
+use bhyve_api::device::*;
+use bhyve_api::guard::SetupGuard;
+use bhyve_api::memory::*;
+use bhyve_api::system::*;
+use bhyve_api::vcpu::*;
+use bhyve_api::vm::*;
+
+use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+
+const BSP: i32 = 0;
+
+const RTC_LMEM_LSB: i32 = 0x34;
+const RTC_LMEM_MSB: i32 = 0x35;
+
+const KB: usize = 1024;
+const MB: usize = 1024 * KB;
+
+const MEM_SIZE: usize = 256 * MB;
+const COM1: u16 = 0x3f8;
+
+// The transmit side of a 16550 UART, enough for firmware to print. Bytes
+// written to the transmit holding register go to stdout, and the line
+// status register always reports the transmitter as empty.
+struct SerialOut;
+
+const UART_THR: u16 = 0;    // transmit holding register
+const UART_LSR: u16 = 5;    // line status register
+const LSR_THRE: u32 = 0x20; // transmit holding register empty
+const LSR_TEMT: u32 = 0x40; // transmitter empty
+
+impl GuestDevice for SerialOut {}
+
+impl GuestPioDevice for SerialOut {
+    fn pio_read(&mut self, offset: u16, _width: IoWidth) -> u32 {
+        match offset {
+            UART_LSR => LSR_THRE | LSR_TEMT,
+            _ => 0,
+        }
+    }
+
+    fn pio_write(&mut self, offset: u16, value: IoValue) {
+        if offset == UART_THR {
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(&[value.as_u8()]);
+            let _ = stdout.flush();
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        println!("usage: boot <vmname> <firmware>");
+        return;
+    }
+    let vm_name = &args[1];
+    let firmware = Path::new(&args[2]);
+
+    let vmmctl = VMMSystem::new().expect("failed to create VMM system ioctl handle");
+    // The guard destroys the VM when main() returns, including on failure
+    let mut guard = SetupGuard::new();
+    vmmctl.create_vm(vm_name).expect("failed to create VM device");
+    guard.created_vm(&vmmctl, vm_name);
+
+    let vm = Arc::new(VirtualMachine::new(vm_name).expect("failed to open filehandle to VM device"));
+    vm.set_topology(1, 1, 1).expect("failed to set CPU topology");
+    vm.set_x2apic_state(BSP, false).expect("failed to disable x2APIC");
+
+    let lowmem = alloc_guest_backing(MEM_SIZE, BackingOptions::default()).expect("failed to allocate guest memory");
+    vm.setup_lowmem(lowmem.addr(), lowmem.len()).expect("failed to set up guest memory");
+    let lomem = (MEM_SIZE - 16 * MB) / (64 * KB);
+    vm.rtc_write(RTC_LMEM_LSB, lomem as u8).expect("failed to set RTC memory size");
+    vm.rtc_write(RTC_LMEM_MSB, (lomem >> 8) as u8).expect("failed to set RTC memory size");
+
+    // The bootrom is mapped between guard pages, and kept until main() returns
+    let _bootrom = vm.load_bootrom_file(firmware).expect("failed to load firmware image");
+
+    match vm.configure_hpet().expect("failed to query HPET") {
+        Some(hpet) => println!("HPET with {} timers, not advertised without an ACPI RSDT", hpet.num_timers()),
+        None => println!("No HPET available"),
+    }
+
+    let mut bus = PioBus::new();
+    bus.register(COM1, 8, Arc::new(Mutex::new(SerialOut))).expect("failed to register COM1");
+
+    vm.vcpu_reset(BSP).expect("failed to set initial state of registers");
+    vm.activate_vcpu(BSP).expect("failed to activate BSP");
+
+    let (supervisor, reports) = mpsc::channel();
+    let mut vcpus = VcpuSet::new(Arc::clone(&vm));
+    vcpus.spawn(BSP, supervisor, move |vcpu, exit| {
+        match exit {
+            VmExit::Interrupted => Ok(ExitAction::Continue),
+            VmExit::Suspended(_) => Ok(ExitAction::Stop),
+            VmExit::SpinupAp(ap, _) => {
+                println!("\nFirmware tried to start VCPU {}, but this example only runs the BSP", ap);
+                Ok(ExitAction::Stop)
+            }
+            VmExit::InOut(..) => {
+                // Ports without a device are ignored
+                bus.handle(vcpu.vm(), vcpu.id(), &exit)?;
+                Ok(ExitAction::Continue)
+            }
+            other => {
+                println!("\nUnhandled exit on VCPU {}: {:?}", vcpu.id(), other);
+                Ok(ExitAction::Stop)
+            }
+        }
+    }).expect("failed to spawn VCPU thread");
+
+    match reports.recv() {
+        Ok(VcpuReport::Exited(id, Ok(()))) => println!("VCPU {} stopped", id),
+        Ok(VcpuReport::Exited(id, Err(e))) => println!("VCPU {} failed, with error: {}", id, e),
+        Ok(VcpuReport::Panicked(id, message)) => println!("VCPU {} panicked: {}", id, message),
+        Err(_) => println!("VCPU thread ended without reporting"),
+    }
+    vcpus.join();
+    guard.rollback();
+}
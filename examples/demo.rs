@@ -6,12 +6,13 @@
 
 extern crate bhyve_api;
 
+use bhyve_api::capability::*;
+use bhyve_api::log::*;
+use bhyve_api::memory::*;
 use bhyve_api::system::*;
 use bhyve_api::vm::*;
 
-use std::io::Write;
-use std::slice;
-use std::ptr::null_mut;
+use std::sync::Arc;
 
 const BSP: i32 = 0;
 
@@ -43,16 +44,8 @@ fn main() {
     ];
 
 
-    let host_addr: *mut u8 = unsafe {
-        libc::mmap(
-            null_mut(),
-            mem_size,
-            libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
-            -1,
-            0,
-        ) as *mut u8
-    };
+    let backing = alloc_guest_backing(mem_size, BackingOptions::default()).expect("failed to allocate guest memory");
+    let host_addr = backing.as_ptr();
 
     let vmmctl = VMMSystem::new().expect("failed to create VMM system ioctl handle");
     println!("Opened a filehandle to /dev/vmmctl");
@@ -61,12 +54,14 @@ fn main() {
 
     let vm = VirtualMachine::new(vm_name).expect("failed to open filehandle to VM device");
     println!("Opened a filehandle to /dev/vmm/{}", vm.name);
+    // Show the library's description of each exit
+    vm.set_log_sink(Some(Arc::new(StderrSink::new(LogLevel::Debug))));
 
     vm.reinit().expect("failed to re-initialize VM");
     vm.set_topology(1, 1, 1).expect("failed to set CPU topology");
     vm.set_x2apic_state(BSP, false).expect("failed to disable x2APIC");
-    vm.set_capability(BSP, vm_cap_type::VM_CAP_UNRESTRICTED_GUEST, 1).expect("unrestricted guest capability not available");
-    vm.set_capability(BSP, vm_cap_type::VM_CAP_HALT_EXIT, 1).expect("exit on halt guest capability not available");
+    vm.set_cap::<UnrestrictedGuest>(BSP, true).expect("unrestricted guest capability not available");
+    vm.set_cap::<HaltExit>(BSP, true).expect("exit on halt guest capability not available");
 
     vm.setup_lowmem(host_addr as u64, mem_size).expect("failed to set guest memory");
 
@@ -75,12 +70,7 @@ fn main() {
     vm.rtc_write(RTC_LMEM_MSB, (lomem >> 8) as u8).expect("failed to set RTC memory size");
 
     // Write the x86 assembly code in the guest memory.
-    let offset: u64 = host_addr as u64 + guest_addr as u64;
-    let slice_size = mem_size - guest_addr;
-    unsafe {
-        let mut slice = slice::from_raw_parts_mut(offset as *mut u8, slice_size);
-        slice.write(&asm_code).unwrap();
-    }
+    vm.memory().write_slice(guest_addr as u64, asm_code).expect("failed to write guest memory");
 
     // Setup registers
     vm.vcpu_reset(BSP).expect("failed to set initial state of registers");
@@ -104,15 +94,15 @@ fn main() {
         println!("RIP reg before run is {}", rip);
 
         match vm.run(BSP).expect("failed to run VM") {
-            VmExit::IoOut(port, bytes, value) => {
-                let data: [u8; 4] = value.to_le_bytes();
-                println!("exit for IoOut, port={}, bytes={}, value={}", port, bytes, value);
+            VmExit::InOut(io) => {
+                let data: [u8; 4] = io.value.to_le_bytes();
+                println!("exit for InOut, port={}, bytes={}, direction={:?}, value={}", io.port, io.bytes, io.direction, io.value);
                 if data[0] == 53 {
                     println!("Got expected result, ASCII code for the number 5");
                 }
             }
-            VmExit::IoOutStr(port, bytes, index, count, repeat) => {
-                println!("exit for IoOutStr, port={}, bytes={}, index={}, count={}, repeat={}", port, bytes, index, count, repeat);
+            VmExit::InOutStr(io, operands) => {
+                println!("exit for InOutStr, port={}, bytes={}, direction={:?}, index={}, count={}, rep={}", io.port, io.bytes, io.direction, operands.index, operands.count, io.rep);
             }
             VmExit::Vmx(s, r, q, t, e) => {
                 println!("exit for Vmx, source={}, reason={}, qualification={:b}, inst type={}, inst error={}", s, r, q, t, e);
@@ -125,12 +115,12 @@ fn main() {
                 println!("exit for Bogus");
                 break;
             }
-            VmExit::Halt => {
+            VmExit::Halt(..) => {
                 println!("exit for Halt");
                 break;
             }
-            VmExit::Suspended => {
-                println!("exit for Suspended");
+            VmExit::Suspended(reason) => {
+                println!("exit for Suspended ({:?})", reason);
                 break;
             }
             reason => println!("Unhandled exit reason {:?}", reason)
@@ -5,6 +5,7 @@
 extern crate bhyve_api;
 
 use std::env;
+use std::io::{self, BufRead, Write};
 use bhyve_api::system::*;
 use bhyve_api::vm::*;
 
@@ -28,9 +29,26 @@ fn main() {
         cmd_vcpu_suspend(&args[2]);
     } else if "resume" == &args[1] {
         cmd_vcpu_resume(&args[2]);
+    } else if "list" == &args[1] {
+        cmd_list();
+    } else if "destroy-all" == &args[1] {
+        cmd_destroy_all(args.iter().any(|arg| arg == "--force"));
+    } else if "regs" == &args[1] {
+        cmd_regs(&args[2], &args[3]);
+    } else if "mem" == &args[1] {
+        cmd_mem(&args[2], &args[3], &args[4]);
     }
 }
 
+// Parses a decimal number, or a hexadecimal one with a '0x' prefix.
+fn parse_num(arg: &str) -> u64 {
+    let parsed = match arg.starts_with("0x") {
+        true => u64::from_str_radix(&arg[2..], 16),
+        false => arg.parse(),
+    };
+    parsed.expect("expected a number")
+}
+
 fn cmd_create(vm_name: &str) {
     let vmmctl = VMMSystem::new().expect("failed to create VMM system ioctl handle");
     match vmmctl.create_vm(vm_name) {
@@ -47,6 +65,43 @@ fn cmd_destroy(vm_name: &str) {
     };
 }
 
+fn cmd_list() {
+    let vmmctl = VMMSystem::new().expect("failed to create VMM system ioctl handle");
+    let names = vmmctl.list_vms().expect("failed to list VM devices");
+    for name in names {
+        println!("{}", name);
+    }
+}
+
+// Destroys every VM device, after asking for confirmation unless 'force'
+// is set.
+fn cmd_destroy_all(force: bool) {
+    let vmmctl = VMMSystem::new().expect("failed to create VMM system ioctl handle");
+    let names = vmmctl.list_vms().expect("failed to list VM devices");
+    if names.is_empty() {
+        println!("No devices found in /dev/vmm");
+        return;
+    }
+
+    if !force {
+        print!("Destroy {} devices ({})? [y/N] ", names.len(), names.join(", "));
+        io::stdout().flush().expect("failed to write prompt");
+        let mut answer = String::new();
+        io::stdin().lock().read_line(&mut answer).expect("failed to read answer");
+        if answer.trim() != "y" && answer.trim() != "yes" {
+            println!("Nothing destroyed");
+            return;
+        }
+    }
+
+    for name in names {
+        match vmmctl.destroy_vm(&name) {
+            Ok(_) => println!("Destroyed a device at /dev/vmm/{}", name),
+            Err(e) => println!("Unable to destroy device at /dev/vmm/{}, with error: {}", name, e),
+        };
+    }
+}
+
 fn cmd_run_vm(vm_name: &str) {
     let vm = VirtualMachine::new(vm_name).expect("failed to open filehandle to VM device");
     println!("Opened a filehandle to /dev/vmm/{}", vm.name);
@@ -119,3 +174,60 @@ fn cmd_vcpu_resume(vm_name: &str) {
         Err(e) => println!("Failed to resume CPU 0 for VM at /dev/vmm/{}, with error: {}", vm_name, e),
     };
 }
+
+// Registers shown by the 'regs' command, in bhyvectl order.
+const REGS: [(&str, vm_reg_name); 24] = [
+    ("rax", vm_reg_name::VM_REG_GUEST_RAX),
+    ("rbx", vm_reg_name::VM_REG_GUEST_RBX),
+    ("rcx", vm_reg_name::VM_REG_GUEST_RCX),
+    ("rdx", vm_reg_name::VM_REG_GUEST_RDX),
+    ("rsi", vm_reg_name::VM_REG_GUEST_RSI),
+    ("rdi", vm_reg_name::VM_REG_GUEST_RDI),
+    ("rbp", vm_reg_name::VM_REG_GUEST_RBP),
+    ("rsp", vm_reg_name::VM_REG_GUEST_RSP),
+    ("r8", vm_reg_name::VM_REG_GUEST_R8),
+    ("r9", vm_reg_name::VM_REG_GUEST_R9),
+    ("r10", vm_reg_name::VM_REG_GUEST_R10),
+    ("r11", vm_reg_name::VM_REG_GUEST_R11),
+    ("r12", vm_reg_name::VM_REG_GUEST_R12),
+    ("r13", vm_reg_name::VM_REG_GUEST_R13),
+    ("r14", vm_reg_name::VM_REG_GUEST_R14),
+    ("r15", vm_reg_name::VM_REG_GUEST_R15),
+    ("rip", vm_reg_name::VM_REG_GUEST_RIP),
+    ("rflags", vm_reg_name::VM_REG_GUEST_RFLAGS),
+    ("cr0", vm_reg_name::VM_REG_GUEST_CR0),
+    ("cr2", vm_reg_name::VM_REG_GUEST_CR2),
+    ("cr3", vm_reg_name::VM_REG_GUEST_CR3),
+    ("cr4", vm_reg_name::VM_REG_GUEST_CR4),
+    ("dr7", vm_reg_name::VM_REG_GUEST_DR7),
+    ("efer", vm_reg_name::VM_REG_GUEST_EFER),
+];
+
+fn cmd_regs(vm_name: &str, vcpu_arg: &str) {
+    let vm = VirtualMachine::new(vm_name).expect("failed to open filehandle to VM device");
+    let vcpu_id = parse_num(vcpu_arg) as i32;
+
+    for (name, reg) in REGS.iter() {
+        match vm.get_register(vcpu_id, *reg) {
+            Ok(value) => println!("{:>6} {:#018x}", name, value),
+            Err(e) => println!("{:>6} unavailable, with error: {}", name, e),
+        };
+    }
+}
+
+fn cmd_mem(vm_name: &str, gpa_arg: &str, len_arg: &str) {
+    let vm = VirtualMachine::new(vm_name).expect("failed to open filehandle to VM device");
+    let gpa = parse_num(gpa_arg);
+    let mut buf = vec![0; parse_num(len_arg) as usize];
+
+    if let Err(e) = vm.read_guest_memory(gpa, &mut buf) {
+        println!("Failed to read guest memory at {:#x} for VM at /dev/vmm/{}, with error: {}", gpa, vm_name, e);
+        return;
+    }
+    // Hex dump, 16 bytes per line
+    for (i, line) in buf.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line.iter().map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' }).collect();
+        println!("{:016x}  {:<47}  {}", gpa + 16 * i as u64, hex.join(" "), ascii);
+    }
+}
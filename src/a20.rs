@@ -0,0 +1,140 @@
+//! The A20 gate, and the system control port at 0x92.
+//!
+//! Legacy boot code enables the A20 address line before leaving real mode,
+//! either through the i8042 output port or through bit 1 of System Control
+//! Port A at 0x92, and the same port offers a fast reset in bit 0. Both
+//! paths share an `A20Gate`, so the state the guest sets through one is
+//! seen through the other, and by the VMM. Resets requested through either
+//! port suspend the VM with `SuspendReason::Reset`, which a
+//! `ResetController` turns into a restart.
+//!
+//! bhyve has no way to mask address line 20, and guests always run with it
+//! enabled. The gate only records what the guest asked for, which is
+//! enough for boot code that checks its write took effect through the
+//! port rather than by testing memory wraparound.
+//!
+//!     use bhyve_api::a20::*;
+//!     use bhyve_api::device::PioBus;
+//!     use bhyve_api::i8042::*;
+//!     use bhyve_api::vm::VirtualMachine;
+//!     use std::sync::{Arc, Mutex};
+//!
+//!     fn setup(vm: Arc<VirtualMachine>, bus: &mut PioBus) -> Result<A20Gate, bhyve_api::Error> {
+//!         let gate = A20Gate::new();
+//!         let mut kbc = I8042::new(Arc::clone(&vm));
+//!         kbc.set_a20_gate(gate.clone());
+//!         register_i8042(bus, &Arc::new(Mutex::new(kbc)))?;
+//!         bus.register(SYSCTL_PORT_A, 1, Arc::new(Mutex::new(SysCtlPortA::new(vm, gate.clone()))))?;
+//!         Ok(gate)
+//!     }
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::device::{GuestDevice, GuestPioDevice, IoValue, IoWidth};
+use crate::vm::VirtualMachine;
+
+/// I/O port of System Control Port A.
+pub const SYSCTL_PORT_A: u16 = 0x92;
+
+// System Control Port A bits.
+const PORT_A_FAST_RESET: u8 = 0x01;
+const PORT_A_A20: u8 = 0x02;
+
+/// The state of the A20 gate, shared by the devices that control it.
+/// Clones refer to the same gate.
+#[derive(Debug, Clone)]
+pub struct A20Gate {
+    enabled: Arc<AtomicBool>,
+}
+
+impl A20Gate {
+    /// Creates a gate in its power-on state, which is enabled as on all
+    /// modern chipsets.
+    pub fn new() -> A20Gate {
+        A20Gate { enabled: Arc::new(AtomicBool::new(true)) }
+    }
+
+    /// Returns true if A20 is enabled.
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Enables or disables A20.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+    }
+}
+
+impl Default for A20Gate {
+    fn default() -> A20Gate {
+        A20Gate::new()
+    }
+}
+
+// Applies a write to port A to 'gate', returning true if it asks for a
+// fast reset.
+fn write_port_a(gate: &A20Gate, value: u8) -> bool {
+    gate.set_enabled((value & PORT_A_A20) != 0);
+    (value & PORT_A_FAST_RESET) != 0
+}
+
+/// System Control Port A, for fast A20 control and fast reset.
+///
+/// Register it on the `PioBus` at `SYSCTL_PORT_A` with length 1.
+pub struct SysCtlPortA {
+    vm: Arc<VirtualMachine>,
+    gate: A20Gate,
+}
+
+impl SysCtlPortA {
+    /// Creates the port for 'vm', controlling 'gate'.
+    pub fn new(vm: Arc<VirtualMachine>, gate: A20Gate) -> SysCtlPortA {
+        SysCtlPortA { vm: vm, gate: gate }
+    }
+
+    /// Returns the A20 gate the port controls.
+    pub fn gate(&self) -> &A20Gate {
+        &self.gate
+    }
+}
+
+impl GuestDevice for SysCtlPortA {
+    fn reset(&mut self) {
+        self.gate.set_enabled(true);
+    }
+}
+
+impl GuestPioDevice for SysCtlPortA {
+    fn pio_read(&mut self, _offset: u16, width: IoWidth) -> u32 {
+        // The fast reset bit always reads as zero
+        let value = if self.gate.enabled() { PORT_A_A20 } else { 0 };
+        IoValue::new(width, value as u32).value()
+    }
+
+    fn pio_write(&mut self, _offset: u16, value: IoValue) {
+        if write_port_a(&self.gate, value.as_u8()) {
+            // This fails if the VM is already suspended, which leaves it
+            // stopped anyway.
+            let _ = self.vm.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_a() {
+        let gate = A20Gate::new();
+        let shared = gate.clone();
+        assert!(shared.enabled());
+
+        assert!(!write_port_a(&gate, 0));
+        assert!(!shared.enabled());
+        assert!(!write_port_a(&gate, PORT_A_A20));
+        assert!(shared.enabled());
+        assert!(write_port_a(&gate, PORT_A_A20 | PORT_A_FAST_RESET));
+    }
+}
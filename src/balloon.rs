@@ -0,0 +1,149 @@
+//! Memory ballooning.
+//!
+//! A balloon device lets the host take memory back from a running guest: the
+//! guest's balloon driver allocates pages and reports their addresses, and
+//! the VMM releases the host memory behind them. When the host has memory
+//! to spare again, it deflates the balloon, and the guest driver hands the
+//! pages back to the guest.
+//!
+//! `VirtualMachine::inflate_balloon()` and `deflate_balloon()` are the VMM
+//! side of that exchange. Inflating unmaps the range from the guest with
+//! `munmap_memseg()`, splitting the system memory mapping around it, and
+//! deflating maps it again. Both replace guest mappings, so the VCPUs have
+//! to be suspended while they run:
+//!
+//!     use bhyve_api::vm::VirtualMachine;
+//!
+//!     // Called as the guest driver reports pages it has given up
+//!     fn inflate(vm: &VirtualMachine, pages: &[u64]) -> Result<(), bhyve_api::Error> {
+//!         for gpa in pages {
+//!             vm.inflate_balloon(*gpa, 4096)?;
+//!         }
+//!         let stats = vm.balloon_stats();
+//!         println!("{} bytes in the balloon", stats.inflated);
+//!         Ok(())
+//!     }
+//!
+//! Kernels without `KernelFeatures::munmap_memseg` can't take memory from
+//! the guest, and inflating fails with `ENOTSUP`. Each split takes another
+//! of the kernel's few guest mappings, so a balloon made of many scattered
+//! pages fails with `Error::TooManyMappings`; guest drivers that report
+//! large contiguous ranges suit it best.
+
+use libc::{EEXIST, ENOENT};
+
+use crate::stats::BalloonStats;
+use crate::vm::{continues, MemMap};
+use crate::Error;
+
+/// The ballooned ranges of guest memory, kept by `VirtualMachine` as the
+/// mappings they were unmapped from, so they can be mapped again.
+#[derive(Debug, Default)]
+pub(crate) struct BalloonState {
+    // Sorted, and merged where they carry on from each other
+    pieces: Vec<MemMap>,
+    inflations: u64,
+    deflations: u64,
+}
+
+impl BalloonState {
+    // Returns true if any of [gpa,gpa+len) is in the balloon.
+    pub(crate) fn overlaps(&self, gpa: u64, len: u64) -> bool {
+        self.pieces.iter().any(|piece| piece.overlaps(gpa, len))
+    }
+
+    // Adds the unmapped 'piece' to the balloon. Returns `EEXIST` if any of
+    // it is already there.
+    pub(crate) fn inflate(&mut self, piece: MemMap) -> Result<(), Error> {
+        if self.overlaps(piece.gpa, piece.len as u64) {
+            return Err(Error::new(EEXIST));
+        }
+        self.pieces.push(piece);
+        self.pieces.sort_by_key(|piece| piece.gpa);
+        // Merge pieces that carry on from each other, so any part of them
+        // can be deflated at once
+        let mut merged: Vec<MemMap> = Vec::with_capacity(self.pieces.len());
+        for piece in self.pieces.iter() {
+            match merged.last_mut() {
+                Some(last) if last.prot == piece.prot && continues(last, piece) => last.len += piece.len,
+                _ => merged.push(*piece),
+            }
+        }
+        self.pieces = merged;
+        Ok(())
+    }
+
+    // Takes [gpa,gpa+len) out of the balloon, returning the mapping to
+    // restore. Returns `ENOENT` unless the whole range is ballooned.
+    pub(crate) fn deflate(&mut self, gpa: u64, len: u64) -> Result<MemMap, Error> {
+        let index = match self.pieces.iter().position(|p| p.gpa <= gpa && gpa + len <= p.gpa + p.len as u64) {
+            Some(index) => index,
+            None => return Err(Error::new(ENOENT)),
+        };
+        let piece = self.pieces.remove(index);
+        let end = piece.gpa + piece.len as u64;
+        if gpa + len < end {
+            self.pieces.insert(index, piece.slice(gpa + len, end - gpa - len));
+        }
+        if piece.gpa < gpa {
+            self.pieces.insert(index, piece.slice(piece.gpa, gpa - piece.gpa));
+        }
+        Ok(piece.slice(gpa, len))
+    }
+
+    // Empties the balloon, returning the ballooned mappings.
+    pub(crate) fn take(&mut self) -> Vec<MemMap> {
+        std::mem::take(&mut self.pieces)
+    }
+
+    // Counts a completed inflation or deflation.
+    pub(crate) fn count(&mut self, inflation: bool) {
+        if inflation {
+            self.inflations += 1;
+        } else {
+            self.deflations += 1;
+        }
+    }
+
+    pub(crate) fn stats(&self) -> BalloonStats {
+        BalloonStats {
+            inflated: self.pieces.iter().map(|piece| piece.len as u64).sum(),
+            inflations: self.inflations,
+            deflations: self.deflations,
+        }
+    }
+
+    // Returns the ballooned ranges, in order of guest physical address.
+    pub(crate) fn ranges(&self) -> Vec<(u64, u64)> {
+        self.pieces.iter().map(|piece| (piece.gpa, piece.len as u64)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_balloon_state() {
+        let map = |gpa: u64, len: usize| MemMap { gpa: gpa, segid: 1, segoff: (gpa - 0x100000) as i64, len: len, prot: 3, flags: 0 };
+        let mut state = BalloonState::default();
+        assert!(!state.overlaps(0x104000, 0x1000));
+        state.inflate(map(0x104000, 0x1000)).unwrap();
+        state.inflate(map(0x105000, 0x2000)).unwrap();
+        assert!(state.inflate(map(0x106000, 0x1000)).is_err());
+        assert!(state.overlaps(0x103000, 0x2000));
+        assert_eq!(state.ranges(), vec![(0x104000, 0x3000)]);
+
+        // Deflating the middle of the merged range leaves two ballooned
+        // ranges, and returns the mapping of the middle
+        assert_eq!(state.deflate(0x105000, 0x1000).unwrap(), map(0x105000, 0x1000));
+        assert_eq!(state.ranges(), vec![(0x104000, 0x1000), (0x106000, 0x1000)]);
+        assert!(state.deflate(0x105000, 0x1000).is_err());
+
+        state.deflate(0x104000, 0x1000).unwrap();
+        assert_eq!(state.take(), vec![map(0x106000, 0x1000)]);
+        assert!(state.ranges().is_empty());
+        let stats = state.stats();
+        assert_eq!((stats.inflated, stats.inflations, stats.deflations), (0, 0, 0));
+    }
+}
@@ -0,0 +1,148 @@
+//! Plain data types that can be copied to and from guest memory.
+//!
+//! Guest memory holds bytes in the guest's little-endian byte order. Types
+//! implementing `FromBytes` can be read from and written to guest memory as
+//! a whole with `VirtualMachine::read_obj()` and `write_obj()`, at any
+//! guest physical address, aligned or not.
+//!
+//!     use bhyve_api::bytes::FromBytes;
+//!
+//!     #[repr(C, packed)]
+//!     #[derive(Copy, Clone, Default)]
+//!     struct E820Entry {
+//!         base: u64,
+//!         len: u64,
+//!         kind: u32,
+//!     }
+//!
+//!     // Safe because the struct is packed plain data, valid for any bytes
+//!     unsafe impl FromBytes for E820Entry {
+//!         fn to_native(self) -> E820Entry {
+//!             E820Entry {
+//!                 base: u64::from_le(self.base),
+//!                 len: u64::from_le(self.len),
+//!                 kind: u32::from_le(self.kind),
+//!             }
+//!         }
+//!         fn to_le(self) -> E820Entry {
+//!             E820Entry { base: self.base.to_le(), len: self.len.to_le(), kind: self.kind.to_le() }
+//!         }
+//!     }
+
+use std::mem::size_of;
+use std::slice;
+
+/// A type that is plain data: it is `Copy`, has no padding bytes, and every
+/// bit pattern of its size is a valid value, so it can be copied from and
+/// to arbitrary bytes.
+///
+/// # Safety
+///
+/// Implementing the trait is unsafe because a type with padding or invalid
+/// bit patterns (such as `bool`, references, or most enums) would lead to
+/// undefined behavior.
+pub unsafe trait FromBytes: Copy {
+    /// Converts a value read from guest memory from the guest's little-endian
+    /// byte order. Integers swap their bytes on big-endian hosts; structs
+    /// should convert each of their fields. The default leaves the value
+    /// unchanged, which is correct for byte arrays.
+    fn to_native(self) -> Self {
+        self
+    }
+
+    /// Converts a value to the guest's little-endian byte order before it is
+    /// written to guest memory.
+    fn to_le(self) -> Self {
+        self
+    }
+}
+
+macro_rules! from_bytes_int {
+    ($($t:ty),*) => {
+        $(
+            unsafe impl FromBytes for $t {
+                fn to_native(self) -> $t {
+                    <$t>::from_le(self)
+                }
+
+                fn to_le(self) -> $t {
+                    <$t>::to_le(self)
+                }
+            }
+        )*
+    };
+}
+
+from_bytes_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+macro_rules! from_bytes_array {
+    ($($n:expr),*) => {
+        $(
+            unsafe impl<T: FromBytes> FromBytes for [T; $n] {
+                fn to_native(mut self) -> [T; $n] {
+                    for item in self.iter_mut() {
+                        *item = item.to_native();
+                    }
+                    self
+                }
+
+                fn to_le(mut self) -> [T; $n] {
+                    for item in self.iter_mut() {
+                        *item = item.to_le();
+                    }
+                    self
+                }
+            }
+        )*
+    };
+}
+
+from_bytes_array!(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+                  17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32,
+                  64, 128, 256, 512, 1024, 4096);
+
+/// Returns the bytes of 'value'.
+pub fn as_bytes<T: FromBytes>(value: &T) -> &[u8] {
+    // Safe because FromBytes types have no padding, so every byte is
+    // initialized, and the slice borrows 'value'.
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+/// Returns the bytes of 'value', for filling it in.
+pub fn as_mut_bytes<T: FromBytes>(value: &mut T) -> &mut [u8] {
+    // Safe because any bytes written make a valid FromBytes value, and the
+    // slice borrows 'value' mutably.
+    unsafe { slice::from_raw_parts_mut(value as *mut T as *mut u8, size_of::<T>()) }
+}
+
+/// Reads a value from the start of 'bytes', converting it from the guest's
+/// byte order. Returns 'None' if 'bytes' is too short.
+pub fn from_slice<T: FromBytes>(bytes: &[u8]) -> Option<T> {
+    if bytes.len() < size_of::<T>() {
+        return None;
+    }
+    // Safe because the slice holds enough bytes, read_unaligned() doesn't
+    // require alignment, and any bytes make a valid FromBytes value.
+    let value = unsafe { (bytes.as_ptr() as *const T).read_unaligned() };
+    Some(value.to_native())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_slice() {
+        let bytes = [0xff, 0x78, 0x56, 0x34, 0x12, 0xaa];
+        // Unaligned, little-endian
+        assert_eq!(from_slice::<u32>(&bytes[1..]), Some(0x12345678));
+        assert_eq!(from_slice::<u64>(&bytes[1..]), None);
+        assert_eq!(from_slice::<[u16; 2]>(&bytes[1..]), Some([0x5678, 0x1234]));
+
+        let value = 0x1122u16.to_le();
+        assert_eq!(as_bytes(&value), &[0x22, 0x11]);
+        let mut value = 0u16;
+        as_mut_bytes(&mut value).copy_from_slice(&[0x34, 0x12]);
+        assert_eq!(value.to_native(), 0x1234);
+    }
+}
@@ -0,0 +1,160 @@
+//! Typed access to optional VCPU capabilities.
+//!
+//! `set_capability()` takes the raw integer the kernel stores for every
+//! capability. The types here describe what each capability means, so a
+//! capability can be set with a value of the right type:
+//!
+//!     use bhyve_api::capability::*;
+//!     use bhyve_api::vm::*;
+//!
+//!     fn setup(vm: &VirtualMachine) -> Result<(), bhyve_api::Error> {
+//!         vm.set_cap::<UnrestrictedGuest>(0, true)?;
+//!         if !vm.get_cap::<HaltExit>(0)? {
+//!             vm.set_cap::<HaltExit>(0, true)?;
+//!         }
+//!         // Values read from a configuration file are checked at runtime
+//!         vm.set_capability_value(0, vm_cap_type::VM_CAP_PAUSE_EXIT, CapValue::Bool(false))?;
+//!         Ok(())
+//!     }
+
+use libc::EINVAL;
+
+use crate::vm::vm_cap_type;
+use crate::Error;
+
+/// The value of a capability, for code that handles capabilities chosen at
+/// runtime.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CapValue {
+    /// A capability that is either enabled or disabled.
+    Bool(bool),
+    /// A capability with a numeric setting.
+    Int(i32),
+}
+
+/// The kind of value a capability takes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CapKind {
+    /// Enabled or disabled, stored as 1 or 0.
+    Bool,
+    /// Any integer.
+    Int,
+}
+
+/// Returns the kind of value that 'cap' takes. Every capability the kernel
+/// currently defines is boolean.
+pub fn cap_kind(cap: vm_cap_type) -> CapKind {
+    match cap {
+        vm_cap_type::VM_CAP_HALT_EXIT |
+        vm_cap_type::VM_CAP_MTRAP_EXIT |
+        vm_cap_type::VM_CAP_PAUSE_EXIT |
+        vm_cap_type::VM_CAP_UNRESTRICTED_GUEST |
+        vm_cap_type::VM_CAP_ENABLE_INVPCID => CapKind::Bool,
+        // Not a capability, so any value is passed on for the kernel to reject
+        vm_cap_type::VM_CAP_MAX => CapKind::Int,
+    }
+}
+
+impl CapValue {
+    /// Converts the value to the integer passed to the kernel, checking that
+    /// it is the kind of value 'cap' takes. Returns `EINVAL` otherwise.
+    pub fn to_raw(self, cap: vm_cap_type) -> Result<i32, Error> {
+        match (cap_kind(cap), self) {
+            (CapKind::Bool, CapValue::Bool(enabled)) => Ok(enabled as i32),
+            (CapKind::Int, CapValue::Int(value)) => Ok(value),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    /// Interprets the integer the kernel reports for 'cap'.
+    pub fn from_raw(cap: vm_cap_type, raw: i32) -> CapValue {
+        match cap_kind(cap) {
+            CapKind::Bool => CapValue::Bool(raw != 0),
+            CapKind::Int => CapValue::Int(raw),
+        }
+    }
+}
+
+/// Conversions between a capability value type and the kernel's integer.
+pub trait CapType: Copy {
+    /// Converts the value to the integer passed to the kernel.
+    fn to_raw(self) -> i32;
+    /// Interprets the integer the kernel reports.
+    fn from_raw(raw: i32) -> Self;
+}
+
+impl CapType for bool {
+    fn to_raw(self) -> i32 {
+        self as i32
+    }
+
+    fn from_raw(raw: i32) -> bool {
+        raw != 0
+    }
+}
+
+impl CapType for i32 {
+    fn to_raw(self) -> i32 {
+        self
+    }
+
+    fn from_raw(raw: i32) -> i32 {
+        raw
+    }
+}
+
+/// A capability, with the type of its value.
+pub trait Capability {
+    type Value: CapType;
+
+    /// Returns the kernel identifier of the capability.
+    fn cap() -> vm_cap_type;
+}
+
+macro_rules! bool_capability {
+    ($(#[$doc:meta])* $name:ident, $cap:ident) => {
+        $(#[$doc])*
+        #[derive(Debug, Copy, Clone)]
+        pub struct $name;
+
+        impl Capability for $name {
+            type Value = bool;
+
+            fn cap() -> vm_cap_type {
+                vm_cap_type::$cap
+            }
+        }
+    };
+}
+
+bool_capability!(
+    /// Exit to userspace when the guest executes HLT.
+    HaltExit, VM_CAP_HALT_EXIT);
+bool_capability!(
+    /// Exit to userspace after every guest instruction, with the monitor
+    /// trap flag.
+    MtrapExit, VM_CAP_MTRAP_EXIT);
+bool_capability!(
+    /// Exit to userspace when the guest executes PAUSE.
+    PauseExit, VM_CAP_PAUSE_EXIT);
+bool_capability!(
+    /// Run the guest in real mode and without paging, without emulation.
+    UnrestrictedGuest, VM_CAP_UNRESTRICTED_GUEST);
+bool_capability!(
+    /// Allow the guest to execute INVPCID.
+    EnableInvpcid, VM_CAP_ENABLE_INVPCID);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cap_value() {
+        let cap = vm_cap_type::VM_CAP_HALT_EXIT;
+        assert_eq!(CapValue::Bool(true).to_raw(cap), Ok(1));
+        assert!(CapValue::Int(2).to_raw(cap).is_err());
+        assert_eq!(CapValue::from_raw(cap, 1), CapValue::Bool(true));
+        assert!(!<HaltExit as Capability>::Value::from_raw(0));
+        assert_eq!(PauseExit::cap() as i32, vm_cap_type::VM_CAP_PAUSE_EXIT as i32);
+    }
+}
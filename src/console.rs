@@ -0,0 +1,394 @@
+//! Multiplexing guest consoles onto host endpoints.
+//!
+//! A guest usually has several byte streams worth showing on the host: a
+//! virtio console, the debug port, a serial port. A `ConsoleMux` gives each
+//! of them a named channel that writes to a host endpoint, such as a pty, a
+//! socket, or a log file. Sources write to a `ChannelWriter`, which only
+//! copies into the channel's buffer, and a thread per channel writes the
+//! buffer out, so a slow endpoint never stalls a VCPU in the middle of an
+//! exit. When an endpoint falls behind and the buffer fills, the channel's
+//! `Overflow` policy decides whether sources wait for space or lose output.
+//! Input from an endpoint, such as keystrokes on a pty, is passed to the
+//! guest by a thread that reads it and hands it to the channel's sink.
+//!
+//!     use bhyve_api::console::*;
+//!     use bhyve_api::debugcon::DebugCon;
+//!     use bhyve_api::virtio_console::VirtioConsole;
+//!     use bhyve_api::vm::VirtualMachine;
+//!     use std::io::Write;
+//!     use std::sync::{Arc, Mutex};
+//!
+//!     fn setup(vm: Arc<VirtualMachine>, mux: &mut ConsoleMux) -> Result<Arc<Mutex<VirtioConsole>>, bhyve_api::Error> {
+//!         let (pty, path) = open_pty()?;
+//!         println!("console on {}", path.display());
+//!         let output = mux.add_channel("console", Box::new(pty.try_clone()?), ChannelOptions::default())?;
+//!         let console = Arc::new(Mutex::new(VirtioConsole::new(vm, Box::new(output))?));
+//!         let guest = Arc::clone(&console);
+//!         mux.add_input("console", Box::new(pty), Box::new(move |data: &[u8]| {
+//!             // Input the driver has no room for is lost, as on a serial line
+//!             guest.lock().unwrap().receive(data).map(|_| ())
+//!         }))?;
+//!         Ok(console)
+//!     }
+//!
+//!     // Called periodically to pass on the debug port's output
+//!     fn pump(debugcon: &mut DebugCon, channel: &mut ChannelWriter) -> std::io::Result<()> {
+//!         channel.write_all(&debugcon.take())
+//!     }
+
+use libc::{EEXIST, EINVAL, ENOENT};
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::Error;
+
+/// Default capacity of a channel's buffer.
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 64 * 1024;
+
+/// Receives the input read from a channel's endpoint, and passes it to the
+/// guest.
+pub type InputSink = Box<dyn FnMut(&[u8]) -> Result<(), Error> + Send>;
+
+// Most bytes read from an input endpoint at a time.
+const INPUT_CHUNK_SIZE: usize = 4096;
+
+/// What a channel does with output that doesn't fit in its buffer.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Overflow {
+    /// Drop the output that doesn't fit, and count it, as a serial line
+    /// does. Suits sources written from VCPU threads.
+    Drop,
+    /// Make the source wait until the endpoint has taken enough output.
+    /// Suits sources with a thread of their own, which can afford to wait.
+    Block,
+}
+
+/// Settings of a console channel.
+#[derive(Debug, Copy, Clone)]
+pub struct ChannelOptions {
+    /// Bytes buffered for the endpoint before the overflow policy applies.
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for ChannelOptions {
+    fn default() -> ChannelOptions {
+        ChannelOptions { capacity: DEFAULT_CHANNEL_CAPACITY, overflow: Overflow::Drop }
+    }
+}
+
+// Output waiting for a channel's endpoint.
+#[derive(Debug)]
+struct Buffer {
+    data: VecDeque<u8>,
+    capacity: usize,
+    dropped: u64,
+    closed: bool,
+}
+
+impl Buffer {
+    fn new(capacity: usize) -> Buffer {
+        Buffer { data: VecDeque::new(), capacity: capacity, dropped: 0, closed: false }
+    }
+
+    // Appends as much of 'data' as fits, returning how much did.
+    fn push(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(self.capacity - self.data.len());
+        self.data.extend(&data[..n]);
+        n
+    }
+
+    // Discards 'n' bytes of output, counting them as dropped.
+    fn drop_output(&mut self, n: usize) {
+        self.dropped += n as u64;
+    }
+}
+
+#[derive(Debug)]
+struct ChannelState {
+    buffer: Mutex<Buffer>,
+    readable: Condvar,
+    writable: Condvar,
+}
+
+/// The source side of a console channel. Clones write to the same channel.
+#[derive(Debug, Clone)]
+pub struct ChannelWriter {
+    state: Arc<ChannelState>,
+    overflow: Overflow,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let mut buffer = self.state.buffer.lock().unwrap();
+        loop {
+            if buffer.closed {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "console channel closed"));
+            }
+            let n = buffer.push(data);
+            if n > 0 {
+                self.state.readable.notify_one();
+            }
+            match self.overflow {
+                Overflow::Drop => {
+                    buffer.drop_output(data.len() - n);
+                    return Ok(data.len());
+                }
+                Overflow::Block if n > 0 => return Ok(n),
+                Overflow::Block => buffer = self.state.writable.wait(buffer).unwrap(),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Output is written out by the channel's thread
+        Ok(())
+    }
+}
+
+struct Channel {
+    name: String,
+    state: Arc<ChannelState>,
+    thread: Option<JoinHandle<()>>,
+    has_input: bool,
+}
+
+/// A set of named console channels, each writing to a host endpoint.
+/// Dropping the mux writes out the buffered output and closes the
+/// endpoints.
+#[derive(Default)]
+pub struct ConsoleMux {
+    channels: Vec<Channel>,
+}
+
+impl ConsoleMux {
+    /// Creates a mux with no channels.
+    pub fn new() -> ConsoleMux {
+        ConsoleMux::default()
+    }
+
+    /// Adds a channel called 'name' that writes to 'endpoint' from a thread
+    /// named `console-<name>`, and returns the writer for its source.
+    /// Returns `EEXIST` if the name is taken.
+    pub fn add_channel(&mut self, name: &str, mut endpoint: Box<dyn Write + Send>, options: ChannelOptions) -> Result<ChannelWriter, Error> {
+        if options.capacity == 0 {
+            return Err(Error::new(EINVAL));
+        }
+        if self.channels.iter().any(|channel| channel.name == name) {
+            return Err(Error::new(EEXIST));
+        }
+        let state = Arc::new(ChannelState {
+            buffer: Mutex::new(Buffer::new(options.capacity)),
+            readable: Condvar::new(),
+            writable: Condvar::new(),
+        });
+        let thread_state = Arc::clone(&state);
+        let thread = thread::Builder::new().name(format!("console-{}", name)).spawn(move || {
+            drain(&thread_state, &mut *endpoint);
+        })?;
+        self.channels.push(Channel { name: name.to_string(), state: Arc::clone(&state), thread: Some(thread), has_input: false });
+        Ok(ChannelWriter { state: state, overflow: options.overflow })
+    }
+
+    /// Passes input read from 'source', usually the channel's endpoint or a
+    /// clone of it, to 'sink', from a thread named `console-<name>-input`.
+    /// The thread ends when 'source' reaches end of file or fails, or when
+    /// 'sink' returns an error. Since it may be blocked reading, it is not
+    /// joined when the mux is dropped. Returns `ENOENT` if there is no
+    /// channel 'name', and `EEXIST` if it already has an input.
+    pub fn add_input(&mut self, name: &str, mut source: Box<dyn Read + Send>, mut sink: InputSink) -> Result<(), Error> {
+        let channel = match self.channels.iter_mut().find(|channel| channel.name == name) {
+            Some(channel) => channel,
+            None => return Err(Error::new(ENOENT)),
+        };
+        if channel.has_input {
+            return Err(Error::new(EEXIST));
+        }
+        thread::Builder::new().name(format!("console-{}-input", name)).spawn(move || {
+            forward(&mut *source, &mut *sink);
+        })?;
+        channel.has_input = true;
+        Ok(())
+    }
+
+    /// Returns the names of the channels, in the order they were added.
+    pub fn names(&self) -> Vec<&str> {
+        self.channels.iter().map(|channel| channel.name.as_str()).collect()
+    }
+
+    /// Returns the number of bytes waiting for the endpoint of channel
+    /// 'name'.
+    pub fn pending(&self, name: &str) -> Result<usize, Error> {
+        Ok(self.channel(name)?.state.buffer.lock().unwrap().data.len())
+    }
+
+    /// Returns the number of bytes channel 'name' has dropped because the
+    /// buffer was full or the endpoint failed.
+    pub fn dropped(&self, name: &str) -> Result<u64, Error> {
+        Ok(self.channel(name)?.state.buffer.lock().unwrap().dropped)
+    }
+
+    fn channel(&self, name: &str) -> Result<&Channel, Error> {
+        match self.channels.iter().find(|channel| channel.name == name) {
+            Some(channel) => Ok(channel),
+            None => Err(Error::new(ENOENT)),
+        }
+    }
+}
+
+impl Drop for ConsoleMux {
+    fn drop(&mut self) {
+        for channel in self.channels.iter() {
+            channel.state.buffer.lock().unwrap().closed = true;
+            channel.state.readable.notify_all();
+            channel.state.writable.notify_all();
+        }
+        for channel in self.channels.iter_mut() {
+            if let Some(thread) = channel.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+// Writes the channel's output to 'endpoint' until the channel is closed and
+// empty. Once the endpoint fails, further output is dropped, so sources
+// never wait on an endpoint that has gone away.
+fn drain(state: &ChannelState, endpoint: &mut dyn Write) {
+    let mut failed = false;
+    loop {
+        let chunk: Vec<u8> = {
+            let mut buffer = state.buffer.lock().unwrap();
+            while buffer.data.is_empty() && !buffer.closed {
+                buffer = state.readable.wait(buffer).unwrap();
+            }
+            if buffer.data.is_empty() {
+                return;
+            }
+            let chunk: Vec<u8> = buffer.data.drain(..).collect();
+            if failed {
+                buffer.drop_output(chunk.len());
+            }
+            state.writable.notify_all();
+            chunk
+        };
+        if !failed && (endpoint.write_all(&chunk).is_err() || endpoint.flush().is_err()) {
+            failed = true;
+            state.buffer.lock().unwrap().drop_output(chunk.len());
+        }
+    }
+}
+
+// Passes input from 'source' to 'sink' until either fails or 'source' is
+// at end of file.
+fn forward(source: &mut dyn Read, sink: &mut dyn FnMut(&[u8]) -> Result<(), Error>) {
+    let mut chunk = [0; INPUT_CHUNK_SIZE];
+    loop {
+        let n = match source.read(&mut chunk) {
+            Ok(0) => return,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return,
+        };
+        if sink(&chunk[..n]).is_err() {
+            return;
+        }
+    }
+}
+
+/// Opens a pseudo-terminal, returning its controlling side for use as a
+/// channel endpoint, and the path of the terminal side for users to attach
+/// to, for example with `screen` or `cu`.
+pub fn open_pty() -> Result<(File, PathBuf), Error> {
+    let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(Error::last());
+    }
+    // Safe because the descriptor was just opened, and is owned by the File
+    // from here on, which closes it on the error paths.
+    let master = unsafe { File::from_raw_fd(fd) };
+    if unsafe { libc::grantpt(fd) } != 0 || unsafe { libc::unlockpt(fd) } != 0 {
+        return Err(Error::last());
+    }
+    // ptsname_r() fills in a buffer of our own, so opening ptys on other
+    // threads at the same time can't overwrite the name.
+    let mut name = [0 as c_char; libc::PATH_MAX as usize];
+    let result = unsafe { libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) };
+    if result != 0 {
+        return Err(Error::new(result));
+    }
+    // Safe because ptsname_r() succeeded, so the buffer holds a
+    // NUL-terminated string.
+    let path = unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy().into_owned();
+    Ok((master, PathBuf::from(path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer() {
+        let mut buffer = Buffer::new(4);
+        assert_eq!(buffer.push(b"abc"), 3);
+        assert_eq!(buffer.push(b"def"), 1);
+        buffer.drop_output(2);
+        assert_eq!(buffer.push(b"g"), 0);
+        assert_eq!(buffer.data.iter().cloned().collect::<Vec<u8>>(), b"abcd");
+        assert_eq!(buffer.dropped, 2);
+    }
+
+    #[test]
+    fn test_console_mux() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        struct Endpoint(std::sync::mpsc::Sender<Vec<u8>>);
+        impl Write for Endpoint {
+            fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+                let _ = self.0.send(data.to_vec());
+                Ok(data.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut mux = ConsoleMux::new();
+        let options = ChannelOptions { capacity: 16, overflow: Overflow::Block };
+        let mut writer = mux.add_channel("debug", Box::new(Endpoint(tx)), options).unwrap();
+        assert!(mux.add_channel("debug", Box::new(io::sink()), options).is_err());
+        writer.write_all(b"hello from the guest, over capacity").unwrap();
+        drop(mux);
+
+        let output: Vec<u8> = rx.iter().flatten().collect();
+        assert_eq!(output, b"hello from the guest, over capacity".to_vec());
+        assert!(writer.write(b"x").is_err());
+    }
+
+    #[test]
+    fn test_console_input() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut mux = ConsoleMux::new();
+        let sink: InputSink = Box::new(move |data: &[u8]| {
+            let _ = tx.send(data.to_vec());
+            Ok(())
+        });
+        assert!(mux.add_input("serial", Box::new(io::empty()), Box::new(|_: &[u8]| Ok(()))).is_err());
+        mux.add_channel("serial", Box::new(io::sink()), ChannelOptions::default()).unwrap();
+        mux.add_input("serial", Box::new(&b"typed at the host"[..]), sink).unwrap();
+        assert!(mux.add_input("serial", Box::new(io::empty()), Box::new(|_: &[u8]| Ok(()))).is_err());
+
+        // The sender is dropped with the sink once the source is exhausted
+        let input: Vec<u8> = rx.iter().flatten().collect();
+        assert_eq!(input, b"typed at the host".to_vec());
+    }
+}
@@ -0,0 +1,202 @@
+//! Sets of virtual CPUs.
+//!
+//! A `CpuSet` holds VCPU IDs as a bitmap in the layout the kernel uses for
+//! `cpuset_t`, so it can be filled in directly by VM_GET_CPUS. It is used
+//! for the active and suspended VCPUs of a virtual machine, and for
+//! describing groups of VCPU threads.
+//!
+//!     use bhyve_api::cpuset::CpuSet;
+//!
+//!     let mut cpus = CpuSet::new();
+//!     cpus.set(0).unwrap();
+//!     cpus.set(3).unwrap();
+//!     let all: CpuSet = (0..4).collect();
+//!
+//!     assert_eq!((all - cpus).iter().collect::<Vec<_>>(), vec![1, 2]);
+//!     assert_eq!(cpus.to_string(), "0,3");
+
+use libc::EINVAL;
+use std::fmt;
+use std::iter::FromIterator;
+use std::ops::{BitAnd, BitOr, Not, Sub};
+
+use crate::include::vmm::VM_MAXCPU;
+use crate::Error;
+
+// Number of 64-bit words needed for a bit per VCPU.
+pub(crate) const CPUSET_WORDS: usize = (VM_MAXCPU - 1) / 64 + 1;
+
+/// A set of VCPU IDs, from 0 up to the maximum number of VCPUs.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct CpuSet {
+    bits: [u64; CPUSET_WORDS],
+}
+
+impl CpuSet {
+    /// Creates an empty set.
+    pub fn new() -> CpuSet {
+        CpuSet { bits: [0; CPUSET_WORDS] }
+    }
+
+    /// Creates a set containing every possible VCPU ID.
+    pub fn full() -> CpuSet {
+        !CpuSet::new()
+    }
+
+    /// Creates a set from the kernel representation of a cpuset.
+    pub(crate) fn from_bits(bits: [u64; CPUSET_WORDS]) -> CpuSet {
+        CpuSet { bits: bits } & CpuSet::full()
+    }
+
+    /// Returns the maximum number of VCPUs a set can hold.
+    pub fn capacity() -> usize {
+        VM_MAXCPU
+    }
+
+    /// Adds 'vcpu_id' to the set. Returns `EINVAL` if the ID is out of range.
+    pub fn set(&mut self, vcpu_id: i32) -> Result<(), Error> {
+        let (word, mask) = position(vcpu_id)?;
+        self.bits[word] |= mask;
+        Ok(())
+    }
+
+    /// Removes 'vcpu_id' from the set. Returns `EINVAL` if the ID is out of
+    /// range.
+    pub fn clear(&mut self, vcpu_id: i32) -> Result<(), Error> {
+        let (word, mask) = position(vcpu_id)?;
+        self.bits[word] &= !mask;
+        Ok(())
+    }
+
+    /// Returns true if 'vcpu_id' is in the set.
+    pub fn contains(&self, vcpu_id: i32) -> bool {
+        match position(vcpu_id) {
+            Ok((word, mask)) => (self.bits[word] & mask) != 0,
+            Err(_) => false,
+        }
+    }
+
+    /// Returns the number of VCPUs in the set.
+    pub fn len(&self) -> usize {
+        self.bits.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /// Returns true if the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|word| *word == 0)
+    }
+
+    /// Returns the lowest VCPU ID in the set, if any.
+    pub fn first(&self) -> Option<i32> {
+        self.iter().next()
+    }
+
+    /// Iterates over the VCPU IDs in the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = i32> {
+        let set = *self;
+        (0..VM_MAXCPU as i32).filter(move |id| set.contains(*id))
+    }
+}
+
+// Returns the word index and bit mask of 'vcpu_id' in the bitmap.
+fn position(vcpu_id: i32) -> Result<(usize, u64), Error> {
+    if vcpu_id < 0 || vcpu_id as usize >= VM_MAXCPU {
+        return Err(Error::new(EINVAL));
+    }
+    let id = vcpu_id as usize;
+    Ok((id / 64, 1 << (id % 64)))
+}
+
+impl BitOr for CpuSet {
+    type Output = CpuSet;
+
+    fn bitor(mut self, other: CpuSet) -> CpuSet {
+        for (word, other) in self.bits.iter_mut().zip(other.bits.iter()) {
+            *word |= other;
+        }
+        self
+    }
+}
+
+impl BitAnd for CpuSet {
+    type Output = CpuSet;
+
+    fn bitand(mut self, other: CpuSet) -> CpuSet {
+        for (word, other) in self.bits.iter_mut().zip(other.bits.iter()) {
+            *word &= other;
+        }
+        self
+    }
+}
+
+impl Sub for CpuSet {
+    type Output = CpuSet;
+
+    fn sub(self, other: CpuSet) -> CpuSet {
+        self & !other
+    }
+}
+
+impl Not for CpuSet {
+    type Output = CpuSet;
+
+    fn not(mut self) -> CpuSet {
+        for (index, word) in self.bits.iter_mut().enumerate() {
+            // Only VCPU IDs below VM_MAXCPU are valid
+            let valid = match VM_MAXCPU - index * 64 {
+                n if n >= 64 => !0,
+                n => (1 << n) - 1,
+            };
+            *word = !*word & valid;
+        }
+        self
+    }
+}
+
+impl FromIterator<i32> for CpuSet {
+    /// Collects VCPU IDs into a set, ignoring any that are out of range.
+    fn from_iter<I: IntoIterator<Item = i32>>(iter: I) -> CpuSet {
+        let mut set = CpuSet::new();
+        for vcpu_id in iter {
+            let _ = set.set(vcpu_id);
+        }
+        set
+    }
+}
+
+impl fmt::Display for CpuSet {
+    /// Formats the set as a comma separated list of VCPU IDs.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ids: Vec<String> = self.iter().map(|id| id.to_string()).collect();
+        write!(f, "{}", ids.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpuset_ops() {
+        let mut cpus = CpuSet::new();
+        assert!(cpus.is_empty());
+        cpus.set(1).unwrap();
+        cpus.set(VM_MAXCPU as i32 - 1).unwrap();
+        assert!(cpus.set(VM_MAXCPU as i32).is_err());
+        assert!(cpus.set(-1).is_err());
+        assert_eq!(cpus.len(), 2);
+        assert_eq!(cpus.first(), Some(1));
+
+        let low: CpuSet = (0..4).collect();
+        assert_eq!((cpus & low).iter().collect::<Vec<_>>(), vec![1]);
+        assert_eq!((low - cpus).to_string(), "0,2,3");
+        assert_eq!((cpus | low).len(), 5);
+        assert_eq!((!low).len(), VM_MAXCPU - 4);
+        assert_eq!(CpuSet::full().len(), CpuSet::capacity());
+
+        cpus.clear(1).unwrap();
+        assert!(!cpus.contains(1));
+        // Bits for IDs beyond the maximum are dropped
+        assert_eq!(CpuSet::from_bits([!0; CPUSET_WORDS]), CpuSet::full());
+    }
+}
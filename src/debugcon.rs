@@ -0,0 +1,159 @@
+//! Capture of the Bochs/QEMU debug console port.
+//!
+//! Firmware and early kernel code commonly print to port 0xE9 long before
+//! a UART driver is running: OVMF debug builds, SeaBIOS, and Linux with
+//! 'earlyprintk' all support it. `DebugCon` keeps the most recent output
+//! in a fixed-size ring buffer, which the VMM can read back at any time,
+//! such as after a failed boot. Use `PortStream` from the `portio` module
+//! instead to forward the output to a host stream as it is written.
+//!
+//!     use bhyve_api::debugcon::*;
+//!     use bhyve_api::device::PioBus;
+//!     use std::sync::{Arc, Mutex};
+//!
+//!     fn setup(bus: &mut PioBus) -> Result<Arc<Mutex<DebugCon>>, bhyve_api::Error> {
+//!         let debugcon = Arc::new(Mutex::new(DebugCon::new(64 * 1024)));
+//!         bus.register(DEBUGCON_PORT, 1, debugcon.clone())?;
+//!         Ok(debugcon)
+//!     }
+//!
+//!     fn report(debugcon: &Mutex<DebugCon>) {
+//!         let output = debugcon.lock().unwrap().contents();
+//!         eprintln!("{}", String::from_utf8_lossy(&output));
+//!     }
+
+use std::collections::VecDeque;
+
+use crate::device::{GuestDevice, GuestPioDevice, IoValue, IoWidth};
+
+/// I/O port of the debug console.
+pub const DEBUGCON_PORT: u16 = 0xe9;
+
+// Value read from the port, which lets guests detect the device.
+const DEBUGCON_READBACK: u32 = 0xe9;
+
+/// The debug console port, recording guest output in a ring buffer.
+///
+/// Register it on the `PioBus` at `DEBUGCON_PORT` with length 1.
+pub struct DebugCon {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    total: u64,
+    taken: u64, // bytes returned by take()
+}
+
+impl DebugCon {
+    /// Creates a debug console that keeps the last 'capacity' bytes of
+    /// output.
+    pub fn new(capacity: usize) -> DebugCon {
+        DebugCon {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity: capacity,
+            total: 0,
+            taken: 0,
+        }
+    }
+
+    /// Returns the number of bytes the buffer holds at most.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of bytes currently in the buffer.
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Returns true if the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Returns the number of bytes the guest has written since the device
+    /// was created or cleared, including any that have been overwritten.
+    pub fn total_written(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns the number of bytes that were overwritten before being read.
+    pub fn dropped(&self) -> u64 {
+        self.total - self.taken - self.buffer.len() as u64
+    }
+
+    /// Returns a copy of the buffered output, oldest byte first.
+    pub fn contents(&self) -> Vec<u8> {
+        self.buffer.iter().cloned().collect()
+    }
+
+    /// Returns the buffered output, oldest byte first, and empties the
+    /// buffer.
+    pub fn take(&mut self) -> Vec<u8> {
+        self.taken += self.buffer.len() as u64;
+        self.buffer.drain(..).collect()
+    }
+
+    /// Empties the buffer and resets the counters.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.total = 0;
+        self.taken = 0;
+    }
+
+    /// Appends 'data' to the buffer, overwriting the oldest output if it
+    /// is full.
+    pub fn push(&mut self, data: &[u8]) {
+        self.total += data.len() as u64;
+        if self.capacity == 0 {
+            return;
+        }
+        // Only the tail of a write larger than the buffer survives
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+        let excess = (self.buffer.len() + data.len()).saturating_sub(self.capacity);
+        self.buffer.drain(..excess);
+        self.buffer.extend(data);
+    }
+}
+
+impl GuestDevice for DebugCon {
+    // The output is kept across guest resets, since it's most useful for
+    // finding out why the guest reset.
+}
+
+impl GuestPioDevice for DebugCon {
+    fn pio_read(&mut self, _offset: u16, width: IoWidth) -> u32 {
+        DEBUGCON_READBACK & width.mask()
+    }
+
+    fn pio_write(&mut self, _offset: u16, value: IoValue) {
+        let data = value.value().to_le_bytes();
+        self.push(&data[..value.width().bytes() as usize]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut debugcon = DebugCon::new(4);
+        debugcon.pio_write(0, IoValue::new(IoWidth::Byte, 0x61));
+        debugcon.pio_write(0, IoValue::new(IoWidth::Word, 0x6362));
+        assert_eq!(debugcon.contents(), b"abc");
+        assert_eq!(debugcon.pio_read(0, IoWidth::Byte), 0xe9);
+
+        // The oldest output is overwritten
+        debugcon.push(b"def");
+        assert_eq!(debugcon.contents(), b"cdef");
+        assert_eq!(debugcon.dropped(), 2);
+        debugcon.push(b"0123456");
+        assert_eq!(debugcon.take(), b"3456");
+        assert!(debugcon.is_empty());
+        assert_eq!(debugcon.dropped(), 9);
+        // Taking output doesn't change the running total
+        assert_eq!(debugcon.total_written(), 13);
+        debugcon.push(b"g");
+        assert_eq!(debugcon.total_written(), 14);
+        assert_eq!(debugcon.dropped(), 9);
+    }
+}
@@ -0,0 +1,435 @@
+//! Common interfaces for emulated devices.
+//!
+//! Device models implement `GuestPioDevice` for port I/O, `GuestMmioDevice`
+//! for memory-mapped I/O, or both, and are registered on a `PioBus` or
+//! `MmioBus` that dispatches guest accesses to them by address. Keeping
+//! the traits here lets device crates interoperate without depending on
+//! each other.
+//!
+//!     use bhyve_api::device::*;
+//!     use std::sync::{Arc, Mutex};
+//!
+//!     struct Scratch(u8);
+//!
+//!     impl GuestDevice for Scratch {
+//!         fn reset(&mut self) {
+//!             self.0 = 0;
+//!         }
+//!     }
+//!
+//!     impl GuestPioDevice for Scratch {
+//!         fn pio_read(&mut self, _offset: u16, _width: IoWidth) -> u32 {
+//!             self.0 as u32
+//!         }
+//!         fn pio_write(&mut self, _offset: u16, value: IoValue) {
+//!             self.0 = value.as_u8();
+//!         }
+//!     }
+//!
+//!     let mut bus = PioBus::new();
+//!     bus.register(0x80, 1, Arc::new(Mutex::new(Scratch(0)))).unwrap();
+//!     assert!(bus.write(0x80, IoValue::new(IoWidth::Byte, 0x42)));
+//!     assert_eq!(bus.read(0x80, IoWidth::Byte).map(|v| v.value()), Some(0x42));
+
+use libc::{EEXIST, EINVAL, ENOTSUP};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::vm::{IoDirection, VirtualMachine, VmExit};
+use crate::Error;
+
+/// Operations common to all emulated devices.
+pub trait GuestDevice: Send {
+    /// Returns the device to its power-on state.
+    fn reset(&mut self) {}
+
+    /// Saves the device state for a snapshot or migration. Devices that
+    /// don't support saving their state return 'None'.
+    fn save_state(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Restores device state produced by `save_state()`.
+    fn restore_state(&mut self, _state: &[u8]) -> Result<(), Error> {
+        Err(Error::new(ENOTSUP))
+    }
+}
+
+/// The width of a port I/O access.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoWidth {
+    Byte,
+    Word,
+    Dword,
+}
+
+impl IoWidth {
+    /// Returns the width of an access of 'bytes' bytes, as reported by IN
+    /// and OUT exits, or 'None' if it isn't 1, 2, or 4.
+    pub fn from_bytes(bytes: u16) -> Option<IoWidth> {
+        match bytes {
+            1 => Some(IoWidth::Byte),
+            2 => Some(IoWidth::Word),
+            4 => Some(IoWidth::Dword),
+            _ => None,
+        }
+    }
+
+    /// Returns the width in bytes.
+    pub fn bytes(self) -> u16 {
+        match self {
+            IoWidth::Byte => 1,
+            IoWidth::Word => 2,
+            IoWidth::Dword => 4,
+        }
+    }
+
+    /// Returns a mask of the bits an access of this width covers.
+    pub fn mask(self) -> u32 {
+        match self {
+            IoWidth::Byte => 0xff,
+            IoWidth::Word => 0xffff,
+            IoWidth::Dword => 0xffff_ffff,
+        }
+    }
+}
+
+/// A value read or written by a port I/O access, together with the width of
+/// the access. Bits beyond the width are always zero.
+///
+/// Devices with registers wider than the access, such as a 4-byte register
+/// written one byte at a time, use `from_reg()` and `merge_into()` to read
+/// and update just the bytes the access covers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IoValue {
+    width: IoWidth,
+    value: u32,
+}
+
+impl IoValue {
+    /// Creates a value of 'width', discarding the bits of 'value' beyond it.
+    pub fn new(width: IoWidth, value: u32) -> IoValue {
+        IoValue { width: width, value: value & width.mask() }
+    }
+
+    /// Returns the bytes of the register 'reg' that an access of 'width' at
+    /// byte 'offset' within the register reads.
+    pub fn from_reg(width: IoWidth, reg: u32, offset: u16) -> IoValue {
+        IoValue::new(width, reg.checked_shr(offset as u32 * 8).unwrap_or(0))
+    }
+
+    /// Returns the width of the access.
+    pub fn width(&self) -> IoWidth {
+        self.width
+    }
+
+    /// Returns the value, zero extended to 32 bits.
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+
+    /// Returns the low byte of the value.
+    pub fn as_u8(&self) -> u8 {
+        self.value as u8
+    }
+
+    /// Returns the low two bytes of the value.
+    pub fn as_u16(&self) -> u16 {
+        self.value as u16
+    }
+
+    /// Returns the register 'reg' with the bytes this value was written to,
+    /// at byte 'offset' within the register, replaced by the value. Bytes
+    /// beyond the end of the register are dropped.
+    pub fn merge_into(&self, reg: u32, offset: u16) -> u32 {
+        let shift = offset as u32 * 8;
+        let mask = self.width.mask().checked_shl(shift).unwrap_or(0);
+        let value = self.value.checked_shl(shift).unwrap_or(0);
+        (reg & !mask) | value
+    }
+}
+
+/// A device accessed through I/O ports. Accesses are passed the offset from
+/// the base port of the device's range, and the width of the access.
+pub trait GuestPioDevice: GuestDevice {
+    /// Handles an IN instruction, returning the value read. Bits beyond
+    /// 'width' are discarded.
+    fn pio_read(&mut self, offset: u16, width: IoWidth) -> u32;
+
+    /// Handles an OUT instruction of 'value'.
+    fn pio_write(&mut self, offset: u16, value: IoValue);
+}
+
+/// A device accessed through guest physical memory. Accesses are passed the
+/// offset from the base address of the device's range, and the access size
+/// in bytes.
+pub trait GuestMmioDevice: GuestDevice {
+    /// Handles a read, returning the value read.
+    fn mmio_read(&mut self, offset: u64, size: u8) -> u64;
+
+    /// Handles a write of 'value'.
+    fn mmio_write(&mut self, offset: u64, size: u8, value: u64);
+}
+
+// Non-overlapping address ranges, keyed by base address, each mapped to the
+// length of the range and the device handling it.
+struct Ranges<D: ?Sized> {
+    ranges: BTreeMap<u64, (u64, Arc<Mutex<D>>)>,
+}
+
+impl<D: ?Sized> Ranges<D> {
+    fn new() -> Ranges<D> {
+        Ranges { ranges: BTreeMap::new() }
+    }
+
+    fn insert(&mut self, base: u64, len: u64, device: Arc<Mutex<D>>) -> Result<(), Error> {
+        let end = match base.checked_add(len) {
+            Some(end) if len > 0 => end,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        if let Some((prev_base, (prev_len, _))) = self.ranges.range(..end).next_back() {
+            if prev_base + prev_len > base {
+                return Err(Error::new(EEXIST));
+            }
+        }
+        self.ranges.insert(base, (len, device));
+        Ok(())
+    }
+
+    fn remove(&mut self, base: u64) -> Option<Arc<Mutex<D>>> {
+        self.ranges.remove(&base).map(|(_, device)| device)
+    }
+
+    // Finds the device covering 'addr', with the offset of 'addr' into its
+    // range.
+    fn find(&self, addr: u64) -> Option<(u64, &Arc<Mutex<D>>)> {
+        match self.ranges.range(..=addr).next_back() {
+            Some((base, (len, device))) if addr - base < *len => Some((addr - base, device)),
+            _ => None,
+        }
+    }
+
+    fn devices(&self) -> impl Iterator<Item = &Arc<Mutex<D>>> {
+        self.ranges.values().map(|(_, device)| device)
+    }
+}
+
+/// Dispatches port I/O to registered `GuestPioDevice`s.
+pub struct PioBus {
+    ranges: Ranges<dyn GuestPioDevice>,
+}
+
+impl PioBus {
+    /// Creates a bus with no devices.
+    pub fn new() -> PioBus {
+        PioBus { ranges: Ranges::new() }
+    }
+
+    /// Registers 'device' to handle the 'len' ports starting at 'base'.
+    /// Returns `EEXIST` if the range overlaps one already registered.
+    pub fn register(&mut self, base: u16, len: u16, device: Arc<Mutex<dyn GuestPioDevice>>) -> Result<(), Error> {
+        if base as u32 + len as u32 > 0x10000 {
+            return Err(Error::new(EINVAL));
+        }
+        self.ranges.insert(base as u64, len as u64, device)
+    }
+
+    /// Removes the device registered at 'base', returning it.
+    pub fn unregister(&mut self, base: u16) -> Option<Arc<Mutex<dyn GuestPioDevice>>> {
+        self.ranges.remove(base as u64)
+    }
+
+    /// Reads from 'port', or returns 'None' if no device handles it.
+    pub fn read(&self, port: u16, width: IoWidth) -> Option<IoValue> {
+        let (offset, device) = self.ranges.find(port as u64)?;
+        let value = device.lock().unwrap().pio_read(offset as u16, width);
+        Some(IoValue::new(width, value))
+    }
+
+    /// Writes to 'port', returning false if no device handles it.
+    pub fn write(&self, port: u16, value: IoValue) -> bool {
+        match self.ranges.find(port as u64) {
+            Some((offset, device)) => {
+                device.lock().unwrap().pio_write(offset as u16, value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Handles 'exit' if it is an IN or OUT on a registered port, updating
+    /// RAX on the VCPU for IN. Returns false for any other exit, which the
+    /// caller should handle itself.
+    pub fn handle(&self, vm: &VirtualMachine, vcpu_id: i32, exit: &VmExit) -> Result<bool, Error> {
+        match *exit {
+            VmExit::InOut(ref io) => {
+                let width = match IoWidth::from_bytes(io.bytes) {
+                    Some(width) => width,
+                    None => return Ok(false),
+                };
+                match io.direction {
+                    IoDirection::Out => Ok(self.write(io.port, IoValue::new(width, io.value))),
+                    IoDirection::In => {
+                        let value = match self.read(io.port, width) {
+                            Some(value) => value,
+                            None => return Ok(false),
+                        };
+                        vm.complete_inout(vcpu_id, io, value.value())?;
+                        Ok(true)
+                    }
+                }
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Resets every registered device.
+    pub fn reset_all(&self) {
+        for device in self.ranges.devices() {
+            device.lock().unwrap().reset();
+        }
+    }
+}
+
+impl Default for PioBus {
+    fn default() -> PioBus {
+        PioBus::new()
+    }
+}
+
+/// Dispatches memory-mapped I/O to registered `GuestMmioDevice`s.
+///
+/// The VM exits in this interface don't decode MMIO accesses, so the
+/// caller decodes the faulting instruction and calls `read()` or `write()`.
+pub struct MmioBus {
+    ranges: Ranges<dyn GuestMmioDevice>,
+}
+
+impl MmioBus {
+    /// Creates a bus with no devices.
+    pub fn new() -> MmioBus {
+        MmioBus { ranges: Ranges::new() }
+    }
+
+    /// Registers 'device' to handle the 'len' bytes of guest physical
+    /// address space starting at 'gpa'. Returns `EEXIST` if the range
+    /// overlaps one already registered.
+    pub fn register(&mut self, gpa: u64, len: u64, device: Arc<Mutex<dyn GuestMmioDevice>>) -> Result<(), Error> {
+        self.ranges.insert(gpa, len, device)
+    }
+
+    /// Removes the device registered at 'gpa', returning it.
+    pub fn unregister(&mut self, gpa: u64) -> Option<Arc<Mutex<dyn GuestMmioDevice>>> {
+        self.ranges.remove(gpa)
+    }
+
+    /// Reads from 'gpa', or returns 'None' if no device handles it.
+    pub fn read(&self, gpa: u64, size: u8) -> Option<u64> {
+        let (offset, device) = self.ranges.find(gpa)?;
+        Some(device.lock().unwrap().mmio_read(offset, size))
+    }
+
+    /// Writes to 'gpa', returning false if no device handles it.
+    pub fn write(&self, gpa: u64, size: u8, value: u64) -> bool {
+        match self.ranges.find(gpa) {
+            Some((offset, device)) => {
+                device.lock().unwrap().mmio_write(offset, size, value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Resets every registered device.
+    pub fn reset_all(&self) {
+        for device in self.ranges.devices() {
+            device.lock().unwrap().reset();
+        }
+    }
+}
+
+impl Default for MmioBus {
+    fn default() -> MmioBus {
+        MmioBus::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records the last write, and reads back the offset of the access.
+    struct Probe {
+        last: Option<(u64, u8, u64)>,
+    }
+
+    impl GuestDevice for Probe {
+        fn reset(&mut self) {
+            self.last = None;
+        }
+    }
+
+    impl GuestPioDevice for Probe {
+        fn pio_read(&mut self, offset: u16, _width: IoWidth) -> u32 {
+            0xabcd_0000 | offset as u32
+        }
+        fn pio_write(&mut self, offset: u16, value: IoValue) {
+            self.last = Some((offset as u64, value.width().bytes() as u8, value.value() as u64));
+        }
+    }
+
+    impl GuestMmioDevice for Probe {
+        fn mmio_read(&mut self, offset: u64, _size: u8) -> u64 {
+            offset
+        }
+        fn mmio_write(&mut self, offset: u64, size: u8, value: u64) {
+            self.last = Some((offset, size, value));
+        }
+    }
+
+    #[test]
+    fn test_pio_dispatch() {
+        let probe = Arc::new(Mutex::new(Probe { last: None }));
+        let mut bus = PioBus::new();
+        bus.register(0x3f8, 8, probe.clone()).unwrap();
+        assert!(bus.register(0x3f0, 9, Arc::new(Mutex::new(Probe { last: None }))).is_err());
+        assert!(bus.register(0x400, 1, Arc::new(Mutex::new(Probe { last: None }))).is_ok());
+
+        assert_eq!(bus.read(0x3fd, IoWidth::Byte), Some(IoValue::new(IoWidth::Byte, 5)));
+        assert_eq!(bus.read(0x3fd, IoWidth::Dword).map(|v| v.value()), Some(0xabcd_0005));
+        assert_eq!(bus.read(0x3f7, IoWidth::Byte), None);
+        // Bits beyond the access width are dropped
+        assert!(bus.write(0x3f9, IoValue::new(IoWidth::Word, 0xff1234)));
+        assert_eq!(probe.lock().unwrap().last, Some((1, 2, 0x1234)));
+
+        bus.reset_all();
+        assert_eq!(probe.lock().unwrap().last, None);
+        assert!(bus.unregister(0x3f8).is_some());
+        assert_eq!(bus.read(0x3fd, IoWidth::Byte), None);
+    }
+
+    #[test]
+    fn test_io_value_reg() {
+        let reg = 0x11223344;
+        assert_eq!(IoValue::from_reg(IoWidth::Byte, reg, 2).value(), 0x22);
+        assert_eq!(IoValue::from_reg(IoWidth::Word, reg, 3).value(), 0x11);
+        assert_eq!(IoValue::new(IoWidth::Byte, 0xaa).merge_into(reg, 1), 0x1122aa44);
+        assert_eq!(IoValue::new(IoWidth::Word, 0xbbaa).merge_into(reg, 3), 0xaa223344);
+        assert_eq!(IoValue::new(IoWidth::Dword, 0xaabbccdd).merge_into(reg, 0), 0xaabbccdd);
+        assert_eq!(IoWidth::from_bytes(3), None);
+    }
+
+    #[test]
+    fn test_mmio_dispatch() {
+        let probe = Arc::new(Mutex::new(Probe { last: None }));
+        let mut bus = MmioBus::new();
+        bus.register(0xc000_0000, 0x1000, probe.clone()).unwrap();
+        assert!(bus.register(0xbfff_f000, 0x1001, Arc::new(Mutex::new(Probe { last: None }))).is_err());
+        assert!(bus.register(0xd000_0000, 0, Arc::new(Mutex::new(Probe { last: None }))).is_err());
+
+        assert_eq!(bus.read(0xc000_0010, 4), Some(0x10));
+        assert_eq!(bus.read(0xc000_1000, 4), None);
+        assert!(bus.write(0xc000_0ff8, 8, 7));
+        assert_eq!(probe.lock().unwrap().last, Some((0xff8, 8, 7)));
+    }
+}
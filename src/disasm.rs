@@ -0,0 +1,54 @@
+//! Disassembly of instructions that the guest needs emulated.
+//!
+//! When a VMM doesn't handle an instruction emulation exit, usually an
+//! access to an MMIO range with no device behind it, the faulting
+//! instruction is the most useful thing to report. This module is only
+//! built with the `disasm` feature, which adds a dependency on `iced-x86`.
+//!
+//!     use bhyve_api::vm::*;
+//!
+//!     fn report(exit: &VmExit) {
+//!         if let VmExit::InstEmul(gpa, _, _, inst) = exit {
+//!             eprintln!("unhandled access to {:#x}: {}", gpa, inst.disassemble());
+//!         }
+//!     }
+
+use iced_x86::{Decoder, DecoderOptions, Formatter, IntelFormatter};
+
+use crate::vm::FaultingInst;
+
+impl FaultingInst {
+    /// Returns the instruction in Intel syntax, prefixed with its address
+    /// and bytes, or a placeholder if the bytes don't decode.
+    pub fn disassemble(&self) -> String {
+        let bytes = self.bytes();
+        if bytes.is_empty() {
+            return format!("{:#x}: <instruction not fetched>", self.rip);
+        }
+        let mut decoder = Decoder::with_ip(self.bitness, bytes, self.rip, DecoderOptions::NONE);
+        let instruction = decoder.decode();
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        if instruction.is_invalid() {
+            return format!("{:#x}: {} <invalid instruction>", self.rip, hex.join(" "));
+        }
+
+        let mut text = String::new();
+        IntelFormatter::new().format(&instruction, &mut text);
+        format!("{:#x}: {} {}", self.rip, hex[..instruction.len()].join(" "), text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        // mov [rax],ebx followed by trailing bytes that aren't part of it
+        let inst = FaultingInst::new(0x1000, 64, &[0x89, 0x18, 0x90, 0x90]);
+        assert_eq!(inst.disassemble(), "0x1000: 89 18 mov [rax],ebx");
+
+        let inst = FaultingInst::new(0x7c00, 16, &[]);
+        assert_eq!(inst.disassemble(), "0x7c00: <instruction not fetched>");
+    }
+}
@@ -0,0 +1,279 @@
+//! Snapshots of VCPU register state for crash reports.
+//!
+//! A `VcpuDump` gathers the registers that bhyvectl shows with
+//! `--get-all`, so a VMM can log the full state of a VCPU when the guest
+//! fails, without a separate tool. A dump can also be loaded back into a
+//! VCPU with `restore()`, which batches the register writes so that a
+//! restore takes a dozen ioctls rather than one per register.
+//!
+//!     use bhyve_api::vm::*;
+//!
+//!     fn report(vm: &VirtualMachine, vcpu_id: i32) -> Result<(), bhyve_api::Error> {
+//!         eprintln!("{}", vm.dump_vcpu(vcpu_id)?);
+//!         Ok(())
+//!     }
+
+use libc::EINVAL;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::vm::{vm_reg_name, VirtualMachine};
+use crate::Error;
+
+// Registers captured in every dump, in display order.
+const REGS: [(&str, vm_reg_name); 30] = [
+    ("rax", vm_reg_name::VM_REG_GUEST_RAX),
+    ("rbx", vm_reg_name::VM_REG_GUEST_RBX),
+    ("rcx", vm_reg_name::VM_REG_GUEST_RCX),
+    ("rdx", vm_reg_name::VM_REG_GUEST_RDX),
+    ("rsi", vm_reg_name::VM_REG_GUEST_RSI),
+    ("rdi", vm_reg_name::VM_REG_GUEST_RDI),
+    ("rbp", vm_reg_name::VM_REG_GUEST_RBP),
+    ("rsp", vm_reg_name::VM_REG_GUEST_RSP),
+    ("r8", vm_reg_name::VM_REG_GUEST_R8),
+    ("r9", vm_reg_name::VM_REG_GUEST_R9),
+    ("r10", vm_reg_name::VM_REG_GUEST_R10),
+    ("r11", vm_reg_name::VM_REG_GUEST_R11),
+    ("r12", vm_reg_name::VM_REG_GUEST_R12),
+    ("r13", vm_reg_name::VM_REG_GUEST_R13),
+    ("r14", vm_reg_name::VM_REG_GUEST_R14),
+    ("r15", vm_reg_name::VM_REG_GUEST_R15),
+    ("rip", vm_reg_name::VM_REG_GUEST_RIP),
+    ("rflags", vm_reg_name::VM_REG_GUEST_RFLAGS),
+    ("cr0", vm_reg_name::VM_REG_GUEST_CR0),
+    ("cr2", vm_reg_name::VM_REG_GUEST_CR2),
+    ("cr3", vm_reg_name::VM_REG_GUEST_CR3),
+    ("cr4", vm_reg_name::VM_REG_GUEST_CR4),
+    ("dr0", vm_reg_name::VM_REG_GUEST_DR0),
+    ("dr1", vm_reg_name::VM_REG_GUEST_DR1),
+    ("dr2", vm_reg_name::VM_REG_GUEST_DR2),
+    ("dr3", vm_reg_name::VM_REG_GUEST_DR3),
+    ("dr6", vm_reg_name::VM_REG_GUEST_DR6),
+    ("dr7", vm_reg_name::VM_REG_GUEST_DR7),
+    ("efer", vm_reg_name::VM_REG_GUEST_EFER),
+    ("intr_shadow", vm_reg_name::VM_REG_GUEST_INTR_SHADOW),
+];
+
+// Segment registers, with whether they have a selector. The descriptor
+// table registers only have a base and limit.
+const SEGMENTS: [(&str, vm_reg_name, bool); 10] = [
+    ("cs", vm_reg_name::VM_REG_GUEST_CS, true),
+    ("ds", vm_reg_name::VM_REG_GUEST_DS, true),
+    ("es", vm_reg_name::VM_REG_GUEST_ES, true),
+    ("fs", vm_reg_name::VM_REG_GUEST_FS, true),
+    ("gs", vm_reg_name::VM_REG_GUEST_GS, true),
+    ("ss", vm_reg_name::VM_REG_GUEST_SS, true),
+    ("ldtr", vm_reg_name::VM_REG_GUEST_LDTR, true),
+    ("tr", vm_reg_name::VM_REG_GUEST_TR, true),
+    ("gdtr", vm_reg_name::VM_REG_GUEST_GDTR, false),
+    ("idtr", vm_reg_name::VM_REG_GUEST_IDTR, false),
+];
+
+// A segment descriptor to load: the register, base, limit, and access.
+type DescWrite = (vm_reg_name, u64, u32, u32);
+
+// The descriptor writes of a restore, and the register writes to batch.
+type RestorePlan = (Vec<DescWrite>, Vec<(vm_reg_name, u64)>);
+
+/// The state of a segment register.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SegmentDump {
+    pub name: &'static str,
+    /// Selector, or 'None' for the descriptor table registers.
+    pub selector: Option<u16>,
+    pub base: u64,
+    pub limit: u32,
+    pub access: u32,
+}
+
+/// The register state of a VCPU at one point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VcpuDump {
+    pub vcpu_id: i32,
+    /// General purpose, control, and debug registers, and EFER, by name.
+    pub regs: Vec<(&'static str, u64)>,
+    pub segments: Vec<SegmentDump>,
+    /// Pending event injection information, as returned by `get_intinfo()`.
+    pub intinfo: (u64, u64),
+}
+
+impl VcpuDump {
+    /// Captures the registers of the VCPU. The VCPU should not be running,
+    /// or the registers may change while they are read.
+    pub fn capture(vm: &VirtualMachine, vcpu_id: i32) -> Result<VcpuDump, Error> {
+        let names: Vec<vm_reg_name> = REGS.iter().map(|(_, reg)| *reg).collect();
+        let values = vm.get_registers(vcpu_id, &names)?;
+        let regs = REGS.iter().map(|(name, _)| *name).zip(values).collect();
+
+        let mut segments = Vec::with_capacity(SEGMENTS.len());
+        for (name, reg, has_selector) in SEGMENTS.iter() {
+            let (base, limit, access) = vm.get_desc(vcpu_id, *reg)?;
+            let selector = match has_selector {
+                true => Some(vm.get_register(vcpu_id, *reg)? as u16),
+                false => None,
+            };
+            segments.push(SegmentDump { name: name, selector: selector, base: base, limit: limit, access: access });
+        }
+
+        Ok(VcpuDump {
+            vcpu_id: vcpu_id,
+            regs: regs,
+            segments: segments,
+            intinfo: vm.get_intinfo(vcpu_id)?,
+        })
+    }
+
+    /// Loads the registers in the dump into the VCPU identified by
+    /// 'vcpu_id', which should not be running. Segment descriptors are
+    /// loaded first, then the selectors and the other registers in one
+    /// batch, and the first part of the pending event information is
+    /// restored with `set_intinfo()`; the second part is maintained by the
+    /// kernel and can't be set.
+    ///
+    /// The descriptors take one `VM_SET_SEGMENT_DESCRIPTOR` ioctl each:
+    /// `VM_SET_REGISTER_SET` carries a single 64-bit value per register,
+    /// which the kernel loads into the selector of a segment register, and
+    /// it rejects the descriptor table registers, so there is no batched
+    /// path for base, limit, and access.
+    pub fn restore(&self, vm: &VirtualMachine, vcpu_id: i32) -> Result<(), Error> {
+        self.restore_with_stats(vm, vcpu_id).map(|_| ())
+    }
+
+    /// Loads the dump into the VCPU as `restore()` does, and reports how
+    /// many ioctls it took and how long, for tracking restore and migration
+    /// downtime.
+    pub fn restore_with_stats(&self, vm: &VirtualMachine, vcpu_id: i32) -> Result<RestoreStats, Error> {
+        let start = Instant::now();
+        let (descs, regs) = self.restore_plan()?;
+        // The descriptors can't be batched, see restore()
+        for (reg, base, limit, access) in descs.iter() {
+            vm.set_desc(vcpu_id, *reg, *base, *limit, *access)?;
+        }
+        vm.set_registers(vcpu_id, &regs)?;
+        vm.set_intinfo(vcpu_id, self.intinfo.0)?;
+        Ok(RestoreStats {
+            ioctls: descs.len() + 2,
+            unbatched_ioctls: descs.len() + regs.len() + 1,
+            elapsed: start.elapsed(),
+        })
+    }
+
+    // Splits the dump into the descriptor writes, and the register writes
+    // that can be batched, with the segment selectors ahead of the rest.
+    fn restore_plan(&self) -> Result<RestorePlan, Error> {
+        let mut descs = Vec::with_capacity(self.segments.len());
+        let mut regs = Vec::with_capacity(self.segments.len() + self.regs.len());
+        for seg in self.segments.iter() {
+            let reg = match SEGMENTS.iter().find(|(name, _, _)| *name == seg.name) {
+                Some((_, reg, _)) => *reg,
+                None => return Err(Error::new(EINVAL)),
+            };
+            descs.push((reg, seg.base, seg.limit, seg.access));
+            if let Some(selector) = seg.selector {
+                regs.push((reg, selector as u64));
+            }
+        }
+        for (name, value) in self.regs.iter() {
+            let reg = match REGS.iter().find(|(reg_name, _)| reg_name == name) {
+                Some((_, reg)) => *reg,
+                None => return Err(Error::new(EINVAL)),
+            };
+            regs.push((reg, *value));
+        }
+        Ok((descs, regs))
+    }
+
+    /// Returns the value of the register called 'name' (for example "rip").
+    pub fn reg(&self, name: &str) -> Option<u64> {
+        self.regs.iter().find(|(reg, _)| *reg == name).map(|(_, value)| *value)
+    }
+}
+
+/// The cost of loading a `VcpuDump` into a VCPU.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RestoreStats {
+    /// The number of ioctls the restore made.
+    pub ioctls: usize,
+    /// The number of ioctls the restore would have made setting one
+    /// register at a time.
+    pub unbatched_ioctls: usize,
+    /// How long the restore took.
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for VcpuDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "vcpu {}", self.vcpu_id)?;
+        // Two registers per line
+        for pair in self.regs.chunks(2) {
+            for (i, (name, value)) in pair.iter().enumerate() {
+                if i > 0 {
+                    write!(f, "  ")?;
+                }
+                write!(f, "{:>11} {:#018x}", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        for seg in self.segments.iter() {
+            let selector = match seg.selector {
+                Some(selector) => format!("{:#06x}", selector),
+                None => String::from("-"),
+            };
+            writeln!(f, "{:>11} sel {:>6} base {:#018x} limit {:#010x} access {:#010x}",
+                     seg.name, selector, seg.base, seg.limit, seg.access)?;
+        }
+        write!(f, "{:>11} {:#018x} {:#018x}", "intinfo", self.intinfo.0, self.intinfo.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let dump = VcpuDump {
+            vcpu_id: 1,
+            regs: vec![("rax", 1), ("rip", 0xfff0), ("efer", 0x500)],
+            segments: vec![
+                SegmentDump { name: "cs", selector: Some(0xf000), base: 0xffff0000, limit: 0xffff, access: 0x93 },
+                SegmentDump { name: "gdtr", selector: None, base: 0, limit: 0xffff, access: 0 },
+            ],
+            intinfo: (0, 0),
+        };
+        assert_eq!(dump.reg("rip"), Some(0xfff0));
+        assert_eq!(dump.reg("cr3"), None);
+
+        let text = dump.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "vcpu 1");
+        assert_eq!(lines[1], "        rax 0x0000000000000001          rip 0x000000000000fff0");
+        assert_eq!(lines[2], "       efer 0x0000000000000500");
+        assert!(lines[3].contains("sel 0xf000 base 0x00000000ffff0000"));
+        assert!(lines[4].contains("sel      -"));
+    }
+
+    #[test]
+    fn test_restore_plan() {
+        let mut dump = VcpuDump {
+            vcpu_id: 0,
+            regs: vec![("rax", 1), ("rip", 0xfff0)],
+            segments: vec![
+                SegmentDump { name: "cs", selector: Some(0xf000), base: 0xffff0000, limit: 0xffff, access: 0x93 },
+                SegmentDump { name: "gdtr", selector: None, base: 0, limit: 0xffff, access: 0 },
+            ],
+            intinfo: (0, 0),
+        };
+        let (descs, regs) = dump.restore_plan().unwrap();
+        assert_eq!(descs.len(), 2);
+        assert_eq!(regs.len(), 3);
+        match regs[0] {
+            (vm_reg_name::VM_REG_GUEST_CS, 0xf000) => (),
+            _ => panic!("expected the CS selector first"),
+        }
+
+        dump.regs.push(("bogus", 0));
+        assert!(dump.restore_plan().is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! Errors returned by the Bhyve API library.
+//!
+//! Most failures come straight from the kernel as an `errno` value, but some
+//! operations are validated in userspace first, so the caller gets a
+//! description of what went wrong instead of a bare `EINVAL` from an ioctl.
+
+use std::fmt;
+use std::io;
+
+use vmm_sys_util::errno;
+
+use crate::storm::ExitSource;
+use crate::vm::{GuestRegion, MemMap};
+
+/// The error type for Bhyve API operations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// A system call failed with the contained `errno` value.
+    Errno(errno::Error),
+    /// A bootrom image is empty, or too large to fit below 4GB once padded
+    /// to the page size.
+    BootromSize { len: usize, max: usize },
+    /// A guest physical address range overlaps a region reserved for
+    /// in-kernel device emulation.
+    ReservedRange { gpa: u64, len: u64, region: &'static str },
+    /// An ioctl failed with `EFAULT` or `ENOTTY`, which usually means the
+    /// argument struct doesn't match the layout the kernel expects.
+    Ioctl { name: &'static str, size: usize, errno: errno::Error },
+    /// The process lacks the privileges or devices needed for 'operation'.
+    /// The 'hint' describes what is missing.
+    Privilege { operation: &'static str, errno: errno::Error, hint: &'static str },
+    /// The kernel's VMM interface version differs from the one this crate's
+    /// ioctl structs were written for.
+    AbiMismatch { expected: i32, found: i32 },
+    /// A guest physical range can't be mapped because it overlaps the
+    /// contained existing mapping.
+    AlreadyMapped(MemMap),
+    /// A guest physical address range overlaps a region already set up by
+    /// the library, such as lowmem or the bootrom.
+    RegionOverlap { gpa: u64, len: u64, region: GuestRegion },
+    /// A change to the guest memory mappings would need 'needed' mappings,
+    /// more than the 'max' the kernel allows.
+    TooManyMappings { needed: usize, max: usize },
+    /// An `ExitStormGuard`'s policy stopped a VCPU after 'exits' exits from
+    /// 'source' within one window.
+    ExitStorm { vcpu_id: i32, source: ExitSource, exits: u64 },
+    /// An exit handler run by a `VcpuScheduler` panicked while handling an
+    /// exit of VCPU 'vcpu_id'.
+    HandlerPanicked { vcpu_id: i32 },
+}
+
+impl Error {
+    /// Constructs an `Error` from a raw `errno` value.
+    pub fn new(errno: i32) -> Error {
+        Error::Errno(errno::Error::new(errno))
+    }
+
+    /// Constructs an `Error` from the last `errno` value set by a system call.
+    pub fn last() -> Error {
+        Error::Errno(errno::Error::last())
+    }
+
+    /// Constructs an `Error` for a failed ioctl from the last `errno` value.
+    /// Failures that point to a struct layout mismatch between this library
+    /// and the kernel are annotated with the ioctl 'name' and the 'size' of
+    /// its argument struct.
+    pub(crate) fn ioctl(name: &'static str, size: usize) -> Error {
+        let e = errno::Error::last();
+        match e.errno() {
+            libc::EFAULT | libc::ENOTTY => Error::Ioctl { name: name, size: size, errno: e },
+            _ => Error::Errno(e),
+        }
+    }
+
+    /// Returns the `errno` value that best describes the error. Errors that
+    /// are detected in userspace report the value the kernel would most
+    /// likely have returned for the same request.
+    pub fn errno(&self) -> i32 {
+        match self {
+            Error::Errno(e) => e.errno(),
+            Error::Ioctl { errno, .. } => errno.errno(),
+            Error::Privilege { errno, .. } => errno.errno(),
+            Error::AbiMismatch { .. } => libc::ENOTSUP,
+            Error::AlreadyMapped(_) => libc::EEXIST,
+            Error::TooManyMappings { .. } => libc::ENOSPC,
+            Error::ExitStorm { .. } => libc::EIO,
+            Error::HandlerPanicked { .. } => libc::EIO,
+            _ => libc::EINVAL,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Errno(e) => write!(f, "{}", e),
+            Error::BootromSize { len, max } => {
+                write!(f, "bootrom size {:#x} is outside the supported range (1 to {:#x} bytes)", len, max)
+            }
+            Error::ReservedRange { gpa, len, region } => {
+                write!(f, "guest physical range {:#x}-{:#x} overlaps the reserved {} region",
+                       gpa, gpa + len, region)
+            }
+            Error::Ioctl { name, size, errno } => {
+                write!(f, "{} failed: {} (argument struct is {:#x} bytes; the kernel may expect a \
+                           different layout, check that the library matches the kernel's VMM interface)",
+                       name, errno, size)
+            }
+            Error::Privilege { operation, errno, hint } => {
+                write!(f, "unable to {}: {} ({})", operation, errno, hint)
+            }
+            Error::AbiMismatch { expected, found } => {
+                write!(f, "kernel VMM interface version {} does not match version {} supported by this library",
+                       found, expected)
+            }
+            Error::RegionOverlap { gpa, len, region } => {
+                write!(f, "guest physical range {:#x}-{:#x} overlaps the {} region at {:#x}-{:#x}",
+                       gpa, gpa + len, region.name, region.gpa, region.gpa + region.len)
+            }
+            Error::AlreadyMapped(map) => {
+                write!(f, "guest physical range overlaps the existing mapping of segment {} at {:#x}-{:#x}",
+                       map.segid, map.gpa, map.gpa + map.len as u64)
+            }
+            Error::TooManyMappings { needed, max } => {
+                write!(f, "the change needs {} guest memory mappings, more than the kernel's limit of {}", needed, max)
+            }
+            Error::ExitStorm { vcpu_id, source, exits } => {
+                write!(f, "VCPU {} stopped after {} exits from {} within one window", vcpu_id, exits, source)
+            }
+            Error::HandlerPanicked { vcpu_id } => {
+                write!(f, "the exit handler of VCPU {} panicked", vcpu_id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<errno::Error> for Error {
+    fn from(e: errno::Error) -> Error {
+        Error::Errno(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Errno(errno::Error::from(e))
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Errno(e) => io::Error::from(e),
+            Error::Ioctl { errno, .. } | Error::Privilege { errno, .. } => {
+                io::Error::new(io::Error::from(errno).kind(), e.to_string())
+            }
+            Error::AlreadyMapped(_) => io::Error::new(io::ErrorKind::AlreadyExists, e.to_string()),
+            _ => io::Error::new(io::ErrorKind::InvalidInput, e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,322 @@
+//! A poll(2) based event loop for device backends.
+//!
+//! Device backends wait on host file descriptors: a serial port on a
+//! socket, a block device on an I/O completion pipe, a network device on a
+//! tap device. A `Reactor` waits on all of them in one thread and calls the
+//! handler registered for each descriptor that becomes ready, without
+//! requiring an async runtime. Other threads use a `Waker` to interrupt the
+//! wait, or to stop the loop.
+//!
+//!     use bhyve_api::event::*;
+//!     use std::io::Read;
+//!     use std::net::TcpStream;
+//!     use std::os::unix::io::AsRawFd;
+//!
+//!     fn serve(mut console: TcpStream) -> Result<(), bhyve_api::Error> {
+//!         let mut reactor = Reactor::new()?;
+//!         let fd = console.as_raw_fd();
+//!         reactor.register(fd, Interest::READABLE, move |ready| {
+//!             let mut buf = [0; 64];
+//!             match console.read(&mut buf) {
+//!                 Ok(n) if n > 0 && !ready.hangup => EventAction::Keep,
+//!                 _ => EventAction::Remove,
+//!             }
+//!         })?;
+//!         // Hand reactor.waker() to the threads that need to stop the loop
+//!         reactor.run()
+//!     }
+
+use libc::{c_void, pollfd, EAGAIN, EINTR, ENOENT, POLLERR, POLLHUP, POLLIN, POLLNVAL, POLLOUT};
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::Error;
+
+/// The readiness a handler is interested in.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Interest {
+    pub readable: bool,
+    pub writable: bool,
+}
+
+impl Interest {
+    /// Interest in the descriptor becoming readable.
+    pub const READABLE: Interest = Interest { readable: true, writable: false };
+    /// Interest in the descriptor becoming writable.
+    pub const WRITABLE: Interest = Interest { readable: false, writable: true };
+    /// Interest in either.
+    pub const BOTH: Interest = Interest { readable: true, writable: true };
+
+    fn events(self) -> i16 {
+        let mut events = 0;
+        if self.readable {
+            events |= POLLIN;
+        }
+        if self.writable {
+            events |= POLLOUT;
+        }
+        events
+    }
+}
+
+/// The state of a descriptor reported to its handler.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Readiness {
+    pub readable: bool,
+    pub writable: bool,
+    /// The peer has closed its end, or the device has gone away.
+    pub hangup: bool,
+    /// An error is pending on the descriptor, or it isn't open.
+    pub error: bool,
+}
+
+impl Readiness {
+    fn from_revents(revents: i16) -> Readiness {
+        Readiness {
+            readable: (revents & POLLIN) != 0,
+            writable: (revents & POLLOUT) != 0,
+            hangup: (revents & POLLHUP) != 0,
+            error: (revents & (POLLERR | POLLNVAL)) != 0,
+        }
+    }
+}
+
+/// What the reactor should do with a handler after calling it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EventAction {
+    /// Keep waiting on the descriptor.
+    Keep,
+    /// Unregister the descriptor. The reactor doesn't close it.
+    Remove,
+}
+
+/// Identifies a registered descriptor.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Token(u64);
+
+struct Registration {
+    token: Token,
+    fd: RawFd,
+    interest: Interest,
+    handler: Box<dyn FnMut(Readiness) -> EventAction + Send>,
+}
+
+// The pipe used to interrupt poll() from other threads.
+struct WakePipe {
+    read: File,
+    write: File,
+    stopped: AtomicBool,
+}
+
+/// A handle for waking a `Reactor` from another thread.
+#[derive(Clone)]
+pub struct Waker {
+    pipe: Arc<WakePipe>,
+}
+
+impl Waker {
+    /// Interrupts the reactor's current or next wait, so it can notice
+    /// state that changed outside of its descriptors.
+    pub fn wake(&self) -> Result<(), Error> {
+        let byte = 1u8;
+        let result = unsafe { libc::write(self.pipe.write.as_raw_fd(), &byte as *const u8 as *const c_void, 1) };
+        if result < 0 {
+            let e = Error::last();
+            // A full pipe already guarantees a wakeup
+            if e.errno() != EAGAIN {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes `Reactor::run()` return once it has finished dispatching the
+    /// current events.
+    pub fn stop(&self) -> Result<(), Error> {
+        self.pipe.stopped.store(true, Ordering::SeqCst);
+        self.wake()
+    }
+}
+
+/// Waits on registered file descriptors, and calls their handlers when
+/// they become ready.
+pub struct Reactor {
+    registrations: Vec<Registration>,
+    next_token: u64,
+    pipe: Arc<WakePipe>,
+}
+
+impl Reactor {
+    /// Creates a reactor with no registered descriptors.
+    pub fn new() -> Result<Reactor, Error> {
+        // Create the pipe non-blocking and close-on-exec in one step, so it
+        // never leaks into a child forked by another thread.
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+            return Err(Error::last());
+        }
+        // Safe because the descriptors were just created, and are owned by
+        // the files from here on.
+        let (read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        Ok(Reactor {
+            registrations: Vec::new(),
+            next_token: 0,
+            pipe: Arc::new(WakePipe { read: read, write: write, stopped: AtomicBool::new(false) }),
+        })
+    }
+
+    /// Returns a handle for waking or stopping the reactor from another
+    /// thread.
+    pub fn waker(&self) -> Waker {
+        Waker { pipe: Arc::clone(&self.pipe) }
+    }
+
+    /// Calls 'handler' whenever 'fd' is ready as described by 'interest',
+    /// until it returns `EventAction::Remove` or is unregistered. The
+    /// descriptor must stay open while it is registered, and should be
+    /// non-blocking so the handler can't stall the loop.
+    pub fn register<F>(&mut self, fd: RawFd, interest: Interest, handler: F) -> Result<Token, Error>
+        where F: FnMut(Readiness) -> EventAction + Send + 'static
+    {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        self.registrations.push(Registration {
+            token: token,
+            fd: fd,
+            interest: interest,
+            handler: Box::new(handler),
+        });
+        Ok(token)
+    }
+
+    /// Changes the readiness that the handler for 'token' is called for,
+    /// for example to wait for a socket to become writable only while
+    /// output is queued. Returns `ENOENT` if the token isn't registered.
+    pub fn set_interest(&mut self, token: Token, interest: Interest) -> Result<(), Error> {
+        match self.registrations.iter_mut().find(|r| r.token == token) {
+            Some(registration) => {
+                registration.interest = interest;
+                Ok(())
+            }
+            None => Err(Error::new(ENOENT)),
+        }
+    }
+
+    /// Unregisters the descriptor for 'token'. Returns false if it wasn't
+    /// registered.
+    pub fn unregister(&mut self, token: Token) -> bool {
+        let before = self.registrations.len();
+        self.registrations.retain(|r| r.token != token);
+        self.registrations.len() != before
+    }
+
+    /// Waits up to 'timeout' (forever for 'None') for registered descriptors
+    /// to become ready or for the reactor to be woken, and calls the
+    /// handlers of those that are ready. Returns the number of handlers
+    /// called, which is zero on a timeout or wakeup.
+    pub fn poll_once(&mut self, timeout: Option<Duration>) -> Result<usize, Error> {
+        let mut fds: Vec<pollfd> = Vec::with_capacity(self.registrations.len() + 1);
+        fds.push(pollfd { fd: self.pipe.read.as_raw_fd(), events: POLLIN, revents: 0 });
+        for registration in self.registrations.iter() {
+            fds.push(pollfd { fd: registration.fd, events: registration.interest.events(), revents: 0 });
+        }
+        let timeout_ms = match timeout {
+            Some(timeout) => timeout.as_millis().min(0x7fff_ffff) as i32,
+            None => -1,
+        };
+
+        let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+        if result < 0 {
+            let e = Error::last();
+            return match e.errno() {
+                EINTR => Ok(0),
+                _ => Err(e),
+            };
+        }
+        if fds[0].revents != 0 {
+            self.drain_wakeups();
+        }
+
+        // The poll results are in registration order, after the wakeup pipe,
+        // and the list doesn't change until every handler has been called.
+        let mut called = 0;
+        let mut removed = Vec::new();
+        for (registration, fd) in self.registrations.iter_mut().zip(fds[1..].iter()) {
+            if fd.revents == 0 {
+                continue;
+            }
+            called += 1;
+            if (registration.handler)(Readiness::from_revents(fd.revents)) == EventAction::Remove {
+                removed.push(registration.token);
+            }
+        }
+        self.registrations.retain(|r| !removed.contains(&r.token));
+        Ok(called)
+    }
+
+    /// Dispatches events until `Waker::stop()` is called.
+    pub fn run(&mut self) -> Result<(), Error> {
+        while !self.pipe.stopped.load(Ordering::SeqCst) {
+            self.poll_once(None)?;
+        }
+        Ok(())
+    }
+
+    // Empties the wakeup pipe, so the next poll() waits again.
+    fn drain_wakeups(&self) {
+        let mut buf = [0u8; 64];
+        let mut read = &self.pipe.read;
+        while let Ok(n) = read.read(&mut buf) {
+            if n < buf.len() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn test_reactor_dispatch() {
+        let mut reactor = Reactor::new().unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (mut read, mut write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handler_seen = Arc::clone(&seen);
+        reactor.register(fds[0], Interest::READABLE, move |ready| {
+            let mut buf = [0; 1];
+            if ready.readable && read.read(&mut buf).unwrap() == 1 {
+                handler_seen.lock().unwrap().push(buf[0]);
+                return EventAction::Keep;
+            }
+            EventAction::Remove
+        }).unwrap();
+
+        assert_eq!(reactor.poll_once(Some(Duration::from_millis(1))).unwrap(), 0);
+        write.write_all(&[7]).unwrap();
+        assert_eq!(reactor.poll_once(Some(Duration::from_secs(5))).unwrap(), 1);
+        assert_eq!(*seen.lock().unwrap(), vec![7]);
+
+        // Closing the write end hangs up the pipe, and the handler removes
+        // itself
+        drop(write);
+        assert_eq!(reactor.poll_once(Some(Duration::from_secs(5))).unwrap(), 1);
+        assert!(reactor.registrations.is_empty());
+
+        let waker = reactor.waker();
+        let stopper = thread::spawn(move || waker.stop().unwrap());
+        reactor.run().unwrap();
+        stopper.join().unwrap();
+    }
+}
@@ -0,0 +1,44 @@
+//! Detection of optional Bhyve kernel interfaces.
+//!
+//! Not every kernel that provides `/dev/vmm` supports the full set of ioctls.
+//! The probes here run once, when a `VirtualMachine` is opened, so higher
+//! layers can check for an interface before relying on it instead of hitting
+//! `ENOTTY` part way through an operation.
+
+use libc::{ioctl, ENOTTY};
+use std::os::unix::io::RawFd;
+
+use crate::include::vmm_dev::{vm_munmap, VM_MUNMAP_MEMSEG};
+use crate::Error;
+
+/// Optional ioctls supported by the running kernel.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct KernelFeatures {
+    /// Unmapping memory segments from the guest address space.
+    pub munmap_memseg: bool,
+}
+
+impl KernelFeatures {
+    /// Probes the VM device behind 'fd' for optional ioctls. Each probe is
+    /// issued with arguments the kernel rejects without side effects, so only
+    /// an `ENOTTY` result marks the ioctl as unsupported.
+    ///
+    /// The vmm-data, dirty tracking, and CPUID control ioctls are not part of
+    /// the interface version this crate is written against, so there is
+    /// nothing to probe for them.
+    pub(crate) fn probe(fd: RawFd) -> KernelFeatures {
+        // A zero-length unmap never matches an existing mapping.
+        let munmap_data = vm_munmap { gpa: 0, len: 0 };
+        let munmap_result = unsafe { ioctl(fd, VM_MUNMAP_MEMSEG, &munmap_data) };
+
+        KernelFeatures {
+            munmap_memseg: supported(munmap_result),
+        }
+    }
+}
+
+// Interprets the result of a probe ioctl, which must be checked immediately
+// after the call so errno is still meaningful.
+fn supported(result: i32) -> bool {
+    result == 0 || Error::last().errno() != ENOTTY
+}
@@ -0,0 +1,145 @@
+//! Routing of global system interrupts (GSIs) to injection ioctls.
+//!
+//! Device models raise interrupts by GSI number, and a `GsiRouter` decides
+//! how each one reaches the guest: on an I/O APIC pin, on a legacy ISA IRQ
+//! (which drives both the 8259 PIC and the I/O APIC), or as a message
+//! signaled interrupt. Changing the platform configuration then only means
+//! changing the routes.
+//!
+//!     use bhyve_api::gsi::*;
+//!     use bhyve_api::vm::VirtualMachine;
+//!     use std::sync::Arc;
+//!
+//!     fn route(vm: Arc<VirtualMachine>) -> Result<(), bhyve_api::Error> {
+//!         let router = GsiRouter::new(vm);
+//!         router.add_isa_defaults();
+//!         router.set_route(16, GsiRoute::Ioapic(16));
+//!         router.pulse(4)?;   // COM1
+//!         router.assert(16)?;
+//!         Ok(())
+//!     }
+
+use libc::ENOENT;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::vm::VirtualMachine;
+use crate::Error;
+
+/// Number of legacy ISA IRQs.
+pub const NUM_ISA_IRQS: u32 = 16;
+
+/// How a GSI is delivered to the guest.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum GsiRoute {
+    /// An I/O APIC pin.
+    Ioapic(i32 /* pin */),
+    /// A legacy ISA IRQ, on an 8259 PIC line and an I/O APIC pin. Either
+    /// may be -1 if the IRQ isn't connected to that controller.
+    Isa(i32 /* atpic irq */, i32 /* ioapic pin */),
+    /// A message signaled interrupt. MSIs are edge triggered, so asserting
+    /// the GSI sends the message and deasserting it does nothing.
+    Msi(u64 /* addr */, u64 /* msg */),
+}
+
+/// Returns the conventional route for legacy ISA IRQ 'irq': identity mapped
+/// to the I/O APIC, except that the PIT on IRQ 0 is wired to pin 2, and
+/// IRQ 2 (the 8259 cascade) has no route.
+pub fn isa_default_route(irq: u32) -> Option<GsiRoute> {
+    match irq {
+        0 => Some(GsiRoute::Isa(0, 2)),
+        2 => None,
+        irq if irq < NUM_ISA_IRQS => Some(GsiRoute::Isa(irq as i32, irq as i32)),
+        _ => None,
+    }
+}
+
+/// Maps GSIs to routes, and raises interrupts through them.
+pub struct GsiRouter {
+    vm: Arc<VirtualMachine>,
+    routes: RwLock<HashMap<u32, GsiRoute>>,
+}
+
+impl GsiRouter {
+    /// Creates a router for 'vm' with no routes.
+    pub fn new(vm: Arc<VirtualMachine>) -> GsiRouter {
+        GsiRouter { vm: vm, routes: RwLock::new(HashMap::new()) }
+    }
+
+    /// Routes GSIs 0 to 15 to the legacy ISA IRQs, as given by
+    /// `isa_default_route()`.
+    pub fn add_isa_defaults(&self) {
+        let mut routes = self.routes.write().unwrap();
+        for gsi in 0..NUM_ISA_IRQS {
+            if let Some(route) = isa_default_route(gsi) {
+                routes.insert(gsi, route);
+            }
+        }
+    }
+
+    /// Sets the route for 'gsi', replacing any existing route.
+    pub fn set_route(&self, gsi: u32, route: GsiRoute) {
+        self.routes.write().unwrap().insert(gsi, route);
+    }
+
+    /// Removes the route for 'gsi', returning it.
+    pub fn remove_route(&self, gsi: u32) -> Option<GsiRoute> {
+        self.routes.write().unwrap().remove(&gsi)
+    }
+
+    /// Returns the route for 'gsi'.
+    pub fn route(&self, gsi: u32) -> Option<GsiRoute> {
+        self.routes.read().unwrap().get(&gsi).cloned()
+    }
+
+    fn lookup(&self, gsi: u32) -> Result<GsiRoute, Error> {
+        self.route(gsi).ok_or(Error::new(ENOENT))
+    }
+
+    /// Asserts the interrupt line for 'gsi'. Returns `ENOENT` if the GSI has
+    /// no route.
+    pub fn assert(&self, gsi: u32) -> Result<(), Error> {
+        match self.lookup(gsi)? {
+            GsiRoute::Ioapic(pin) => self.vm.ioapic_assert_irq(pin)?,
+            GsiRoute::Isa(atpic, ioapic) => self.vm.isa_assert_irq(atpic, ioapic)?,
+            GsiRoute::Msi(addr, msg) => self.vm.lapic_msi(addr, msg)?,
+        };
+        Ok(())
+    }
+
+    /// Deasserts the interrupt line for 'gsi'. Returns `ENOENT` if the GSI
+    /// has no route.
+    pub fn deassert(&self, gsi: u32) -> Result<(), Error> {
+        match self.lookup(gsi)? {
+            GsiRoute::Ioapic(pin) => self.vm.ioapic_deassert_irq(pin)?,
+            GsiRoute::Isa(atpic, ioapic) => self.vm.isa_deassert_irq(atpic, ioapic)?,
+            GsiRoute::Msi(..) => true,
+        };
+        Ok(())
+    }
+
+    /// Asserts and then deasserts the interrupt line for 'gsi', for edge
+    /// triggered interrupts. Returns `ENOENT` if the GSI has no route.
+    pub fn pulse(&self, gsi: u32) -> Result<(), Error> {
+        match self.lookup(gsi)? {
+            GsiRoute::Ioapic(pin) => self.vm.ioapic_pulse_irq(pin)?,
+            GsiRoute::Isa(atpic, ioapic) => self.vm.isa_pulse_irq(atpic, ioapic)?,
+            GsiRoute::Msi(addr, msg) => self.vm.lapic_msi(addr, msg)?,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_isa_default_route() {
+        assert_eq!(isa_default_route(0), Some(GsiRoute::Isa(0, 2)));
+        assert_eq!(isa_default_route(2), None);
+        assert_eq!(isa_default_route(4), Some(GsiRoute::Isa(4, 4)));
+        assert_eq!(isa_default_route(15), Some(GsiRoute::Isa(15, 15)));
+        assert_eq!(isa_default_route(16), None);
+    }
+}
@@ -0,0 +1,135 @@
+//! Rollback of partially completed VM setup.
+//!
+//! Setting up a virtual machine takes a sequence of kernel operations, and
+//! a failure (or panic) part way through leaves a half-built VM instance in
+//! the kernel, which outlives the process. A `SetupGuard` records an undo
+//! action for each completed step and runs them in reverse order when it is
+//! dropped, unless the sequence was committed.
+//!
+//!     use bhyve_api::guard::SetupGuard;
+//!     use bhyve_api::system::*;
+//!     use bhyve_api::vm::*;
+//!
+//!     fn build(system: &VMMSystem, name: &str) -> Result<VirtualMachine, bhyve_api::Error> {
+//!         let mut guard = SetupGuard::new();
+//!         system.create_vm(name)?;
+//!         guard.created_vm(system, name);
+//!
+//!         let vm = VirtualMachine::new(name)?;
+//!         vm.set_topology(1, 1, 1)?;
+//!         guard.commit();
+//!         Ok(vm)
+//!     }
+
+use std::fmt;
+
+use crate::system::VMMSystem;
+use crate::vm::VirtualMachine;
+
+/// Undo actions for the completed steps of a setup sequence.
+pub struct SetupGuard<'a> {
+    steps: Vec<(String, Box<dyn FnOnce() + 'a>)>,
+}
+
+impl<'a> SetupGuard<'a> {
+    /// Creates a guard with no recorded steps.
+    pub fn new() -> SetupGuard<'a> {
+        SetupGuard { steps: Vec::new() }
+    }
+
+    /// Records a completed step named 'name', with the action that undoes it.
+    pub fn push<F>(&mut self, name: &str, undo: F) where F: FnOnce() + 'a {
+        self.steps.push((name.to_string(), Box::new(undo)));
+    }
+
+    /// Records the creation of the VM device 'name', undone by destroying it.
+    /// Destroying the VM also releases its memory segments and mappings.
+    pub fn created_vm(&mut self, system: &'a VMMSystem, name: &str) {
+        let vm_name = name.to_string();
+        self.push(&format!("create VM {}", name), move || {
+            let _ = system.destroy_vm(&vm_name);
+        });
+    }
+
+    /// Records a guest mapping of [gpa,gpa+len), undone by unmapping it.
+    pub fn mapped(&mut self, vm: &'a VirtualMachine, gpa: u64, len: usize) {
+        self.push(&format!("map {:#x}-{:#x}", gpa, gpa + len as u64), move || {
+            let _ = vm.munmap_memseg(gpa, len);
+        });
+    }
+
+    /// Returns the names of the recorded steps, in the order they completed.
+    pub fn steps(&self) -> Vec<&str> {
+        self.steps.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// Marks the setup sequence as complete, discarding the undo actions.
+    pub fn commit(mut self) {
+        self.steps.clear();
+    }
+
+    /// Runs the undo actions immediately, most recent step first.
+    pub fn rollback(mut self) {
+        self.undo_all();
+    }
+
+    fn undo_all(&mut self) {
+        while let Some((_, undo)) = self.steps.pop() {
+            undo();
+        }
+    }
+}
+
+impl<'a> Default for SetupGuard<'a> {
+    fn default() -> SetupGuard<'a> {
+        SetupGuard::new()
+    }
+}
+
+impl<'a> fmt::Debug for SetupGuard<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SetupGuard").field("steps", &self.steps()).finish()
+    }
+}
+
+impl<'a> Drop for SetupGuard<'a> {
+    fn drop(&mut self) {
+        self.undo_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::panic;
+
+    #[test]
+    fn test_rollback_order() {
+        let undone = RefCell::new(Vec::new());
+        {
+            let mut guard = SetupGuard::new();
+            guard.push("first", || undone.borrow_mut().push(1));
+            guard.push("second", || undone.borrow_mut().push(2));
+            assert_eq!(guard.steps(), vec!["first", "second"]);
+        }
+        assert_eq!(*undone.borrow(), vec![2, 1]);
+
+        let mut guard = SetupGuard::new();
+        guard.push("third", || undone.borrow_mut().push(3));
+        guard.commit();
+        assert_eq!(*undone.borrow(), vec![2, 1]);
+    }
+
+    #[test]
+    fn test_rollback_on_panic() {
+        let undone = RefCell::new(false);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let mut guard = SetupGuard::new();
+            guard.push("step", || *undone.borrow_mut() = true);
+            panic!("setup failed");
+        }));
+        assert!(result.is_err());
+        assert!(*undone.borrow());
+    }
+}
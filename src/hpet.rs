@@ -0,0 +1,141 @@
+//! Configuration of the in-kernel HPET for guests.
+//!
+//! Bhyve emulates an HPET at a fixed guest physical address. Guests only
+//! use it if it is described by an ACPI HPET table, which must match the
+//! capabilities of the emulated device, so the table is built here from the
+//! capabilities reported by the kernel.
+//!
+//!     use bhyve_api::vm::*;
+//!
+//!     fn hpet_table(vm: &VirtualMachine) -> Result<Option<Vec<u8>>, bhyve_api::Error> {
+//!         match vm.configure_hpet()? {
+//!             Some(hpet) => Ok(Some(hpet.table().to_vec())),
+//!             None => Ok(None),
+//!         }
+//!     }
+
+use libc::{ENOTTY, ENXIO};
+
+use crate::vm::VirtualMachine;
+use crate::Error;
+
+/// Guest physical address of the emulated HPET registers.
+pub const HPET_BASE: u64 = 0xfed00000;
+
+/// Length of the ACPI HPET table.
+pub const HPET_TABLE_LEN: usize = 56;
+
+// Identification written into the ACPI table header.
+const OEM_ID: &[u8; 6] = b"BHYVE ";
+const OEM_TABLE_ID: &[u8; 8] = b"BVHPET  ";
+const CREATOR_ID: &[u8; 4] = b"BHYV";
+
+/// The in-kernel HPET, as advertised to the guest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HpetConfig {
+    capabilities: u32,
+    table: Vec<u8>,
+}
+
+impl HpetConfig {
+    /// Queries the HPET capabilities of 'vm'. Returns 'None' if the kernel
+    /// doesn't emulate an HPET, or reports one without any timers, in which
+    /// case it shouldn't be advertised to the guest.
+    pub fn probe(vm: &VirtualMachine) -> Result<Option<HpetConfig>, Error> {
+        let capabilities = match vm.get_hpet_capabilities() {
+            Ok(capabilities) => capabilities,
+            Err(e) if e.errno() == ENOTTY || e.errno() == ENXIO => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(HpetConfig::from_capabilities(capabilities))
+    }
+
+    /// Builds the configuration for an HPET with the given capabilities.
+    pub fn from_capabilities(capabilities: u32) -> Option<HpetConfig> {
+        // The vendor ID is never zero on a real device
+        if capabilities >> 16 == 0 {
+            return None;
+        }
+        Some(HpetConfig { capabilities: capabilities, table: build_table(capabilities) })
+    }
+
+    /// Returns the lower 32 bits of the HPET General Capabilities and ID
+    /// register.
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    /// Returns the number of comparators (timers) in the HPET.
+    pub fn num_timers(&self) -> u32 {
+        ((self.capabilities >> 8) & 0x1f) + 1
+    }
+
+    /// Returns true if the main counter is 64 bits wide.
+    pub fn counter_64bit(&self) -> bool {
+        self.capabilities & (1 << 13) != 0
+    }
+
+    /// Returns true if the HPET can replace the legacy PIT and RTC
+    /// interrupt routing.
+    pub fn legacy_replacement(&self) -> bool {
+        self.capabilities & (1 << 15) != 0
+    }
+
+    /// Returns the ACPI HPET table describing the device, with a valid
+    /// checksum.
+    pub fn table(&self) -> &[u8] {
+        &self.table
+    }
+}
+
+// Builds an ACPI HPET table (ACPI "IA-PC HPET Specification", table 3) for
+// the HPET at HPET_BASE.
+fn build_table(capabilities: u32) -> Vec<u8> {
+    let mut table = Vec::with_capacity(HPET_TABLE_LEN);
+    // System description table header
+    table.extend_from_slice(b"HPET");
+    table.extend_from_slice(&(HPET_TABLE_LEN as u32).to_le_bytes());
+    table.push(1);      // revision
+    table.push(0);      // checksum, filled in below
+    table.extend_from_slice(OEM_ID);
+    table.extend_from_slice(OEM_TABLE_ID);
+    table.extend_from_slice(&1u32.to_le_bytes());    // OEM revision
+    table.extend_from_slice(CREATOR_ID);
+    table.extend_from_slice(&1u32.to_le_bytes());    // creator revision
+    // Event timer block ID
+    table.extend_from_slice(&capabilities.to_le_bytes());
+    // Base address, as a generic address structure in system memory
+    table.extend_from_slice(&[0, 0, 0, 0]);
+    table.extend_from_slice(&HPET_BASE.to_le_bytes());
+    table.push(0);      // HPET number
+    table.extend_from_slice(&0u16.to_le_bytes());    // minimum clock tick
+    table.push(0);      // page protection
+
+    let sum = table.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    table[9] = 0u8.wrapping_sub(sum);
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hpet_config() {
+        // Capabilities reported by bhyve: 8 timers, 64-bit counter, legacy
+        // replacement, Intel vendor ID
+        let hpet = HpetConfig::from_capabilities(0x8086a701).expect("HPET should be advertised");
+        assert_eq!(hpet.num_timers(), 8);
+        assert!(hpet.counter_64bit());
+        assert!(hpet.legacy_replacement());
+
+        let table = hpet.table();
+        assert_eq!(table.len(), HPET_TABLE_LEN);
+        assert_eq!(&table[0..4], b"HPET");
+        assert_eq!(table.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)), 0);
+        assert_eq!(&table[36..40], &0x8086a701u32.to_le_bytes());
+        assert_eq!(&table[44..52], &HPET_BASE.to_le_bytes());
+
+        assert_eq!(HpetConfig::from_capabilities(0), None);
+    }
+}
@@ -0,0 +1,415 @@
+//! An emulated i8042 PS/2 keyboard controller.
+//!
+//! Firmware and many guests expect a keyboard controller to be present,
+//! even without a keyboard attached: they run its self test, use it to
+//! enable the A20 line, and reset the machine by pulsing its reset output.
+//! `I8042` emulates the controller and a PS/2 keyboard behind it, and
+//! accepts scancodes from the host with `inject_scancodes()`. No auxiliary
+//! (mouse) device is attached.
+//!
+//!     use bhyve_api::device::PioBus;
+//!     use bhyve_api::i8042::*;
+//!     use bhyve_api::vm::VirtualMachine;
+//!     use std::sync::{Arc, Mutex};
+//!
+//!     fn setup(vm: Arc<VirtualMachine>, bus: &mut PioBus) -> Result<Arc<Mutex<I8042>>, bhyve_api::Error> {
+//!         let kbc = Arc::new(Mutex::new(I8042::new(vm)));
+//!         register_i8042(bus, &kbc)?;
+//!         // Type 'a' (scancode set 1, as translated by the controller)
+//!         kbc.lock().unwrap().inject_scancodes(&[0x1e, 0x9e])?;
+//!         Ok(kbc)
+//!     }
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::a20::A20Gate;
+use crate::device::{GuestDevice, GuestPioDevice, IoValue, IoWidth, PioBus};
+use crate::vm::VirtualMachine;
+use crate::Error;
+
+/// I/O port of the data register.
+pub const I8042_DATA_PORT: u16 = 0x60;
+/// I/O port of the status register (reads) and command register (writes).
+pub const I8042_CMD_PORT: u16 = 0x64;
+/// ISA IRQ raised when keyboard data is available.
+pub const KBD_IRQ: i32 = 1;
+
+// Status register bits.
+const STS_OUTPUT_FULL: u8 = 0x01;
+const STS_SYS_FLAG: u8 = 0x04;
+const STS_LAST_CMD: u8 = 0x08;  // last write was to the command port
+const STS_UNLOCKED: u8 = 0x10;  // keyboard not inhibited by the keylock
+
+// Configuration byte bits.
+const CFG_KBD_INT: u8 = 0x01;
+const CFG_SYS_FLAG: u8 = 0x04;
+const CFG_KBD_DISABLED: u8 = 0x10;
+const CFG_AUX_DISABLED: u8 = 0x20;
+const CFG_TRANSLATE: u8 = 0x40;
+
+// Output port bits.
+const OUT_RESET: u8 = 0x01;     // active low system reset
+const OUT_A20: u8 = 0x02;
+
+// Keyboard replies.
+const KBD_ACK: u8 = 0xfa;
+const KBD_RESEND: u8 = 0xfe;
+const KBD_SELF_TEST_OK: u8 = 0xaa;
+
+const OUTPUT_QUEUE_MAX: usize = 16;
+
+// State of the controller and keyboard, without the side effects on the VM.
+#[derive(Debug)]
+struct Kbc {
+    config: u8,
+    output_port: u8,
+    last_cmd: bool,
+    // Controller command waiting for its data byte
+    pending_cmd: Option<u8>,
+    // Keyboard command waiting for its argument byte
+    pending_kbd: Option<u8>,
+    scanning: bool,
+    scancode_set: u8,
+    leds: u8,
+    output: VecDeque<u8>,
+    last_output: u8,
+    // Set when new data becomes readable, until taken by the device
+    irq: bool,
+    // Set when the guest asks for a system reset
+    reset: bool,
+}
+
+impl Kbc {
+    fn new() -> Kbc {
+        Kbc {
+            config: CFG_KBD_INT | CFG_SYS_FLAG | CFG_AUX_DISABLED | CFG_TRANSLATE,
+            output_port: OUT_RESET | OUT_A20,
+            last_cmd: false,
+            pending_cmd: None,
+            pending_kbd: None,
+            scanning: true,
+            scancode_set: 2,
+            leds: 0,
+            output: VecDeque::new(),
+            last_output: 0,
+            irq: false,
+            reset: false,
+        }
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = STS_UNLOCKED;
+        if !self.output.is_empty() {
+            status |= STS_OUTPUT_FULL;
+        }
+        if (self.config & CFG_SYS_FLAG) != 0 {
+            status |= STS_SYS_FLAG;
+        }
+        if self.last_cmd {
+            status |= STS_LAST_CMD;
+        }
+        status
+    }
+
+    fn push_output(&mut self, byte: u8) {
+        if self.output.len() >= OUTPUT_QUEUE_MAX {
+            return;
+        }
+        if self.output.is_empty() {
+            self.irq = true;
+        }
+        self.output.push_back(byte);
+    }
+
+    fn read_data(&mut self) -> u8 {
+        if let Some(byte) = self.output.pop_front() {
+            self.last_output = byte;
+            // The next byte is delivered with another interrupt
+            if !self.output.is_empty() {
+                self.irq = true;
+            }
+        }
+        self.last_output
+    }
+
+    fn write_command(&mut self, cmd: u8) {
+        self.last_cmd = true;
+        self.pending_cmd = None;
+        match cmd {
+            0x20 => self.push_output(self.config),
+            0x60 | 0xd1 | 0xd2 | 0xd3 | 0xd4 => self.pending_cmd = Some(cmd),
+            0xa7 => self.config |= CFG_AUX_DISABLED,
+            0xa8 => self.config &= !CFG_AUX_DISABLED,
+            // Port tests pass, and the self test succeeds
+            0xa9 | 0xab => self.push_output(0x00),
+            0xaa => self.push_output(0x55),
+            0xad => self.config |= CFG_KBD_DISABLED,
+            0xae => self.config &= !CFG_KBD_DISABLED,
+            0xd0 => self.push_output(self.output_port),
+            // A20 control commands found on some controllers
+            0xdd => self.output_port &= !OUT_A20,
+            0xdf => self.output_port |= OUT_A20,
+            // Pulse output port bits low, where bit 0 is the reset line
+            0xf0..=0xff if (cmd & OUT_RESET) == 0 => self.reset = true,
+            _ => (),
+        }
+    }
+
+    fn write_data(&mut self, data: u8) {
+        self.last_cmd = false;
+        match self.pending_cmd.take() {
+            Some(0x60) => self.config = data,
+            Some(0xd1) => {
+                self.output_port = data;
+                if (data & OUT_RESET) == 0 {
+                    self.reset = true;
+                }
+            }
+            Some(0xd2) => self.push_output(data),
+            // No auxiliary device is attached, so its data is dropped
+            Some(0xd3) | Some(0xd4) => (),
+            _ => {
+                // Writing to the keyboard enables it
+                self.config &= !CFG_KBD_DISABLED;
+                self.keyboard_write(data);
+            }
+        }
+    }
+
+    fn keyboard_write(&mut self, data: u8) {
+        if let Some(cmd) = self.pending_kbd.take() {
+            match cmd {
+                0xed => self.leds = data & 0x7,
+                0xf0 if data == 0 => {
+                    self.push_output(KBD_ACK);
+                    let set = self.scancode_set;
+                    self.push_output(set);
+                    return;
+                }
+                0xf0 if data <= 3 => self.scancode_set = data,
+                _ => (),
+            }
+            self.push_output(KBD_ACK);
+            return;
+        }
+        match data {
+            0xed | 0xf0 | 0xf3 => {
+                self.pending_kbd = Some(data);
+                self.push_output(KBD_ACK);
+            }
+            0xee => self.push_output(0xee),
+            0xf2 => {
+                self.push_output(KBD_ACK);
+                self.push_output(0xab);
+                self.push_output(0x83);
+            }
+            0xf4 => {
+                self.scanning = true;
+                self.push_output(KBD_ACK);
+            }
+            // Both restore the defaults, and 0xf5 also stops scanning
+            0xf5 | 0xf6 => {
+                self.scanning = data == 0xf6;
+                self.scancode_set = 2;
+                self.push_output(KBD_ACK);
+            }
+            0xff => {
+                self.output.clear();
+                self.scanning = true;
+                self.scancode_set = 2;
+                self.leds = 0;
+                self.push_output(KBD_ACK);
+                self.push_output(KBD_SELF_TEST_OK);
+            }
+            0xf7..=0xfd => self.push_output(KBD_ACK),
+            _ => self.push_output(KBD_RESEND),
+        }
+    }
+
+    fn inject(&mut self, scancodes: &[u8]) -> bool {
+        if !self.scanning || (self.config & CFG_KBD_DISABLED) != 0 {
+            return false;
+        }
+        for byte in scancodes {
+            self.push_output(*byte);
+        }
+        true
+    }
+}
+
+/// The i8042 keyboard controller, with a PS/2 keyboard attached.
+///
+/// Register it on the `PioBus` with `register_i8042()`, which handles both
+/// of its ports.
+pub struct I8042 {
+    vm: Arc<VirtualMachine>,
+    kbc: Kbc,
+    a20: A20Gate,
+}
+
+impl I8042 {
+    /// Creates the controller for 'vm', in its power-on state.
+    pub fn new(vm: Arc<VirtualMachine>) -> I8042 {
+        I8042 { vm: vm, kbc: Kbc::new(), a20: A20Gate::new() }
+    }
+
+    /// Makes the controller's output port drive 'gate', so the A20 state
+    /// is shared with System Control Port A and visible to the VMM. By
+    /// default the controller has a gate of its own.
+    pub fn set_a20_gate(&mut self, gate: A20Gate) {
+        self.a20 = gate;
+    }
+
+    /// Queues scancodes from the keyboard for the guest to read, raising
+    /// the keyboard interrupt if the guest has enabled it. The scancodes
+    /// should be in set 1 while `translation_enabled()` is true, which is
+    /// how most guests run the controller, and in `scancode_set()`
+    /// otherwise. Returns false, dropping the scancodes, while the guest has
+    /// disabled the keyboard. Scancodes are also dropped once the
+    /// controller's buffer is full.
+    pub fn inject_scancodes(&mut self, scancodes: &[u8]) -> Result<bool, Error> {
+        let accepted = self.kbc.inject(scancodes);
+        self.update_irq()?;
+        Ok(accepted)
+    }
+
+    /// Returns true if the controller translates keyboard scancodes to set 1.
+    pub fn translation_enabled(&self) -> bool {
+        (self.kbc.config & CFG_TRANSLATE) != 0
+    }
+
+    /// Returns the scancode set the guest has selected on the keyboard.
+    pub fn scancode_set(&self) -> u8 {
+        self.kbc.scancode_set
+    }
+
+    /// Returns the keyboard LEDs the guest has set: bit 0 is Scroll Lock,
+    /// bit 1 Num Lock, and bit 2 Caps Lock.
+    pub fn leds(&self) -> u8 {
+        self.kbc.leds
+    }
+
+    /// Returns true if the controller's A20 gate output is enabled. bhyve
+    /// guests always run with A20 enabled, so this only reflects what the
+    /// guest asked for.
+    pub fn a20_enabled(&self) -> bool {
+        self.a20.enabled()
+    }
+
+    fn read(&mut self, port: u16) -> u8 {
+        match port {
+            I8042_DATA_PORT => self.kbc.read_data(),
+            _ => self.kbc.status(),
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u8) {
+        // The gate may have been changed through another port since
+        if self.a20.enabled() {
+            self.kbc.output_port |= OUT_A20;
+        } else {
+            self.kbc.output_port &= !OUT_A20;
+        }
+        match port {
+            I8042_DATA_PORT => self.kbc.write_data(value),
+            _ => self.kbc.write_command(value),
+        }
+        self.a20.set_enabled((self.kbc.output_port & OUT_A20) != 0);
+        if self.kbc.reset {
+            self.kbc.reset = false;
+            self.kbc.output_port |= OUT_RESET;
+            // This fails if the VM is already suspended, which leaves it
+            // stopped anyway.
+            let _ = self.vm.reset();
+        }
+    }
+
+    // Raises the keyboard interrupt if new data became readable.
+    fn update_irq(&mut self) -> Result<(), Error> {
+        let irq = self.kbc.irq;
+        self.kbc.irq = false;
+        if irq && (self.kbc.config & CFG_KBD_INT) != 0 {
+            self.vm.isa_pulse_irq(KBD_IRQ, KBD_IRQ)?;
+        }
+        Ok(())
+    }
+}
+
+impl GuestDevice for I8042 {
+    fn reset(&mut self) {
+        self.kbc = Kbc::new();
+        self.a20.set_enabled(true);
+    }
+}
+
+// Routes one of the controller's ports to the shared controller.
+struct I8042Port {
+    kbc: Arc<Mutex<I8042>>,
+    port: u16,
+}
+
+impl GuestDevice for I8042Port {}
+
+impl GuestPioDevice for I8042Port {
+    fn pio_read(&mut self, _offset: u16, width: IoWidth) -> u32 {
+        let mut kbc = self.kbc.lock().unwrap();
+        let value = kbc.read(self.port);
+        let _ = kbc.update_irq();
+        IoValue::new(width, value as u32).value()
+    }
+
+    fn pio_write(&mut self, _offset: u16, value: IoValue) {
+        let mut kbc = self.kbc.lock().unwrap();
+        kbc.write(self.port, value.as_u8());
+        let _ = kbc.update_irq();
+    }
+}
+
+/// Registers the data and command ports of 'kbc' on 'bus'.
+pub fn register_i8042(bus: &mut PioBus, kbc: &Arc<Mutex<I8042>>) -> Result<(), Error> {
+    for port in [I8042_DATA_PORT, I8042_CMD_PORT].iter() {
+        let device = I8042Port { kbc: Arc::clone(kbc), port: *port };
+        bus.register(*port, 1, Arc::new(Mutex::new(device)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kbc_commands() {
+        let mut kbc = Kbc::new();
+        kbc.write_command(0xaa);
+        assert_eq!(kbc.status() & (STS_OUTPUT_FULL | STS_LAST_CMD), STS_OUTPUT_FULL | STS_LAST_CMD);
+        assert_eq!(kbc.read_data(), 0x55);
+        assert_eq!(kbc.status() & STS_OUTPUT_FULL, 0);
+
+        // Write the configuration byte, then read it back
+        kbc.write_command(0x60);
+        kbc.write_data(CFG_SYS_FLAG | CFG_KBD_DISABLED);
+        kbc.write_command(0x20);
+        assert_eq!(kbc.read_data(), CFG_SYS_FLAG | CFG_KBD_DISABLED);
+        assert!(!kbc.inject(&[0x1e]));
+
+        // Resetting the keyboard enables it again
+        kbc.irq = false;
+        kbc.write_data(0xff);
+        assert!(kbc.irq);
+        assert_eq!((kbc.read_data(), kbc.read_data()), (KBD_ACK, KBD_SELF_TEST_OK));
+        assert!(kbc.inject(&[0x1e, 0x9e]));
+        assert_eq!(kbc.output.len(), 2);
+
+        // Disabling A20 through the output port, then pulsing reset
+        kbc.write_command(0xd1);
+        kbc.write_data(OUT_RESET);
+        assert_eq!(kbc.output_port & OUT_A20, 0);
+        assert!(!kbc.reset);
+        kbc.write_command(0xdf);
+        assert_eq!(kbc.output_port & OUT_A20, OUT_A20);
+        kbc.write_command(0xfe);
+        assert!(kbc.reset);
+    }
+}
@@ -0,0 +1,75 @@
+//! Conversions between Rust strings and the fixed-size `char` arrays
+//! embedded in Bhyve ioctl structs, such as the name of a `vm_memseg`.
+//!
+//! `c_char` is signed on some targets and unsigned on others, so bytes are
+//! converted with `as c_char` rather than assuming `i8`.
+
+use libc::EINVAL;
+use std::os::raw::c_char;
+
+use crate::Error;
+
+/// Copies 'src' into the C string field 'dst', followed by a terminating
+/// NUL, and zeroes the rest of the field. Returns `EINVAL` if 'src' contains
+/// a NUL byte, or doesn't fit in the field along with its terminator.
+pub fn copy_to_field(dst: &mut [c_char], src: &str) -> Result<(), Error> {
+    let bytes = src.as_bytes();
+    if bytes.len() >= dst.len() || bytes.contains(&0) {
+        return Err(Error::new(EINVAL));
+    }
+    for (to, from) in dst.iter_mut().zip(bytes.iter().chain(std::iter::repeat(&0))) {
+        *to = *from as c_char;
+    }
+    Ok(())
+}
+
+/// Returns the bytes of the C string field 'src' up to its terminating NUL,
+/// or the whole field if the kernel left it unterminated.
+pub fn field_bytes(src: &[c_char]) -> Vec<u8> {
+    src.iter().map(|c| *c as u8).take_while(|b| *b != 0).collect()
+}
+
+/// Compares the C string field 'field' with 's'.
+pub fn field_eq(field: &[c_char], s: &str) -> bool {
+    field_bytes(field) == s.as_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_to_field() {
+        let mut field = [0x7f as c_char; 8];
+        copy_to_field(&mut field, "bootrom").expect("name should fit");
+        assert_eq!(field_bytes(&field), b"bootrom");
+        assert!(field_eq(&field, "bootrom"));
+        assert!(!field_eq(&field, "boot"));
+
+        // Shorter names clear whatever was in the field before
+        copy_to_field(&mut field, "fb").expect("name should fit");
+        assert_eq!(field, [b'f' as c_char, b'b' as c_char, 0, 0, 0, 0, 0, 0]);
+
+        copy_to_field(&mut field, "").expect("empty name should fit");
+        assert_eq!(field_bytes(&field), b"");
+    }
+
+    #[test]
+    fn test_copy_to_field_rejects() {
+        let mut field = [0 as c_char; 8];
+        // No room for the terminating NUL
+        assert!(copy_to_field(&mut field, "eightchr").is_err());
+        assert!(copy_to_field(&mut field, "a\0b").is_err());
+    }
+
+    #[test]
+    fn test_field_bytes_high_bit() {
+        // Bytes above 0x7f survive the round trip whatever the sign of c_char
+        let mut field = [0 as c_char; 4];
+        copy_to_field(&mut field, "\u{e9}").expect("name should fit");
+        assert_eq!(field_bytes(&field), "\u{e9}".as_bytes());
+
+        let unterminated = [b'a' as c_char; 3];
+        assert_eq!(field_bytes(&unterminated), b"aaa");
+    }
+}
@@ -1,3 +1,4 @@
+pub mod cstring;
 pub mod vmm;
 pub mod vmm_dev;
 pub mod specialreg;
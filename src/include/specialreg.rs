@@ -3,4 +3,11 @@
 //! These are defined in Rust, but mimic the C constants defined
 //! in `machine/specialreg.h`.
 
+pub const CR0_PE: u64 = 0x00000001; // Protected mode Enable
 pub const CR0_NE: u64 = 0x00000020; // Numeric Error enable (EX16 vs IRQ13)
+pub const CR0_PG: u64 = 0x80000000; // PaGing enable
+
+pub const CR4_PAE: u64 = 0x00000020; // Physical Address Extension
+
+pub const EFER_LME: u64 = 0x00000100; // Long mode enable (R/W)
+pub const EFER_LMA: u64 = 0x00000400; // Long mode active (R)
@@ -4,9 +4,11 @@
 //! defined in `machine/vmm.h`.
 
 
+use std::convert::TryFrom;
 use std::os::raw::{c_int, c_uint, c_ulonglong};
 
 pub const VM_MAXCPU: usize = 32;    // maximum virtual cpus
+pub const VM_MAX_MEMMAPS: usize = 8;  // maximum guest memory mappings, from vmm.c
 
 #[repr(C)]
 #[allow(non_camel_case_types, unused)]
@@ -20,10 +22,28 @@ pub enum vm_suspend_how {
         VM_SUSPEND_LAST
 }
 
+impl TryFrom<c_int> for vm_suspend_how {
+    type Error = c_int;
+
+    // The kernel reports the reason as a plain integer, which may be one
+    // this library doesn't know about.
+    fn try_from(how: c_int) -> Result<vm_suspend_how, c_int> {
+        match how {
+            0 => Ok(vm_suspend_how::VM_SUSPEND_NONE),
+            1 => Ok(vm_suspend_how::VM_SUSPEND_RESET),
+            2 => Ok(vm_suspend_how::VM_SUSPEND_POWEROFF),
+            3 => Ok(vm_suspend_how::VM_SUSPEND_HALT),
+            4 => Ok(vm_suspend_how::VM_SUSPEND_TRIPLEFAULT),
+            5 => Ok(vm_suspend_how::VM_SUSPEND_LAST),
+            _ => Err(how),
+        }
+    }
+}
+
 // Identifiers for architecturally defined registers.
 #[repr(C)]
 #[allow(non_camel_case_types, unused)]
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum vm_reg_name {
         VM_REG_GUEST_RAX,
         VM_REG_GUEST_RBX,
@@ -81,6 +101,14 @@ pub enum x2apic_state {
 	X2APIC_STATE_LAST
 }
 
+#[repr(C)]
+#[allow(non_camel_case_types, unused)]
+#[derive(Copy, Clone)]
+pub enum vm_intr_trigger {
+	EDGE_TRIGGER,
+	LEVEL_TRIGGER
+}
+
 // Identifiers for optional vmm capabilities
 #[repr(C)]
 #[allow(non_camel_case_types, unused)]
@@ -110,7 +138,7 @@ pub struct seg_desc {
 
 #[repr(C)]
 #[allow(non_camel_case_types, unused)]
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum vm_cpu_mode {
         CPU_MODE_REAL,
         CPU_MODE_PROTECTED,
@@ -120,7 +148,7 @@ pub enum vm_cpu_mode {
 
 #[repr(C)]
 #[allow(non_camel_case_types, unused)]
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum vm_paging_mode {
         PAGING_MODE_FLAT,
         PAGING_MODE_32,
@@ -129,7 +157,7 @@ pub enum vm_paging_mode {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct vm_guest_paging {
     pub cr3: c_ulonglong,
     pub cpl: c_int,
@@ -178,7 +206,7 @@ struct vie {
 
 #[repr(C)]
 #[allow(non_camel_case_types, unused)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum vm_exitcode {
         VM_EXITCODE_INOUT,
         VM_EXITCODE_VMX,
@@ -207,6 +235,37 @@ pub enum vm_exitcode {
         VM_EXITCODE_MAX
 }
 
+impl vm_exitcode {
+    // Every exit code, in numeric order, for code that keeps per-exit data.
+    pub const ALL: [vm_exitcode; 25] = [
+        vm_exitcode::VM_EXITCODE_INOUT,
+        vm_exitcode::VM_EXITCODE_VMX,
+        vm_exitcode::VM_EXITCODE_BOGUS,
+        vm_exitcode::VM_EXITCODE_RDMSR,
+        vm_exitcode::VM_EXITCODE_WRMSR,
+        vm_exitcode::VM_EXITCODE_HLT,
+        vm_exitcode::VM_EXITCODE_MTRAP,
+        vm_exitcode::VM_EXITCODE_PAUSE,
+        vm_exitcode::VM_EXITCODE_PAGING,
+        vm_exitcode::VM_EXITCODE_INST_EMUL,
+        vm_exitcode::VM_EXITCODE_SPINUP_AP,
+        vm_exitcode::VM_EXITCODE_DEPRECATED1,
+        vm_exitcode::VM_EXITCODE_RUNBLOCK,
+        vm_exitcode::VM_EXITCODE_IOAPIC_EOI,
+        vm_exitcode::VM_EXITCODE_SUSPENDED,
+        vm_exitcode::VM_EXITCODE_INOUT_STR,
+        vm_exitcode::VM_EXITCODE_TASK_SWITCH,
+        vm_exitcode::VM_EXITCODE_MONITOR,
+        vm_exitcode::VM_EXITCODE_MWAIT,
+        vm_exitcode::VM_EXITCODE_SVM,
+        vm_exitcode::VM_EXITCODE_REQIDLE,
+        vm_exitcode::VM_EXITCODE_DEBUG,
+        vm_exitcode::VM_EXITCODE_VMINSN,
+        vm_exitcode::VM_EXITCODE_HT,
+        vm_exitcode::VM_EXITCODE_MAX,
+    ];
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct vm_inout {
@@ -266,7 +325,7 @@ pub struct vm_inout_str {
 
 #[repr(C)]
 #[allow(non_camel_case_types, unused)]
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum task_switch_reason {
         TSR_CALL,
         TSR_IRET,
@@ -274,15 +333,30 @@ pub enum task_switch_reason {
         TSR_IDT_GATE,   // task gate in IDT
 }
 
+impl TryFrom<c_int> for task_switch_reason {
+    type Error = c_int;
+
+    // Reported by the kernel as a plain integer, like vm_suspend_how.
+    fn try_from(reason: c_int) -> Result<task_switch_reason, c_int> {
+        match reason {
+            0 => Ok(task_switch_reason::TSR_CALL),
+            1 => Ok(task_switch_reason::TSR_IRET),
+            2 => Ok(task_switch_reason::TSR_JMP),
+            3 => Ok(task_switch_reason::TSR_IDT_GATE),
+            _ => Err(reason),
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct vm_task_switch {
-    tsssel: u16,                         // new TSS selector
-    ext: c_int,                          // task switch due to external event
-    errcode: c_uint,
-    errcode_valid: c_int,                // push 'errcode' on the new stack
-    reason: task_switch_reason,
-    paging: vm_guest_paging,
+    pub tsssel: u16,                     // new TSS selector
+    pub ext: c_int,                      // task switch due to external event
+    pub errcode: c_uint,
+    pub errcode_valid: c_int,            // push 'errcode' on the new stack
+    pub reason: c_int,                   // task_switch_reason
+    pub paging: vm_guest_paging,
 }
 
 #[repr(C)]
@@ -343,6 +417,16 @@ pub struct vm_exit_inst_emul {
     vie: vie,
 }
 
+impl vm_exit_inst_emul {
+    // Returns the bytes of the faulting instruction fetched by the kernel,
+    // and the number of them that are valid. Only the leading fields of
+    // 'vie' are relied on here, since its bitfields are approximated.
+    pub fn inst_bytes(&self) -> ([u8; 15], usize) {
+        let len = (self.vie.num_valid as usize).min(self.vie.inst.len());
+        (self.vie.inst, len)
+    }
+}
+
 // VMX specific payload. Used when there is no "better"
 // exitcode to represent the VM-exit.
 #[repr(C)]
@@ -399,5 +483,5 @@ pub struct vm_exit_ioapic_eoi {
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct vm_exit_suspended {
-    pub how: vm_suspend_how,
+    pub how: c_int, // vm_suspend_how
 }
@@ -3,7 +3,7 @@
 //! These are defined in Rust, but mimic the C constants and structs
 //! defined in `machine/vmm_dev.h`, `sys/ioccom.h`, and `sys/time.h`.
 
-use std::os::raw::{c_int, c_uint, c_long, c_longlong, c_ulonglong, c_char};
+use std::os::raw::{c_int, c_uint, c_long, c_longlong, c_ulonglong, c_char, c_void};
 use std::mem::size_of;
 use libc::{size_t};
 
@@ -22,6 +22,15 @@ pub struct timeval {
     pub tv_usec: c_long,   // and microseconds
 }
 
+// High-resolution time in nanoseconds since an arbitrary point in the past,
+// which is not affected by adjustments to the time of day.
+#[allow(non_camel_case_types)]
+pub type hrtime_t = c_longlong;
+
+extern "C" {
+    pub fn gethrtime() -> hrtime_t;
+}
+
 // Define constants from sys/ioccom.h
 
 // Ioctl's have the command encoded in the lower word, and the size of
@@ -145,6 +154,9 @@ pub const VM_REINIT: c_int = define_ioctl_op!(IOC_VOID, IocNum::IOCNUM_REINIT as
 pub const VM_ALLOC_MEMSEG: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_ALLOC_MEMSEG as c_uint, (size_of::<vm_memseg>() as c_uint));
 pub const VM_GET_MEMSEG: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GET_MEMSEG as c_uint, (size_of::<vm_memseg>() as c_uint));
 
+pub const VM_GET_GPA_PMAP: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GET_GPA_PMAP as c_uint, (size_of::<vm_gpa_pte>() as c_uint));
+pub const VM_GLA2GPA: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GLA2GPA as c_uint, (size_of::<vm_gla2gpa>() as c_uint));
+pub const VM_GLA2GPA_NOFAULT: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GLA2GPA_NOFAULT as c_uint, (size_of::<vm_gla2gpa>() as c_uint));
 pub const VM_MMAP_MEMSEG: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_MMAP_MEMSEG as c_uint, (size_of::<vm_memmap>() as c_uint));
 pub const VM_MMAP_GETNEXT: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_MMAP_GETNEXT as c_uint, (size_of::<vm_memmap>() as c_uint));
 pub const VM_MUNMAP_MEMSEG: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_MUNMAP_MEMSEG as c_uint, (size_of::<vm_munmap>() as c_uint));
@@ -153,6 +165,8 @@ pub const VM_SET_REGISTER: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_SET_R
 pub const VM_GET_REGISTER: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GET_REGISTER as c_uint, (size_of::<vm_register>() as c_uint));
 pub const VM_SET_SEGMENT_DESCRIPTOR: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_SET_SEGMENT_DESCRIPTOR as c_uint, (size_of::<vm_seg_desc>() as c_uint));
 pub const VM_GET_SEGMENT_DESCRIPTOR: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GET_SEGMENT_DESCRIPTOR as c_uint, (size_of::<vm_seg_desc>() as c_uint));
+pub const VM_SET_REGISTER_SET: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_SET_REGISTER_SET as c_uint, (size_of::<vm_register_set>() as c_uint));
+pub const VM_GET_REGISTER_SET: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GET_REGISTER_SET as c_uint, (size_of::<vm_register_set>() as c_uint));
 
 pub const VM_SET_CAPABILITY: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_SET_CAPABILITY as c_uint, (size_of::<vm_capability>() as c_uint));
 pub const VM_GET_CAPABILITY: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GET_CAPABILITY as c_uint, (size_of::<vm_capability>() as c_uint));
@@ -160,12 +174,16 @@ pub const VM_GET_CAPABILITY: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_
 pub const VM_SET_X2APIC_STATE: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_SET_X2APIC_STATE as c_uint, (size_of::<vm_x2apic>() as c_uint));
 pub const VM_GET_X2APIC_STATE: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GET_X2APIC_STATE as c_uint, (size_of::<vm_x2apic>() as c_uint));
 
+pub const VM_GET_HPET_CAPABILITIES: c_int = define_ioctl_op!(IOC_OUT, IocNum::IOCNUM_GET_HPET_CAPABILITIES as c_uint, (size_of::<vm_hpet_cap>() as c_uint));
+
 pub const VM_SET_TOPOLOGY: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_SET_TOPOLOGY as c_uint, (size_of::<vm_cpu_topology>() as c_uint));
 pub const VM_GET_TOPOLOGY: c_int = define_ioctl_op!(IOC_OUT, IocNum::IOCNUM_GET_TOPOLOGY as c_uint, (size_of::<vm_cpu_topology>() as c_uint));
 pub const VM_STATS_IOC: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_VM_STATS as c_uint, (size_of::<vm_stats>() as c_uint));
+pub const VM_STAT_DESC: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_VM_STAT_DESC as c_uint, (size_of::<vm_stat_desc>() as c_uint));
 
 
 pub const VM_ACTIVATE_CPU: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_ACTIVATE_CPU as c_uint, (size_of::<vm_activate_cpu>() as c_uint));
+pub const VM_GET_CPUS: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_GET_CPUSET as c_uint, (size_of::<vm_cpuset>() as c_uint));
 pub const VM_SUSPEND_CPU: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_SUSPEND_CPU as c_uint, (size_of::<vm_activate_cpu>() as c_uint));
 pub const VM_RESUME_CPU: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_RESUME_CPU as c_uint, (size_of::<vm_activate_cpu>() as c_uint));
 
@@ -184,9 +202,20 @@ pub const VM_LAPIC_MSI: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_LAPIC_MS
 pub const VM_IOAPIC_ASSERT_IRQ: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_IOAPIC_ASSERT_IRQ as c_uint, (size_of::<vm_ioapic_irq>() as c_uint));
 pub const VM_IOAPIC_DEASSERT_IRQ: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_IOAPIC_DEASSERT_IRQ as c_uint, (size_of::<vm_ioapic_irq>() as c_uint));
 pub const VM_IOAPIC_PULSE_IRQ: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_IOAPIC_PULSE_IRQ as c_uint, (size_of::<vm_ioapic_irq>() as c_uint));
+pub const VM_ISA_ASSERT_IRQ: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_ISA_ASSERT_IRQ as c_uint, (size_of::<vm_isa_irq>() as c_uint));
+pub const VM_ISA_DEASSERT_IRQ: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_ISA_DEASSERT_IRQ as c_uint, (size_of::<vm_isa_irq>() as c_uint));
+pub const VM_ISA_PULSE_IRQ: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_ISA_PULSE_IRQ as c_uint, (size_of::<vm_isa_irq>() as c_uint));
+pub const VM_ISA_SET_IRQ_TRIGGER: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_ISA_SET_IRQ_TRIGGER as c_uint, (size_of::<vm_isa_irq_trigger>() as c_uint));
 pub const VM_IOAPIC_PINCOUNT: c_int = define_ioctl_op!(IOC_OUT, IocNum::IOCNUM_IOAPIC_PINCOUNT as c_uint, (size_of::<c_int>() as c_uint));
 pub const VM_RESTART_INSTRUCTION: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_RESTART_INSTRUCTION as c_uint, (size_of::<c_int>() as c_uint));
 
+pub const VM_BIND_PPTDEV: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_BIND_PPTDEV as c_uint, (size_of::<vm_pptdev>() as c_uint));
+pub const VM_UNBIND_PPTDEV: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_UNBIND_PPTDEV as c_uint, (size_of::<vm_pptdev>() as c_uint));
+pub const VM_MAP_PPTDEV_MMIO: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_MAP_PPTDEV_MMIO as c_uint, (size_of::<vm_pptdev_mmio>() as c_uint));
+pub const VM_PPTDEV_MSI: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_PPTDEV_MSI as c_uint, (size_of::<vm_pptdev_msi>() as c_uint));
+pub const VM_PPTDEV_MSIX: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_PPTDEV_MSIX as c_uint, (size_of::<vm_pptdev_msix>() as c_uint));
+pub const VM_GET_PPTDEV_LIMITS: c_int = define_ioctl_op!(IOC_OUT, IocNum::IOCNUM_GET_PPTDEV_LIMITS as c_uint, (size_of::<vm_pptdev_limits>() as c_uint));
+
 pub const VM_DEVMEM_GETOFFSET: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_DEVMEM_GETOFFSET as c_uint, (size_of::<vm_devmem_offset>() as c_uint));
 
 
@@ -194,6 +223,11 @@ pub const VM_DEVMEM_GETOFFSET: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_D
 const VMM_IOC_BASE: c_int = (86 << 16) | (77 << 8); // ASCII for 'V' and 'M'
 pub const VMM_CREATE_VM: c_int = VMM_IOC_BASE | 0x01;
 pub const VMM_DESTROY_VM: c_int = VMM_IOC_BASE | 0x02;
+pub const VMM_INTERFACE_VERSION: c_int = VMM_IOC_BASE | 0x04;
+
+// Kernels that predate VMM_INTERFACE_VERSION use the ioctl numbers and struct
+// layouts defined in this file, and are treated as interface version 0.
+pub const VMM_LEGACY_INTERFACE_VERSION: c_int = 0;
 
 
 // Define structs from machine/vmm_dev.h
@@ -264,6 +298,27 @@ pub struct vm_devmem_offset {
     pub offset: c_longlong,
 }
 
+// For VM_GET_GPA_PMAP
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_gpa_pte {
+    pub gpa: c_ulonglong,           // in
+    pub pte: [c_ulonglong; 4],      // out
+    pub ptenum: c_int,
+}
+
+// For VM_GLA2GPA and VM_GLA2GPA_NOFAULT
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct vm_gla2gpa {
+    pub vcpuid: c_int,          // inputs
+    pub prot: c_int,            // PROT_READ or PROT_WRITE
+    pub gla: c_ulonglong,
+    pub paging: vm_guest_paging,
+    pub fault: c_int,           // outputs
+    pub gpa: c_ulonglong,
+}
+
 // For VM_SET_REGISTER and VM_GET_REGISTER
 #[repr(C)]
 #[derive(Copy, Clone, Default)]
@@ -273,6 +328,18 @@ pub struct vm_register {
     pub regval: c_ulonglong,
 }
 
+// For VM_SET_REGISTER_SET and VM_GET_REGISTER_SET. The kernel reads
+// 'count' register numbers from 'regnums', and reads or writes the same
+// number of values at 'regvals'.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct vm_register_set {
+    pub cpuid: c_int,
+    pub count: c_uint,
+    pub regnums: *const c_int,              // enum vm_reg_name
+    pub regvals: *mut c_ulonglong,
+}
+
 // For VM_SET_SEGMENT_DESCRIPTOR and VM_GET_SEGMENT_DESCRIPTOR
 // data or code segment
 #[repr(C)]
@@ -343,6 +410,21 @@ pub struct vm_activate_cpu {
     pub vcpuid: c_int,
 }
 
+// 'which' values for VM_GET_CPUS
+pub const VM_ACTIVE_CPUS: c_int = 0;
+pub const VM_SUSPENDED_CPUS: c_int = 1;
+pub const VM_DEBUG_CPUS: c_int = 2;
+
+// For VM_GET_CPUS. The kernel copies out 'cpusetsize' bytes of the set to
+// 'cpus'; sets no larger than a ulong_t receive its low bits.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct vm_cpuset {
+    pub which: c_int,
+    pub cpusetsize: c_int,
+    pub cpus: *mut c_void,
+}
+
 // For VM_SET_TOPOLOGY and VM_GET_TOPOLOGY
 #[repr(C)]
 #[derive(Copy, Clone, Default)]
@@ -353,7 +435,7 @@ pub struct vm_cpu_topology {
     pub maxcpus: u16,
 }
 
-const MAX_VM_STATS: usize = 64 + VM_MAXCPU;
+pub const MAX_VM_STATS: usize = 64 + VM_MAXCPU;
 
 // For VM_STATS_IOC
 #[repr(C)]
@@ -376,6 +458,25 @@ impl Default for vm_stats {
     }
 }
 
+const MAX_STAT_DESC_LEN: usize = 128;
+
+// For VM_STAT_DESC
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct vm_stat_desc {
+    pub index: c_int,                       // in
+    pub desc: [c_char; MAX_STAT_DESC_LEN],  // out
+}
+
+impl Default for vm_stat_desc {
+    fn default() -> vm_stat_desc {
+        vm_stat_desc {
+            index: 0,
+            desc: [0; MAX_STAT_DESC_LEN],
+        }
+    }
+}
+
 // For VM_SET_INTINFO and VM_GET_INTINFO
 #[repr(C)]
 #[derive(Copy, Clone, Default)]
@@ -426,6 +527,87 @@ pub struct vm_ioapic_irq {
     pub irq: c_int,
 }
 
+// For VM_GET_HPET_CAPABILITIES
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_hpet_cap {
+    pub capabilities: u32,    // lower 32 bits of HPET capabilities
+}
+
+// For VM_ISA_ASSERT_IRQ, VM_ISA_DEASSERT_IRQ, and VM_ISA_PULSE_IRQ
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_isa_irq {
+    pub atpic_irq: c_int,
+    pub ioapic_irq: c_int,
+}
+
+// For VM_ISA_SET_IRQ_TRIGGER
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct vm_isa_irq_trigger {
+    pub atpic_irq: c_int,
+    pub trigger: vm_intr_trigger,
+}
+
+impl Default for vm_isa_irq_trigger {
+    fn default() -> vm_isa_irq_trigger {
+        vm_isa_irq_trigger {
+            atpic_irq: 0,
+            trigger: vm_intr_trigger::EDGE_TRIGGER,
+        }
+    }
+}
+
+// For VM_BIND_PPTDEV and VM_UNBIND_PPTDEV
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_pptdev {
+    pub pptfd: c_int,
+}
+
+// For VM_MAP_PPTDEV_MMIO
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_pptdev_mmio {
+    pub pptfd: c_int,
+    pub gpa: c_ulonglong,
+    pub hpa: c_ulonglong,
+    pub len: size_t,
+}
+
+// For VM_PPTDEV_MSI
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_pptdev_msi {
+    pub vcpu: c_int,
+    pub pptfd: c_int,
+    pub numvec: c_int,              // 0 means disabled
+    pub msg: c_ulonglong,
+    pub addr: c_ulonglong,
+}
+
+// For VM_PPTDEV_MSIX
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_pptdev_msix {
+    pub vcpu: c_int,
+    pub pptfd: c_int,
+    pub idx: c_int,
+    pub msg: c_ulonglong,
+    pub vector_control: c_uint,
+    pub addr: c_ulonglong,
+}
+
+// For VM_GET_PPTDEV_LIMITS
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_pptdev_limits {
+    pub pptfd: c_int,               // in
+    pub msi_limit: c_int,           // out
+    pub msix_limit: c_int,          // out
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -435,6 +617,8 @@ mod tests {
     fn test_ioctl_stats() {
         assert_eq!(size_of::<vm_stats>(), 0x318);
         assert_eq!(VM_STATS_IOC as u32, 0xc0187632);
+        assert_eq!(size_of::<vm_stat_desc>(), 0x84);
+        assert_eq!(VM_STAT_DESC as u32, 0xc0847633);
     }
 
     #[test]
@@ -456,6 +640,13 @@ mod tests {
         assert_eq!(VM_GET_TOPOLOGY as u32, 0x40087640);
     }
 
+    #[test]
+    fn test_ioctl_cpuset() {
+        assert_eq!(size_of::<vm_cpuset>(), 0x10);
+        assert_eq!(VM_GET_CPUS as u32, 0x8010765b);
+        assert_eq!(VM_ACTIVATE_CPU as u32, 0x8004765a);
+    }
+
     #[test]
     fn test_ioctl_memory() {
         assert_eq!(size_of::<vm_memseg>(), 0x110);
@@ -464,4 +655,73 @@ mod tests {
         assert_eq!(VM_MMAP_MEMSEG as u32, 0x80287610);
         assert_eq!(VM_MMAP_GETNEXT as u32, 0xc0287611);
     }
+
+    #[test]
+    fn test_ioctl_gpa_pmap() {
+        assert_eq!(size_of::<vm_gpa_pte>(), 0x30);
+        assert_eq!(VM_GET_GPA_PMAP as u32, 0xc030760c);
+    }
+
+    #[test]
+    fn test_ioctl_gla2gpa() {
+        assert_eq!(size_of::<vm_gla2gpa>(), 0x38);
+        assert_eq!(VM_GLA2GPA as u32, 0xc038760d);
+        assert_eq!(VM_GLA2GPA_NOFAULT as u32, 0xc0387612);
+    }
+
+    #[test]
+    fn test_ioctl_register_set() {
+        assert_eq!(size_of::<vm_register_set>(), 0x18);
+        assert_eq!(VM_SET_REGISTER_SET as u32, 0x80187618);
+        assert_eq!(VM_GET_REGISTER_SET as u32, 0xc0187619);
+    }
+
+    #[test]
+    fn test_ioctl_hpet() {
+        assert_eq!(size_of::<vm_hpet_cap>(), 4);
+        assert_eq!(VM_GET_HPET_CAPABILITIES as u32, 0x4004763e);
+    }
+
+    #[test]
+    fn test_ioctl_isa_irq() {
+        assert_eq!(size_of::<vm_isa_irq>(), 8);
+        assert_eq!(VM_ISA_ASSERT_IRQ as u32, 0x80087650);
+        assert_eq!(VM_ISA_DEASSERT_IRQ as u32, 0x80087651);
+        assert_eq!(VM_ISA_PULSE_IRQ as u32, 0x80087652);
+        assert_eq!(size_of::<vm_isa_irq_trigger>(), 8);
+        assert_eq!(VM_ISA_SET_IRQ_TRIGGER as u32, 0x80087653);
+    }
+
+    #[test]
+    fn test_ioctl_lapic() {
+        assert_eq!(size_of::<vm_lapic_irq>(), 8);
+        assert_eq!(size_of::<vm_lapic_msi>(), 0x10);
+        assert_eq!(VM_LAPIC_IRQ as u32, 0x8008761f);
+        assert_eq!(VM_LAPIC_LOCAL_IRQ as u32, 0x80087625);
+        assert_eq!(VM_LAPIC_MSI as u32, 0x80107624);
+    }
+
+    #[test]
+    fn test_ioctl_pptdev() {
+        assert_eq!(size_of::<vm_pptdev>(), 4);
+        assert_eq!(size_of::<vm_pptdev_mmio>(), 0x20);
+        assert_eq!(size_of::<vm_pptdev_msi>(), 0x20);
+        assert_eq!(size_of::<vm_pptdev_msix>(), 0x28);
+        assert_eq!(size_of::<vm_pptdev_limits>(), 0xc);
+        assert_eq!(VM_BIND_PPTDEV as u32, 0x80047628);
+        assert_eq!(VM_UNBIND_PPTDEV as u32, 0x80047629);
+        assert_eq!(VM_MAP_PPTDEV_MMIO as u32, 0x8020762a);
+        assert_eq!(VM_PPTDEV_MSI as u32, 0x8020762b);
+        assert_eq!(VM_PPTDEV_MSIX as u32, 0x8028762c);
+        assert_eq!(VM_GET_PPTDEV_LIMITS as u32, 0x400c762d);
+    }
+
+    #[test]
+    fn test_ioctl_ioapic() {
+        assert_eq!(size_of::<vm_ioapic_irq>(), 4);
+        assert_eq!(VM_IOAPIC_ASSERT_IRQ as u32, 0x80047621);
+        assert_eq!(VM_IOAPIC_DEASSERT_IRQ as u32, 0x80047622);
+        assert_eq!(VM_IOAPIC_PULSE_IRQ as u32, 0x80047623);
+        assert_eq!(VM_IOAPIC_PINCOUNT as u32, 0x40047626);
+    }
 }
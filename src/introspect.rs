@@ -0,0 +1,302 @@
+//! Reading guest kernel structures by symbol, for test triage.
+//!
+//! When a guest hangs or panics under test, the first things worth
+//! collecting are usually the kernel's version banner and its panic
+//! message. Given the guest kernel's symbol map, in the `System.map` or
+//! `nm` format, a `GuestIntrospector` finds such structures by name and
+//! reads them through the VCPU's page tables, without any help from the
+//! guest:
+//!
+//!     use bhyve_api::introspect::*;
+//!     use bhyve_api::vm::VirtualMachine;
+//!
+//!     fn triage(vm: &VirtualMachine, map: &str) -> Result<(), bhyve_api::Error> {
+//!         let symbols = SymbolMap::parse(&std::fs::read_to_string(map)?)?;
+//!         let guest = GuestIntrospector::new(vm, 0, &symbols);
+//!         if let Some(banner) = guest.linux_banner()? {
+//!             println!("guest kernel: {}", banner.trim_end());
+//!         }
+//!         let rip = vm.get_register(0, bhyve_api::vm::vm_reg_name::VM_REG_GUEST_RIP)?;
+//!         if let Some((name, offset)) = guest.symbolize(rip) {
+//!             println!("VCPU 0 at {}+{:#x}", name, offset);
+//!         }
+//!         Ok(())
+//!     }
+//!
+//! Kernels loaded at a randomized address (KASLR) need the offset from
+//! their link address set with `with_slide()`. Reads go through the page
+//! tables the VCPU is using at the time, so they see the address space of
+//! whatever the VCPU is running, and never inject faults into it.
+
+use libc::{sysconf, EINVAL, ENOENT, PROT_READ, _SC_PAGESIZE};
+use std::collections::BTreeMap;
+
+use crate::vm::VirtualMachine;
+use crate::Error;
+
+/// Longest string read by `read_string()` for well-known structures.
+pub const MAX_STRING_LEN: usize = 8192;
+
+// Size of the illumos panicbuf, which starts with a panic_data_t header
+// whose second word is the offset of the message in the buffer.
+const PANICBUFSIZE: usize = 8192;
+
+/// The symbols of a guest kernel, with their link addresses.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymbolMap {
+    by_name: BTreeMap<String, u64>,
+    by_addr: BTreeMap<u64, String>,
+}
+
+impl SymbolMap {
+    /// Creates an empty symbol map.
+    pub fn new() -> SymbolMap {
+        SymbolMap::default()
+    }
+
+    /// Parses a symbol map with a line per symbol, each holding the address
+    /// in hex, the symbol type, and the name, as in a Linux `System.map`,
+    /// `/proc/kallsyms`, or the output of `nm`. Anything after the name,
+    /// such as a module name, is ignored, as are undefined symbols, which
+    /// have no address. Returns `EINVAL` for any other line.
+    pub fn parse(text: &str) -> Result<SymbolMap, Error> {
+        let mut symbols = SymbolMap::new();
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.len() {
+                0 => continue,
+                2 if fields[0] == "U" || fields[0] == "w" => continue,
+                1 | 2 => return Err(Error::new(EINVAL)),
+                _ => (),
+            }
+            let addr = match u64::from_str_radix(fields[0], 16) {
+                Ok(addr) => addr,
+                Err(_) => return Err(Error::new(EINVAL)),
+            };
+            symbols.insert(fields[2], addr);
+        }
+        Ok(symbols)
+    }
+
+    /// Adds the symbol 'name' at 'addr', replacing any symbol of that name.
+    pub fn insert(&mut self, name: &str, addr: u64) {
+        if let Some(old) = self.by_name.insert(name.to_string(), addr) {
+            if self.by_addr.get(&old).map(|n| n.as_str()) == Some(name) {
+                self.by_addr.remove(&old);
+                // Fall back to another name for the old address, if any
+                let other = self.by_name.iter().find(|&(_, a)| *a == old).map(|(n, _)| n.clone());
+                if let Some(other) = other {
+                    self.by_addr.insert(old, other);
+                }
+            }
+        }
+        // Of several names for an address, keep the first for symbolize()
+        self.by_addr.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    /// Returns the address of the symbol 'name'.
+    pub fn lookup(&self, name: &str) -> Option<u64> {
+        self.by_name.get(name).cloned()
+    }
+
+    /// Returns the symbol at or below 'addr', and the offset of 'addr' from
+    /// it, for showing code addresses such as the RIP of a hung VCPU.
+    pub fn symbolize(&self, addr: u64) -> Option<(&str, u64)> {
+        self.by_addr.range(..=addr).next_back().map(|(start, name)| (name.as_str(), addr - start))
+    }
+
+    /// Returns the number of symbols.
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    /// Returns true if there are no symbols.
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+}
+
+/// Reads guest kernel structures, located with a `SymbolMap`, through the
+/// page tables of a VCPU.
+pub struct GuestIntrospector<'a> {
+    vm: &'a VirtualMachine,
+    vcpu_id: i32,
+    symbols: &'a SymbolMap,
+    slide: u64, // added to link addresses to get run-time addresses
+}
+
+impl<'a> GuestIntrospector<'a> {
+    /// Creates an introspector that translates addresses with the page
+    /// tables of 'vcpu_id', which must be running the kernel described by
+    /// 'symbols'.
+    pub fn new(vm: &'a VirtualMachine, vcpu_id: i32, symbols: &'a SymbolMap) -> GuestIntrospector<'a> {
+        GuestIntrospector { vm: vm, vcpu_id: vcpu_id, symbols: symbols, slide: 0 }
+    }
+
+    /// Sets the offset of the kernel's run-time addresses from the link
+    /// addresses in the symbol map, for kernels loaded at a randomized
+    /// address. Negative offsets wrap around.
+    pub fn with_slide(mut self, slide: u64) -> GuestIntrospector<'a> {
+        self.slide = slide;
+        self
+    }
+
+    /// Returns the run-time address of the symbol 'name', or `ENOENT` if it
+    /// isn't in the symbol map.
+    pub fn symbol_addr(&self, name: &str) -> Result<u64, Error> {
+        match self.symbols.lookup(name) {
+            Some(addr) => Ok(addr.wrapping_add(self.slide)),
+            None => Err(Error::new(ENOENT)),
+        }
+    }
+
+    /// Returns the symbol at or below the run-time address 'addr', and the
+    /// offset of 'addr' from it, taking the slide set with `with_slide()`
+    /// into account.
+    pub fn symbolize(&self, addr: u64) -> Option<(&'a str, u64)> {
+        self.symbols.symbolize(addr.wrapping_sub(self.slide))
+    }
+
+    /// Reads guest virtual memory at 'gla' into 'buf', a page at a time.
+    /// Returns false, leaving 'buf' partly filled, if part of the range
+    /// isn't mapped by the guest.
+    pub fn read_virt(&self, gla: u64, buf: &mut [u8]) -> Result<bool, Error> {
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as u64 };
+        let paging = self.vm.guest_paging(self.vcpu_id)?;
+        let mut done = 0;
+        for (addr, len) in page_chunks(gla, buf.len(), page_size) {
+            let gpa = match self.vm.gla2gpa_nofault(self.vcpu_id, &paging, addr, PROT_READ)? {
+                Some(gpa) => gpa,
+                None => return Ok(false),
+            };
+            self.vm.read_guest_memory(gpa, &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(true)
+    }
+
+    /// Reads a NUL-terminated string of at most 'max' bytes at 'gla'.
+    /// Returns 'None' if the string isn't mapped by the guest. Bytes that
+    /// aren't UTF-8 are replaced.
+    pub fn read_string(&self, gla: u64, max: usize) -> Result<Option<String>, Error> {
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as u64 };
+        let mut bytes = Vec::new();
+        // Read a page at a time, so a string ending just before an unmapped
+        // page can still be read.
+        for (addr, len) in page_chunks(gla, max, page_size) {
+            let mut chunk = vec![0; len];
+            if !self.read_virt(addr, &mut chunk)? {
+                return Ok(None);
+            }
+            if let Some(end) = chunk.iter().position(|&b| b == 0) {
+                bytes.extend_from_slice(&chunk[..end]);
+                break;
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(Some(String::from_utf8_lossy(&bytes).into_owned()))
+    }
+
+    /// Reads the symbol 'name' into 'buf', as `read_virt()` does. Returns
+    /// `ENOENT` if the symbol isn't in the symbol map.
+    pub fn read_symbol(&self, name: &str, buf: &mut [u8]) -> Result<bool, Error> {
+        let addr = self.symbol_addr(name)?;
+        self.read_virt(addr, buf)
+    }
+
+    /// Reads a little-endian 64-bit value, such as a pointer, at the symbol
+    /// 'name'. Returns 'None' if it isn't mapped by the guest.
+    pub fn read_symbol_u64(&self, name: &str) -> Result<Option<u64>, Error> {
+        let mut bytes = [0; 8];
+        match self.read_symbol(name, &mut bytes)? {
+            true => Ok(Some(u64::from_le_bytes(bytes))),
+            false => Ok(None),
+        }
+    }
+
+    /// Returns the version banner of a Linux guest, from `linux_banner`.
+    pub fn linux_banner(&self) -> Result<Option<String>, Error> {
+        self.read_string(self.symbol_addr("linux_banner")?, MAX_STRING_LEN)
+    }
+
+    /// Returns the panic message of an illumos guest, from `panicbuf`,
+    /// which is empty unless the guest has panicked. The message follows
+    /// the buffer's `panic_data_t` header, at the offset in its `pd_msgoff`.
+    pub fn panic_buffer(&self) -> Result<Option<String>, Error> {
+        let addr = self.symbol_addr("panicbuf")?;
+        let mut header = [0; 8];
+        if !self.read_virt(addr, &mut header)? {
+            return Ok(None);
+        }
+        match panic_msg_offset(&header) {
+            Some(offset) => self.read_string(addr + offset as u64, PANICBUFSIZE - offset),
+            None => Ok(Some(String::new())),
+        }
+    }
+}
+
+// Returns the offset of the message in a panicbuf starting with 'header',
+// or 'None' if the guest hasn't panicked, leaving it zero, or the offset
+// lies outside the buffer.
+fn panic_msg_offset(header: &[u8; 8]) -> Option<usize> {
+    let offset = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if offset < header.len() || offset >= PANICBUFSIZE {
+        return None;
+    }
+    Some(offset)
+}
+
+// Splits [gla,gla+len) at page boundaries, so each piece can be translated
+// on its own.
+fn page_chunks(gla: u64, len: usize, page_size: u64) -> Vec<(u64, usize)> {
+    let mut chunks = Vec::new();
+    let mut addr = gla;
+    let mut left = len as u64;
+    while left > 0 {
+        let size = left.min(page_size - (addr & (page_size - 1)));
+        chunks.push((addr, size as usize));
+        addr = addr.wrapping_add(size);
+        left -= size;
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_map() {
+        let text = "ffffffff81000000 T _text\n\
+                    ffffffff81000000 T startup_64\n\
+                    \n\
+                    ffffffff82000100 D linux_banner\n\
+                    ffffffffc0000000 t helper\t[ext4]\n\
+                    \x20                U printk\n";
+        let mut symbols = SymbolMap::parse(text).unwrap();
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.lookup("linux_banner"), Some(0xffffffff82000100));
+        assert_eq!(symbols.symbolize(0xffffffff81000010), Some(("_text", 0x10)));
+        assert_eq!(symbols.symbolize(0xffffffffc0000004), Some(("helper", 4)));
+        assert_eq!(symbols.symbolize(0x1000), None);
+
+        symbols.insert("_text", 0xffffffff80000000);
+        assert_eq!(symbols.symbolize(0xffffffff81000010), Some(("startup_64", 0x10)));
+        assert!(SymbolMap::parse("linux_banner\n").is_err());
+        assert!(SymbolMap::parse("zzzz D linux_banner\n").is_err());
+    }
+
+    #[test]
+    fn test_panic_msg_offset() {
+        assert_eq!(panic_msg_offset(&[1, 0, 0, 0, 0x58, 0, 0, 0]), Some(0x58));
+        assert_eq!(panic_msg_offset(&[0; 8]), None);
+        assert_eq!(panic_msg_offset(&[1, 0, 0, 0, 0, 0x20, 0, 0]), None);
+    }
+
+    #[test]
+    fn test_page_chunks() {
+        assert_eq!(page_chunks(0x1ff0, 0x30, 0x1000), vec![(0x1ff0, 0x10), (0x2000, 0x20)]);
+        assert_eq!(page_chunks(0x3000, 0x2000, 0x1000), vec![(0x3000, 0x1000), (0x4000, 0x1000)]);
+        assert!(page_chunks(0x3000, 0, 0x1000).is_empty());
+    }
+}
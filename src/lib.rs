@@ -15,8 +15,53 @@
 //! and maintainability, and simplifies reasoning from a security
 //! perspective.
 
+pub mod a20;
+pub mod balloon;
+pub mod bytes;
+pub mod capability;
+pub mod console;
+pub mod cpuset;
+pub mod debugcon;
+pub mod device;
+#[cfg(feature = "disasm")]
+pub mod disasm;
+pub mod dump;
+pub mod event;
+pub mod features;
+pub mod gsi;
+pub mod guard;
+pub mod hpet;
+pub mod i8042;
+pub mod introspect;
+pub mod lifecycle;
+pub mod log;
+pub mod memory;
+pub mod metadata;
+pub mod msix;
+pub mod pci;
+pub mod pci_passthru;
+pub mod pit;
+pub mod policy;
+pub mod portio;
+pub mod pvpanic;
+pub mod reset;
+pub mod rtc;
+pub mod scatter;
+pub mod sched;
+pub mod shutdown;
+pub mod smp;
+pub mod stats;
+pub mod storm;
 pub mod system;
+pub mod timer;
+pub mod trace;
+pub mod vcpu;
+pub mod virtio;
+pub mod virtio_console;
 pub mod vm;
+pub mod volatile;
+pub mod watchdog;
+mod error;
 mod include;
 
-pub use vmm_sys_util::errno::Error;
+pub use crate::error::Error;
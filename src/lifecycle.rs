@@ -0,0 +1,105 @@
+//! Lifecycle events of virtual machines.
+//!
+//! Management layers need to know when a VM is created or destroyed, when
+//! its VCPUs come up, and when the guest stops, without polling the kernel
+//! for each of them. `VMMSystem` and `VirtualMachine` each own an
+//! `EventStream`, and send a `VmEvent` to every subscriber as the library's
+//! own operations and run loops observe the change. Subscribers receive the
+//! events on a channel, in the order they happened.
+//!
+//!     use bhyve_api::lifecycle::VmEvent;
+//!     use bhyve_api::vm::VirtualMachine;
+//!     use std::thread;
+//!
+//!     fn watch(vm: &VirtualMachine) {
+//!         let events = vm.events().subscribe();
+//!         thread::spawn(move || {
+//!             for event in events {
+//!                 if let VmEvent::Suspended(reason) = event {
+//!                     println!("guest stopped: {:?}", reason);
+//!                 }
+//!             }
+//!         });
+//!     }
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use crate::pvpanic::GuestPanic;
+use crate::vm::SuspendReason;
+
+/// A change in the lifecycle of a virtual machine.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmEvent {
+    /// The VM with the contained name was created, by `VMMSystem`.
+    Created(String),
+    /// The VM with the contained name was destroyed, by `VMMSystem`.
+    Destroyed(String),
+    /// The VCPU was activated.
+    VcpuActivated(i32 /* vcpu */),
+    /// The VCPUs of a `VcpuSet` were paused, and have all left the guest.
+    Paused,
+    /// The VCPUs of a `VcpuSet` were resumed after a pause.
+    Resumed,
+    /// The VM was suspended, so no VCPU will enter the guest until it is
+    /// reinitialized. Sent once per suspension, although every VCPU exits.
+    Suspended(SuspendReason),
+    /// The guest reported a panic through the pvpanic device.
+    GuestPanic(GuestPanic),
+}
+
+/// A stream of lifecycle events, delivered to any number of subscribers.
+/// Clones send to the same subscribers.
+#[derive(Debug, Clone, Default)]
+pub struct EventStream {
+    subscribers: Arc<Mutex<Vec<Sender<VmEvent>>>>,
+}
+
+impl EventStream {
+    /// Creates a stream with no subscribers.
+    pub fn new() -> EventStream {
+        EventStream::default()
+    }
+
+    /// Returns a channel that receives every event sent from now on. Events
+    /// are buffered until received, so a subscriber that stops receiving
+    /// should drop the channel.
+    pub fn subscribe(&self) -> Receiver<VmEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Sends 'event' to every subscriber, forgetting those that have
+    /// dropped their channel.
+    pub fn emit(&self, event: VmEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    /// Returns the number of subscribers, counting any that have dropped
+    /// their channel since the last event.
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_stream() {
+        let events = EventStream::new();
+        let first = events.subscribe();
+        let second = events.clone().subscribe();
+        events.emit(VmEvent::VcpuActivated(0));
+        drop(second);
+        events.emit(VmEvent::Suspended(SuspendReason::Halt));
+
+        assert_eq!(events.subscribers(), 1);
+        assert_eq!(first.try_recv(), Ok(VmEvent::VcpuActivated(0)));
+        assert_eq!(first.try_recv(), Ok(VmEvent::Suspended(SuspendReason::Halt)));
+        assert!(first.try_recv().is_err());
+    }
+}
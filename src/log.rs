@@ -0,0 +1,90 @@
+//! Diagnostic output from the library.
+//!
+//! The library doesn't print anything itself. Diagnostics go to the
+//! `LogSink` installed with `VirtualMachine::set_log_sink()`, so an
+//! embedding VMM can route them to its own logging, filter them, or leave
+//! them out entirely, which is the default.
+//!
+//!     use bhyve_api::log::*;
+//!     use bhyve_api::vm::VirtualMachine;
+//!     use std::sync::Arc;
+//!
+//!     fn verbose(vm: &VirtualMachine) {
+//!         vm.set_log_sink(Some(Arc::new(StderrSink::new(LogLevel::Debug))));
+//!     }
+
+use std::fmt;
+
+/// Importance of a diagnostic message, most important first.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A destination for diagnostic messages.
+///
+/// Messages may be logged from VCPU threads in the run loop, so the sink
+/// should not block for long.
+pub trait LogSink: Send + Sync {
+    /// Returns true if messages at 'level' are wanted. Messages that aren't
+    /// wanted are not formatted at all.
+    fn enabled(&self, _level: LogLevel) -> bool {
+        true
+    }
+
+    /// Records a message.
+    fn log(&self, level: LogLevel, message: &str);
+}
+
+/// Writes messages at or above a minimum level to stderr.
+#[derive(Debug, Copy, Clone)]
+pub struct StderrSink {
+    max_level: LogLevel,
+}
+
+impl StderrSink {
+    /// Creates a sink that writes messages as important as 'max_level' or
+    /// more so.
+    pub fn new(max_level: LogLevel) -> StderrSink {
+        StderrSink { max_level: max_level }
+    }
+}
+
+impl LogSink for StderrSink {
+    fn enabled(&self, level: LogLevel) -> bool {
+        level <= self.max_level
+    }
+
+    fn log(&self, level: LogLevel, message: &str) {
+        eprintln!("bhyve-api {}: {}", level, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        let sink = StderrSink::new(LogLevel::Info);
+        assert!(sink.enabled(LogLevel::Error));
+        assert!(sink.enabled(LogLevel::Info));
+        assert!(!sink.enabled(LogLevel::Debug));
+        assert_eq!(LogLevel::Warn.to_string(), "warn");
+    }
+}
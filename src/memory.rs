@@ -0,0 +1,870 @@
+//! Host memory for backing guest physical memory.
+//!
+//! `setup_lowmem()` and `setup_highmem()` map guest memory over a host
+//! address range supplied by the caller. `alloc_guest_backing()` reserves
+//! a suitably aligned range and unmaps it again when dropped. On hosts with
+//! several locality groups, `BackingOptions::locality` controls which of
+//! them the guest's pages are allocated from.
+//!
+//!     use bhyve_api::memory::*;
+//!     use bhyve_api::vm::*;
+//!
+//!     fn setup(vm: &VirtualMachine) -> Result<GuestBacking, bhyve_api::Error> {
+//!         let backing = alloc_guest_backing(512 << 20, BackingOptions::default())?;
+//!         vm.setup_lowmem(backing.addr(), backing.len())?;
+//!         Ok(backing)
+//!     }
+//!
+//! Alternatively, `setup_lowmem_mapped()`, `setup_highmem_mapped()` and
+//! `setup_bootrom_mapped()` reserve the host range themselves, with guard
+//! pages either side, and return it as a `HostMapping`:
+//!
+//!     use bhyve_api::vm::*;
+//!
+//!     fn setup(vm: &VirtualMachine, firmware: &[u8]) -> Result<(), bhyve_api::Error> {
+//!         let lowmem = vm.setup_lowmem_mapped(512 << 20)?;
+//!         let bootrom = vm.setup_bootrom_mapped(firmware.len())?;
+//!         bootrom.slice().copy_from(firmware);
+//!         // ... run the guest. Dropping a mapping removes its regions from
+//!         // the VM's guest memory before unmapping it.
+//!         drop((lowmem, bootrom));
+//!         Ok(())
+//!     }
+//!
+//! UEFI firmware split into code and a variable store is set up with
+//! `setup_bootrom_layout()`, which maps the store writable:
+//!
+//!     use bhyve_api::vm::*;
+//!
+//!     fn setup(vm: &VirtualMachine, code: &[u8], vars: &[u8]) -> Result<Bootrom, bhyve_api::Error> {
+//!         let rom = vm.setup_bootrom_layout(&BootromLayout::split(code.len(), vars.len()))?;
+//!         rom.part("bootrom").unwrap().copy_from(code);
+//!         rom.part("varstore").unwrap().copy_from(vars);
+//!         Ok(rom)
+//!     }
+//!
+//! Once set up, the regions are tracked by the VM's `GuestMemory`, which
+//! device emulation uses to reach guest memory. An emulated DMA transfer
+//! borrows the guest range for its duration, so the mapping can't be
+//! removed from under it:
+//!
+//!     use bhyve_api::vm::VirtualMachine;
+//!
+//!     fn dma_write(vm: &VirtualMachine, gpa: u64, data: &[u8]) -> Result<(), bhyve_api::Error> {
+//!         let dma = vm.memory().borrow_dma(gpa, data.len())?;
+//!         dma.slice().copy_from(data);
+//!         Ok(())
+//!     }
+//!
+//! For a single access, `GuestMemory` borrows the range just for the copy:
+//!
+//!     use bhyve_api::vm::VirtualMachine;
+//!
+//!     fn read_descriptor(vm: &VirtualMachine, gpa: u64) -> Result<(u64, u32), bhyve_api::Error> {
+//!         let addr: u64 = vm.memory().read_obj(gpa)?;
+//!         let len: u32 = vm.memory().read_obj(gpa + 8)?;
+//!         Ok((addr, len))
+//!     }
+//!
+//! `scan_guest_mem()` searches guest memory for a signature, for example to
+//! check that firmware placed its tables where expected:
+//!
+//!     use bhyve_api::vm::VirtualMachine;
+//!
+//!     // The RSDP is on a 16-byte boundary in the BIOS area
+//!     fn find_rsdp(vm: &VirtualMachine) -> Result<Option<u64>, bhyve_api::Error> {
+//!         let matches = vm.memory().scan_guest_mem(b"RSD PTR ", 0xe0000..0x100000)?;
+//!         Ok(matches.into_iter().find(|gpa| gpa % 16 == 0))
+//!     }
+
+use libc::{c_void, sysconf, EBUSY, EFAULT, EINVAL, _SC_PAGESIZE};
+use std::mem::size_of;
+use std::ops::Range;
+use std::ptr::null_mut;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+use crate::bytes::FromBytes;
+use crate::vm::{GuestRegion, VM_MMAP_GUARD_SIZE};
+use crate::volatile::VolatileSlice;
+use crate::Error;
+
+// Memory access pattern advice from sys/mman.h on illumos, which the
+// kernel uses to choose the locality group (lgroup) that pages are
+// allocated from.
+const MADV_ACCESS_DEFAULT: i32 = 6;    // default access
+const MADV_ACCESS_LWP: i32 = 7;        // next LWP to touch is heavy user
+const MADV_ACCESS_MANY: i32 = 8;       // many processes to access heavily
+
+// Guest memory copied at a time by scan_guest_mem()
+const SCAN_CHUNK_SIZE: usize = 1 << 20;
+
+/// Where the host places guest memory on machines with more than one
+/// locality group (for example, a multi-socket host).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Locality {
+    /// Let the host choose, usually near the thread that first touches
+    /// each page.
+    Default,
+    /// Place the memory near the next thread to touch it, which will use it
+    /// heavily. Touching the memory from a VCPU thread bound to a socket
+    /// keeps that VCPU's memory local.
+    NextThread,
+    /// Spread the memory across locality groups, for memory that is
+    /// accessed heavily by VCPUs on every socket.
+    Spread,
+}
+
+impl Locality {
+    fn advice(&self) -> i32 {
+        match self {
+            Locality::Default => MADV_ACCESS_DEFAULT,
+            Locality::NextThread => MADV_ACCESS_LWP,
+            Locality::Spread => MADV_ACCESS_MANY,
+        }
+    }
+}
+
+/// Alignment of guest memory backing by default, so the host can use large
+/// pages for it.
+pub const DEFAULT_BACKING_ALIGN: usize = 2 * 1024 * 1024;
+
+/// Options for `alloc_guest_backing()`.
+#[derive(Debug, Copy, Clone)]
+pub struct BackingOptions {
+    /// Alignment of the start of the region, which must be a power of two
+    /// and a multiple of the page size.
+    pub align: usize,
+    /// Reserve swap space for the whole region up front, rather than as the
+    /// memory is touched.
+    pub reserve: bool,
+    /// Placement of the memory across host locality groups.
+    pub locality: Locality,
+}
+
+impl Default for BackingOptions {
+    fn default() -> BackingOptions {
+        BackingOptions { align: DEFAULT_BACKING_ALIGN, reserve: false, locality: Locality::Default }
+    }
+}
+
+/// An anonymous shared mapping in the host address space, for backing guest
+/// memory. The mapping is removed when the `GuestBacking` is dropped, so it
+/// must live as long as the guest memory set up over it is in use.
+#[derive(Debug)]
+pub struct GuestBacking {
+    addr: *mut u8,
+    len: usize,
+}
+
+// Safe because the region is plain memory owned by this struct, and access
+// through the raw pointer is up to the caller.
+unsafe impl Send for GuestBacking {}
+unsafe impl Sync for GuestBacking {}
+
+/// Maps 'len' bytes of anonymous MAP_SHARED memory, aligned as requested in
+/// 'options', for use as the host address range of guest memory. 'len' is
+/// rounded up to the page size.
+pub fn alloc_guest_backing(len: usize, options: BackingOptions) -> Result<GuestBacking, Error> {
+    let page_size = unsafe { sysconf(_SC_PAGESIZE) as usize };
+    let align = options.align.max(page_size);
+    if len == 0 || !align.is_power_of_two() {
+        return Err(Error::new(EINVAL));
+    }
+    let len = (len + page_size - 1) & !(page_size - 1);
+
+    // Over-allocate, then trim the unaligned head and the tail
+    let map_len = len + align - page_size;
+    let mut flags = libc::MAP_ANONYMOUS | libc::MAP_SHARED;
+    if !options.reserve {
+        flags |= libc::MAP_NORESERVE;
+    }
+    let ptr = unsafe {
+        libc::mmap(null_mut(), map_len, libc::PROT_READ | libc::PROT_WRITE, flags, -1, 0)
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(Error::last());
+    }
+
+    let start = ptr as usize;
+    let aligned = (start + align - 1) & !(align - 1);
+    let head = aligned - start;
+    let tail = map_len - head - len;
+    // Safe because both ranges are within the mapping just created and
+    // outside the returned region.
+    unsafe {
+        if head > 0 {
+            libc::munmap(ptr, head);
+        }
+        if tail > 0 {
+            libc::munmap((aligned + len) as *mut c_void, tail);
+        }
+    }
+    let backing = GuestBacking { addr: aligned as *mut u8, len: len };
+    if options.locality != Locality::Default {
+        backing.advise_locality(options.locality)?;
+    }
+    Ok(backing)
+}
+
+impl GuestBacking {
+    /// Returns the host address of the region, in the form taken by
+    /// `setup_lowmem()` and `setup_highmem()`.
+    pub fn addr(&self) -> u64 {
+        self.addr as u64
+    }
+
+    /// Returns a raw pointer to the start of the region.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.addr
+    }
+
+    /// Returns the length of the region in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Changes the placement of pages in the region that haven't been
+    /// touched yet. Pages already allocated stay where they are.
+    pub fn advise_locality(&self, locality: Locality) -> Result<(), Error> {
+        self.advise_range(0, self.len, locality)
+    }
+
+    /// Changes the placement of untouched pages in [offset,offset+len) of
+    /// the region, for example to place each NUMA node's memory near the
+    /// VCPUs of that node. 'offset' must be page aligned.
+    pub fn advise_range(&self, offset: usize, len: usize, locality: Locality) -> Result<(), Error> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => (),
+            _ => return Err(Error::new(EINVAL)),
+        }
+        let result = unsafe {
+            libc::madvise(self.addr.add(offset) as *mut c_void, len, locality.advice())
+        };
+        if result == 0 {
+            return Ok(());
+        } else {
+            return Err(Error::last());
+        }
+    }
+
+    /// Returns true if the region is empty, which it never is.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Drop for GuestBacking {
+    fn drop(&mut self) {
+        // Safe because the region was mapped by alloc_guest_backing() and
+        // nothing else refers to it through this struct.
+        unsafe {
+            libc::munmap(self.addr as *mut c_void, self.len);
+        }
+    }
+}
+
+/// A host mapping of guest memory reserved by the library, with guard pages
+/// either side that fault on any access, so an overrun off either end of
+/// guest memory can't reach other host memory. Returned by
+/// `VirtualMachine::setup_lowmem_mapped()` and its siblings, with the
+/// guest memory mapped over it. When the `HostMapping` is dropped, the
+/// regions in it are removed from the VM's `GuestMemory`, and the whole
+/// reservation is unmapped, unless part of it is still borrowed for DMA,
+/// in which case it is left reserved rather than pulled out from under the
+/// transfer.
+#[derive(Debug)]
+pub struct HostMapping {
+    base: *mut u8, // start of the reservation, including the leading guard
+    len: usize,
+    memory: Option<Weak<GuestMemory>>, // the VM's memory, once registered
+}
+
+// Safe because the mapping is owned by this struct, and the memory is only
+// reached through volatile accesses or raw pointers.
+unsafe impl Send for HostMapping {}
+unsafe impl Sync for HostMapping {}
+
+impl HostMapping {
+    // Reserves 'len' bytes, rounded up to the page size, between two guard
+    // regions of VM_MMAP_GUARD_SIZE. The whole reservation is inaccessible
+    // until guest memory is mapped over the middle of it.
+    pub(crate) fn reserve(len: usize) -> Result<HostMapping, Error> {
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as usize };
+        if len == 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let len = (len + page_size - 1) & !(page_size - 1);
+        let ptr = unsafe {
+            libc::mmap(
+                null_mut(),
+                VM_MMAP_GUARD_SIZE + len + VM_MMAP_GUARD_SIZE,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::last());
+        }
+        Ok(HostMapping { base: ptr as *mut u8, len: len, memory: None })
+    }
+
+    // Ties the mapping to the VM's guest memory, whose regions over it are
+    // removed when it is dropped, and which may release it on teardown.
+    pub(crate) fn attach(&mut self, memory: &Arc<GuestMemory>) {
+        memory.owned.lock().unwrap().push((self.addr(), self.len as u64));
+        self.memory = Some(Arc::downgrade(memory));
+    }
+
+    /// Returns the host address of the guest memory, after the leading
+    /// guard.
+    pub fn addr(&self) -> u64 {
+        self.as_ptr() as u64
+    }
+
+    /// Returns a raw pointer to the guest memory.
+    pub fn as_ptr(&self) -> *mut u8 {
+        // Safe because the reservation extends past the guard
+        unsafe { self.base.add(VM_MMAP_GUARD_SIZE) }
+    }
+
+    /// Returns the length of the guest memory in bytes, not counting the
+    /// guards.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the mapping is empty, which it never is.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the guest memory as a `VolatileSlice`.
+    pub fn slice(&self) -> VolatileSlice<'_> {
+        // Safe because the guest memory stays mapped while it is borrowed
+        unsafe { VolatileSlice::new(self.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for HostMapping {
+    fn drop(&mut self) {
+        if let Some(memory) = self.memory.as_ref().and_then(|memory| memory.upgrade()) {
+            if !memory.remove_host_range(self.addr(), self.len as u64) {
+                return;
+            }
+        }
+        // Safe because the reservation was mapped by reserve(), no region
+        // refers to it any more, and the guest memory mapped over it goes
+        // with it.
+        unsafe {
+            libc::munmap(self.base as *mut c_void, VM_MMAP_GUARD_SIZE + self.len + VM_MMAP_GUARD_SIZE);
+        }
+    }
+}
+
+/// The guest memory regions of a virtual machine, and the parts of them
+/// currently borrowed for DMA by emulated devices. Returned by
+/// `VirtualMachine::memory()`.
+#[derive(Debug, Default)]
+pub struct GuestMemory {
+    regions: Mutex<Vec<GuestRegion>>, // guest memory set up by setup_*()
+    owned: Mutex<Vec<(u64, u64)>>, // host ranges of attached HostMappings
+    dma: Mutex<Vec<(u64, u64)>>, // guest physical ranges borrowed for DMA
+    dma_done: Condvar,
+}
+
+impl GuestMemory {
+    pub(crate) fn new() -> GuestMemory {
+        GuestMemory::default()
+    }
+
+    /// Returns the guest memory regions, in the order they were set up.
+    pub fn regions(&self) -> Vec<GuestRegion> {
+        self.regions.lock().unwrap().clone()
+    }
+
+    // Locks the region list, for setting up or removing regions.
+    pub(crate) fn lock_regions(&self) -> MutexGuard<'_, Vec<GuestRegion>> {
+        self.regions.lock().unwrap()
+    }
+
+    // Removes every region, newest first. The host mapping of a region in
+    // a `HostMapping` is replaced with an inaccessible reservation of the
+    // same range, which drops the host's reference to the VM's memory but
+    // leaves the range for the `HostMapping` to unmap. Regions over host
+    // memory supplied by the caller, such as a `GuestBacking`, are only
+    // forgotten: the library can't tell whether the caller still holds the
+    // range or has unmapped it, and it may since have been reused. Returns
+    // `EBUSY` if any guest memory is borrowed for DMA.
+    pub(crate) fn teardown(&self) -> Result<(), Error> {
+        let mut regions = self.regions.lock().unwrap();
+        let _dma = self.lock_dma_free(0, !0)?;
+        while let Some(region) = regions.last().cloned() {
+            if self.owns(region.host_addr, region.len) {
+                release_host_range(region.host_addr, region.len as usize)?;
+            }
+            regions.pop();
+        }
+        Ok(())
+    }
+
+    // Returns true if [addr,addr+len) of the host address space lies within
+    // a `HostMapping` attached to this memory, and so is still reserved by
+    // the library.
+    fn owns(&self, addr: u64, len: u64) -> bool {
+        let owned = self.owned.lock().unwrap();
+        owned.iter().any(|&(start, size)| start <= addr && addr + len <= start + size)
+    }
+
+    // Removes the regions whose host mapping lies in [addr,addr+len), for
+    // when the mapping goes away. Returns false, leaving the regions in
+    // place, if any of them is borrowed for DMA.
+    pub(crate) fn remove_host_range(&self, addr: u64, len: u64) -> bool {
+        let mut regions = self.regions.lock().unwrap();
+        let inside = |r: &GuestRegion| addr <= r.host_addr && r.host_addr + r.len <= addr + len;
+        let dma = self.dma.lock().unwrap();
+        let borrowed = regions.iter().filter(|r| inside(r)).any(|r| {
+            dma.iter().any(|&(start, size)| start < r.gpa + r.len && r.gpa < start + size)
+        });
+        if borrowed {
+            return false;
+        }
+        regions.retain(|r| !inside(r));
+        self.owned.lock().unwrap().retain(|&(start, size)| !(addr <= start && start + size <= addr + len));
+        true
+    }
+
+    /// Borrows [gpa,gpa+len) for an emulated DMA transfer. While the
+    /// returned guard exists, operations that would unmap the range, such
+    /// as `munmap_memseg()` and `reinit()`, fail with `EBUSY`.
+    ///
+    /// The range must lie within a single region in `regions()`, so that it
+    /// is contiguous in the host address space, or `EFAULT` is returned.
+    pub fn borrow_dma(&self, gpa: u64, len: usize) -> Result<DmaGuard<'_>, Error> {
+        let end = match gpa.checked_add(len as u64) {
+            Some(end) if len > 0 => end,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        // Hold the region lock while pinning, so the region can't be
+        // replaced between the lookup and the pin.
+        let regions = self.regions.lock().unwrap();
+        let region = match regions.iter().find(|r| r.gpa <= gpa && end <= r.gpa + r.len) {
+            Some(region) => *region,
+            None => return Err(Error::new(EFAULT)),
+        };
+        self.dma.lock().unwrap().push((gpa, len as u64));
+        Ok(DmaGuard {
+            memory: self,
+            gpa: gpa,
+            len: len,
+            host_addr: region.host_addr + (gpa - region.gpa),
+            wired: false,
+        })
+    }
+
+    // Returns true if [gpa,gpa+len) lies within a single region that the
+    // guest can access with 'prot', so it can be reached through the host
+    // mapping of the region.
+    pub(crate) fn covers(&self, gpa: u64, len: u64, prot: i32) -> bool {
+        let end = match gpa.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+        let regions = self.regions.lock().unwrap();
+        regions.iter().any(|r| r.gpa <= gpa && end <= r.gpa + r.len && (r.prot & prot) == prot)
+    }
+
+    /// Reads guest memory at 'gpa' into 'buf', through the host mapping of
+    /// the region holding it. The range must lie within a single region in
+    /// `regions()`, or `EFAULT` is returned.
+    pub fn read_slice(&self, gpa: u64, buf: &mut [u8]) -> Result<(), Error> {
+        if !buf.is_empty() {
+            self.borrow_dma(gpa, buf.len())?.slice().copy_to(buf);
+        }
+        Ok(())
+    }
+
+    /// Writes 'buf' to guest memory at 'gpa', as `read_slice()` reads it.
+    pub fn write_slice(&self, gpa: u64, buf: &[u8]) -> Result<(), Error> {
+        if !buf.is_empty() {
+            self.borrow_dma(gpa, buf.len())?.slice().copy_from(buf);
+        }
+        Ok(())
+    }
+
+    /// Reads a value of type 'T' from guest memory at 'gpa', converting it
+    /// from the guest's byte order. Aligned values are read with a single
+    /// access, as with `VolatileSlice::read()`.
+    pub fn read_obj<T: FromBytes>(&self, gpa: u64) -> Result<T, Error> {
+        self.borrow_dma(gpa, size_of::<T>())?.slice().read(0)
+    }
+
+    /// Writes 'value' to guest memory at 'gpa' in the guest's byte order.
+    pub fn write_obj<T: FromBytes>(&self, gpa: u64, value: T) -> Result<(), Error> {
+        self.borrow_dma(gpa, size_of::<T>())?.slice().write(0, value)
+    }
+
+    /// Returns the guest physical address of every occurrence of 'pattern'
+    /// in 'range' of guest memory, in order of address, for example to find
+    /// the ACPI RSDP or a multiboot header. Parts of the range outside
+    /// `regions()` are skipped, and a match can't span two regions, as they
+    /// aren't contiguous in the host address space. Returns `EINVAL` if the
+    /// pattern is empty.
+    pub fn scan_guest_mem(&self, pattern: &[u8], range: Range<u64>) -> Result<Vec<u64>, Error> {
+        if pattern.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+        // Each chunk moves the scan on by more than a pattern, however long
+        let chunk_size = SCAN_CHUNK_SIZE.max(2 * pattern.len());
+        let mut matches = Vec::new();
+        for region in self.regions() {
+            let start = range.start.max(region.gpa);
+            let end = range.end.min(region.gpa + region.len);
+            let mut gpa = start;
+            // Read a chunk at a time, each overlapping the one before by
+            // less than a pattern, so DMA borrows stay short.
+            while end.saturating_sub(gpa) >= pattern.len() as u64 {
+                let len = (end - gpa).min(chunk_size as u64) as usize;
+                let mut chunk = vec![0; len];
+                self.read_slice(gpa, &mut chunk)?;
+                find_all(&chunk, pattern, gpa, &mut matches);
+                if gpa + len as u64 == end {
+                    break;
+                }
+                gpa += (len - (pattern.len() - 1)) as u64;
+            }
+        }
+        matches.sort();
+        Ok(matches)
+    }
+
+    /// Returns true if any part of guest memory is borrowed for DMA.
+    pub fn dma_in_flight(&self) -> bool {
+        !self.dma.lock().unwrap().is_empty()
+    }
+
+    /// Waits up to 'timeout' for every DMA borrow to be released, for
+    /// example before taking a snapshot of guest memory or changing the
+    /// memory layout. Returns false if a borrow is still held. New borrows
+    /// may start as soon as this returns, so devices should be paused first.
+    pub fn wait_dma_idle(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut dma = self.dma.lock().unwrap();
+        while !dma.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            dma = self.dma_done.wait_timeout(dma, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    // Checks that no part of [gpa,gpa+len) is borrowed for DMA, returning
+    // `EBUSY` if it is. The returned lock keeps new borrows from starting
+    // until it is dropped, so it must be held while the range is unmapped.
+    pub(crate) fn lock_dma_free(&self, gpa: u64, len: u64) -> Result<MutexGuard<'_, Vec<(u64, u64)>>, Error> {
+        let dma = self.dma.lock().unwrap();
+        let end = gpa.saturating_add(len);
+        if dma.iter().any(|&(start, size)| start < end && gpa < start + size) {
+            return Err(Error::new(EBUSY));
+        }
+        Ok(dma)
+    }
+
+    fn release_dma(&self, gpa: u64, len: u64) {
+        let mut dma = self.dma.lock().unwrap();
+        if let Some(index) = dma.iter().position(|&range| range == (gpa, len)) {
+            dma.remove(index);
+        }
+        self.dma_done.notify_all();
+    }
+}
+
+// Maps an inaccessible reservation over [addr,addr+len) of the host address
+// space, in place of whatever was mapped there.
+fn release_host_range(addr: u64, len: usize) -> Result<(), Error> {
+    let ptr = unsafe {
+        libc::mmap(
+            addr as *mut c_void,
+            len,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | libc::MAP_FIXED,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(Error::last());
+    }
+    Ok(())
+}
+
+// Appends the address of each occurrence of 'pattern' in 'data', which was
+// read from guest memory at 'gpa', to 'matches'.
+fn find_all(data: &[u8], pattern: &[u8], gpa: u64, matches: &mut Vec<u64>) {
+    if data.len() < pattern.len() {
+        return;
+    }
+    for offset in 0..=(data.len() - pattern.len()) {
+        if data[offset] == pattern[0] && &data[offset..offset + pattern.len()] == pattern {
+            matches.push(gpa + offset as u64);
+        }
+    }
+}
+
+/// A range of guest memory borrowed for an emulated DMA transfer, which
+/// stays mapped in the guest and host address spaces until the guard is
+/// dropped. The guest can still access the memory concurrently, so it must
+/// be accessed through `slice()` or raw pointers, never references.
+#[derive(Debug)]
+pub struct DmaGuard<'a> {
+    memory: &'a GuestMemory,
+    gpa: u64,
+    len: usize,
+    host_addr: u64,
+    wired: bool,
+}
+
+impl<'a> DmaGuard<'a> {
+    /// Returns the guest physical address of the range.
+    pub fn gpa(&self) -> u64 {
+        self.gpa
+    }
+
+    /// Returns the length of the range in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the range is empty, which it never is.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the host virtual address of the start of the range.
+    pub fn host_addr(&self) -> u64 {
+        self.host_addr
+    }
+
+    /// Returns a raw pointer to the start of the range in the host mapping.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.host_addr as *mut u8
+    }
+
+    /// Returns the range as a `VolatileSlice`, for accessing it safely while
+    /// the guest may be using it.
+    pub fn slice(&self) -> VolatileSlice<'_> {
+        // Safe because the guard keeps the range mapped while it is borrowed
+        unsafe { VolatileSlice::new(self.as_ptr(), self.len) }
+    }
+
+    /// Locks the host pages of the range in memory until the guard is
+    /// dropped, for transfers that must not wait on the host paging guest
+    /// memory back in. This isn't needed if the VM's memory is wired.
+    /// Page locks don't nest, so the pages are unlocked on drop even if they
+    /// were locked before.
+    pub fn wire(&mut self) -> Result<(), Error> {
+        if self.wired {
+            return Ok(());
+        }
+        // mlock() needs a page aligned start address
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as u64 };
+        let start = self.host_addr & !(page_size - 1);
+        let len = (self.host_addr - start) as usize + self.len;
+        let result = unsafe { libc::mlock(start as *const c_void, len) };
+        if result == 0 {
+            self.wired = true;
+            return Ok(());
+        } else {
+            return Err(Error::last());
+        }
+    }
+}
+
+impl<'a> Drop for DmaGuard<'a> {
+    fn drop(&mut self) {
+        if self.wired {
+            let page_size = unsafe { sysconf(_SC_PAGESIZE) as u64 };
+            let start = self.host_addr & !(page_size - 1);
+            let len = (self.host_addr - start) as usize + self.len;
+            unsafe {
+                libc::munlock(start as *const c_void, len);
+            }
+        }
+        self.memory.release_dma(self.gpa, self.len as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_guest_backing() {
+        let backing = alloc_guest_backing(3 * 4096 + 1, BackingOptions::default()).unwrap();
+        assert_eq!(backing.addr() % DEFAULT_BACKING_ALIGN as u64, 0);
+        assert_eq!(backing.len() % 4096, 0);
+        assert!(backing.len() > 3 * 4096);
+        unsafe {
+            *backing.as_ptr().add(backing.len() - 1) = 0xaa;
+        }
+
+        assert!(backing.advise_range(4096, backing.len(), Locality::Default).is_err());
+
+        let options = BackingOptions { align: 3 * 4096, reserve: true, locality: Locality::Default };
+        assert!(alloc_guest_backing(4096, options).is_err());
+        assert!(alloc_guest_backing(0, BackingOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_host_mapping() {
+        let mut mapping = HostMapping::reserve(4096 + 1).unwrap();
+        assert_eq!(mapping.len(), 2 * 4096);
+        assert_eq!(mapping.addr(), mapping.base as u64 + VM_MMAP_GUARD_SIZE as u64);
+        // Stand in for the guest memory the VM maps over the reservation
+        let ptr = unsafe {
+            libc::mmap(mapping.as_ptr() as *mut c_void, mapping.len(), libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED, -1, 0)
+        };
+        assert_eq!(ptr as u64, mapping.addr());
+        mapping.slice().write(mapping.len() - 4, 0xaabbccddu32).unwrap();
+        assert_eq!(mapping.slice().read::<u32>(mapping.len() - 4).unwrap(), 0xaabbccdd);
+        assert!(HostMapping::reserve(0).is_err());
+
+        // Dropping the mapping removes its region, unless it is borrowed
+        let memory = Arc::new(GuestMemory::new());
+        memory.lock_regions().push(GuestRegion {
+            name: "lowmem", segid: 0, segoff: 0, gpa: 0, len: mapping.len() as u64, prot: 0, host_addr: mapping.addr(),
+        });
+        mapping.attach(&memory);
+        let dma = memory.borrow_dma(0, 4).unwrap();
+        assert!(!memory.remove_host_range(mapping.addr(), mapping.len() as u64));
+        drop(dma);
+        drop(mapping);
+        assert!(memory.regions().is_empty());
+    }
+
+    #[test]
+    fn test_borrow_dma() {
+        let memory = GuestMemory::new();
+        memory.lock_regions().push(GuestRegion {
+            name: "lowmem", segid: 0, segoff: 0, gpa: 0, len: 0x10000, prot: 0, host_addr: 0x7000_0000,
+        });
+        assert!(memory.borrow_dma(0xff00, 0x200).is_err());
+        assert!(memory.borrow_dma(0x1000, 0).is_err());
+
+        let dma = memory.borrow_dma(0x1000, 0x100).unwrap();
+        assert_eq!(dma.host_addr(), 0x7000_1000);
+        assert!(memory.dma_in_flight());
+        assert_eq!(memory.lock_dma_free(0x10ff, 1).unwrap_err().errno(), EBUSY);
+        assert!(memory.lock_dma_free(0x1100, 0x100).is_ok());
+        assert!(!memory.wait_dma_idle(Duration::from_millis(1)));
+
+        drop(dma);
+        assert!(memory.wait_dma_idle(Duration::from_millis(1)));
+        assert!(memory.lock_dma_free(0, !0).is_ok());
+    }
+
+    #[test]
+    fn test_guest_memory_access() {
+        let mut backing = vec![0u8; 0x100];
+        let memory = GuestMemory::new();
+        memory.lock_regions().push(GuestRegion {
+            name: "lowmem", segid: 0, segoff: 0, gpa: 0x1000, len: 0x100, prot: 0, host_addr: backing.as_mut_ptr() as u64,
+        });
+        memory.write_slice(0x1010, b"guest").unwrap();
+        memory.write_obj(0x1021, 0x11223344u32).unwrap();
+        let mut buf = [0; 5];
+        memory.read_slice(0x1010, &mut buf).unwrap();
+        assert_eq!(&buf, b"guest");
+        assert_eq!(memory.read_obj::<u32>(0x1021).unwrap(), 0x11223344);
+        assert_eq!(memory.read_obj::<u8>(0x1021).unwrap(), 0x44);
+
+        assert_eq!(memory.read_obj::<u64>(0x10fc).unwrap_err().errno(), EFAULT);
+        assert!(memory.write_slice(0xff0, &[0; 0x20]).is_err());
+        assert!(memory.read_slice(0x2000, &mut []).is_ok());
+        assert!(!memory.dma_in_flight());
+        drop(memory);
+        assert_eq!(&backing[0x10..0x15], b"guest");
+    }
+
+    #[test]
+    fn test_covers() {
+        let memory = GuestMemory::new();
+        memory.lock_regions().push(GuestRegion {
+            name: "bootrom", segid: 1, segoff: 0, gpa: 0xffe0_0000, len: 0x20_0000, prot: libc::PROT_READ | libc::PROT_EXEC, host_addr: 0,
+        });
+        assert!(memory.covers(0xffe0_0000, 0x20_0000, libc::PROT_READ));
+        assert!(!memory.covers(0xffe0_0000, 0x20_0000, libc::PROT_READ | libc::PROT_WRITE));
+        assert!(!memory.covers(0xffdf_f000, 0x2000, libc::PROT_READ));
+        assert!(!memory.covers(0xffff_0000, !0, libc::PROT_READ));
+    }
+
+    #[test]
+    fn test_guest_memory_teardown() {
+        let backing = alloc_guest_backing(0x2000, BackingOptions::default()).unwrap();
+        let memory = GuestMemory::new();
+        memory.lock_regions().push(GuestRegion {
+            name: "lowmem", segid: 0, segoff: 0, gpa: 0, len: 0x2000, prot: 0, host_addr: backing.addr(),
+        });
+        memory.write_slice(0x1000, b"guest").unwrap();
+        let dma = memory.borrow_dma(0x1000, 5).unwrap();
+        assert_eq!(memory.teardown().unwrap_err().errno(), EBUSY);
+        drop(dma);
+
+        memory.teardown().unwrap();
+        assert!(memory.regions().is_empty());
+        assert_eq!(memory.read_obj::<u8>(0x1000).unwrap_err().errno(), EFAULT);
+
+        // Memory supplied by the caller is left alone
+        let guest = unsafe { std::slice::from_raw_parts(backing.as_ptr().add(0x1000), 5) };
+        assert_eq!(guest, b"guest");
+
+        // The library's own mapping is reserved again, for it to unmap
+        let mut mapping = HostMapping::reserve(0x1000).unwrap();
+        let memory = Arc::new(memory);
+        mapping.attach(&memory);
+        memory.lock_regions().push(GuestRegion {
+            name: "highmem", segid: 1, segoff: 0, gpa: 1 << 32, len: 0x1000, prot: 0, host_addr: mapping.addr(),
+        });
+        assert!(memory.owns(mapping.addr(), 0x1000));
+        assert!(!memory.owns(backing.addr(), 0x1000));
+        memory.teardown().unwrap();
+        assert!(memory.regions().is_empty());
+        let addr = mapping.addr();
+        drop(mapping);
+        assert!(!memory.owns(addr, 0x1000));
+    }
+
+    #[test]
+    fn test_scan_guest_mem() {
+        let mut matches = Vec::new();
+        find_all(b"abcabcab", b"cab", 0x1000, &mut matches);
+        find_all(b"ab", b"abc", 0x2000, &mut matches);
+        assert_eq!(matches, vec![0x1002, 0x1005]);
+
+        // A match straddling two chunks of the scan is still found
+        let mut backing = vec![0u8; SCAN_CHUNK_SIZE + 0x1000];
+        backing[SCAN_CHUNK_SIZE - 2..SCAN_CHUNK_SIZE + 2].copy_from_slice(b"RSDP");
+        backing[0x10..0x14].copy_from_slice(b"RSDP");
+        let memory = GuestMemory::new();
+        memory.lock_regions().push(GuestRegion {
+            name: "lowmem", segid: 0, segoff: 0, gpa: 0, len: backing.len() as u64, prot: 0, host_addr: backing.as_mut_ptr() as u64,
+        });
+        let end = backing.len() as u64;
+        assert_eq!(memory.scan_guest_mem(b"RSDP", 0..end).unwrap(), vec![0x10, SCAN_CHUNK_SIZE as u64 - 2]);
+        assert_eq!(memory.scan_guest_mem(b"RSDP", 0x11..0x1000000).unwrap(), vec![SCAN_CHUNK_SIZE as u64 - 2]);
+        assert!(memory.scan_guest_mem(b"", 0..end).is_err());
+
+        // Patterns longer than a chunk are found too
+        let mut long = vec![7u8; SCAN_CHUNK_SIZE + 2];
+        let memory = GuestMemory::new();
+        memory.lock_regions().push(GuestRegion {
+            name: "lowmem", segid: 0, segoff: 0, gpa: 0, len: long.len() as u64, prot: 0, host_addr: long.as_mut_ptr() as u64,
+        });
+        assert_eq!(memory.scan_guest_mem(&[7; SCAN_CHUNK_SIZE + 1], 0..end).unwrap(), vec![0, 1]);
+    }
+}
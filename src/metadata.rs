@@ -0,0 +1,339 @@
+//! UUIDs and metadata for virtual machine instances.
+//!
+//! The kernel only knows a VM by its name. Orchestration systems that need
+//! to correlate a kernel VM with their own records can attach a UUID and
+//! key/value metadata when the VM is created, which the library keeps in a
+//! `MetadataStore`. The default store lives under `/var/run`, which is
+//! cleared on reboot along with the kernel VMs themselves.
+//!
+//!     use bhyve_api::metadata::*;
+//!     use bhyve_api::system::VMMSystem;
+//!
+//!     fn create(system: &VMMSystem, name: &str) -> Result<Uuid, bhyve_api::Error> {
+//!         let mut metadata = VmMetadata::new(Uuid::random()?);
+//!         metadata.insert("tenant", "test-farm");
+//!         system.create_vm_with_metadata(name, &metadata, &MetadataStore::default())?;
+//!         Ok(metadata.uuid)
+//!     }
+//!
+//! The guest sees the UUID as its system UUID once the firmware's SMBIOS
+//! type 1 table is filled in from `Uuid::smbios_bytes()`.
+
+use libc::EINVAL;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::Error;
+
+/// Directory of the default metadata store.
+pub const DEFAULT_METADATA_DIR: &str = "/var/run/bhyve-api";
+
+/// A 128-bit universally unique identifier.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Uuid([u8; 16]);
+
+impl Uuid {
+    /// Creates a UUID from its 16 bytes, in the big-endian order of its
+    /// string form.
+    pub fn from_bytes(bytes: [u8; 16]) -> Uuid {
+        Uuid(bytes)
+    }
+
+    /// Creates a random (version 4) UUID.
+    pub fn random() -> Result<Uuid, Error> {
+        let mut bytes = [0; 16];
+        File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Ok(Uuid(bytes))
+    }
+
+    /// Returns the bytes of the UUID, in the big-endian order of its string
+    /// form.
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    /// Returns the bytes of the UUID in the order of the SMBIOS System
+    /// Information (type 1) table, where the first three fields are little
+    /// endian.
+    pub fn smbios_bytes(&self) -> [u8; 16] {
+        let mut bytes = self.0;
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        bytes
+    }
+}
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i == 4 || i == 6 || i == 8 || i == 10 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Uuid {
+    type Err = Error;
+
+    /// Parses the hyphenated form of a UUID, in either case.
+    fn from_str(s: &str) -> Result<Uuid, Error> {
+        let groups: Vec<&str> = s.split('-').collect();
+        let lens: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+        if lens != [8, 4, 4, 4, 12] {
+            return Err(Error::new(EINVAL));
+        }
+        // Checked up front, since the groups are sliced by byte and
+        // from_str_radix() accepts a leading '+'
+        let hex: String = groups.concat();
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(Error::new(EINVAL));
+        }
+        let mut bytes = [0; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = match u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16) {
+                Ok(byte) => byte,
+                Err(_) => return Err(Error::new(EINVAL)),
+            };
+        }
+        Ok(Uuid(bytes))
+    }
+}
+
+/// The UUID and key/value metadata attached to a virtual machine.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VmMetadata {
+    pub uuid: Uuid,
+    entries: BTreeMap<String, String>,
+}
+
+impl VmMetadata {
+    /// Creates metadata with 'uuid' and no entries.
+    pub fn new(uuid: Uuid) -> VmMetadata {
+        VmMetadata { uuid: uuid, entries: BTreeMap::new() }
+    }
+
+    /// Sets 'key' to 'value', returning the previous value.
+    pub fn insert(&mut self, key: &str, value: &str) -> Option<String> {
+        self.entries.insert(key.to_string(), value.to_string())
+    }
+
+    /// Returns the value of 'key'.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(|value| value.as_str())
+    }
+
+    /// Removes 'key', returning its value.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.entries.remove(key)
+    }
+
+    /// Returns the entries, sorted by key.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    // Serializes the metadata as "uuid=..." followed by a "key=value" line
+    // per entry.
+    fn encode(&self) -> String {
+        let mut text = format!("uuid={}\n", self.uuid);
+        for (key, value) in self.entries.iter() {
+            text.push_str(&format!("{}={}\n", escape(key), escape(value)));
+        }
+        text
+    }
+
+    fn decode(text: &str) -> Result<VmMetadata, Error> {
+        let mut lines = text.lines();
+        let uuid = match lines.next() {
+            Some(line) if line.starts_with("uuid=") => line["uuid=".len()..].parse()?,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        let mut metadata = VmMetadata::new(uuid);
+        for line in lines {
+            let (key, value) = split_entry(line)?;
+            metadata.entries.insert(key, value);
+        }
+        Ok(metadata)
+    }
+}
+
+// Escapes the characters that would break the line format.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '=' => escaped.push_str("\\="),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Splits an escaped "key=value" line at its first unescaped '=', and
+// unescapes both halves.
+fn split_entry(line: &str) -> Result<(String, String), Error> {
+    let mut key = String::new();
+    let mut value = String::new();
+    let mut in_value = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        let out = if in_value { &mut value } else { &mut key };
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some(c @ '\\') | Some(c @ '=') => out.push(c),
+                _ => return Err(Error::new(EINVAL)),
+            },
+            '=' if !in_value => in_value = true,
+            c => out.push(c),
+        }
+    }
+    if !in_value {
+        return Err(Error::new(EINVAL));
+    }
+    Ok((key, value))
+}
+
+/// A directory holding the metadata of virtual machines, in a file per VM
+/// named after it.
+#[derive(Debug, Clone)]
+pub struct MetadataStore {
+    dir: PathBuf,
+}
+
+impl MetadataStore {
+    /// Creates a store in 'dir', which is created when metadata is first
+    /// saved.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> MetadataStore {
+        MetadataStore { dir: dir.into() }
+    }
+
+    /// Saves the metadata of the VM called 'name', replacing any saved
+    /// before. The file is replaced atomically, so readers never see a
+    /// partial write.
+    pub fn save(&self, name: &str, metadata: &VmMetadata) -> Result<(), Error> {
+        let path = self.path(name)?;
+        fs::create_dir_all(&self.dir)?;
+        let tmp = self.dir.join(format!(".{}.tmp", name));
+        let mut file = File::create(&tmp)?;
+        file.write_all(metadata.encode().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Loads the metadata of the VM called 'name', or 'None' if none was
+    /// saved.
+    pub fn load(&self, name: &str) -> Result<Option<VmMetadata>, Error> {
+        let text = match fs::read_to_string(self.path(name)?) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::from(e)),
+        };
+        VmMetadata::decode(&text).map(Some)
+    }
+
+    /// Removes the metadata of the VM called 'name', if any was saved.
+    pub fn remove(&self, name: &str) -> Result<(), Error> {
+        match fs::remove_file(self.path(name)?) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    /// Returns the name of the VM whose saved metadata has 'uuid', or 'None'
+    /// if there is none.
+    pub fn find(&self, uuid: &Uuid) -> Result<Option<String>, Error> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::from(e)),
+        };
+        for entry in entries {
+            let entry = entry?;
+            // Skips the temporary files of saves in progress
+            let name = match entry.file_name().to_str() {
+                Some(name) if !name.starts_with('.') => name.to_string(),
+                _ => continue,
+            };
+            if let Some(metadata) = self.load(&name)? {
+                if metadata.uuid == *uuid {
+                    return Ok(Some(name));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Returns the file for 'name', rejecting names that would escape the
+    // store's directory.
+    fn path(&self, name: &str) -> Result<PathBuf, Error> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(self.dir.join(name))
+    }
+}
+
+impl Default for MetadataStore {
+    fn default() -> MetadataStore {
+        MetadataStore::new(DEFAULT_METADATA_DIR)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uuid() {
+        let uuid: Uuid = "4A3B2C1D-0E0F-4011-8213-141516171819".parse().unwrap();
+        assert_eq!(uuid.to_string(), "4a3b2c1d-0e0f-4011-8213-141516171819");
+        assert_eq!(&uuid.smbios_bytes()[..8], &[0x1d, 0x2c, 0x3b, 0x4a, 0x0f, 0x0e, 0x11, 0x40]);
+        assert_eq!(&uuid.smbios_bytes()[8..], &uuid.as_bytes()[8..]);
+        assert!("4a3b2c1d0e0f-4011-8213-141516171819".parse::<Uuid>().is_err());
+        assert!("4a3b2c1d-0e0f-4011-8213-14151617181g".parse::<Uuid>().is_err());
+        assert!("+a3b2c1d-0e0f-4011-8213-141516171819".parse::<Uuid>().is_err());
+        assert!("4a3b2c1d-0e0f-4011-8213-1415161718\u{e9}".parse::<Uuid>().is_err());
+    }
+
+    #[test]
+    fn test_store_find() {
+        let dir = std::env::temp_dir().join(format!("bhyve-api-metadata-{}", std::process::id()));
+        let store = MetadataStore::new(&dir);
+        let uuid = Uuid::from_bytes([3; 16]);
+        assert_eq!(store.find(&uuid).unwrap(), None);
+
+        store.save("vm1", &VmMetadata::new(Uuid::from_bytes([1; 16]))).unwrap();
+        store.save("vm3", &VmMetadata::new(uuid)).unwrap();
+        let found = store.find(&uuid);
+        let missing = store.find(&Uuid::from_bytes([2; 16]));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found.unwrap(), Some("vm3".to_string()));
+        assert_eq!(missing.unwrap(), None);
+    }
+
+    #[test]
+    fn test_metadata_encoding() {
+        let mut metadata = VmMetadata::new(Uuid::from_bytes([7; 16]));
+        metadata.insert("owner", "ci");
+        metadata.insert("a=b", "line\nbreak \\ and =");
+        let decoded = VmMetadata::decode(&metadata.encode()).unwrap();
+        assert_eq!(decoded, metadata);
+        assert_eq!(decoded.get("a=b"), Some("line\nbreak \\ and ="));
+        assert!(VmMetadata::decode("owner=ci\n").is_err());
+    }
+}
@@ -0,0 +1,338 @@
+//! MSI-X emulation for PCI devices.
+//!
+//! MSI-X lets a device signal each of its interrupt vectors with a message
+//! the guest programs in a table in one of the device's BARs. The guest can
+//! mask vectors individually or all at once, and a vector signalled while
+//! masked is recorded in the pending bit array (PBA) and delivered when it
+//! is unmasked. `Msix` implements the capability, the table, and the PBA, so
+//! a device model only has to route the accesses to it, and call `notify()`
+//! to signal a vector.
+//!
+//!     use bhyve_api::msix::Msix;
+//!     use bhyve_api::pci::*;
+//!     use bhyve_api::vm::VirtualMachine;
+//!     use std::sync::Arc;
+//!
+//!     fn setup(vm: Arc<VirtualMachine>, config: &mut PciConfig) -> Result<Msix, bhyve_api::Error> {
+//!         // Two vectors, with the table and PBA in BAR 1
+//!         let mut msix = Msix::new(vm, 2)?;
+//!         config.add_bar(1, Bar { kind: BarKind::Mmio32, size: 0x1000, prefetchable: false })?;
+//!         msix.add_capability(config, 1, 0, 1, 0x800)?;
+//!         Ok(msix)
+//!     }
+//!
+//! The device's `config_write()` calls `config_updated()` after updating
+//! the configuration, and its `bar_read()` and `bar_write()` pass accesses to
+//! the table and PBA to `table_read()`, `table_write()`, and `pba_read()`.
+
+use libc::EINVAL;
+use std::sync::Arc;
+
+use crate::pci::PciConfig;
+use crate::vm::VirtualMachine;
+use crate::Error;
+
+/// Capability ID of MSI-X.
+pub const MSIX_CAP_ID: u8 = 0x11;
+/// Size of an entry in the MSI-X table.
+pub const MSIX_ENTRY_SIZE: u64 = 16;
+/// Maximum number of vectors a device can have.
+pub const MSIX_MAX_VECTORS: u16 = 2048;
+
+const MSIX_CAP_LEN: usize = 12;
+
+// Message control register bits
+const CONTROL_ENABLE: u16 = 0x8000;
+const CONTROL_FUNCTION_MASK: u16 = 0x4000;
+
+// Vector control bit of a table entry
+const ENTRY_MASKED: u32 = 0x1;
+
+// A message the guest programmed for a vector, ready to be sent.
+type Message = (u64, u32);
+
+#[derive(Debug, Copy, Clone)]
+struct Entry {
+    addr: u64,
+    data: u32,
+    control: u32,
+}
+
+// The table, PBA, and control bits, without the means of delivery.
+#[derive(Debug, Clone)]
+struct MsixTable {
+    entries: Vec<Entry>,
+    pending: Vec<u64>,
+    enabled: bool,
+    function_masked: bool,
+}
+
+impl MsixTable {
+    fn new(vectors: u16) -> MsixTable {
+        MsixTable {
+            // Vectors are masked until the guest sets them up
+            entries: vec![Entry { addr: 0, data: 0, control: ENTRY_MASKED }; vectors as usize],
+            pending: vec![0; vectors.div_ceil(64) as usize],
+            enabled: false,
+            function_masked: false,
+        }
+    }
+
+    fn masked(&self, vector: usize) -> bool {
+        self.function_masked || (self.entries[vector].control & ENTRY_MASKED) != 0
+    }
+
+    fn is_pending(&self, vector: usize) -> bool {
+        (self.pending[vector / 64] & (1 << (vector % 64))) != 0
+    }
+
+    fn set_pending(&mut self, vector: usize, pending: bool) {
+        if pending {
+            self.pending[vector / 64] |= 1 << (vector % 64);
+        } else {
+            self.pending[vector / 64] &= !(1 << (vector % 64));
+        }
+    }
+
+    // Returns the message to send for 'vector', or 'None' if it was left
+    // pending, or MSI-X is disabled.
+    fn signal(&mut self, vector: usize) -> Option<Message> {
+        if !self.enabled {
+            return None;
+        }
+        if self.masked(vector) {
+            self.set_pending(vector, true);
+            return None;
+        }
+        let entry = &self.entries[vector];
+        Some((entry.addr, entry.data))
+    }
+
+    // Clears and returns the messages of pending vectors that are no longer
+    // masked.
+    fn take_unmasked(&mut self) -> Vec<Message> {
+        if !self.enabled || self.function_masked {
+            return Vec::new();
+        }
+        let mut messages = Vec::new();
+        for vector in 0..self.entries.len() {
+            if self.is_pending(vector) && !self.masked(vector) {
+                self.set_pending(vector, false);
+                messages.push((self.entries[vector].addr, self.entries[vector].data));
+            }
+        }
+        messages
+    }
+
+    fn set_control(&mut self, control: u16) -> Vec<Message> {
+        self.enabled = (control & CONTROL_ENABLE) != 0;
+        self.function_masked = (control & CONTROL_FUNCTION_MASK) != 0;
+        self.take_unmasked()
+    }
+
+    fn read_dword(&self, offset: u64) -> u32 {
+        let entry = match self.entries.get((offset / MSIX_ENTRY_SIZE) as usize) {
+            Some(entry) => entry,
+            None => return 0,
+        };
+        match (offset % MSIX_ENTRY_SIZE) / 4 {
+            0 => entry.addr as u32,
+            1 => (entry.addr >> 32) as u32,
+            2 => entry.data,
+            _ => entry.control,
+        }
+    }
+
+    fn write_dword(&mut self, offset: u64, value: u32) {
+        let entry = match self.entries.get_mut((offset / MSIX_ENTRY_SIZE) as usize) {
+            Some(entry) => entry,
+            None => return,
+        };
+        match (offset % MSIX_ENTRY_SIZE) / 4 {
+            0 => entry.addr = (entry.addr & !0xffff_ffff) | value as u64,
+            1 => entry.addr = (entry.addr & 0xffff_ffff) | (value as u64) << 32,
+            2 => entry.data = value,
+            _ => entry.control = value & ENTRY_MASKED,
+        }
+    }
+
+    fn read(&self, offset: u64, size: u8) -> u64 {
+        match size {
+            4 => self.read_dword(offset) as u64,
+            8 => self.read_dword(offset) as u64 | (self.read_dword(offset + 4) as u64) << 32,
+            _ => 0,
+        }
+    }
+
+    // Writes to the table, and returns the messages of pending vectors the
+    // write unmasked. Accesses must be dword or qword aligned.
+    fn write(&mut self, offset: u64, size: u8, value: u64) -> Vec<Message> {
+        match size {
+            4 => self.write_dword(offset, value as u32),
+            8 => {
+                self.write_dword(offset, value as u32);
+                self.write_dword(offset + 4, (value >> 32) as u32);
+            }
+            _ => return Vec::new(),
+        }
+        self.take_unmasked()
+    }
+
+    fn pba_read(&self, offset: u64, size: u8) -> u64 {
+        let qword = self.pending.get((offset / 8) as usize).cloned().unwrap_or(0);
+        match size {
+            4 => (qword >> ((offset % 8) * 8)) & 0xffff_ffff,
+            8 => qword,
+            _ => 0,
+        }
+    }
+}
+
+/// The MSI-X capability, table, and PBA of a device, delivering messages to
+/// the guest's local APICs.
+pub struct Msix {
+    vm: Arc<VirtualMachine>,
+    table: MsixTable,
+    capability: Option<usize>,
+}
+
+impl Msix {
+    /// Creates MSI-X state for 'vectors' vectors, from 1 to
+    /// `MSIX_MAX_VECTORS`, delivering messages to 'vm'.
+    pub fn new(vm: Arc<VirtualMachine>, vectors: u16) -> Result<Msix, Error> {
+        if vectors == 0 || vectors > MSIX_MAX_VECTORS {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(Msix { vm: vm, table: MsixTable::new(vectors), capability: None })
+    }
+
+    /// Returns the number of vectors.
+    pub fn vectors(&self) -> u16 {
+        self.table.entries.len() as u16
+    }
+
+    /// Returns the size of the table in bytes.
+    pub fn table_len(&self) -> u64 {
+        self.table.entries.len() as u64 * MSIX_ENTRY_SIZE
+    }
+
+    /// Returns the size of the PBA in bytes.
+    pub fn pba_len(&self) -> u64 {
+        self.table.pending.len() as u64 * 8
+    }
+
+    /// Adds the MSI-X capability to 'config', locating the table at
+    /// 'table_offset' in BAR 'table_bar', and the PBA at 'pba_offset' in BAR
+    /// 'pba_bar'. Returns the offset of the capability, or `EINVAL` if an
+    /// offset isn't 8-byte aligned or a BAR index is out of range.
+    pub fn add_capability(&mut self, config: &mut PciConfig, table_bar: u8, table_offset: u32, pba_bar: u8, pba_offset: u32) -> Result<usize, Error> {
+        if table_bar > 5 || pba_bar > 5 || (table_offset | pba_offset) & 0x7 != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let offset = config.add_capability(MSIX_CAP_ID, MSIX_CAP_LEN)?;
+        config.set_u16(offset + 2, self.vectors() - 1);
+        // The guest can only change the enable and function mask bits
+        config.set_writable(offset + 3, &[0xc0]);
+        config.set_u32(offset + 4, table_offset | table_bar as u32);
+        config.set_u32(offset + 8, pba_offset | pba_bar as u32);
+        self.capability = Some(offset);
+        Ok(offset)
+    }
+
+    /// Picks up changes the guest made to the message control register in
+    /// 'config', delivering pending vectors that are no longer masked.
+    pub fn config_updated(&mut self, config: &PciConfig) -> Result<(), Error> {
+        let offset = match self.capability {
+            Some(offset) => offset,
+            None => return Ok(()),
+        };
+        let messages = self.table.set_control(config.get_u16(offset + 2));
+        self.deliver(messages)
+    }
+
+    /// Returns true if the guest has enabled MSI-X. While it is disabled,
+    /// the device uses INTx instead.
+    pub fn enabled(&self) -> bool {
+        self.table.enabled
+    }
+
+    /// Handles a read of 'size' bytes at 'offset' in the table.
+    pub fn table_read(&self, offset: u64, size: u8) -> u64 {
+        self.table.read(offset, size)
+    }
+
+    /// Handles a write to the table, delivering pending vectors that the
+    /// write unmasks.
+    pub fn table_write(&mut self, offset: u64, size: u8, value: u64) -> Result<(), Error> {
+        let messages = self.table.write(offset, size, value);
+        self.deliver(messages)
+    }
+
+    /// Handles a read of 'size' bytes at 'offset' in the PBA. The PBA is
+    /// read-only.
+    pub fn pba_read(&self, offset: u64, size: u8) -> u64 {
+        self.table.pba_read(offset, size)
+    }
+
+    /// Signals 'vector'. The message is sent if the vector is unmasked, and
+    /// left pending otherwise. Returns false if MSI-X is disabled, in which
+    /// case the caller should raise INTx, or `EINVAL` for an invalid vector.
+    pub fn notify(&mut self, vector: u16) -> Result<bool, Error> {
+        if vector >= self.vectors() {
+            return Err(Error::new(EINVAL));
+        }
+        if !self.table.enabled {
+            return Ok(false);
+        }
+        if let Some(message) = self.table.signal(vector as usize) {
+            self.deliver(vec![message])?;
+        }
+        Ok(true)
+    }
+
+    /// Returns the table and PBA to their power-on state, with every vector
+    /// masked and none pending.
+    pub fn reset(&mut self) {
+        self.table = MsixTable::new(self.vectors());
+    }
+
+    fn deliver(&self, messages: Vec<Message>) -> Result<(), Error> {
+        for (addr, data) in messages {
+            self.vm.lapic_msi(addr, data as u64)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_msix_masking() {
+        let mut table = MsixTable::new(65);
+        assert_eq!(table.pending.len(), 2);
+        table.write(0x10, 8, 0xfee0_1000);
+        table.write(0x18, 4, 0x41);
+        assert_eq!(table.read(0x10, 4), 0xfee0_1000);
+        assert_eq!(table.read(0x1c, 4), ENTRY_MASKED as u64);
+
+        // Nothing is sent or recorded until MSI-X is enabled
+        assert_eq!(table.signal(1), None);
+        assert!(table.set_control(CONTROL_ENABLE | CONTROL_FUNCTION_MASK).is_empty());
+        // Masked vectors are left pending, and sent once unmasked
+        assert_eq!(table.signal(1), None);
+        assert_eq!(table.pba_read(0, 8), 0x2);
+        assert!(table.set_control(CONTROL_ENABLE).is_empty());
+        assert_eq!(table.write(0x1c, 4, 0), vec![(0xfee0_1000, 0x41)]);
+        assert_eq!(table.pba_read(0, 8), 0);
+        assert_eq!(table.signal(1), Some((0xfee0_1000, 0x41)));
+
+        table.write(64 * MSIX_ENTRY_SIZE + 12, 4, 0);
+        table.set_control(CONTROL_ENABLE | CONTROL_FUNCTION_MASK);
+        table.signal(64);
+        assert_eq!(table.pba_read(8, 4), 1);
+        assert_eq!(table.set_control(CONTROL_ENABLE), vec![(0, 0)]);
+        assert_eq!(table.read(0x1000, 4), 0);
+    }
+}
@@ -0,0 +1,695 @@
+//! PCI configuration space, a host bridge, and BAR allocation.
+//!
+//! Emulated PCI devices implement `PciDevice`, which gives the guest access
+//! to their configuration space and to the memory ranges of their base
+//! address registers (BARs). A `PciHostBridge` on the `PioBus` provides
+//! configuration mechanism #1 for bus 0. When a device is added, the bridge
+//! assigns its BARs addresses from a `BarAllocator`, programs them in the
+//! device's configuration space, and registers them on the `MmioBus`. If
+//! the guest later moves a BAR or turns off memory decoding, the bridge
+//! updates the `MmioBus` to match.
+//!
+//!     use bhyve_api::device::{MmioBus, PioBus};
+//!     use bhyve_api::pci::*;
+//!     use bhyve_api::vm::VirtualMachine;
+//!     use std::sync::{Arc, Mutex};
+//!
+//!     fn setup(vm: &VirtualMachine, pio: &mut PioBus, mmio: Arc<Mutex<MmioBus>>) -> Result<Arc<Mutex<PciHostBridge>>, bhyve_api::Error> {
+//!         // The PCI hole below 4GB, and a 64-bit window above guest memory
+//!         let allocator = BarAllocator::new(vm.lowmem_limit as u64..PCI_HOLE_END, 0x80_0000_0000..0x100_0000_0000);
+//!         let bridge = Arc::new(Mutex::new(PciHostBridge::new(mmio, allocator)));
+//!         pio.register(PCI_CONFIG_ADDRESS_PORT, PCI_CONFIG_PORT_LEN, bridge.clone())?;
+//!         Ok(bridge)
+//!     }
+
+use libc::{EEXIST, EINVAL, ENOSPC};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use crate::device::{GuestDevice, GuestMmioDevice, GuestPioDevice, IoValue, IoWidth, MmioBus};
+use crate::Error;
+
+/// I/O port of the configuration address register, followed by the
+/// configuration data register at 0xcfc.
+pub const PCI_CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+/// Number of I/O ports used by configuration mechanism #1.
+pub const PCI_CONFIG_PORT_LEN: u16 = 8;
+/// Size of the configuration space of a device.
+pub const PCI_CONFIG_SPACE_LEN: usize = 256;
+/// Number of device slots on a bus.
+pub const PCI_SLOTS: u8 = 32;
+/// Number of base address registers in a type 0 header.
+pub const PCI_BARS: usize = 6;
+/// End of the PCI hole below 4GB, where the I/O APIC, HPET, and local APIC
+/// ranges begin.
+pub const PCI_HOLE_END: u64 = 0xfe00_0000;
+
+/// Vendor ID of the host bridge, as used by bhyve.
+pub const HOST_BRIDGE_VENDOR_ID: u16 = 0x1275;
+/// Device ID of the host bridge, as used by bhyve.
+pub const HOST_BRIDGE_DEVICE_ID: u16 = 0x1275;
+
+// Offsets in the type 0 configuration header.
+const PCI_VENDOR_ID: usize = 0x00;
+const PCI_DEVICE_ID: usize = 0x02;
+const PCI_COMMAND: usize = 0x04;
+const PCI_STATUS: usize = 0x06;
+const PCI_REVISION: usize = 0x08;
+const PCI_BAR0: usize = 0x10;
+const PCI_SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+const PCI_SUBSYSTEM_ID: usize = 0x2e;
+const PCI_CAPABILITIES_POINTER: usize = 0x34;
+const PCI_INTERRUPT_LINE: usize = 0x3c;
+const PCI_INTERRUPT_PIN: usize = 0x3d;
+
+// Command register bits the guest can change: I/O and memory decoding, bus
+// mastering, and INTx disable.
+const COMMAND_MEMORY: u16 = 0x0002;
+const COMMAND_WRITABLE: u16 = 0x0407;
+
+const STATUS_CAPABILITIES: u16 = 0x0010;
+// Capabilities are placed after the type 0 header.
+const CAPABILITIES_START: usize = 0x40;
+
+const CONFIG_ENABLE: u32 = 0x8000_0000;
+
+/// The kind of a memory base address register.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BarKind {
+    /// A BAR that must be placed below 4GB.
+    Mmio32,
+    /// A BAR that can be placed anywhere, using two BAR registers.
+    Mmio64,
+}
+
+/// A memory range a device decodes, described by a base address register.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Bar {
+    pub kind: BarKind,
+    /// Size in bytes, a power of two of at least 16.
+    pub size: u64,
+    pub prefetchable: bool,
+}
+
+/// The configuration space of a device with a type 0 header. Each byte has a
+/// mask of the bits the guest can write; the rest are read-only.
+#[derive(Debug, Clone)]
+pub struct PciConfig {
+    data: Vec<u8>,
+    writable: Vec<u8>,
+    bars: [Option<Bar>; PCI_BARS],
+    // Offsets of the last capability in the list, and of the free space
+    last_capability: Option<usize>,
+    next_capability: usize,
+}
+
+impl PciConfig {
+    /// Creates the configuration space for a single function device with
+    /// the given IDs and class code, and no BARs.
+    pub fn new(vendor_id: u16, device_id: u16, class: u8, subclass: u8, prog_if: u8) -> PciConfig {
+        let mut config = PciConfig {
+            data: vec![0; PCI_CONFIG_SPACE_LEN],
+            writable: vec![0; PCI_CONFIG_SPACE_LEN],
+            bars: [None; PCI_BARS],
+            last_capability: None,
+            next_capability: CAPABILITIES_START,
+        };
+        config.set_u16(PCI_VENDOR_ID, vendor_id);
+        config.set_u16(PCI_DEVICE_ID, device_id);
+        config.set_u32(PCI_REVISION, (class as u32) << 24 | (subclass as u32) << 16 | (prog_if as u32) << 8);
+        config.set_writable(PCI_COMMAND, &COMMAND_WRITABLE.to_le_bytes());
+        config.set_writable(PCI_INTERRUPT_LINE, &[0xff]);
+        config
+    }
+
+    /// Sets the subsystem vendor and subsystem IDs.
+    pub fn set_subsystem(&mut self, vendor_id: u16, subsystem_id: u16) {
+        self.set_u16(PCI_SUBSYSTEM_VENDOR_ID, vendor_id);
+        self.set_u16(PCI_SUBSYSTEM_ID, subsystem_id);
+    }
+
+    /// Sets the INTx pin the device uses, 1 for INTA# through 4 for INTD#,
+    /// or 0 for none.
+    pub fn set_interrupt_pin(&mut self, pin: u8) {
+        self.set_u8(PCI_INTERRUPT_PIN, pin);
+    }
+
+    /// Describes BAR 'index'. A 64-bit BAR also uses the register at
+    /// 'index' + 1. Returns `EINVAL` if the size isn't a power of two of at
+    /// least 16, or the registers are out of range, and `EEXIST` if they are
+    /// already in use.
+    pub fn add_bar(&mut self, index: usize, bar: Bar) -> Result<(), Error> {
+        let last = match bar.kind {
+            BarKind::Mmio32 => index,
+            BarKind::Mmio64 => index + 1,
+        };
+        if last >= PCI_BARS || bar.size < 16 || !bar.size.is_power_of_two() {
+            return Err(Error::new(EINVAL));
+        }
+        if bar.kind == BarKind::Mmio32 && bar.size > 1 << 31 {
+            return Err(Error::new(EINVAL));
+        }
+        if (index..=last).any(|i| self.bars[i].is_some() || self.is_upper_half(i)) {
+            return Err(Error::new(EEXIST));
+        }
+
+        let mut flags = 0u32;
+        if bar.kind == BarKind::Mmio64 {
+            flags |= 0x4;
+        }
+        if bar.prefetchable {
+            flags |= 0x8;
+        }
+        let mask = !(bar.size - 1);
+        let offset = PCI_BAR0 + index * 4;
+        self.set_u32(offset, flags);
+        self.set_writable(offset, &((mask as u32) & !0xf).to_le_bytes());
+        if bar.kind == BarKind::Mmio64 {
+            self.set_writable(offset + 4, &((mask >> 32) as u32).to_le_bytes());
+        }
+        self.bars[index] = Some(bar);
+        Ok(())
+    }
+
+    /// Adds a capability with ID 'id' to the end of the capability list,
+    /// and returns its offset. 'len' is the length of the capability,
+    /// including the ID and next pointer, which are filled in; the caller
+    /// fills in the rest. Returns `ENOSPC` if it doesn't fit.
+    pub fn add_capability(&mut self, id: u8, len: usize) -> Result<usize, Error> {
+        let offset = self.next_capability;
+        if len < 2 || offset + len > PCI_CONFIG_SPACE_LEN {
+            return Err(Error::new(ENOSPC));
+        }
+        self.set_u8(offset, id);
+        self.set_u8(offset + 1, 0);
+        match self.last_capability {
+            Some(last) => self.set_u8(last + 1, offset as u8),
+            None => {
+                self.set_u8(PCI_CAPABILITIES_POINTER, offset as u8);
+                let status = self.get_u16(PCI_STATUS);
+                self.set_u16(PCI_STATUS, status | STATUS_CAPABILITIES);
+            }
+        }
+        self.last_capability = Some(offset);
+        // Capabilities are dword aligned
+        self.next_capability = (offset + len + 3) & !3;
+        Ok(offset)
+    }
+
+    /// Returns BAR 'index', or 'None' if it isn't in use, or is the upper
+    /// half of a 64-bit BAR.
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        self.bars.get(index).cloned().unwrap_or(None)
+    }
+
+    /// Returns the address BAR 'index' is programmed with.
+    pub fn bar_address(&self, index: usize) -> Option<u64> {
+        let bar = self.bar(index)?;
+        let offset = PCI_BAR0 + index * 4;
+        let mut address = (self.get_u32(offset) & !0xf) as u64;
+        if bar.kind == BarKind::Mmio64 {
+            address |= (self.get_u32(offset + 4) as u64) << 32;
+        }
+        Some(address)
+    }
+
+    /// Returns true if the guest has written all ones to BAR 'index', or to
+    /// the upper half of a 64-bit BAR, to read back its size, so the BAR
+    /// doesn't hold an address until the guest writes one.
+    pub fn bar_sizing(&self, index: usize) -> bool {
+        let bar = match self.bar(index) {
+            Some(bar) => bar,
+            None => return false,
+        };
+        let offset = PCI_BAR0 + index * 4;
+        let mask = !(bar.size - 1) as u32 & !0xf;
+        if (self.get_u32(offset) & mask) == mask {
+            return true;
+        }
+        bar.kind == BarKind::Mmio64 && self.get_u32(offset + 4) == !0
+    }
+
+    /// Programs BAR 'index' with 'address', which is aligned down to the
+    /// size of the BAR. Returns `EINVAL` if the BAR isn't in use, or a
+    /// 32-bit BAR is given an address above 4GB.
+    pub fn set_bar_address(&mut self, index: usize, address: u64) -> Result<(), Error> {
+        let bar = match self.bar(index) {
+            Some(bar) => bar,
+            None => return Err(Error::new(EINVAL)),
+        };
+        if bar.kind == BarKind::Mmio32 && address >> 32 != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let address = address & !(bar.size - 1);
+        let offset = PCI_BAR0 + index * 4;
+        let flags = self.get_u32(offset) & 0xf;
+        self.set_u32(offset, address as u32 | flags);
+        if bar.kind == BarKind::Mmio64 {
+            self.set_u32(offset + 4, (address >> 32) as u32);
+        }
+        Ok(())
+    }
+
+    /// Returns true if the guest has enabled memory decoding, so the device
+    /// responds at its BAR addresses.
+    pub fn memory_enabled(&self) -> bool {
+        (self.get_u16(PCI_COMMAND) & COMMAND_MEMORY) != 0
+    }
+
+    /// Enables or disables memory decoding.
+    pub fn set_memory_enabled(&mut self, enabled: bool) {
+        let command = self.get_u16(PCI_COMMAND) & !COMMAND_MEMORY;
+        self.set_u16(PCI_COMMAND, if enabled { command | COMMAND_MEMORY } else { command });
+    }
+
+    /// Handles a guest read of 'width' at byte 'offset'. Bytes beyond the
+    /// end of the configuration space read as zero.
+    pub fn read(&self, offset: u16, width: IoWidth) -> u32 {
+        let mut value = 0;
+        for i in (0..width.bytes() as usize).rev() {
+            let byte = self.data.get(offset as usize + i).cloned().unwrap_or(0);
+            value = (value << 8) | byte as u32;
+        }
+        value
+    }
+
+    /// Handles a guest write at byte 'offset', changing only the writable
+    /// bits.
+    pub fn write(&mut self, offset: u16, value: IoValue) {
+        for i in 0..value.width().bytes() as usize {
+            let index = offset as usize + i;
+            if index >= PCI_CONFIG_SPACE_LEN {
+                break;
+            }
+            let byte = (value.value() >> (i * 8)) as u8;
+            let mask = self.writable[index];
+            self.data[index] = (self.data[index] & !mask) | (byte & mask);
+        }
+    }
+
+    /// Returns the byte at 'offset'.
+    pub fn get_u8(&self, offset: usize) -> u8 {
+        self.data[offset]
+    }
+
+    /// Returns the little-endian 16-bit value at 'offset'.
+    pub fn get_u16(&self, offset: usize) -> u16 {
+        self.read(offset as u16, IoWidth::Word) as u16
+    }
+
+    /// Returns the little-endian 32-bit value at 'offset'.
+    pub fn get_u32(&self, offset: usize) -> u32 {
+        self.read(offset as u16, IoWidth::Dword)
+    }
+
+    /// Sets the byte at 'offset', regardless of which bits are writable.
+    pub fn set_u8(&mut self, offset: usize, value: u8) {
+        self.data[offset] = value;
+    }
+
+    /// Sets the 16-bit value at 'offset', regardless of which bits are
+    /// writable.
+    pub fn set_u16(&mut self, offset: usize, value: u16) {
+        self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Sets the 32-bit value at 'offset', regardless of which bits are
+    /// writable.
+    pub fn set_u32(&mut self, offset: usize, value: u32) {
+        self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Sets the masks of the bits the guest can write, for the bytes
+    /// starting at 'offset'.
+    pub fn set_writable(&mut self, offset: usize, mask: &[u8]) {
+        self.writable[offset..offset + mask.len()].copy_from_slice(mask);
+    }
+
+    fn is_upper_half(&self, index: usize) -> bool {
+        index > 0 && self.bar(index - 1).map(|bar| bar.kind) == Some(BarKind::Mmio64)
+    }
+}
+
+/// An emulated PCI device function.
+pub trait PciDevice: GuestDevice {
+    /// Returns the device's configuration space.
+    fn config(&self) -> &PciConfig;
+
+    /// Returns the device's configuration space for changes.
+    fn config_mut(&mut self) -> &mut PciConfig;
+
+    /// Handles a guest read of configuration space. Devices with registers
+    /// that change on their own, such as capability status, override this.
+    fn config_read(&mut self, offset: u16, width: IoWidth) -> u32 {
+        self.config().read(offset, width)
+    }
+
+    /// Handles a guest write to configuration space. Devices that act on
+    /// configuration changes override this, and call `PciConfig::write()`.
+    fn config_write(&mut self, offset: u16, value: IoValue) {
+        self.config_mut().write(offset, value)
+    }
+
+    /// Handles a read at 'offset' within BAR 'bar'.
+    fn bar_read(&mut self, bar: usize, offset: u64, size: u8) -> u64;
+
+    /// Handles a write at 'offset' within BAR 'bar'.
+    fn bar_write(&mut self, bar: usize, offset: u64, size: u8, value: u64);
+}
+
+// Registered on the MmioBus for one BAR of a device.
+struct BarRegion {
+    device: Arc<Mutex<dyn PciDevice>>,
+    bar: usize,
+}
+
+impl GuestDevice for BarRegion {}
+
+impl GuestMmioDevice for BarRegion {
+    fn mmio_read(&mut self, offset: u64, size: u8) -> u64 {
+        self.device.lock().unwrap().bar_read(self.bar, offset, size)
+    }
+
+    fn mmio_write(&mut self, offset: u64, size: u8, value: u64) {
+        self.device.lock().unwrap().bar_write(self.bar, offset, size, value)
+    }
+}
+
+/// Assigns guest physical addresses to BARs, from the PCI hole below 4GB
+/// for 32-bit BARs, and from a window above guest memory for 64-bit BARs.
+#[derive(Debug, Clone)]
+pub struct BarAllocator {
+    hole: Range<u64>,
+    window: Range<u64>,
+}
+
+impl BarAllocator {
+    /// Creates an allocator for the ranges 'hole' and 'window'.
+    pub fn new(hole: Range<u64>, window: Range<u64>) -> BarAllocator {
+        BarAllocator { hole: hole, window: window }
+    }
+
+    /// Returns an address for 'bar', aligned to its size, or 'None' if
+    /// there's no space left. 64-bit BARs are placed in the hole once the
+    /// window is full.
+    pub fn allocate(&mut self, bar: Bar) -> Option<u64> {
+        if bar.kind == BarKind::Mmio64 {
+            if let Some(address) = allocate_from(&mut self.window, bar.size) {
+                return Some(address);
+            }
+        }
+        allocate_from(&mut self.hole, bar.size)
+    }
+}
+
+fn allocate_from(range: &mut Range<u64>, size: u64) -> Option<u64> {
+    let address = range.start.checked_add(size - 1)? & !(size - 1);
+    let end = address.checked_add(size)?;
+    if end > range.end {
+        return None;
+    }
+    range.start = end;
+    Some(address)
+}
+
+/// The host bridge of PCI bus 0, handling configuration mechanism #1 at
+/// `PCI_CONFIG_ADDRESS_PORT`. The bridge itself is device 0.
+pub struct PciHostBridge {
+    config: PciConfig,
+    address: u32,
+    slots: Vec<Option<Arc<Mutex<dyn PciDevice>>>>,
+    mmio: Arc<Mutex<MmioBus>>,
+    allocator: BarAllocator,
+    // Addresses BARs are registered at on the MmioBus, by slot and BAR
+    mapped: HashMap<(u8, usize), u64>,
+}
+
+impl PciHostBridge {
+    /// Creates a host bridge that registers BARs on 'mmio', and assigns
+    /// them addresses from 'allocator'.
+    pub fn new(mmio: Arc<Mutex<MmioBus>>, allocator: BarAllocator) -> PciHostBridge {
+        PciHostBridge {
+            config: PciConfig::new(HOST_BRIDGE_VENDOR_ID, HOST_BRIDGE_DEVICE_ID, 0x06, 0x00, 0x00),
+            address: 0,
+            slots: (0..PCI_SLOTS).map(|_| None).collect(),
+            mmio: mmio,
+            allocator: allocator,
+            mapped: HashMap::new(),
+        }
+    }
+
+    /// Adds 'device' to the bus in 'slot', assigning addresses to its BARs,
+    /// enabling memory decoding, and registering the BARs on the `MmioBus`.
+    /// Returns `EINVAL` for slot 0 or an invalid slot, `EEXIST` if the slot
+    /// is in use, and `ENOSPC` if a BAR doesn't fit.
+    pub fn add_device(&mut self, slot: u8, device: Arc<Mutex<dyn PciDevice>>) -> Result<(), Error> {
+        if slot == 0 || slot >= PCI_SLOTS {
+            return Err(Error::new(EINVAL));
+        }
+        if self.slots[slot as usize].is_some() {
+            return Err(Error::new(EEXIST));
+        }
+        {
+            let mut device = device.lock().unwrap();
+            let config = device.config_mut();
+            for index in 0..PCI_BARS {
+                if let Some(bar) = config.bar(index) {
+                    let address = match self.allocator.allocate(bar) {
+                        Some(address) => address,
+                        None => return Err(Error::new(ENOSPC)),
+                    };
+                    config.set_bar_address(index, address)?;
+                }
+            }
+            config.set_memory_enabled(true);
+        }
+        self.slots[slot as usize] = Some(device);
+        self.update_mappings(slot)
+    }
+
+    /// Returns the device in 'slot'.
+    pub fn device(&self, slot: u8) -> Option<Arc<Mutex<dyn PciDevice>>> {
+        self.slots.get(slot as usize).cloned().unwrap_or(None)
+    }
+
+    // Registers the BARs of the device in 'slot' on the MmioBus at their
+    // programmed addresses while memory decoding is enabled, and unregisters
+    // them otherwise. A BAR the guest is sizing stays where it was until the
+    // guest writes an address to it again, and a BAR that overlaps another
+    // range is left unregistered until it moves.
+    fn update_mappings(&mut self, slot: u8) -> Result<(), Error> {
+        let device = match self.device(slot) {
+            Some(device) => device,
+            None => return Ok(()),
+        };
+        let wanted: Vec<(usize, Option<(u64, u64)>)> = {
+            let device = device.lock().unwrap();
+            let config = device.config();
+            (0..PCI_BARS).filter_map(|index| {
+                let bar = config.bar(index)?;
+                if config.bar_sizing(index) {
+                    return None;
+                }
+                let address = config.bar_address(index).filter(|_| config.memory_enabled());
+                Some((index, address.map(|address| (address, bar.size))))
+            }).collect()
+        };
+
+        let mut mmio = self.mmio.lock().unwrap();
+        let mut result = Ok(());
+        for (index, range) in wanted {
+            let current = self.mapped.get(&(slot, index)).cloned();
+            if current.is_some() && current == range.map(|(address, _)| address) {
+                continue;
+            }
+            if let Some(address) = current {
+                mmio.unregister(address);
+                self.mapped.remove(&(slot, index));
+            }
+            if let Some((address, size)) = range {
+                let region = Arc::new(Mutex::new(BarRegion { device: Arc::clone(&device), bar: index }));
+                match mmio.register(address, size, region) {
+                    Ok(()) => {
+                        self.mapped.insert((slot, index), address);
+                    }
+                    Err(e) => result = Err(e),
+                }
+            }
+        }
+        result
+    }
+
+    // Returns the slot and register offset selected by the address register,
+    // or 'None' if it doesn't select a function on this bus.
+    fn selected(&self) -> Option<(u8, u16)> {
+        let bus = (self.address >> 16) & 0xff;
+        let slot = ((self.address >> 11) & 0x1f) as u8;
+        let function = (self.address >> 8) & 0x7;
+        if (self.address & CONFIG_ENABLE) == 0 || bus != 0 || function != 0 {
+            return None;
+        }
+        if slot != 0 && self.slots[slot as usize].is_none() {
+            return None;
+        }
+        Some((slot, (self.address & 0xfc) as u16))
+    }
+}
+
+impl GuestDevice for PciHostBridge {
+    fn reset(&mut self) {
+        self.address = 0;
+        for slot in 1..PCI_SLOTS {
+            if let Some(device) = self.device(slot) {
+                device.lock().unwrap().reset();
+                let _ = self.update_mappings(slot);
+            }
+        }
+    }
+}
+
+impl GuestPioDevice for PciHostBridge {
+    fn pio_read(&mut self, offset: u16, width: IoWidth) -> u32 {
+        if offset < 4 {
+            return match width {
+                IoWidth::Dword => self.address,
+                _ => width.mask(),
+            };
+        }
+        let (slot, register) = match self.selected() {
+            Some(selected) => selected,
+            None => return width.mask(),
+        };
+        let offset = register + (offset - 4);
+        match self.device(slot) {
+            Some(device) => device.lock().unwrap().config_read(offset, width),
+            None => self.config.read(offset, width),
+        }
+    }
+
+    fn pio_write(&mut self, offset: u16, value: IoValue) {
+        if offset < 4 {
+            // Only dword accesses reach the address register
+            if value.width() == IoWidth::Dword {
+                self.address = value.value() & 0x80ff_fffc;
+            }
+            return;
+        }
+        let (slot, register) = match self.selected() {
+            Some(selected) => selected,
+            None => return,
+        };
+        let offset = register + (offset - 4);
+        match self.device(slot) {
+            Some(device) => device.lock().unwrap().config_write(offset, value),
+            None => self.config.write(offset, value),
+        }
+        // Writes to the command register or the BARs can move the device
+        if slot != 0 && offset < (PCI_BAR0 + PCI_BARS * 4) as u16 {
+            let _ = self.update_mappings(slot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Scratch {
+        config: PciConfig,
+        regs: [u64; 2],
+    }
+
+    impl GuestDevice for Scratch {}
+
+    impl PciDevice for Scratch {
+        fn config(&self) -> &PciConfig {
+            &self.config
+        }
+        fn config_mut(&mut self) -> &mut PciConfig {
+            &mut self.config
+        }
+        fn bar_read(&mut self, _bar: usize, offset: u64, _size: u8) -> u64 {
+            self.regs[(offset / 8) as usize % 2]
+        }
+        fn bar_write(&mut self, _bar: usize, offset: u64, _size: u8, value: u64) {
+            self.regs[(offset / 8) as usize % 2] = value;
+        }
+    }
+
+    #[test]
+    fn test_bar_allocation() {
+        let mut allocator = BarAllocator::new(0xc000_0100..0xc001_0000, 0x1_0000_0000..0x1_0000_2000);
+        let small = Bar { kind: BarKind::Mmio32, size: 0x1000, prefetchable: false };
+        let large = Bar { kind: BarKind::Mmio64, size: 0x2000, prefetchable: true };
+        assert_eq!(allocator.allocate(small), Some(0xc000_1000));
+        assert_eq!(allocator.allocate(large), Some(0x1_0000_0000));
+        // The window is full, so the next 64-bit BAR goes in the hole
+        assert_eq!(allocator.allocate(large), Some(0xc000_2000));
+        assert_eq!(allocator.allocate(Bar { size: 0x10_0000, ..small }), None);
+
+        let mut config = PciConfig::new(0x1af4, 0x1003, 0x07, 0x80, 0);
+        config.add_bar(0, small).unwrap();
+        config.add_bar(2, large).unwrap();
+        assert!(config.add_bar(3, small).is_err());
+        assert!(config.add_bar(5, large).is_err());
+        assert_eq!(config.bar(3), None);
+
+        // Sizing reads back the size mask and the BAR type
+        config.write(0x18, IoValue::new(IoWidth::Dword, 0xffff_ffff));
+        config.write(0x1c, IoValue::new(IoWidth::Dword, 0xffff_ffff));
+        assert_eq!(config.read(0x18, IoWidth::Dword), 0xffff_e00c);
+        assert_eq!(config.read(0x1c, IoWidth::Dword), 0xffff_ffff);
+        assert!(config.bar_sizing(2));
+        config.set_bar_address(2, 0x2_0000_1234).unwrap();
+        assert_eq!(config.bar_address(2), Some(0x2_0000_0000));
+        assert_eq!(config.get_u16(0), 0x1af4);
+        assert_eq!(config.read(0x0a, IoWidth::Word), 0x0780);
+
+        assert_eq!(config.add_capability(0x09, 5), Ok(0x40));
+        assert_eq!(config.add_capability(0x11, 12), Ok(0x48));
+        assert_eq!(config.get_u8(0x34), 0x40);
+        assert_eq!(config.get_u8(0x41), 0x48);
+        assert_eq!(config.get_u16(0x06) & 0x10, 0x10);
+        assert!(config.add_capability(0x09, 0x100).is_err());
+    }
+
+    #[test]
+    fn test_host_bridge() {
+        let mmio = Arc::new(Mutex::new(MmioBus::new()));
+        let allocator = BarAllocator::new(0xc000_0000..0xd000_0000, 0..0);
+        let mut bridge = PciHostBridge::new(Arc::clone(&mmio), allocator);
+        let mut config = PciConfig::new(0x1af4, 0x1003, 0x07, 0x80, 0);
+        config.add_bar(0, Bar { kind: BarKind::Mmio32, size: 0x1000, prefetchable: false }).unwrap();
+        let device = Arc::new(Mutex::new(Scratch { config: config, regs: [0; 2] }));
+        bridge.add_device(3, device).unwrap();
+
+        assert!(mmio.lock().unwrap().write(0xc000_0008, 8, 42));
+        assert_eq!(mmio.lock().unwrap().read(0xc000_0008, 8), Some(42));
+
+        // Vendor and device ID of slot 3, then of an empty slot
+        bridge.pio_write(0, IoValue::new(IoWidth::Dword, 0x8000_1800));
+        assert_eq!(bridge.pio_read(4, IoWidth::Dword), 0x1003_1af4);
+        assert_eq!(bridge.pio_read(6, IoWidth::Word), 0x1003);
+        bridge.pio_write(0, IoValue::new(IoWidth::Dword, 0x8000_2000));
+        assert_eq!(bridge.pio_read(4, IoWidth::Dword), 0xffff_ffff);
+
+        // Moving the BAR moves its MMIO range
+        bridge.pio_write(0, IoValue::new(IoWidth::Dword, 0x8000_1810));
+        bridge.pio_write(4, IoValue::new(IoWidth::Dword, 0xc800_0000));
+        assert_eq!(mmio.lock().unwrap().read(0xc000_0008, 8), None);
+        assert_eq!(mmio.lock().unwrap().read(0xc800_0008, 8), Some(42));
+
+        // Sizing the BAR leaves it where it was
+        bridge.pio_write(4, IoValue::new(IoWidth::Dword, 0xffff_ffff));
+        assert_eq!(bridge.pio_read(4, IoWidth::Dword), 0xffff_f000);
+        assert_eq!(mmio.lock().unwrap().read(0xffff_f008, 8), None);
+        assert_eq!(mmio.lock().unwrap().read(0xc800_0008, 8), Some(42));
+        bridge.pio_write(4, IoValue::new(IoWidth::Dword, 0xc800_0000));
+
+        // Disabling memory decoding removes it
+        bridge.pio_write(0, IoValue::new(IoWidth::Dword, 0x8000_1804));
+        bridge.pio_write(4, IoValue::new(IoWidth::Word, 0));
+        assert_eq!(mmio.lock().unwrap().read(0xc800_0008, 8), None);
+    }
+}
@@ -0,0 +1,148 @@
+//! PCI device passthrough.
+//!
+//! A PCI device assigned to the ppt driver on the host appears as
+//! `/dev/pptN`. Once bound to a virtual machine, its BARs can be mapped
+//! into the guest physical address space, and its MSI and MSI-X interrupts
+//! routed to guest VCPUs, so the guest drives the device directly. The
+//! VMM still emulates the device's configuration space, and passes the
+//! guest's BAR and interrupt programming on through these calls.
+//!
+//! A `PassthruDevice` opens a ppt device and binds it to a VM, and unbinds
+//! it again when dropped:
+//!
+//!     use bhyve_api::pci_passthru::PassthruDevice;
+//!     use bhyve_api::vm::VirtualMachine;
+//!     use std::sync::Arc;
+//!
+//!     fn setup(vm: Arc<VirtualMachine>) -> Result<PassthruDevice, bhyve_api::Error> {
+//!         let dev = PassthruDevice::open(vm, "/dev/ppt0")?;
+//!         let limits = dev.limits()?;
+//!         println!("{} MSI, {} MSI-X vectors", limits.msi, limits.msix);
+//!         // Map BAR 0, at host physical 0xf0000000, where the guest put it
+//!         dev.map_mmio(0xc0000000, 0xf0000000, 0x4000)?;
+//!         Ok(dev)
+//!     }
+
+use libc::{open, sysconf, EINVAL, O_CLOEXEC, O_RDWR, _SC_PAGESIZE};
+use std::ffi::CString;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Arc;
+
+use crate::vm::VirtualMachine;
+use crate::Error;
+
+/// Bit of the MSI-X vector control word that masks the entry.
+pub const MSIX_VCTRL_MASK: u32 = 0x1;
+
+/// The number of interrupt vectors the host can allocate for a passthrough
+/// device, from `VirtualMachine::get_pptdev_limits()`.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct PptLimits {
+    /// MSI vectors.
+    pub msi: i32,
+    /// MSI-X table entries.
+    pub msix: i32,
+}
+
+// Checks that an MMIO mapping of [hpa,hpa+len) at 'gpa' is non-empty and
+// page aligned, as the kernel requires.
+fn check_mmio(gpa: u64, hpa: u64, len: usize, page_size: u64) -> Result<(), Error> {
+    let mask = page_size - 1;
+    if len == 0 || (gpa & mask) != 0 || (hpa & mask) != 0 || (len as u64 & mask) != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(())
+}
+
+/// A PCI passthrough device bound to a virtual machine.
+pub struct PassthruDevice {
+    vm: Arc<VirtualMachine>,
+    dev: File,
+}
+
+impl PassthruDevice {
+    /// Opens the ppt device at 'path' and binds it to 'vm'.
+    pub fn open(vm: Arc<VirtualMachine>, path: &str) -> Result<PassthruDevice, Error> {
+        let c_path = match CString::new(path) {
+            Ok(s) => s,
+            Err(_) => return Err(Error::new(EINVAL))
+        };
+        let raw_fd = unsafe { open(c_path.as_ptr(), O_RDWR | O_CLOEXEC) };
+        if raw_fd < 0 {
+            return Err(Error::last());
+        }
+        // Safe because the file descriptor was just opened, and is owned by
+        // the File from here on.
+        let dev = unsafe { File::from_raw_fd(raw_fd) };
+
+        vm.bind_pptdev(dev.as_raw_fd())?;
+        Ok(PassthruDevice { vm: vm, dev: dev })
+    }
+
+    /// Returns the file descriptor of the ppt device, for the passthrough
+    /// calls on `VirtualMachine`.
+    pub fn fd(&self) -> RawFd {
+        self.dev.as_raw_fd()
+    }
+
+    /// Gets the number of MSI and MSI-X vectors available to the device.
+    pub fn limits(&self) -> Result<PptLimits, Error> {
+        self.vm.get_pptdev_limits(self.fd())
+    }
+
+    /// Maps [hpa,hpa+len) of a BAR of the device at 'gpa' in the guest.
+    /// Returns `EINVAL` if the range is empty or not page aligned.
+    pub fn map_mmio(&self, gpa: u64, hpa: u64, len: usize) -> Result<(), Error> {
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as u64 };
+        check_mmio(gpa, hpa, len, page_size)?;
+        self.vm.map_pptdev_mmio(self.fd(), gpa, hpa, len)?;
+        Ok(())
+    }
+
+    /// Enables 'numvec' MSI vectors, delivered to the guest as messages
+    /// 'msg' to 'addr', through 'vcpu_id'.
+    pub fn enable_msi(&self, vcpu_id: i32, addr: u64, msg: u64, numvec: i32) -> Result<(), Error> {
+        if numvec <= 0 {
+            return Err(Error::new(EINVAL));
+        }
+        self.vm.pptdev_msi(vcpu_id, self.fd(), addr, msg, numvec)?;
+        Ok(())
+    }
+
+    /// Disables MSI on the device.
+    pub fn disable_msi(&self, vcpu_id: i32) -> Result<(), Error> {
+        self.vm.pptdev_msi(vcpu_id, self.fd(), 0, 0, 0)?;
+        Ok(())
+    }
+
+    /// Programs MSI-X table entry 'idx' to deliver message 'msg' to 'addr',
+    /// masked if 'masked' is set.
+    pub fn set_msix(&self, vcpu_id: i32, idx: i32, addr: u64, msg: u64, masked: bool) -> Result<(), Error> {
+        let vector_control = if masked { MSIX_VCTRL_MASK } else { 0 };
+        self.vm.pptdev_msix(vcpu_id, self.fd(), idx, addr, msg, vector_control)?;
+        Ok(())
+    }
+}
+
+impl Drop for PassthruDevice {
+    fn drop(&mut self) {
+        // Unbinding only fails if the VM has already gone, which releases
+        // the device anyway.
+        let _ = self.vm.unbind_pptdev(self.dev.as_raw_fd());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_mmio() {
+        assert!(check_mmio(0xc0000000, 0xf0000000, 0x4000, 0x1000).is_ok());
+        assert!(check_mmio(0xc0000000, 0xf0000000, 0, 0x1000).is_err());
+        assert!(check_mmio(0xc0000800, 0xf0000000, 0x1000, 0x1000).is_err());
+        assert!(check_mmio(0xc0000000, 0xf0000010, 0x1000, 0x1000).is_err());
+        assert!(check_mmio(0xc0000000, 0xf0000000, 0x1800, 0x1000).is_err());
+    }
+}
@@ -0,0 +1,509 @@
+//! An emulated 8254 programmable interval timer.
+//!
+//! bhyve normally emulates the PIT in the kernel, and guest accesses to its
+//! ports never reach userspace. `Pit` is a userspace model for
+//! configurations where those accesses do exit, so that real-mode payloads
+//! and firmware that calibrate their timing against the PIT can run.
+//! Channel 0 raises ISA IRQ 0 from a `TimerService` callback. Channel 2 is
+//! gated by system control port B at `PORT_B`, where its output can be
+//! read back, as used for calibration; the speaker it drives is silent.
+//! Channel 1 counts, but isn't connected to anything.
+//!
+//!     use bhyve_api::device::PioBus;
+//!     use bhyve_api::pit::*;
+//!     use bhyve_api::timer::TimerService;
+//!     use bhyve_api::vm::VirtualMachine;
+//!     use std::sync::{Arc, Mutex};
+//!
+//!     fn setup(vm: Arc<VirtualMachine>, timers: Arc<TimerService>, bus: &mut PioBus) -> Result<(), bhyve_api::Error> {
+//!         let pit = Arc::new(Mutex::new(Pit::new(vm, timers)));
+//!         register_pit(bus, &pit)
+//!     }
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::device::{GuestDevice, GuestPioDevice, IoValue, IoWidth, PioBus};
+use crate::timer::{TimerId, TimerService};
+use crate::vm::VirtualMachine;
+use crate::Error;
+
+/// First I/O port of the PIT, the data port of channel 0.
+pub const PIT_BASE_PORT: u16 = 0x40;
+/// Number of I/O ports: three data ports and the control word register.
+pub const PIT_PORT_LEN: u16 = 4;
+/// Input clock frequency of the PIT in Hz.
+pub const PIT_FREQ: u64 = 1_193_182;
+/// ISA IRQ raised by channel 0.
+pub const PIT_IRQ: i32 = 0;
+/// I/O port of system control port B, which holds the channel 2 gate.
+pub const PORT_B: u16 = 0x61;
+// The I/O APIC pin that ISA IRQ 0 is routed to.
+const PIT_IOAPIC_PIN: i32 = 2;
+
+const CONTROL_PORT: u16 = 3;
+
+// Access modes, from bits 5-4 of the control word.
+const ACCESS_LATCH: u8 = 0;
+const ACCESS_LO: u8 = 1;
+const ACCESS_HI: u8 = 2;
+const ACCESS_LOHI: u8 = 3;
+
+const READ_BACK: u8 = 3;
+
+// System control port B bits.
+const PORT_B_GATE2: u8 = 0x01;
+const PORT_B_SPEAKER: u8 = 0x02;
+const PORT_B_REFRESH: u8 = 0x10;
+const PORT_B_OUT2: u8 = 0x20;
+
+// Converts a count to packed BCD, four digits.
+fn to_bcd(value: u64) -> u16 {
+    (0..4).fold(0, |bcd, digit| bcd | (((value / 10u64.pow(digit)) % 10) << (4 * digit)) as u16)
+}
+
+// Converts four digits of packed BCD to a count. Digits above 9 aren't
+// valid BCD, but count as their binary value, as they do on the 8254.
+fn from_bcd(bcd: u16) -> u64 {
+    (0..4).fold(0, |value, digit| value + ((bcd >> (4 * digit)) & 0xf) as u64 * 10u64.pow(digit))
+}
+
+// State of one counter, which is driven by the host clock.
+#[derive(Debug, Clone)]
+struct Channel {
+    mode: u8,
+    access: u8,
+    bcd: bool,
+    reload: u64, // 1 to 65536, or to 10000 when counting in BCD
+    loaded: Option<Instant>,
+    gate: bool,
+    stopped: Option<u64>, // ticks counted before the gate stopped counting
+    latch: Option<u16>,
+    status_latch: Option<u8>,
+    read_hi: bool,
+    write_hi: bool,
+    write_lo: u8,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            mode: 0,
+            access: ACCESS_LOHI,
+            bcd: false,
+            reload: 0x10000,
+            loaded: None,
+            gate: true,
+            stopped: None,
+            latch: None,
+            status_latch: None,
+            read_hi: false,
+            write_hi: false,
+            write_lo: 0,
+        }
+    }
+
+    // Clock ticks counted since the count was loaded, or since the gate
+    // last restarted it.
+    fn ticks(&self, now: Instant) -> Option<u64> {
+        let loaded = self.loaded?;
+        if let Some(ticks) = self.stopped {
+            return Some(ticks);
+        }
+        let elapsed = now.checked_duration_since(loaded).unwrap_or_default();
+        Some((elapsed.as_nanos() * PIT_FREQ as u128 / 1_000_000_000) as u64)
+    }
+
+    // The number of counter values: a full count wraps around to the top.
+    fn modulus(&self) -> u64 {
+        if self.bcd { 10000 } else { 0x10000 }
+    }
+
+    // The current value of the counter, in BCD if the channel counts in
+    // BCD. In the periodic modes the counter wraps to the reload value; in
+    // mode 3 it counts down by two, twice per period, which is approximated
+    // by counting by two once.
+    fn count(&self, now: Instant) -> u16 {
+        let ticks = match self.ticks(now) {
+            Some(ticks) => ticks,
+            None => return 0,
+        };
+        let modulus = self.modulus();
+        let count = match self.mode {
+            2 => self.reload - ticks % self.reload,
+            3 => self.reload - (ticks * 2) % self.reload,
+            _ => (self.reload + modulus - ticks % modulus) % modulus,
+        };
+        if self.bcd {
+            to_bcd(count % modulus)
+        } else {
+            count as u16
+        }
+    }
+
+    // The level of the counter's output.
+    fn output(&self, now: Instant) -> bool {
+        match (self.mode, self.ticks(now)) {
+            (_, None) => true,
+            (0, Some(ticks)) => ticks >= self.reload,
+            // The output stays high until the gate triggers the count
+            (1, Some(ticks)) => self.stopped.is_some() || ticks >= self.reload,
+            // A low gate forces the output high in the periodic modes
+            (2, _) | (3, _) if !self.gate => true,
+            (3, Some(ticks)) => (ticks % self.reload) < self.reload / 2,
+            _ => true,
+        }
+    }
+
+    // Sets the level of the gate input. A low gate stops counting in modes
+    // 0, 2, 3 and 4, and a rising edge starts the count again from the
+    // reload value in modes 1, 2, 3 and 5, or resumes it in modes 0 and 4.
+    fn set_gate(&mut self, gate: bool, now: Instant) {
+        if gate == self.gate {
+            return;
+        }
+        if self.loaded.is_some() {
+            match (self.mode, gate) {
+                (0, true) | (4, true) => {
+                    if let Some(ticks) = self.stopped.take() {
+                        let counted = Duration::from_nanos(ticks * 1_000_000_000 / PIT_FREQ);
+                        self.loaded = Some(now.checked_sub(counted).unwrap_or(now));
+                    }
+                }
+                (_, true) => {
+                    self.loaded = Some(now);
+                    self.stopped = None;
+                }
+                (1, false) | (5, false) => (),
+                (_, false) => self.stopped = self.ticks(now),
+            }
+        }
+        self.gate = gate;
+    }
+
+    fn control(&mut self, access: u8, mode: u8, bcd: bool) {
+        self.access = access;
+        // Modes 6 and 7 are aliases of 2 and 3
+        self.mode = if mode > 5 { mode - 4 } else { mode };
+        self.bcd = bcd;
+        self.loaded = None;
+        self.stopped = None;
+        self.latch = None;
+        self.read_hi = false;
+        self.write_hi = false;
+    }
+
+    fn latch_count(&mut self, now: Instant) {
+        // Latching again before the latched value is read has no effect
+        if self.latch.is_none() {
+            self.latch = Some(self.count(now));
+        }
+    }
+
+    fn latch_status(&mut self, now: Instant) {
+        if self.status_latch.is_some() {
+            return;
+        }
+        let mut status = (self.access << 4) | (self.mode << 1) | self.bcd as u8;
+        if self.output(now) {
+            status |= 0x80;
+        }
+        if self.loaded.is_none() {
+            status |= 0x40; // null count
+        }
+        self.status_latch = Some(status);
+    }
+
+    fn read(&mut self, now: Instant) -> u8 {
+        if let Some(status) = self.status_latch.take() {
+            return status;
+        }
+        let value = self.latch.unwrap_or_else(|| self.count(now));
+        let byte = match self.access {
+            ACCESS_HI => (value >> 8) as u8,
+            ACCESS_LOHI if self.read_hi => (value >> 8) as u8,
+            _ => value as u8,
+        };
+        let done = match self.access {
+            ACCESS_LOHI => {
+                self.read_hi = !self.read_hi;
+                !self.read_hi
+            }
+            _ => true,
+        };
+        if done {
+            self.latch = None;
+        }
+        byte
+    }
+
+    // Writes a byte of the reload value, returning true once the count has
+    // been loaded and the counter restarted. Modes 1 and 5 wait for the
+    // gate to trigger the count, and the other modes for a high gate.
+    fn write(&mut self, value: u8, now: Instant) -> bool {
+        let reload = match self.access {
+            ACCESS_LO => value as u64,
+            ACCESS_HI => (value as u64) << 8,
+            _ if !self.write_hi => {
+                self.write_lo = value;
+                self.write_hi = true;
+                return false;
+            }
+            _ => {
+                self.write_hi = false;
+                ((value as u64) << 8) | self.write_lo as u64
+            }
+        };
+        let reload = if self.bcd { from_bcd(reload as u16) } else { reload };
+        self.reload = if reload == 0 { self.modulus() } else { reload };
+        self.loaded = Some(now);
+        self.stopped = match self.mode {
+            1 | 5 => Some(0),
+            _ if !self.gate => Some(0),
+            _ => None,
+        };
+        true
+    }
+}
+
+/// The 8254 PIT, registered on the `PioBus` at `PIT_BASE_PORT` with length
+/// `PIT_PORT_LEN`, along with port B, by `register_pit()`.
+pub struct Pit {
+    vm: Arc<VirtualMachine>,
+    timers: Arc<TimerService>,
+    timer: Option<TimerId>,
+    channels: [Channel; 3],
+    port_b: u8, // the speaker enable bit, as written
+    refresh: bool,
+}
+
+impl Pit {
+    /// Creates the PIT for 'vm', scheduling channel 0 interrupts on
+    /// 'timers'. No interrupts are raised until the guest programs channel 0.
+    pub fn new(vm: Arc<VirtualMachine>, timers: Arc<TimerService>) -> Pit {
+        Pit {
+            vm: vm,
+            timers: timers,
+            timer: None,
+            channels: Pit::reset_channels(),
+            port_b: 0,
+            refresh: false,
+        }
+    }
+
+    // The channels at power on, when port B holds the channel 2 gate low.
+    fn reset_channels() -> [Channel; 3] {
+        let mut channels = [Channel::new(), Channel::new(), Channel::new()];
+        channels[2].gate = false;
+        channels
+    }
+
+    // Reads port B: the channel 2 gate and speaker bits as written, the
+    // output of channel 2, and the memory refresh bit, which toggles on
+    // every read so that delay loops polling it make progress.
+    fn read_port_b(&mut self, now: Instant) -> u8 {
+        self.refresh = !self.refresh;
+        let mut value = self.port_b;
+        if self.channels[2].gate {
+            value |= PORT_B_GATE2;
+        }
+        if self.channels[2].output(now) {
+            value |= PORT_B_OUT2;
+        }
+        if self.refresh {
+            value |= PORT_B_REFRESH;
+        }
+        value
+    }
+
+    fn write_port_b(&mut self, value: u8, now: Instant) {
+        self.port_b = value & PORT_B_SPEAKER;
+        self.channels[2].set_gate((value & PORT_B_GATE2) != 0, now);
+    }
+
+    // Schedules the channel 0 interrupt to match its mode and reload value.
+    fn update_timer(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            self.timers.cancel(timer);
+        }
+        let channel = &self.channels[0];
+        if channel.loaded.is_none() {
+            return;
+        }
+        let period = Duration::from_nanos(channel.reload * 1_000_000_000 / PIT_FREQ);
+        let repeat = match channel.mode {
+            2 | 3 => Some(period),
+            0 => None,
+            // The other modes need a gate input, which channel 0 doesn't have
+            _ => return,
+        };
+        let vm = Arc::clone(&self.vm);
+        self.timer = Some(self.timers.schedule(period, repeat, move || {
+            let _ = vm.isa_pulse_irq(PIT_IRQ, PIT_IOAPIC_PIN);
+        }));
+    }
+
+    fn write_control(&mut self, value: u8, now: Instant) {
+        let select = value >> 6;
+        if select == READ_BACK {
+            for (index, channel) in self.channels.iter_mut().enumerate() {
+                if (value & (2 << index)) == 0 {
+                    continue;
+                }
+                // The count and status bits are active low
+                if (value & 0x20) == 0 {
+                    channel.latch_count(now);
+                }
+                if (value & 0x10) == 0 {
+                    channel.latch_status(now);
+                }
+            }
+            return;
+        }
+        let channel = &mut self.channels[select as usize];
+        let access = (value >> 4) & 0x3;
+        if access == ACCESS_LATCH {
+            channel.latch_count(now);
+            return;
+        }
+        channel.control(access, (value >> 1) & 0x7, (value & 1) != 0);
+        if select == 0 {
+            self.update_timer();
+        }
+    }
+}
+
+impl GuestDevice for Pit {
+    fn reset(&mut self) {
+        self.channels = Pit::reset_channels();
+        self.port_b = 0;
+        self.update_timer();
+    }
+}
+
+impl GuestPioDevice for Pit {
+    fn pio_read(&mut self, offset: u16, width: IoWidth) -> u32 {
+        let value = match offset {
+            CONTROL_PORT => 0xff,
+            _ => self.channels[offset as usize].read(Instant::now()),
+        };
+        IoValue::new(width, value as u32).value()
+    }
+
+    fn pio_write(&mut self, offset: u16, value: IoValue) {
+        let now = Instant::now();
+        match offset {
+            CONTROL_PORT => self.write_control(value.as_u8(), now),
+            _ => {
+                if self.channels[offset as usize].write(value.as_u8(), now) && offset == 0 {
+                    self.update_timer();
+                }
+            }
+        }
+    }
+}
+
+// Routes system control port B to the shared PIT.
+struct PitPortB {
+    pit: Arc<Mutex<Pit>>,
+}
+
+impl GuestDevice for PitPortB {}
+
+impl GuestPioDevice for PitPortB {
+    fn pio_read(&mut self, _offset: u16, width: IoWidth) -> u32 {
+        let value = self.pit.lock().unwrap().read_port_b(Instant::now());
+        IoValue::new(width, value as u32).value()
+    }
+
+    fn pio_write(&mut self, _offset: u16, value: IoValue) {
+        self.pit.lock().unwrap().write_port_b(value.as_u8(), Instant::now());
+    }
+}
+
+/// Registers the ports of 'pit' on 'bus': the counters and control word
+/// at `PIT_BASE_PORT`, and system control port B at `PORT_B`.
+pub fn register_pit(bus: &mut PioBus, pit: &Arc<Mutex<Pit>>) -> Result<(), Error> {
+    bus.register(PIT_BASE_PORT, PIT_PORT_LEN, pit.clone())?;
+    let port_b = PitPortB { pit: Arc::clone(pit) };
+    bus.register(PORT_B, 1, Arc::new(Mutex::new(port_b)))
+}
+
+impl Drop for Pit {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            self.timers.cancel(timer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_count() {
+        let start = Instant::now();
+        let mut channel = Channel::new();
+        // Mode 2, low then high byte, with a reload value of 1193 (~1ms)
+        channel.control(ACCESS_LOHI, 2, false);
+        assert!(!channel.write(0xa9, start));
+        assert!(channel.write(0x04, start));
+        assert_eq!(channel.reload, 1193);
+
+        let later = start + Duration::from_micros(500);
+        let expected = 1193 - 596;
+        assert_eq!(channel.count(later), expected);
+        // A latched count survives the counter moving on
+        channel.latch_count(later);
+        let much_later = start + Duration::from_micros(900);
+        assert_eq!(channel.read(much_later), (expected & 0xff) as u8);
+        assert_eq!(channel.read(much_later), (expected >> 8) as u8);
+
+        // Mode 6 is mode 2, and the status shows the count is not loaded
+        channel.control(ACCESS_LO, 6, false);
+        channel.latch_status(start);
+        assert_eq!(channel.read(start), 0x80 | 0x40 | (ACCESS_LO << 4) | (2 << 1));
+        assert_eq!(channel.count(start), 0);
+    }
+
+    #[test]
+    fn test_channel_bcd() {
+        let start = Instant::now();
+        let mut channel = Channel::new();
+        // Mode 0 in BCD, with a reload value of 1000 written as 0x1000
+        channel.control(ACCESS_LOHI, 0, true);
+        channel.write(0x00, start);
+        channel.write(0x10, start);
+        assert_eq!(channel.reload, 1000);
+        // 1193 ticks (1ms) later, the count has wrapped from 0 to 9999
+        let later = start + Duration::from_millis(1);
+        assert_eq!(channel.count(later), to_bcd(10000 + 1000 - 1193));
+        assert_eq!(to_bcd(9807), 0x9807);
+        assert_eq!(from_bcd(0x9807), 9807);
+    }
+
+    #[test]
+    fn test_channel_gate() {
+        let start = Instant::now();
+        let mut channel = Channel::new();
+        channel.gate = false;
+        // Mode 0 doesn't count while the gate is low
+        channel.control(ACCESS_LO, 0, false);
+        channel.write(100, start);
+        let later = start + Duration::from_millis(1);
+        assert_eq!(channel.count(later), 100);
+        assert!(!channel.output(later));
+        channel.set_gate(true, later);
+        assert!(channel.output(later + Duration::from_millis(1)));
+
+        // Mode 1 starts counting on a rising edge of the gate
+        channel.control(ACCESS_LO, 1, false);
+        channel.write(100, start);
+        assert!(channel.output(later));
+        channel.set_gate(false, later);
+        channel.set_gate(true, later);
+        assert!(!channel.output(later));
+        assert_eq!(channel.count(later), 100);
+    }
+}
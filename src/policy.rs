@@ -0,0 +1,366 @@
+//! Host-side policies for guest spin and idle loops.
+//!
+//! A guest spinning on a lock held by a descheduled VCPU wastes the host
+//! CPU it runs on. With `VM_CAP_PAUSE_EXIT` enabled, each PAUSE instruction
+//! in the loop exits to userspace, where a `PauseExits` handler applies a
+//! `PausePolicy` to give the host CPU to something more useful.
+//!
+//!     use bhyve_api::policy::PausePolicy;
+//!     use bhyve_api::vm::*;
+//!     use std::time::Duration;
+//!
+//!     fn run(vm: &VirtualMachine, vcpu_id: i32) -> Result<(), bhyve_api::Error> {
+//!         let mut pause = vm.enable_pause_exits(vcpu_id, PausePolicy::Sleep(Duration::from_micros(50)))?;
+//!         loop {
+//!             let exit = vm.run(vcpu_id)?;
+//!             if pause.handle(&exit) {
+//!                 continue;
+//!             }
+//!             // Handle other exits
+//!             break;
+//!         }
+//!         println!("{} PAUSE exits", pause.disable(vm)?);
+//!         Ok(())
+//!     }
+//!
+//! An idle guest executes HLT. Without `VM_CAP_HALT_EXIT`, the VCPU blocks
+//! in the kernel until its next interrupt, which is the cheapest way to be
+//! idle. With it, HLT exits to userspace, which costs more, but lets a VMM
+//! that multiplexes VCPUs onto fewer threads run something else instead. A
+//! `HaltExitToggle` switches between the two as the host's load changes,
+//! so an oversubscribed host multiplexes, and a quiet one doesn't bother:
+//!
+//!     use bhyve_api::policy::*;
+//!     use bhyve_api::vm::*;
+//!
+//!     fn run(vm: &VirtualMachine, vcpu_id: i32) -> Result<(), bhyve_api::Error> {
+//!         let mut halt = HaltExitToggle::new(vcpu_id, HaltLoadOptions::default());
+//!         loop {
+//!             halt.update_from_host(vm)?;
+//!             match vm.run(vcpu_id)? {
+//!                 VmExit::Halt(..) => {
+//!                     // Run another VCPU's work on this thread
+//!                 }
+//!                 _ => break,
+//!             }
+//!         }
+//!         Ok(())
+//!     }
+//!
+//! Guests that idle with MONITOR and MWAIT, rather than HLT, exit on every
+//! pass of their idle loop, since this interface version has no capability
+//! to let the instructions run in the guest. An `MwaitExits` handler
+//! applies an `MwaitPolicy` to those exits, so the run loop neither fails
+//! on them nor spins the host CPU. An MWAIT treated as HLT waits on the
+//! VCPU's `VcpuKicker`, so posted work, such as an interrupt to assert,
+//! ends the wait early:
+//!
+//!     use bhyve_api::policy::*;
+//!     use bhyve_api::vcpu::VcpuKicker;
+//!     use bhyve_api::vm::*;
+//!     use std::time::Duration;
+//!
+//!     fn run_loop(vm: &VirtualMachine, kicker: &VcpuKicker) -> Result<(), bhyve_api::Error> {
+//!         let vcpu_id = kicker.vcpu_id();
+//!         let mut mwait = MwaitExits::new(kicker.clone(), MwaitPolicy::Sleep(Duration::from_micros(100)));
+//!         loop {
+//!             if kicker.take_pending()? {
+//!                 kicker.work().run_pending(vm, vcpu_id)?;
+//!             }
+//!             let exit = vm.run(vcpu_id)?;
+//!             if mwait.handle(vm, &exit)? {
+//!                 continue;
+//!             }
+//!             // Handle other exits
+//!             return Ok(());
+//!         }
+//!     }
+
+use libc::{sysconf, _SC_NPROCESSORS_ONLN};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::vcpu::VcpuKicker;
+use crate::vm::{vm_cap_type, VirtualMachine, VmExit};
+use crate::Error;
+
+/// What to do on the host when the guest executes PAUSE.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PausePolicy {
+    /// Yield the host CPU to other runnable threads.
+    Yield,
+    /// Sleep for the given time before reentering the guest.
+    Sleep(Duration),
+    /// Only count the exits, so the caller can report on them.
+    Count,
+}
+
+/// Applies a `PausePolicy` to PAUSE exits on a single VCPU.
+#[derive(Debug)]
+pub struct PauseExits {
+    vcpu_id: i32,
+    policy: PausePolicy,
+    count: u64,
+}
+
+impl PauseExits {
+    /// Enables PAUSE exits on the VCPU, to be handled with 'policy'.
+    pub fn enable(vm: &VirtualMachine, vcpu_id: i32, policy: PausePolicy) -> Result<PauseExits, Error> {
+        vm.set_capability(vcpu_id, vm_cap_type::VM_CAP_PAUSE_EXIT, 1)?;
+        Ok(PauseExits { vcpu_id: vcpu_id, policy: policy, count: 0 })
+    }
+
+    /// Applies the policy if 'exit' is a PAUSE exit. Returns true if the
+    /// exit was handled and the VCPU can be run again, and false for any
+    /// other kind of exit, which the caller should handle itself.
+    pub fn handle(&mut self, exit: &VmExit) -> bool {
+        match exit {
+            VmExit::Pause => (),
+            _ => return false,
+        }
+        self.count += 1;
+        match self.policy {
+            PausePolicy::Yield => thread::yield_now(),
+            PausePolicy::Sleep(duration) => thread::sleep(duration),
+            PausePolicy::Count => (),
+        }
+        true
+    }
+
+    /// Changes the policy applied to subsequent PAUSE exits.
+    pub fn set_policy(&mut self, policy: PausePolicy) {
+        self.policy = policy;
+    }
+
+    /// Returns the number of PAUSE exits handled so far.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Disables PAUSE exits on the VCPU, and returns the number of exits
+    /// handled.
+    pub fn disable(self, vm: &VirtualMachine) -> Result<u64, Error> {
+        vm.set_capability(self.vcpu_id, vm_cap_type::VM_CAP_PAUSE_EXIT, 0)?;
+        Ok(self.count)
+    }
+}
+
+// Invalid opcode exception vector
+const IDT_UD: i32 = 6;
+
+/// What to do when the guest executes MONITOR or MWAIT.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MwaitPolicy {
+    /// Carry on after the instruction, as if it did nothing. An MWAIT idle
+    /// loop then spins, exiting on every pass.
+    Nop,
+    /// Carry on after MONITOR, and treat MWAIT as a HLT that wakes after
+    /// at most the given time, or as soon as the VCPU is kicked, by waiting
+    /// on its kicker before reentering the guest. Interrupts raised in the
+    /// kernel don't kick the VCPU, so only the timeout bounds how late they
+    /// are seen. The guest sees a spurious wakeup, which MWAIT allows.
+    Sleep(Duration),
+    /// Raise an invalid opcode exception (#UD) for both instructions, as
+    /// for a processor without them. Guests that check CPUID before using
+    /// them never see this.
+    InjectUd,
+}
+
+/// Applies an `MwaitPolicy` to MONITOR and MWAIT exits on a single VCPU.
+pub struct MwaitExits {
+    kicker: VcpuKicker,
+    policy: MwaitPolicy,
+    monitors: u64,
+    mwaits: u64,
+}
+
+impl MwaitExits {
+    /// Creates a handler for the MONITOR and MWAIT exits of the VCPU that
+    /// 'kicker' targets. The exits are always taken, so there is no
+    /// capability to enable.
+    pub fn new(kicker: VcpuKicker, policy: MwaitPolicy) -> MwaitExits {
+        MwaitExits { kicker: kicker, policy: policy, monitors: 0, mwaits: 0 }
+    }
+
+    /// Applies the policy if 'exit' is a MONITOR or MWAIT exit. Returns true
+    /// if the exit was handled and the VCPU can be run again, and false for
+    /// any other kind of exit, which the caller should handle itself.
+    pub fn handle(&mut self, vm: &VirtualMachine, exit: &VmExit) -> Result<bool, Error> {
+        let mwait = match count(&mut self.monitors, &mut self.mwaits, exit) {
+            Some(mwait) => mwait,
+            None => return Ok(false),
+        };
+        // The kernel resumes the guest after the instruction unless told
+        // otherwise, which is all that NOP needs.
+        match self.policy {
+            MwaitPolicy::Nop => (),
+            MwaitPolicy::Sleep(duration) if mwait => {
+                // Leaves any kick pending for the run loop to take
+                self.kicker.wait_kick(duration);
+            }
+            MwaitPolicy::Sleep(_) => (),
+            MwaitPolicy::InjectUd => {
+                vm.inject_exception(self.kicker.vcpu_id(), IDT_UD, 0, 0, 1)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Changes the policy applied to subsequent exits.
+    pub fn set_policy(&mut self, policy: MwaitPolicy) {
+        self.policy = policy;
+    }
+
+    /// Returns the number of MONITOR and MWAIT exits handled so far.
+    pub fn counts(&self) -> (u64, u64) {
+        (self.monitors, self.mwaits)
+    }
+}
+
+// Counts 'exit' if it is a MONITOR or MWAIT exit, returning whether it was
+// MWAIT.
+fn count(monitors: &mut u64, mwaits: &mut u64, exit: &VmExit) -> Option<bool> {
+    match exit {
+        VmExit::Monitor => {
+            *monitors += 1;
+            Some(false)
+        }
+        VmExit::Mwait => {
+            *mwaits += 1;
+            Some(true)
+        }
+        _ => None,
+    }
+}
+
+/// Host load thresholds for `HaltExitToggle`, as the one minute load
+/// average per online CPU. The gap between them keeps a load hovering
+/// around one threshold from toggling the capability on every update.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HaltLoadOptions {
+    /// Load above which HLT exits to userspace.
+    pub exit_above: f64,
+    /// Load below which HLT blocks in the kernel again.
+    pub block_below: f64,
+    /// Shortest time between samples of the host load by
+    /// `update_from_host()`.
+    pub interval: Duration,
+}
+
+impl Default for HaltLoadOptions {
+    fn default() -> HaltLoadOptions {
+        HaltLoadOptions { exit_above: 1.0, block_below: 0.75, interval: Duration::from_secs(1) }
+    }
+}
+
+/// Turns HLT exits on a single VCPU on and off with the host's load.
+/// Changing the capability waits for the VCPU to leave the guest, so the
+/// updates are best made from the VCPU's own thread, between runs.
+#[derive(Debug)]
+pub struct HaltExitToggle {
+    vcpu_id: i32,
+    options: HaltLoadOptions,
+    exiting: Option<bool>, // the capability as last set, if it has been
+    sampled: Option<Instant>,
+    toggles: u64,
+}
+
+impl HaltExitToggle {
+    /// Creates a toggle for 'vcpu_id'. The capability is left alone until
+    /// the first update.
+    pub fn new(vcpu_id: i32, options: HaltLoadOptions) -> HaltExitToggle {
+        HaltExitToggle { vcpu_id: vcpu_id, options: options, exiting: None, sampled: None, toggles: 0 }
+    }
+
+    /// Sets HLT exits for a host 'load', as the load average per CPU,
+    /// returning true if HLT now exits to userspace.
+    pub fn update(&mut self, vm: &VirtualMachine, load: f64) -> Result<bool, Error> {
+        let exiting = halt_exits_for(self.exiting, load, &self.options);
+        if self.exiting != Some(exiting) {
+            vm.set_capability(self.vcpu_id, vm_cap_type::VM_CAP_HALT_EXIT, exiting as i32)?;
+            if self.exiting.is_some() {
+                self.toggles += 1;
+            }
+            self.exiting = Some(exiting);
+        }
+        Ok(exiting)
+    }
+
+    /// Samples the host load, if the interval has passed since the last
+    /// sample, and updates HLT exits for it. Returns true if HLT exits to
+    /// userspace.
+    pub fn update_from_host(&mut self, vm: &VirtualMachine) -> Result<bool, Error> {
+        let now = Instant::now();
+        match (self.sampled, self.exiting) {
+            (Some(sampled), Some(exiting)) if now.duration_since(sampled) < self.options.interval => {
+                return Ok(exiting);
+            }
+            _ => (),
+        }
+        self.sampled = Some(now);
+        self.update(vm, host_load()?)
+    }
+
+    /// Returns the number of times HLT exits have been switched on or off
+    /// since the first update.
+    pub fn toggles(&self) -> u64 {
+        self.toggles
+    }
+}
+
+// Decides whether HLT should exit at 'load', given whether it does now.
+fn halt_exits_for(exiting: Option<bool>, load: f64, options: &HaltLoadOptions) -> bool {
+    match exiting {
+        Some(true) => load >= options.block_below,
+        _ => load > options.exit_above,
+    }
+}
+
+/// Returns the host's one minute load average per online CPU.
+pub fn host_load() -> Result<f64, Error> {
+    let mut loadavg = [0.0; 1];
+    if unsafe { libc::getloadavg(loadavg.as_mut_ptr(), 1) } != 1 {
+        return Err(Error::last());
+    }
+    let cpus = unsafe { sysconf(_SC_NPROCESSORS_ONLN) };
+    if cpus <= 0 {
+        return Err(Error::last());
+    }
+    Ok(loadavg[0] / cpus as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handle_counts_pause_only() {
+        let mut pause = PauseExits { vcpu_id: 0, policy: PausePolicy::Count, count: 0 };
+        assert!(pause.handle(&VmExit::Pause));
+        assert!(!pause.handle(&VmExit::Halt(0x2, 0)));
+        pause.set_policy(PausePolicy::Sleep(Duration::from_micros(1)));
+        assert!(pause.handle(&VmExit::Pause));
+        assert_eq!(pause.count(), 2);
+    }
+
+    #[test]
+    fn test_halt_exits_for() {
+        let options = HaltLoadOptions::default();
+        assert!(!halt_exits_for(None, 0.9, &options));
+        assert!(halt_exits_for(None, 1.5, &options));
+        // Between the thresholds, the current setting holds
+        assert!(halt_exits_for(Some(true), 0.9, &options));
+        assert!(!halt_exits_for(Some(false), 0.9, &options));
+        assert!(!halt_exits_for(Some(true), 0.5, &options));
+        assert!(host_load().unwrap() >= 0.0);
+    }
+
+    #[test]
+    fn test_mwait_exits() {
+        let (mut monitors, mut mwaits) = (0, 0);
+        assert_eq!(count(&mut monitors, &mut mwaits, &VmExit::Monitor), Some(false));
+        assert_eq!(count(&mut monitors, &mut mwaits, &VmExit::Mwait), Some(true));
+        assert_eq!(count(&mut monitors, &mut mwaits, &VmExit::Pause), None);
+        assert_eq!((monitors, mwaits), (1, 1));
+    }
+}
@@ -0,0 +1,142 @@
+//! Forwarding of guest port I/O to host streams.
+//!
+//! A `PortStream` connects a single I/O port to any `std::io::Write` for
+//! OUT instructions and any `std::io::Read` for IN instructions, which is
+//! enough to hook a guest debug port or a simple console to a file, pipe,
+//! or socket.
+//!
+//!     use bhyve_api::portio::PortStream;
+//!     use bhyve_api::vm::*;
+//!     use std::io;
+//!
+//!     fn run(vm: &VirtualMachine, vcpu_id: i32) -> Result<(), bhyve_api::Error> {
+//!         let mut console = PortStream::new(0x3f8, io::stdin(), io::stdout());
+//!         loop {
+//!             let exit = vm.run(vcpu_id)?;
+//!             if !console.handle(vm, vcpu_id, &exit)? {
+//!                 return Ok(());
+//!             }
+//!         }
+//!     }
+
+use std::io::{self, ErrorKind, Read, Write};
+
+use crate::vm::{IoDirection, VirtualMachine, VmExit};
+use crate::Error;
+
+/// Connects an I/O port to a host reader and writer.
+pub struct PortStream<R: Read, W: Write> {
+    port: u16,
+    reader: R,
+    writer: W,
+}
+
+impl<W: Write> PortStream<io::Empty, W> {
+    /// Creates an adapter that only forwards OUT bytes on 'port' to
+    /// 'writer'. IN instructions on the port read as end of input.
+    pub fn output(port: u16, writer: W) -> PortStream<io::Empty, W> {
+        PortStream::new(port, io::empty(), writer)
+    }
+}
+
+impl<R: Read, W: Write> PortStream<R, W> {
+    /// Creates an adapter that forwards OUT bytes on 'port' to 'writer',
+    /// and answers IN instructions on the port with bytes from 'reader'.
+    pub fn new(port: u16, reader: R, writer: W) -> PortStream<R, W> {
+        PortStream { port: port, reader: reader, writer: writer }
+    }
+
+    /// Returns the port the adapter is registered on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Handles 'exit' if it is an IN or OUT on the registered port. Returns
+    /// true if the exit was handled and the VCPU can be run again, and false
+    /// for any other exit, which the caller should handle itself.
+    ///
+    /// Bytes that are not available from the reader, because it is at end
+    /// of input or would block, read as 0xff, like an unconnected port.
+    pub fn handle(&mut self, vm: &VirtualMachine, vcpu_id: i32, exit: &VmExit) -> Result<bool, Error> {
+        match *exit {
+            VmExit::InOut(ref io) if io.port == self.port => {
+                match io.direction {
+                    IoDirection::Out => self.write_out(io.bytes, io.value)?,
+                    IoDirection::In => {
+                        let value = self.read_in(io.bytes)?;
+                        vm.complete_inout(vcpu_id, io, value)?;
+                    }
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // Writes the low 'bytes' bytes of 'value' to the writer.
+    fn write_out(&mut self, bytes: u16, value: u32) -> Result<(), Error> {
+        let data = value.to_le_bytes();
+        let len = (bytes as usize).min(data.len());
+        self.writer.write_all(&data[..len])?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    // Reads up to 'bytes' bytes from the reader, padding with 0xff.
+    fn read_in(&mut self, bytes: u16) -> Result<u32, Error> {
+        let mut data = [0xff; 4];
+        let len = (bytes as usize).min(data.len());
+        let mut filled = 0;
+        while filled < len {
+            match self.reader.read(&mut data[filled..len]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(Error::from(e)),
+            }
+        }
+        Ok(u32::from_le_bytes(data))
+    }
+
+    /// Consumes the adapter, returning the reader and writer.
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+// Replaces the low 'bytes' bytes of 'rax' with 'value', as an IN
+// instruction of that width does.
+pub(crate) fn merge_rax(rax: u64, bytes: u16, value: u32) -> u64 {
+    let mask: u64 = match bytes {
+        1 => 0xff,
+        2 => 0xffff,
+        // 32-bit operands zero the upper half of RAX
+        _ => 0xffffffffffffffff,
+    };
+    (rax & !mask) | (value as u64 & mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_data() {
+        let mut stream = PortStream::new(0xe9, &b"ab"[..], Vec::new());
+        stream.write_out(1, 0x41).unwrap();
+        stream.write_out(2, 0x4443).unwrap();
+        assert_eq!(stream.read_in(1).unwrap(), 0xffffff61);
+        // The reader runs dry part way through
+        assert_eq!(stream.read_in(2).unwrap(), 0xffffff62);
+        let (_, written) = stream.into_inner();
+        assert_eq!(written, b"ACD");
+    }
+
+    #[test]
+    fn test_merge_rax() {
+        assert_eq!(merge_rax(0x1122334455667788, 1, 0xffffffaa), 0x11223344556677aa);
+        assert_eq!(merge_rax(0x1122334455667788, 2, 0xffffaabb), 0x112233445566aabb);
+        assert_eq!(merge_rax(0x1122334455667788, 4, 0xaabbccdd), 0xaabbccdd);
+    }
+}
@@ -0,0 +1,156 @@
+//! The pvpanic device, for guest crash notification.
+//!
+//! Guests with a pvpanic driver (Linux, and Windows with the QEMU drivers)
+//! write to a single I/O port when they panic, and again once a crash
+//! kernel has been loaded, so the VMM learns about a crash without parsing
+//! console output. `PvPanic` emulates the port at `PVPANIC_PORT`, where
+//! QEMU's ACPI tables describe it (as device QEMU0001), so the guest's
+//! driver finds it. Each notification written to the device is sent as
+//! `VmEvent::GuestPanic` on the event stream it was created with, usually
+//! the VM's. Run loops that handle the port without the device can decode
+//! the exit themselves with `GuestPanic::from_exit()`.
+//!
+//!     use bhyve_api::device::PioBus;
+//!     use bhyve_api::lifecycle::VmEvent;
+//!     use bhyve_api::pvpanic::*;
+//!     use bhyve_api::vm::VirtualMachine;
+//!     use std::sync::{mpsc, Arc, Mutex};
+//!
+//!     fn setup(vm: &VirtualMachine, bus: &mut PioBus) -> Result<mpsc::Receiver<VmEvent>, bhyve_api::Error> {
+//!         let pvpanic = PvPanic::new(vm.events().clone());
+//!         bus.register(PVPANIC_PORT, 1, Arc::new(Mutex::new(pvpanic)))?;
+//!         // An orchestrator waits for VmEvent::GuestPanic, and collects a
+//!         // dump or restarts the guest when it panics
+//!         Ok(vm.events().subscribe())
+//!     }
+
+use crate::device::{GuestDevice, GuestPioDevice, IoValue, IoWidth};
+use crate::lifecycle::{EventStream, VmEvent};
+use crate::vm::{InOutRequest, IoDirection, VmExit};
+
+/// I/O port of the pvpanic device.
+pub const PVPANIC_PORT: u16 = 0x505;
+
+/// Bit written by the guest when it panics.
+pub const PVPANIC_PANICKED: u8 = 0x01;
+/// Bit written by the guest when a crash kernel has been loaded to handle
+/// the panic.
+pub const PVPANIC_CRASH_LOADED: u8 = 0x02;
+
+// Events the device advertises to the guest, by reading the port.
+const PVPANIC_FEATURES: u8 = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED;
+
+/// A crash notification from the guest.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum GuestPanic {
+    /// The guest kernel panicked, and has stopped.
+    Panicked,
+    /// The guest kernel panicked, and is handing over to a crash kernel,
+    /// so it will keep running to write out a dump.
+    CrashLoaded,
+}
+
+impl GuestPanic {
+    /// Decodes a value written to the pvpanic port, returning 'None' if it
+    /// carries no event, or sets bits the device doesn't advertise, as a
+    /// stray write to the port would. A guest that sets both bits is
+    /// reported as `Panicked`.
+    pub fn from_value(value: u8) -> Option<GuestPanic> {
+        if (value & !PVPANIC_FEATURES) != 0 {
+            None
+        } else if (value & PVPANIC_PANICKED) != 0 {
+            Some(GuestPanic::Panicked)
+        } else if (value & PVPANIC_CRASH_LOADED) != 0 {
+            Some(GuestPanic::CrashLoaded)
+        } else {
+            None
+        }
+    }
+
+    /// Decodes 'exit' if it is a byte OUT to `PVPANIC_PORT`. The caller is
+    /// still responsible for resuming or stopping the VCPU.
+    pub fn from_exit(exit: &VmExit) -> Option<GuestPanic> {
+        match *exit {
+            VmExit::InOut(InOutRequest { port: PVPANIC_PORT, bytes: 1, direction: IoDirection::Out, value, .. }) => {
+                GuestPanic::from_value(value as u8)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The pvpanic I/O port, which advertises the notifications it supports,
+/// records the last one, and sends each as `VmEvent::GuestPanic`.
+///
+/// Register it on the `PioBus` at `PVPANIC_PORT` with length 1.
+#[derive(Debug)]
+pub struct PvPanic {
+    last: Option<GuestPanic>,
+    events: EventStream,
+}
+
+impl PvPanic {
+    /// Creates the device, with no notification recorded, sending
+    /// notifications on 'events'.
+    pub fn new(events: EventStream) -> PvPanic {
+        PvPanic { last: None, events: events }
+    }
+
+    /// Returns the most recent notification since the device was created
+    /// or reset.
+    pub fn last(&self) -> Option<GuestPanic> {
+        self.last
+    }
+}
+
+impl GuestDevice for PvPanic {
+    fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+impl GuestPioDevice for PvPanic {
+    fn pio_read(&mut self, _offset: u16, width: IoWidth) -> u32 {
+        PVPANIC_FEATURES as u32 & width.mask()
+    }
+
+    fn pio_write(&mut self, _offset: u16, value: IoValue) {
+        if let Some(event) = GuestPanic::from_value(value.as_u8()) {
+            self.last = Some(event);
+            self.events.emit(VmEvent::GuestPanic(event));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::PioBus;
+    use std::sync::{Arc, Mutex};
+
+    fn out(port: u16, value: u32) -> VmExit {
+        VmExit::InOut(InOutRequest { port: port, bytes: 1, direction: IoDirection::Out, value: value, string: false, rep: false })
+    }
+
+    #[test]
+    fn test_pvpanic() {
+        assert_eq!(GuestPanic::from_exit(&out(PVPANIC_PORT, 0x2)), Some(GuestPanic::CrashLoaded));
+        assert_eq!(GuestPanic::from_exit(&out(PVPANIC_PORT, 0x4)), None);
+        assert_eq!(GuestPanic::from_exit(&out(PVPANIC_PORT, 0x81)), None);
+        assert_eq!(GuestPanic::from_exit(&out(0x80, 0x1)), None);
+
+        let events = EventStream::new();
+        let notifications = events.subscribe();
+        let pvpanic = Arc::new(Mutex::new(PvPanic::new(events)));
+        let mut bus = PioBus::new();
+        bus.register(PVPANIC_PORT, 1, pvpanic.clone()).unwrap();
+
+        assert_eq!(bus.read(PVPANIC_PORT, IoWidth::Byte).map(|v| v.value()), Some(0x3));
+        assert!(bus.write(PVPANIC_PORT, IoValue::new(IoWidth::Byte, 0x3)));
+        assert!(bus.write(PVPANIC_PORT, IoValue::new(IoWidth::Byte, 0x0)));
+        assert_eq!(pvpanic.lock().unwrap().last(), Some(GuestPanic::Panicked));
+        assert_eq!(notifications.try_iter().collect::<Vec<_>>(), vec![VmEvent::GuestPanic(GuestPanic::Panicked)]);
+        bus.reset_all();
+        assert_eq!(pvpanic.lock().unwrap().last(), None);
+    }
+}
@@ -0,0 +1,259 @@
+//! Handling of guest-initiated resets and power off.
+//!
+//! When the guest resets, powers off, or triple faults, every VCPU exits
+//! with `VmExit::Suspended`, and the virtual machine stays suspended until it
+//! is reinitialized. A `ResetController` runs the VCPU threads, waits for
+//! all of them to stop on a suspension, and decides what to do with it: for
+//! a reset it runs the registered device reset hooks, reinitializes the VM
+//! with `reinit_full()`, loads the boot state, and starts the VCPUs again.
+//!
+//!     use bhyve_api::reset::ResetController;
+//!     use bhyve_api::vcpu::ExitAction;
+//!     use bhyve_api::vm::*;
+//!     use std::sync::Arc;
+//!
+//!     fn run(vm: Arc<VirtualMachine>) -> Result<(), bhyve_api::Error> {
+//!         let mut controller = ResetController::new(vm);
+//!         controller.add_reset_hook(|reason| println!("guest reset: {:?}", reason));
+//!         let reason = controller.run(&[0], |_vcpu_id| {
+//!             |_vcpu: &_, _exit: VmExit| Ok(ExitAction::Continue)
+//!         })?;
+//!         println!("guest stopped after {} resets: {:?}", controller.resets(), reason);
+//!         Ok(())
+//!     }
+
+use std::sync::{mpsc, Arc, Mutex};
+
+use crate::device::GuestDevice;
+use crate::shutdown::Shutdown;
+use crate::vcpu::{ExitAction, Vcpu, VcpuReport, VcpuSet};
+use crate::vm::{SuspendReason, VirtualMachine, VmExit};
+use crate::Error;
+
+/// What to do with a suspended virtual machine.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ResetAction {
+    /// Reinitialize the VM and start the VCPUs again.
+    Restart,
+    /// Leave the VM suspended and return the reason to the caller.
+    Stop,
+}
+
+// Loads the boot state of the VCPUs with the given IDs after a reset.
+type BootState = Box<dyn FnMut(&VirtualMachine, &[i32]) -> Result<(), Error> + Send>;
+
+/// Restarts a virtual machine when the guest resets it.
+pub struct ResetController {
+    vm: Arc<VirtualMachine>,
+    hooks: Vec<Box<dyn FnMut(SuspendReason) + Send>>,
+    boot: Option<BootState>,
+    restart_on_triplefault: bool,
+    shutdown: Option<Arc<Shutdown>>,
+    resets: u64,
+    last_reason: Option<SuspendReason>,
+}
+
+impl ResetController {
+    /// Creates a controller for 'vm', with no reset hooks. Triple faults
+    /// stop the VM rather than restarting it.
+    pub fn new(vm: Arc<VirtualMachine>) -> ResetController {
+        ResetController {
+            vm: vm,
+            hooks: Vec::new(),
+            boot: None,
+            restart_on_triplefault: false,
+            shutdown: None,
+            resets: 0,
+            last_reason: None,
+        }
+    }
+
+    /// Adds a hook that is called with the reason for each reset, before the
+    /// VM is reinitialized. Hooks are called in the order they were added.
+    pub fn add_reset_hook<F>(&mut self, hook: F) where F: FnMut(SuspendReason) + Send + 'static {
+        self.hooks.push(Box::new(hook));
+    }
+
+    /// Adds a hook that returns 'device' to its power-on state on reset.
+    pub fn add_device<D>(&mut self, device: Arc<Mutex<D>>) where D: GuestDevice + ?Sized + 'static {
+        self.add_reset_hook(move |_| device.lock().unwrap().reset());
+    }
+
+    /// Sets the function that loads the boot state of the VCPUs after the
+    /// VM is reinitialized, such as reloading a kernel image and setting up
+    /// the BSP registers to enter it. It is passed the IDs of the VCPUs
+    /// being restarted. By default each VCPU is set to its architectural
+    /// reset state with `vcpu_reset()`, which starts the bootrom.
+    pub fn set_boot_state<F>(&mut self, boot: F)
+        where F: FnMut(&VirtualMachine, &[i32]) -> Result<(), Error> + Send + 'static
+    {
+        self.boot = Some(Box::new(boot));
+    }
+
+    /// Sets whether a triple fault restarts the VM, as it would on real
+    /// hardware, or stops it so the failure can be investigated.
+    pub fn set_restart_on_triplefault(&mut self, restart: bool) {
+        self.restart_on_triplefault = restart;
+    }
+
+    /// Sets the `Shutdown` to notify when the VM is suspended for a reason
+    /// that stops it, so a shutdown in progress sees the guest stop.
+    pub fn set_shutdown(&mut self, shutdown: Arc<Shutdown>) {
+        self.shutdown = Some(shutdown);
+    }
+
+    /// Returns what the controller does when the VM is suspended for
+    /// 'reason'.
+    pub fn action(&self, reason: SuspendReason) -> ResetAction {
+        action_for(reason, self.restart_on_triplefault)
+    }
+
+    /// Returns the number of times the VM has been restarted.
+    pub fn resets(&self) -> u64 {
+        self.resets
+    }
+
+    /// Returns the reason for the most recent suspension, if any.
+    pub fn last_reason(&self) -> Option<SuspendReason> {
+        self.last_reason
+    }
+
+    /// Resets the VM after a suspension for 'reason': runs the reset hooks,
+    /// reinitializes the VM with `reinit_full()`, and loads the boot state
+    /// of the VCPUs in 'vcpu_ids'. The VCPU threads must have stopped.
+    pub fn reset(&mut self, reason: SuspendReason, vcpu_ids: &[i32]) -> Result<(), Error> {
+        for hook in self.hooks.iter_mut() {
+            hook(reason);
+        }
+        self.vm.reinit_full()?;
+        match self.boot {
+            Some(ref mut boot) => boot(&self.vm, vcpu_ids)?,
+            None => {
+                for vcpu_id in vcpu_ids {
+                    self.vm.vcpu_reset(*vcpu_id)?;
+                }
+            }
+        }
+        self.resets += 1;
+        Ok(())
+    }
+
+    /// Runs the VCPUs in 'vcpu_ids' on their own threads until the VM is
+    /// suspended for a reason that stops it, restarting it on each reset.
+    /// For every start, 'handlers' is called with each VCPU ID to create
+    /// the exit handler for its thread, as passed to `VcpuSet::spawn()`.
+    /// `VmExit::Suspended` exits are handled by the controller, and never
+    /// reach the exit handlers; the `Shutdown` set with `set_shutdown()` is
+    /// notified of those that stop the VM.
+    ///
+    /// If a VCPU thread stops for any other reason, the VM is halted to stop
+    /// the rest: a handler returning `ExitAction::Stop` stops the VM as if
+    /// it had halted, and otherwise the first error is returned. A panic in
+    /// an exit handler is propagated to the caller once the other VCPU
+    /// threads have stopped.
+    pub fn run<F, H>(&mut self, vcpu_ids: &[i32], mut handlers: F) -> Result<SuspendReason, Error>
+        where F: FnMut(i32) -> H,
+              H: FnMut(&Vcpu, VmExit) -> Result<ExitAction, Error> + Send + 'static
+    {
+        loop {
+            let reason = self.run_once(vcpu_ids, &mut handlers)?;
+            self.last_reason = Some(reason);
+            match self.action(reason) {
+                ResetAction::Restart => self.reset(reason, vcpu_ids)?,
+                ResetAction::Stop => return Ok(reason),
+            }
+        }
+    }
+
+    // Runs the VCPUs until every thread has stopped, returning the reason
+    // the VM was suspended.
+    fn run_once<F, H>(&self, vcpu_ids: &[i32], handlers: &mut F) -> Result<SuspendReason, Error>
+        where F: FnMut(i32) -> H,
+              H: FnMut(&Vcpu, VmExit) -> Result<ExitAction, Error> + Send + 'static
+    {
+        let suspended = Arc::new(Mutex::new(None));
+        let (supervisor, reports) = mpsc::channel();
+        let mut vcpus = VcpuSet::new(Arc::clone(&self.vm));
+        let mut failure = None;
+        for vcpu_id in vcpu_ids {
+            let mut handler = handlers(*vcpu_id);
+            let reason = Arc::clone(&suspended);
+            let shutdown = self.shutdown.clone();
+            let restart_on_triplefault = self.restart_on_triplefault;
+            let spawned = vcpus.spawn(*vcpu_id, supervisor.clone(), move |vcpu, exit| {
+                match exit {
+                    VmExit::Suspended(how) => {
+                        reason.lock().unwrap().get_or_insert(how);
+                        if let Some(ref shutdown) = shutdown {
+                            if action_for(how, restart_on_triplefault) == ResetAction::Stop {
+                                shutdown.notify(how);
+                            }
+                        }
+                        Ok(ExitAction::Stop)
+                    }
+                    other => handler(vcpu, other),
+                }
+            });
+            if let Err(e) = spawned {
+                failure = Some(VcpuReport::Exited(*vcpu_id, Err(Error::from(e))));
+                break;
+            }
+        }
+        drop(supervisor);
+
+        let mut halted = false;
+        if failure.is_some() {
+            let _ = self.vm.halt();
+            halted = true;
+        }
+        // The channel closes once every VCPU thread has reported
+        for report in reports.iter() {
+            match report {
+                VcpuReport::Exited(_, Ok(())) => (),
+                report => {
+                    if failure.is_none() {
+                        failure = Some(report);
+                    }
+                }
+            }
+            // A thread that stopped on anything but a suspension leaves the
+            // others running, so bring them down. This fails if the VM was
+            // suspended in the meantime, which is fine.
+            if !halted && suspended.lock().unwrap().is_none() {
+                let _ = self.vm.halt();
+                halted = true;
+            }
+        }
+        vcpus.join();
+
+        match failure {
+            Some(VcpuReport::Exited(_, Err(e))) => Err(e),
+            Some(VcpuReport::Panicked(vcpu_id, message)) => panic!("VCPU {} panicked: {}", vcpu_id, message),
+            _ => Ok(suspended.lock().unwrap().unwrap_or(SuspendReason::Halt)),
+        }
+    }
+}
+
+// The action for a suspension, given whether triple faults restart the VM.
+fn action_for(reason: SuspendReason, restart_on_triplefault: bool) -> ResetAction {
+    match reason {
+        SuspendReason::Reset => ResetAction::Restart,
+        SuspendReason::TripleFault if restart_on_triplefault => ResetAction::Restart,
+        _ => ResetAction::Stop,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_action_for() {
+        assert_eq!(action_for(SuspendReason::Reset, false), ResetAction::Restart);
+        assert_eq!(action_for(SuspendReason::Poweroff, true), ResetAction::Stop);
+        assert_eq!(action_for(SuspendReason::Halt, true), ResetAction::Stop);
+        assert_eq!(action_for(SuspendReason::TripleFault, false), ResetAction::Stop);
+        assert_eq!(action_for(SuspendReason::TripleFault, true), ResetAction::Restart);
+        assert_eq!(action_for(SuspendReason::Unknown(7), true), ResetAction::Stop);
+    }
+}
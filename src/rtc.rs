@@ -0,0 +1,238 @@
+//! A userspace shadow of the in-kernel RTC and its CMOS NVRAM.
+//!
+//! Bhyve emulates the MC146818 RTC in the kernel, so guest accesses to it
+//! never exit to userspace. `RtcShadow` keeps a copy of the RTC registers
+//! and NVRAM in the VMM, so devices that depend on CMOS configuration can
+//! read it cheaply, and tells subscribers when `sync()` finds that the
+//! guest has reprogrammed the alarm, the periodic interrupt, or the NVRAM.
+//! It also captures the RTC for save and restore.
+//!
+//!     use bhyve_api::rtc::*;
+//!     use bhyve_api::vm::VirtualMachine;
+//!     use std::sync::Arc;
+//!
+//!     fn watch_rtc(vm: Arc<VirtualMachine>) -> Result<RtcShadow, bhyve_api::Error> {
+//!         let mut rtc = RtcShadow::new(vm)?;
+//!         rtc.subscribe(|change| {
+//!             if let RtcChange::PeriodicRate(rate) = *change {
+//!                 println!("guest set the RTC periodic rate to {}", rate);
+//!             }
+//!         });
+//!         // Call rtc.sync() periodically, or after the guest stops
+//!         Ok(rtc)
+//!     }
+
+use libc::EINVAL;
+use std::sync::Arc;
+
+use crate::vm::VirtualMachine;
+use crate::Error;
+
+/// Number of bytes of RTC registers and NVRAM.
+pub const RTC_NVRAM_LEN: usize = 128;
+/// Offset of the first byte of NVRAM, after the clock and status registers.
+pub const RTC_NVRAM_START: usize = 0x0e;
+/// Offset of the century byte, which bhyve keeps up to date with the clock.
+pub const RTC_CENTURY: usize = 0x32;
+
+const RTC_SEC_ALARM: usize = 0x01;
+const RTC_MIN_ALARM: usize = 0x03;
+const RTC_HRS_ALARM: usize = 0x05;
+const RTC_STATUS_A: usize = 0x0a;
+const RTC_STATUS_B: usize = 0x0b;
+
+// Interrupt enable bits of status register B
+const STATUS_B_UIE: u8 = 0x10;
+const STATUS_B_AIE: u8 = 0x20;
+const STATUS_B_PIE: u8 = 0x40;
+
+/// A change to the RTC configuration found by `RtcShadow::sync()`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RtcChange {
+    /// The alarm time changed. The values are in the format the guest
+    /// selected in status register B, which may be BCD.
+    Alarm { seconds: u8, minutes: u8, hours: u8 },
+    /// The periodic interrupt rate selection in status register A changed.
+    /// Zero means periodic interrupts are off.
+    PeriodicRate(u8),
+    /// The interrupt enable bits in status register B changed.
+    InterruptEnable { periodic: bool, alarm: bool, update: bool },
+    /// A byte of NVRAM changed.
+    Nvram { offset: u8, old: u8, new: u8 },
+}
+
+/// The state of the RTC captured by `RtcShadow::snapshot()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RtcSnapshot {
+    /// The RTC time in seconds since the epoch.
+    pub time: i64,
+    /// The registers and NVRAM, `RTC_NVRAM_LEN` bytes.
+    pub nvram: Vec<u8>,
+}
+
+type Subscriber = Box<dyn FnMut(&RtcChange) + Send>;
+
+/// A copy of the RTC registers and NVRAM of a VM.
+pub struct RtcShadow {
+    vm: Arc<VirtualMachine>,
+    regs: Vec<u8>,
+    subscribers: Vec<Subscriber>,
+}
+
+impl RtcShadow {
+    /// Creates a shadow of the RTC of 'vm', initialized from its current
+    /// contents.
+    pub fn new(vm: Arc<VirtualMachine>) -> Result<RtcShadow, Error> {
+        let regs = read_all(&vm)?;
+        Ok(RtcShadow {
+            vm: vm,
+            regs: regs,
+            subscribers: Vec::new(),
+        })
+    }
+
+    /// Returns the byte at 'offset' as of the last `sync()`, or 'None' if
+    /// the offset is out of range.
+    pub fn read(&self, offset: usize) -> Option<u8> {
+        self.regs.get(offset).cloned()
+    }
+
+    /// Returns the NVRAM as of the last `sync()`.
+    pub fn nvram(&self) -> &[u8] {
+        &self.regs[RTC_NVRAM_START..]
+    }
+
+    /// Writes 'value' to the NVRAM byte at 'offset'. The kernel only allows
+    /// writes to NVRAM, so this returns `EINVAL` for the clock and status
+    /// registers and for the century byte. Subscribers aren't notified of
+    /// changes made by the VMM.
+    pub fn write_nvram(&mut self, offset: usize, value: u8) -> Result<(), Error> {
+        if !writable(offset) {
+            return Err(Error::new(EINVAL));
+        }
+        self.vm.rtc_write(offset as i32, value)?;
+        self.regs[offset] = value;
+        Ok(())
+    }
+
+    /// Calls 'subscriber' with each change that `sync()` finds.
+    pub fn subscribe<F>(&mut self, subscriber: F)
+        where F: FnMut(&RtcChange) + Send + 'static
+    {
+        self.subscribers.push(Box::new(subscriber));
+    }
+
+    /// Reads the RTC from the kernel, updates the shadow, and notifies
+    /// subscribers of what the guest changed since the last sync. Returns
+    /// the number of changes.
+    pub fn sync(&mut self) -> Result<usize, Error> {
+        let regs = read_all(&self.vm)?;
+        let changes = changes(&self.regs, &regs);
+        self.regs = regs;
+        for change in changes.iter() {
+            for subscriber in self.subscribers.iter_mut() {
+                subscriber(change);
+            }
+        }
+        Ok(changes.len())
+    }
+
+    /// Syncs the shadow, and returns the RTC time and contents.
+    pub fn snapshot(&mut self) -> Result<RtcSnapshot, Error> {
+        self.sync()?;
+        Ok(RtcSnapshot {
+            time: self.vm.rtc_gettime()?,
+            nvram: self.regs.clone(),
+        })
+    }
+
+    /// Restores the RTC time and NVRAM from 'snapshot', then syncs the
+    /// shadow, notifying subscribers of the differences. The kernel doesn't
+    /// allow the status and alarm registers to be written, so they keep
+    /// their current values until the guest programs them.
+    pub fn restore(&mut self, snapshot: &RtcSnapshot) -> Result<(), Error> {
+        if snapshot.nvram.len() != RTC_NVRAM_LEN {
+            return Err(Error::new(EINVAL));
+        }
+        self.vm.rtc_settime(snapshot.time)?;
+        for (offset, value) in snapshot.nvram.iter().enumerate() {
+            if writable(offset) {
+                self.vm.rtc_write(offset as i32, *value)?;
+            }
+        }
+        self.sync()?;
+        Ok(())
+    }
+}
+
+// Returns true if the kernel allows the byte at 'offset' to be written.
+fn writable(offset: usize) -> bool {
+    (RTC_NVRAM_START..RTC_NVRAM_LEN).contains(&offset) && offset != RTC_CENTURY
+}
+
+fn read_all(vm: &VirtualMachine) -> Result<Vec<u8>, Error> {
+    (0..RTC_NVRAM_LEN).map(|offset| vm.rtc_read(offset as i32)).collect()
+}
+
+// Compares two copies of the RTC, ignoring the clock, which changes on its
+// own, and returns the configuration changes between them.
+fn changes(old: &[u8], new: &[u8]) -> Vec<RtcChange> {
+    let mut changes = Vec::new();
+    let alarm = [RTC_SEC_ALARM, RTC_MIN_ALARM, RTC_HRS_ALARM];
+    if alarm.iter().any(|offset| old[*offset] != new[*offset]) {
+        changes.push(RtcChange::Alarm {
+            seconds: new[RTC_SEC_ALARM],
+            minutes: new[RTC_MIN_ALARM],
+            hours: new[RTC_HRS_ALARM],
+        });
+    }
+    if (old[RTC_STATUS_A] ^ new[RTC_STATUS_A]) & 0x0f != 0 {
+        changes.push(RtcChange::PeriodicRate(new[RTC_STATUS_A] & 0x0f));
+    }
+    let enables = STATUS_B_PIE | STATUS_B_AIE | STATUS_B_UIE;
+    if (old[RTC_STATUS_B] ^ new[RTC_STATUS_B]) & enables != 0 {
+        let status = new[RTC_STATUS_B];
+        changes.push(RtcChange::InterruptEnable {
+            periodic: (status & STATUS_B_PIE) != 0,
+            alarm: (status & STATUS_B_AIE) != 0,
+            update: (status & STATUS_B_UIE) != 0,
+        });
+    }
+    for offset in (RTC_NVRAM_START..RTC_NVRAM_LEN).filter(|offset| *offset != RTC_CENTURY) {
+        if old[offset] != new[offset] {
+            changes.push(RtcChange::Nvram { offset: offset as u8, old: old[offset], new: new[offset] });
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtc_changes() {
+        let old = vec![0u8; RTC_NVRAM_LEN];
+        let mut new = old.clone();
+        // The clock ticking and the century changing aren't reported
+        new[0] = 0x59;
+        new[RTC_CENTURY] = 0x21;
+        assert!(changes(&old, &new).is_empty());
+
+        new[RTC_MIN_ALARM] = 0x30;
+        new[RTC_STATUS_A] = 0x26;
+        new[RTC_STATUS_B] = STATUS_B_PIE | 0x02;
+        new[0x40] = 7;
+        assert_eq!(changes(&old, &new), vec![
+            RtcChange::Alarm { seconds: 0, minutes: 0x30, hours: 0 },
+            RtcChange::PeriodicRate(6),
+            RtcChange::InterruptEnable { periodic: true, alarm: false, update: false },
+            RtcChange::Nvram { offset: 0x40, old: 0, new: 7 },
+        ]);
+
+        assert!(!writable(RTC_STATUS_B));
+        assert!(!writable(RTC_CENTURY));
+        assert!(writable(RTC_NVRAM_START));
+        assert!(!writable(RTC_NVRAM_LEN));
+    }
+}
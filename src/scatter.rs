@@ -0,0 +1,156 @@
+//! Scatter-gather access to guest memory by guest linear address.
+//!
+//! String I/O instructions (INS and OUTS) address buffers that are
+//! contiguous in the guest's linear address space, but may be scattered
+//! across guest physical pages. This
+//! module walks such a buffer a page at a time with `gla2gpa()`, and returns
+//! the host addresses backing it as a list of segments, which can be passed
+//! to `readv()` and `writev()` as `iovec`s.
+//!
+//!     use bhyve_api::vm::*;
+//!
+//!     fn outs(vm: &VirtualMachine, vcpu_id: i32, gla: u64, len: usize) -> Result<Vec<u8>, bhyve_api::Error> {
+//!         let paging = vm.guest_paging(vcpu_id)?;
+//!         let mut data = Vec::new();
+//!         // 'None' means the guest faulted, and the fault has been injected
+//!         if let Some(segments) = vm.gla_segments(vcpu_id, &paging, gla, len, libc::PROT_READ)? {
+//!             for segment in segments {
+//!                 let mut buf = vec![0; segment.len];
+//!                 vm.read_guest_memory(segment.gpa, &mut buf)?;
+//!                 data.extend_from_slice(&buf);
+//!             }
+//!         }
+//!         Ok(data)
+//!     }
+//!
+//! For an `InOutStr` exit, `VirtualMachine::string_io_segments()` works out
+//! the buffer from the instruction's operands, and
+//! `VirtualMachine::complete_inout_str()` steps the instruction past the
+//! elements transferred.
+
+use libc::{iovec, sysconf, c_void, EFAULT, _SC_PAGESIZE};
+
+use crate::vm::{vm_guest_paging, GuestRegion, VirtualMachine};
+use crate::Error;
+
+/// A range of guest memory that is contiguous in both the guest physical
+/// and host virtual address spaces.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GuestSegment {
+    /// Guest physical address of the start of the segment.
+    pub gpa: u64,
+    /// Host virtual address the segment is mapped at.
+    pub host_addr: u64,
+    /// Length of the segment in bytes.
+    pub len: usize,
+}
+
+impl GuestSegment {
+    /// Returns the segment as an `iovec`, for vectored I/O on host files.
+    /// The guest can change the memory at any time, so the buffer must only
+    /// be accessed by the kernel, or with volatile accesses.
+    pub fn as_iovec(&self) -> iovec {
+        iovec {
+            iov_base: self.host_addr as *mut c_void,
+            iov_len: self.len,
+        }
+    }
+}
+
+// Implements VirtualMachine::gla_segments().
+pub(crate) fn gla_segments(vm: &VirtualMachine, vcpu_id: i32, paging: &vm_guest_paging, gla: u64, len: usize, prot: i32) -> Result<Option<Vec<GuestSegment>>, Error> {
+    let page_size = unsafe { sysconf(_SC_PAGESIZE) as u64 };
+    let ranges = match gather(gla, len, page_size, |page| vm.gla2gpa(vcpu_id, paging, page, prot))? {
+        Some(ranges) => ranges,
+        None => return Ok(None),
+    };
+    let regions = vm.regions();
+    let mut segments = Vec::with_capacity(ranges.len());
+    for (gpa, len) in ranges {
+        push_host_segments(&mut segments, &regions, gpa, len)?;
+    }
+    Ok(Some(segments))
+}
+
+// Translates [gla,gla+len) a page at a time with 'translate', and returns
+// the guest physical ranges covering it, merging physically contiguous
+// pages. Returns 'None' if any page fails to translate.
+fn gather<F>(gla: u64, len: usize, page_size: u64, mut translate: F) -> Result<Option<Vec<(u64, usize)>>, Error>
+    where F: FnMut(u64) -> Result<Option<u64>, Error>
+{
+    let mut ranges: Vec<(u64, usize)> = Vec::new();
+    let mut addr = gla;
+    let mut remaining = len as u64;
+    while remaining > 0 {
+        let chunk = std::cmp::min(remaining, page_size - (addr & (page_size - 1)));
+        let gpa = match translate(addr)? {
+            Some(gpa) => gpa,
+            None => return Ok(None),
+        };
+        match ranges.last_mut() {
+            Some(last) if last.0 + last.1 as u64 == gpa => last.1 += chunk as usize,
+            _ => ranges.push((gpa, chunk as usize)),
+        }
+        addr = addr.wrapping_add(chunk);
+        remaining -= chunk;
+    }
+    Ok(Some(ranges))
+}
+
+// Appends the host mappings of [gpa,gpa+len) to 'segments', splitting the
+// range where it crosses from one region into the next.
+fn push_host_segments(segments: &mut Vec<GuestSegment>, regions: &[GuestRegion], gpa: u64, len: usize) -> Result<(), Error> {
+    let mut addr = gpa;
+    let end = gpa + len as u64;
+    while addr < end {
+        let region = match regions.iter().find(|r| r.overlaps(addr, 1)) {
+            Some(region) => region,
+            None => return Err(Error::new(EFAULT)),
+        };
+        let chunk_end = std::cmp::min(end, region.gpa + region.len);
+        segments.push(GuestSegment {
+            gpa: addr,
+            host_addr: region.host_addr + (addr - region.gpa),
+            len: (chunk_end - addr) as usize,
+        });
+        addr = chunk_end;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gather() {
+        // Pages 0x1000 and 0x2000 are physically contiguous, 0x3000 isn't
+        let map = |gla: u64| -> Result<Option<u64>, Error> {
+            Ok(match gla & !0xfff {
+                0x1000 => Some(0x8000 + (gla & 0xfff)),
+                0x2000 => Some(0x9000 + (gla & 0xfff)),
+                0x3000 => Some(0x5000 + (gla & 0xfff)),
+                _ => None,
+            })
+        };
+        let ranges = gather(0x1ff0, 0x1020, 0x1000, map).unwrap();
+        assert_eq!(ranges, Some(vec![(0x8ff0, 0x1010), (0x5000, 0x10)]));
+        assert_eq!(gather(0x3ff0, 0x20, 0x1000, map).unwrap(), None);
+        assert_eq!(gather(0x1000, 0, 0x1000, map).unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn test_push_host_segments() {
+        let regions = [
+            GuestRegion { name: "lowmem", segid: 0, segoff: 0, gpa: 0, len: 0x10000, prot: 0, host_addr: 0x7000_0000 },
+            GuestRegion { name: "highmem", segid: 1, segoff: 0, gpa: 0x10000, len: 0x10000, prot: 0, host_addr: 0x9000_0000 },
+        ];
+        let mut segments = Vec::new();
+        push_host_segments(&mut segments, &regions, 0xff00, 0x200).unwrap();
+        assert_eq!(segments, vec![
+            GuestSegment { gpa: 0xff00, host_addr: 0x7000_ff00, len: 0x100 },
+            GuestSegment { gpa: 0x10000, host_addr: 0x9000_0000, len: 0x100 },
+        ]);
+        assert!(push_host_segments(&mut segments, &regions, 0x1ff00, 0x200).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+//! Running many VCPUs on a few host threads.
+//!
+//! The usual model gives every VCPU a host thread of its own. On a host
+//! running many small guests, that means far more threads than CPUs, and
+//! leaves the fairness between VCPUs to the host scheduler. A
+//! `VcpuScheduler` instead runs a fixed set of worker threads, each of
+//! which repeatedly picks a VCPU and runs it for a time slice with
+//! `Vcpu::run_until()`. The VCPU that has spent the least time in the guest
+//! so far, according to `VirtualMachine::run_times()`, is picked first, so
+//! a VCPU that was starved catches up.
+//!
+//!     use bhyve_api::sched::VcpuScheduler;
+//!     use bhyve_api::vcpu::ExitAction;
+//!     use bhyve_api::vm::*;
+//!     use std::sync::Arc;
+//!     use std::time::Duration;
+//!
+//!     fn run(vm: Arc<VirtualMachine>) -> Result<(), bhyve_api::Error> {
+//!         let mut scheduler = VcpuScheduler::new(vm, 2, Duration::from_millis(10));
+//!         for vcpu_id in 0..8 {
+//!             scheduler.add_vcpu(vcpu_id)?;
+//!         }
+//!         scheduler.run(|_vcpu, exit| match exit {
+//!             VmExit::Suspended(_) => Ok(ExitAction::Stop),
+//!             _ => Ok(ExitAction::Continue),
+//!         })
+//!     }
+
+use libc::{EEXIST, EINVAL};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::include::vmm::VM_MAXCPU;
+use crate::timer::TimerService;
+use crate::vcpu::{ExitAction, Vcpu};
+use crate::vm::{VirtualMachine, VmExit};
+use crate::Error;
+
+// Scheduling state of one VCPU.
+#[derive(Debug)]
+struct Entry {
+    vcpu_id: i32,
+    running: bool,
+    stopped: bool,
+    // When the VCPU last finished a slice, to break ties in favour of the
+    // VCPU that has waited longest
+    last_slice: u64,
+}
+
+struct RunQueue {
+    entries: Vec<Entry>,
+    slices: u64,
+    error: Option<Error>,
+}
+
+struct Shared {
+    queue: Mutex<RunQueue>,
+    changed: Condvar,
+}
+
+// Returns the index of the candidate to run next: the one with the least
+// time in the guest, then the one that has waited longest. Candidates are
+// (index, time in the guest, last slice).
+fn pick(candidates: &[(usize, Duration, u64)]) -> Option<usize> {
+    candidates.iter().min_by_key(|(_, time, last)| (*time, *last)).map(|(index, _, _)| *index)
+}
+
+/// Runs the VCPUs of a virtual machine on a fixed number of host threads.
+pub struct VcpuScheduler {
+    vm: Arc<VirtualMachine>,
+    threads: usize,
+    slice: Duration,
+    vcpu_ids: Vec<i32>,
+    stop: Arc<AtomicBool>,
+}
+
+impl VcpuScheduler {
+    /// Creates a scheduler for 'vm' that runs its VCPUs on 'threads' host
+    /// threads, for up to 'slice' at a time.
+    pub fn new(vm: Arc<VirtualMachine>, threads: usize, slice: Duration) -> VcpuScheduler {
+        VcpuScheduler {
+            vm: vm,
+            threads: threads.max(1),
+            slice: slice,
+            vcpu_ids: Vec::new(),
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Adds 'vcpu_id' to the VCPUs to run. Returns `EINVAL` if the ID is
+    /// out of range, and `EEXIST` if the VCPU was already added.
+    pub fn add_vcpu(&mut self, vcpu_id: i32) -> Result<(), Error> {
+        if vcpu_id < 0 || vcpu_id as usize >= VM_MAXCPU {
+            return Err(Error::new(EINVAL));
+        }
+        if self.vcpu_ids.contains(&vcpu_id) {
+            return Err(Error::new(EEXIST));
+        }
+        self.vcpu_ids.push(vcpu_id);
+        Ok(())
+    }
+
+    /// Returns a flag that makes `run()` return once the running slices
+    /// have ended, when set from another thread.
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.stop)
+    }
+
+    /// Runs the VCPUs until every one of them has stopped, passing each
+    /// exit to 'handler' as `Vcpu::spawn()` does. A VCPU stops when the
+    /// handler returns `ExitAction::Stop` for it. If running a VCPU or
+    /// handling an exit fails, the other VCPUs are stopped at the end of
+    /// their slices, and the first error is returned. A panic in the
+    /// handler is caught, and fails the run with `Error::HandlerPanicked`.
+    ///
+    /// Exits are handled on the worker threads, so a VCPU's exit may be
+    /// handled on a different thread each time, and exits of different
+    /// VCPUs are handled concurrently.
+    pub fn run<H>(&self, handler: H) -> Result<(), Error>
+        where H: Fn(&Vcpu, VmExit) -> Result<ExitAction, Error> + Send + Sync + 'static
+    {
+        let timers = Arc::new(TimerService::new()?);
+        let handler = Arc::new(handler);
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(RunQueue {
+                entries: self.vcpu_ids.iter().map(|vcpu_id| Entry {
+                    vcpu_id: *vcpu_id,
+                    running: false,
+                    stopped: false,
+                    last_slice: 0,
+                }).collect(),
+                slices: 0,
+                error: None,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let mut workers = Vec::with_capacity(self.threads);
+        for index in 0..self.threads {
+            let vm = Arc::clone(&self.vm);
+            let timers = Arc::clone(&timers);
+            let handler = Arc::clone(&handler);
+            let worker_shared = Arc::clone(&shared);
+            let stop = Arc::clone(&self.stop);
+            let slice = self.slice;
+            let spawned = thread::Builder::new().name(format!("vcpu-worker-{}", index)).spawn(move || {
+                run_worker(&vm, &timers, &*handler, &worker_shared, &stop, slice);
+            });
+            match spawned {
+                Ok(worker) => workers.push(worker),
+                Err(e) => {
+                    let mut queue = shared.queue.lock().unwrap();
+                    queue.error.get_or_insert(Error::from(e));
+                    self.stop.store(true, Ordering::SeqCst);
+                    shared.changed.notify_all();
+                    break;
+                }
+            }
+        }
+        for worker in workers {
+            let _ = worker.join();
+        }
+
+        let mut queue = shared.queue.lock().unwrap();
+        match queue.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+// Runs slices of VCPUs until they have all stopped, or the scheduler is
+// stopped.
+fn run_worker<H>(vm: &Arc<VirtualMachine>, timers: &TimerService, handler: &H, shared: &Shared, stop: &AtomicBool, slice: Duration)
+    where H: Fn(&Vcpu, VmExit) -> Result<ExitAction, Error>
+{
+    loop {
+        let (index, vcpu_id) = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                if stop.load(Ordering::SeqCst) || queue.entries.iter().all(|entry| entry.stopped) {
+                    return;
+                }
+                let candidates: Vec<(usize, Duration, u64)> = queue.entries.iter().enumerate()
+                    .filter(|(_, entry)| !entry.running && !entry.stopped)
+                    .map(|(index, entry)| {
+                        let time = vm.run_times(entry.vcpu_id).map(|times| times.in_run).unwrap_or_default();
+                        (index, time, entry.last_slice)
+                    })
+                    .collect();
+                if let Some(index) = pick(&candidates) {
+                    queue.entries[index].running = true;
+                    break (index, queue.entries[index].vcpu_id);
+                }
+                // Every runnable VCPU is on another worker
+                queue = shared.changed.wait(queue).unwrap();
+            }
+        };
+        let vcpu = Vcpu::new(Arc::clone(vm), vcpu_id);
+        // A panicking handler fails the VCPU like an error, rather than
+        // ending the worker with the VCPU still marked as running, which
+        // would leave the other workers waiting for it forever
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+            run_slice(&vcpu, timers, handler, Instant::now() + slice)
+        }));
+        let outcome = match outcome {
+            Ok(outcome) => outcome,
+            Err(_) => Err(Error::HandlerPanicked { vcpu_id: vcpu_id }),
+        };
+
+        let mut queue = shared.queue.lock().unwrap();
+        queue.slices += 1;
+        let slices = queue.slices;
+        let entry = &mut queue.entries[index];
+        entry.running = false;
+        entry.last_slice = slices;
+        match outcome {
+            Ok(ExitAction::Continue) => (),
+            Ok(ExitAction::Stop) => entry.stopped = true,
+            Err(e) => {
+                entry.stopped = true;
+                queue.error.get_or_insert(e);
+                stop.store(true, Ordering::SeqCst);
+            }
+        }
+        shared.changed.notify_all();
+    }
+}
+
+// Runs 'vcpu' until 'deadline', or until the handler stops it or fails.
+fn run_slice<H>(vcpu: &Vcpu, timers: &TimerService, handler: &H, deadline: Instant) -> Result<ExitAction, Error>
+    where H: Fn(&Vcpu, VmExit) -> Result<ExitAction, Error>
+{
+    loop {
+        let exit = vcpu.run_until(deadline, timers)?;
+        if let VmExit::Interrupted = exit {
+            if Instant::now() >= deadline {
+                return Ok(ExitAction::Continue);
+            }
+        }
+        if handler(vcpu, exit)? == ExitAction::Stop {
+            return Ok(ExitAction::Stop);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick() {
+        let ms = Duration::from_millis;
+        assert_eq!(pick(&[]), None);
+        assert_eq!(pick(&[(0, ms(20), 1), (2, ms(10), 3), (3, ms(30), 0)]), Some(2));
+        // Equal time in the guest goes to the VCPU that has waited longest
+        assert_eq!(pick(&[(0, ms(10), 5), (1, ms(10), 2), (2, ms(10), 4)]), Some(1));
+    }
+}
@@ -0,0 +1,312 @@
+//! Graceful shutdown of a running guest.
+//!
+//! Stopping a guest cleanly means asking it to shut down, the way pressing
+//! the power button of a real machine does, and only forcing it off if it
+//! doesn't respond in time. A `Shutdown` sends the request, waits for the
+//! guest to power off, and escalates to suspending the VM with `poweroff()`
+//! once the grace period has passed.
+//!
+//! `AcpiPm` emulates the ACPI PM1 event and control registers at the
+//! addresses bhyve's ACPI tables describe, so the request reaches the guest
+//! as a power button event on the SCI, and the guest entering S5 powers off
+//! the VM.
+//!
+//!     use bhyve_api::device::PioBus;
+//!     use bhyve_api::shutdown::*;
+//!     use bhyve_api::vm::VirtualMachine;
+//!     use std::sync::{Arc, Mutex};
+//!     use std::time::Duration;
+//!
+//!     fn setup(vm: Arc<VirtualMachine>, bus: &mut PioBus) -> Result<Shutdown, bhyve_api::Error> {
+//!         let pm = Arc::new(Mutex::new(AcpiPm::new(Arc::clone(&vm))));
+//!         bus.register(PM1A_EVT_ADDR, PM1_BLOCK_LEN, pm.clone())?;
+//!         Ok(Shutdown::with_power_button(vm, pm))
+//!     }
+//!
+//!     // The ResetController running the VCPUs notifies the Shutdown, set
+//!     // with set_shutdown(), when the guest stops, and a supervisor thread
+//!     // asks the guest to stop:
+//!     fn stop(shutdown: &Shutdown) -> Result<(), bhyve_api::Error> {
+//!         match shutdown.run(Duration::from_secs(30))? {
+//!             ShutdownOutcome::Graceful(reason) => println!("guest stopped: {:?}", reason),
+//!             ShutdownOutcome::Forced => println!("guest forced off"),
+//!         }
+//!         Ok(())
+//!     }
+
+use libc::EALREADY;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::device::{GuestDevice, GuestPioDevice, IoValue, IoWidth};
+use crate::vm::{SuspendReason, VirtualMachine};
+use crate::Error;
+
+/// I/O port of the PM1a event block, holding PM1_STS and PM1_EN.
+pub const PM1A_EVT_ADDR: u16 = 0x400;
+/// I/O port of the PM1a control block, holding PM1_CNT.
+pub const PM1A_CNT_ADDR: u16 = 0x404;
+/// Length of the contiguous PM1a event and control blocks.
+pub const PM1_BLOCK_LEN: u16 = 6;
+/// ISA IRQ used for the ACPI system control interrupt.
+pub const SCI_IRQ: i32 = 9;
+
+const PM1_STS: u16 = 0;
+const PM1_EN: u16 = 2;
+const PM1_CNT: u16 = PM1A_CNT_ADDR - PM1A_EVT_ADDR;
+
+const PM1_PWRBTN_STS: u16 = 0x0100;
+const PM1_PWRBTN_EN: u16 = 0x0100;
+const PM1_SCI_EN: u16 = 0x0001;
+const PM1_SLP_TYP_MASK: u16 = 0x1c00;
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+const PM1_SLP_EN: u16 = 0x2000;
+
+// Sleep type for S5 in the \_S5 package of bhyve's DSDT.
+const SLP_TYP_S5: u16 = 5;
+
+// State of the PM1 registers, without the side effects on the VM.
+#[derive(Debug, Default)]
+struct Pm1Regs {
+    status: u16,
+    enable: u16,
+    control: u16,
+}
+
+impl Pm1Regs {
+    fn read(&self, offset: u16) -> u16 {
+        match offset {
+            PM1_STS => self.status,
+            PM1_EN => self.enable,
+            // ACPI mode is always enabled
+            PM1_CNT => self.control | PM1_SCI_EN,
+            _ => 0xffff,
+        }
+    }
+
+    // Returns true if the write requests the S5 (soft off) sleep state.
+    fn write(&mut self, offset: u16, value: u16) -> bool {
+        match offset {
+            // Status bits are cleared by writing ones to them
+            PM1_STS => self.status &= !value,
+            PM1_EN => self.enable = value,
+            PM1_CNT => {
+                // SLP_EN is write-only, and always reads as zero
+                self.control = value & !PM1_SLP_EN;
+                let slp_typ = (value & PM1_SLP_TYP_MASK) >> PM1_SLP_TYP_SHIFT;
+                return (value & PM1_SLP_EN) != 0 && slp_typ == SLP_TYP_S5;
+            }
+            _ => (),
+        }
+        false
+    }
+
+    fn sci_pending(&self) -> bool {
+        (self.status & self.enable) != 0
+    }
+}
+
+/// The ACPI PM1a event and control registers, for power button events and
+/// guest-initiated power off.
+///
+/// Register it on the `PioBus` at `PM1A_EVT_ADDR` with length
+/// `PM1_BLOCK_LEN`. The SCI is level triggered, so ISA IRQ `SCI_IRQ` should
+/// be configured as level triggered in the guest's interrupt controllers.
+pub struct AcpiPm {
+    vm: Arc<VirtualMachine>,
+    regs: Pm1Regs,
+    sci_asserted: bool,
+}
+
+impl AcpiPm {
+    /// Creates the PM1 registers for 'vm', in their power-on state.
+    pub fn new(vm: Arc<VirtualMachine>) -> AcpiPm {
+        AcpiPm {
+            vm: vm,
+            regs: Pm1Regs::default(),
+            sci_asserted: false,
+        }
+    }
+
+    /// Sets the power button status bit, raising an SCI if the guest has
+    /// enabled power button events.
+    pub fn press_power_button(&mut self) -> Result<(), Error> {
+        self.regs.status |= PM1_PWRBTN_STS;
+        self.update_sci()
+    }
+
+    /// Returns true if the guest has enabled power button events, which
+    /// ACPI-aware guests do once their ACPI driver is running.
+    pub fn power_button_enabled(&self) -> bool {
+        (self.regs.enable & PM1_PWRBTN_EN) != 0
+    }
+
+    // Asserts or deasserts the SCI to match the status and enable bits.
+    fn update_sci(&mut self) -> Result<(), Error> {
+        let pending = self.regs.sci_pending();
+        if pending && !self.sci_asserted {
+            self.vm.isa_assert_irq(SCI_IRQ, SCI_IRQ)?;
+        } else if !pending && self.sci_asserted {
+            self.vm.isa_deassert_irq(SCI_IRQ, SCI_IRQ)?;
+        }
+        self.sci_asserted = pending;
+        Ok(())
+    }
+}
+
+impl GuestDevice for AcpiPm {
+    fn reset(&mut self) {
+        self.regs = Pm1Regs::default();
+        let _ = self.update_sci();
+    }
+}
+
+impl GuestPioDevice for AcpiPm {
+    fn pio_read(&mut self, offset: u16, width: IoWidth) -> u32 {
+        // The registers are 16 bits wide, and may be read a byte at a time
+        let reg = self.regs.read(offset & !1) as u32;
+        IoValue::from_reg(width, reg, offset & 1).value()
+    }
+
+    fn pio_write(&mut self, offset: u16, value: IoValue) {
+        // Bytes of PM1_STS that aren't written are zero, so they don't clear
+        // any status bits.
+        let current = match offset & !1 {
+            PM1_STS => 0,
+            reg => self.regs.read(reg) as u32,
+        };
+        let reg = value.merge_into(current, offset & 1);
+        if self.regs.write(offset & !1, reg as u16) {
+            // The guest has finished shutting down. This fails if the VM is
+            // already suspended, which leaves it stopped anyway.
+            let _ = self.vm.poweroff();
+        }
+        let _ = self.update_sci();
+    }
+}
+
+/// How a guest stopped.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ShutdownOutcome {
+    /// The guest suspended itself within the grace period.
+    Graceful(SuspendReason),
+    /// The grace period passed, and the VM was powered off.
+    Forced,
+}
+
+/// Asks a guest to shut down, and forces it off if it doesn't.
+pub struct Shutdown {
+    vm: Arc<VirtualMachine>,
+    request: Mutex<Box<dyn FnMut() -> Result<(), Error> + Send>>,
+    suspended: Mutex<Option<SuspendReason>>,
+    changed: Condvar,
+}
+
+impl Shutdown {
+    /// Creates a coordinator for 'vm' that asks the guest to shut down by
+    /// calling 'request'.
+    pub fn new<F>(vm: Arc<VirtualMachine>, request: F) -> Shutdown
+        where F: FnMut() -> Result<(), Error> + Send + 'static
+    {
+        Shutdown {
+            vm: vm,
+            request: Mutex::new(Box::new(request)),
+            suspended: Mutex::new(None),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Creates a coordinator for 'vm' that asks the guest to shut down by
+    /// pressing the ACPI power button on 'pm'.
+    pub fn with_power_button(vm: Arc<VirtualMachine>, pm: Arc<Mutex<AcpiPm>>) -> Shutdown {
+        Shutdown::new(vm, move || pm.lock().unwrap().press_power_button())
+    }
+
+    /// Records that the VM has been suspended for 'reason', waking any
+    /// thread waiting in `run()` or `wait()`. A `ResetController` given this
+    /// coordinator with `set_shutdown()` calls it when the guest stops;
+    /// otherwise the VCPU exit handlers should call it for each
+    /// `VmExit::Suspended` exit.
+    pub fn notify(&self, reason: SuspendReason) {
+        let mut suspended = self.suspended.lock().unwrap();
+        if suspended.is_none() {
+            *suspended = Some(reason);
+        }
+        self.changed.notify_all();
+    }
+
+    /// Sends the shutdown request to the guest, without waiting.
+    pub fn request(&self) -> Result<(), Error> {
+        let mut request = self.request.lock().unwrap();
+        (*request)()
+    }
+
+    /// Waits up to 'timeout' for the VM to be suspended, returning the
+    /// reason, or 'None' if it is still running.
+    pub fn wait(&self, timeout: Duration) -> Option<SuspendReason> {
+        let deadline = Instant::now() + timeout;
+        let mut suspended = self.suspended.lock().unwrap();
+        while suspended.is_none() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            suspended = self.changed.wait_timeout(suspended, deadline - now).unwrap().0;
+        }
+        *suspended
+    }
+
+    /// Asks the guest to shut down, and waits up to 'grace' for it to do so.
+    /// If it is still running after that, the VM is suspended with
+    /// `poweroff()`, which stops every VCPU with a Suspended exit.
+    ///
+    /// A guest that suspends itself just as the grace period ends still
+    /// stops gracefully, once its reason is passed to `notify()`; if that
+    /// doesn't happen within another 'grace', `EALREADY` is returned.
+    pub fn run(&self, grace: Duration) -> Result<ShutdownOutcome, Error> {
+        // A guest that already stopped needs no request
+        if let Some(reason) = *self.suspended.lock().unwrap() {
+            return Ok(ShutdownOutcome::Graceful(reason));
+        }
+        self.request()?;
+        if let Some(reason) = self.wait(grace) {
+            return Ok(ShutdownOutcome::Graceful(reason));
+        }
+        match self.vm.poweroff() {
+            Ok(_) => Ok(ShutdownOutcome::Forced),
+            // The guest suspended itself at the last moment, which the VCPUs
+            // report as soon as they see it
+            Err(ref e) if e.errno() == EALREADY => {
+                match self.wait(grace) {
+                    Some(reason) => Ok(ShutdownOutcome::Graceful(reason)),
+                    None => Err(Error::new(EALREADY)),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pm1_regs() {
+        let mut regs = Pm1Regs::default();
+        regs.status |= PM1_PWRBTN_STS;
+        assert!(!regs.sci_pending());
+        assert!(!regs.write(PM1_EN, PM1_PWRBTN_EN));
+        assert!(regs.sci_pending());
+
+        // Writing ones clears status bits
+        regs.write(PM1_STS, PM1_PWRBTN_STS);
+        assert_eq!(regs.read(PM1_STS), 0);
+        assert!(!regs.sci_pending());
+
+        // Entering S1 doesn't power off, S5 does
+        assert!(!regs.write(PM1_CNT, (1 << PM1_SLP_TYP_SHIFT) | PM1_SLP_EN));
+        assert!(regs.write(PM1_CNT, (SLP_TYP_S5 << PM1_SLP_TYP_SHIFT) | PM1_SLP_EN));
+        assert_eq!(regs.read(PM1_CNT), (SLP_TYP_S5 << PM1_SLP_TYP_SHIFT) | PM1_SCI_EN);
+    }
+}
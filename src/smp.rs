@@ -0,0 +1,220 @@
+//! Startup of application processors.
+//!
+//! Only the bootstrap processor runs when a guest starts. The guest brings
+//! up each application processor (AP) by sending it an INIT IPI, which
+//! leaves it waiting for a startup IPI, and then a SIPI carrying the page
+//! number of its real-mode entry point. bhyve emulates the local APICs in
+//! the kernel, and hands the SIPI to userspace as a `VmExit::SpinupAp` exit
+//! on the sending VCPU, leaving it to the VMM to set up the target's
+//! registers, activate it, and start running it.
+//!
+//! An `ApBootstrap` does that for every SMP guest: it tracks which APs are
+//! still waiting for a SIPI, ignores SIPIs to APs that are already running
+//! as real hardware does, and calls a start function to launch the run
+//! loop of each AP it brings up.
+//!
+//!     use bhyve_api::smp::ApBootstrap;
+//!     use bhyve_api::vm::*;
+//!     use std::sync::{mpsc, Arc, Mutex};
+//!
+//!     // Returns the bootstrap, and a channel of the APs to start, for the
+//!     // thread that spawns their run loops, for example with
+//!     // VcpuSet::spawn()
+//!     fn setup(vm: Arc<VirtualMachine>, vcpus: i32) -> Result<(Arc<Mutex<ApBootstrap>>, mpsc::Receiver<i32>), bhyve_api::Error> {
+//!         let (tx, rx) = mpsc::channel();
+//!         let aps = ApBootstrap::new(vm, move |vcpu_id| {
+//!             let _ = tx.send(vcpu_id);
+//!             Ok(())
+//!         });
+//!         let aps = Arc::new(Mutex::new(aps));
+//!         for vcpu_id in 1..vcpus {
+//!             aps.lock().unwrap().add_ap(vcpu_id)?;
+//!         }
+//!         Ok((aps, rx))
+//!     }
+//!
+//!     // In the exit handler of every VCPU:
+//!     fn handle(aps: &Mutex<ApBootstrap>, exit: &VmExit) -> Result<bool, bhyve_api::Error> {
+//!         aps.lock().unwrap().handle(exit)
+//!     }
+
+use libc::EINVAL;
+use std::sync::Arc;
+
+use crate::include::vmm::VM_MAXCPU;
+use crate::vm::{vm_reg_name, VirtualMachine, VmExit};
+use crate::Error;
+
+/// The startup state of an application processor.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ApState {
+    /// The AP has been reset, and is waiting for a startup IPI.
+    WaitForSipi,
+    /// The AP has been started, and ignores further startup IPIs.
+    Started,
+}
+
+// The startup state of each VCPU, without the side effects on the VM.
+#[derive(Debug)]
+struct ApStates {
+    states: Vec<Option<ApState>>,
+}
+
+impl ApStates {
+    fn new() -> ApStates {
+        ApStates { states: vec![None; VM_MAXCPU] }
+    }
+
+    fn get(&self, vcpu_id: i32) -> Option<ApState> {
+        if vcpu_id < 0 {
+            return None;
+        }
+        self.states.get(vcpu_id as usize).cloned().unwrap_or(None)
+    }
+
+    fn set(&mut self, vcpu_id: i32, state: Option<ApState>) -> bool {
+        if vcpu_id < 0 {
+            return false;
+        }
+        match self.states.get_mut(vcpu_id as usize) {
+            Some(slot) => {
+                *slot = state;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Marks 'vcpu_id' as started, returning false if it isn't waiting for
+    // a SIPI.
+    fn accept_sipi(&mut self, vcpu_id: i32) -> bool {
+        if self.get(vcpu_id) != Some(ApState::WaitForSipi) {
+            return false;
+        }
+        self.set(vcpu_id, Some(ApState::Started))
+    }
+
+    fn reset(&mut self) {
+        for state in self.states.iter_mut() {
+            if state.is_some() {
+                *state = Some(ApState::WaitForSipi);
+            }
+        }
+    }
+}
+
+// Returns the CS selector and base that start an AP at 'rip', the physical
+// address of the page named by the SIPI vector, with IP zero.
+fn startup_segment(rip: u64) -> (u64, u64) {
+    let vector = (rip >> 12) & 0xff;
+    (vector << 8, vector << 12)
+}
+
+/// Brings up application processors on startup IPIs.
+pub struct ApBootstrap {
+    vm: Arc<VirtualMachine>,
+    aps: ApStates,
+    start: Box<dyn FnMut(i32) -> Result<(), Error> + Send>,
+}
+
+impl ApBootstrap {
+    /// Creates a bootstrap helper for 'vm', with no APs. Once an AP has
+    /// been set up and activated, 'start' is called with its VCPU ID to
+    /// start its run loop.
+    pub fn new<F>(vm: Arc<VirtualMachine>, start: F) -> ApBootstrap
+        where F: FnMut(i32) -> Result<(), Error> + Send + 'static
+    {
+        ApBootstrap {
+            vm: vm,
+            aps: ApStates::new(),
+            start: Box::new(start),
+        }
+    }
+
+    /// Adds 'vcpu_id' as an AP waiting for a startup IPI. Returns `EINVAL`
+    /// if the ID is out of range.
+    pub fn add_ap(&mut self, vcpu_id: i32) -> Result<(), Error> {
+        if !self.aps.set(vcpu_id, Some(ApState::WaitForSipi)) {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(())
+    }
+
+    /// Returns the startup state of 'vcpu_id', or 'None' if it isn't an AP.
+    pub fn state(&self, vcpu_id: i32) -> Option<ApState> {
+        self.aps.get(vcpu_id)
+    }
+
+    /// Returns every AP to waiting for a startup IPI, as after a system
+    /// reset. The run loops of started APs must have stopped.
+    pub fn reset(&mut self) {
+        self.aps.reset();
+    }
+
+    /// Handles 'exit' if it is a `VmExit::SpinupAp`, starting the target AP
+    /// if it is waiting for a SIPI and ignoring the SIPI otherwise. Returns
+    /// true if the exit was handled and the VCPU can be run again, and false
+    /// for any other exit, which the caller should handle itself.
+    pub fn handle(&mut self, exit: &VmExit) -> Result<bool, Error> {
+        match *exit {
+            VmExit::SpinupAp(vcpu_id, rip) => {
+                self.spinup(vcpu_id, rip)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Starts 'vcpu_id' in real mode at the physical address 'rip' if it
+    /// is waiting for a SIPI, returning true if it was started.
+    pub fn spinup(&mut self, vcpu_id: i32, rip: u64) -> Result<bool, Error> {
+        if !self.aps.accept_sipi(vcpu_id) {
+            return Ok(false);
+        }
+        let started = self.load_startup_state(vcpu_id, rip)
+            .and_then(|_| self.vm.activate_vcpu(vcpu_id))
+            .and_then(|_| (self.start)(vcpu_id));
+        if let Err(e) = started {
+            // Leave the AP waiting, so a retried SIPI can start it
+            self.aps.set(vcpu_id, Some(ApState::WaitForSipi));
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    // Puts 'vcpu_id' in its INIT state, then points CS:IP at the startup
+    // code, the way a SIPI does.
+    fn load_startup_state(&self, vcpu_id: i32, rip: u64) -> Result<(), Error> {
+        self.vm.vcpu_reset(vcpu_id)?;
+        let (selector, base) = startup_segment(rip);
+        // CS: present, r/w, accessed, 16-bit, byte granularity
+        self.vm.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_CS, base, 0xffff, 0x0093)?;
+        self.vm.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CS, selector)?;
+        self.vm.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RIP, 0)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ap_states() {
+        assert_eq!(startup_segment(0x9f000), (0x9f00, 0x9f000));
+
+        let mut aps = ApStates::new();
+        assert!(aps.set(1, Some(ApState::WaitForSipi)));
+        assert!(!aps.set(VM_MAXCPU as i32, Some(ApState::WaitForSipi)));
+        assert!(!aps.set(-1, Some(ApState::WaitForSipi)));
+
+        // Only the first SIPI starts an AP, and unknown VCPUs are ignored
+        assert!(aps.accept_sipi(1));
+        assert!(!aps.accept_sipi(1));
+        assert!(!aps.accept_sipi(2));
+        assert_eq!(aps.get(1), Some(ApState::Started));
+        aps.reset();
+        assert_eq!(aps.get(1), Some(ApState::WaitForSipi));
+        assert_eq!(aps.get(2), None);
+    }
+}
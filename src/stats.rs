@@ -0,0 +1,140 @@
+//! Named per-VCPU statistics from the kernel.
+//!
+//! bhyve keeps a set of counters for every VCPU, covering exits by reason,
+//! injected interrupts and exceptions, time spent halted, and more. The
+//! kernel returns them as a bare array, with the name of each entry
+//! available through a separate ioctl. A `VmStats` fetches the names once,
+//! and pairs them with the values each time the counters are read, so
+//! monitoring agents can report them without knowing the kernel's layout.
+//!
+//!     use bhyve_api::stats::VmStats;
+//!     use bhyve_api::vm::VirtualMachine;
+//!
+//!     fn report(vm: &VirtualMachine, vcpus: i32) -> Result<(), bhyve_api::Error> {
+//!         let stats = VmStats::new(vm)?;
+//!         for vcpu_id in 0..vcpus {
+//!             for (name, value) in stats.read(vm, vcpu_id)?.iter() {
+//!                 println!("vcpu{} {}: {}", vcpu_id, name, value);
+//!             }
+//!         }
+//!         Ok(())
+//!     }
+
+use libc::EINVAL;
+use std::time::Duration;
+
+use crate::include::vmm_dev::MAX_VM_STATS;
+use crate::vm::VirtualMachine;
+use crate::Error;
+
+/// The names of the statistics the kernel keeps for each VCPU.
+#[derive(Debug, Clone)]
+pub struct VmStats {
+    names: Vec<String>,
+}
+
+impl VmStats {
+    /// Fetches the statistic names from the kernel.
+    pub fn new(vm: &VirtualMachine) -> Result<VmStats, Error> {
+        let mut names = Vec::new();
+        while names.len() < MAX_VM_STATS {
+            match vm.get_stat_desc(names.len() as i32) {
+                Ok(name) => names.push(name),
+                // The kernel rejects indexes past the last statistic
+                Err(ref e) if e.errno() == EINVAL => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(VmStats { names: names })
+    }
+
+    /// Returns the names of the statistics, in index order.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Reads the statistics of VCPU 'vcpu_id'.
+    pub fn read(&self, vm: &VirtualMachine, vcpu_id: i32) -> Result<VcpuStats, Error> {
+        let (sampled, values) = vm.get_stat_values(vcpu_id)?;
+        Ok(VcpuStats {
+            vcpu_id: vcpu_id,
+            sampled: sampled,
+            entries: name_values(&self.names, &values),
+        })
+    }
+}
+
+// Pairs each value with its name, naming any the kernel didn't describe
+// by their index.
+fn name_values(names: &[String], values: &[u64]) -> Vec<(String, u64)> {
+    values.iter().enumerate().map(|(index, value)| {
+        let name = match names.get(index) {
+            Some(name) => name.clone(),
+            None => format!("stat{}", index),
+        };
+        (name, *value)
+    }).collect()
+}
+
+/// The statistics of a VCPU at one point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VcpuStats {
+    pub vcpu_id: i32,
+    /// When the kernel sampled the statistics, since the Unix epoch.
+    pub sampled: Duration,
+    entries: Vec<(String, u64)>,
+}
+
+impl VcpuStats {
+    /// Returns the value of the statistic called 'name'.
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.entries.iter().find(|(entry, _)| entry == name).map(|(_, value)| *value)
+    }
+
+    /// Returns the statistics as (name, value) pairs, in index order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.entries.iter().map(|(name, value)| (name.as_str(), *value))
+    }
+
+    /// Returns the number of statistics.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the kernel reported no statistics.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Accounting of the memory taken from the guest by a balloon device, from
+/// `VirtualMachine::balloon_stats()`. The kernel doesn't track ballooned
+/// memory, so this is the library's own accounting.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct BalloonStats {
+    /// Bytes of guest memory currently in the balloon.
+    pub inflated: u64,
+    /// Ranges added to the balloon since the VM was opened.
+    pub inflations: u64,
+    /// Ranges returned to the guest since the VM was opened.
+    pub deflations: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_values() {
+        let names = vec!["number of times hlt was intercepted".to_string(), "vm exits due to nested page fault".to_string()];
+        let stats = VcpuStats {
+            vcpu_id: 0,
+            sampled: Duration::from_secs(0),
+            entries: name_values(&names, &[4, 5, 6]),
+        };
+        assert_eq!(stats.len(), 3);
+        assert_eq!(stats.get("vm exits due to nested page fault"), Some(5));
+        assert_eq!(stats.get("stat2"), Some(6));
+        assert_eq!(stats.iter().next(), Some(("number of times hlt was intercepted", 4)));
+    }
+}
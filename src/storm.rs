@@ -0,0 +1,228 @@
+//! Guarding the host against exit storms.
+//!
+//! A guest that polls a device the VMM doesn't implement, or waits on a
+//! status bit that never changes, can exit to userspace hundreds of
+//! thousands of times a second from a single port or MMIO address, keeping
+//! a host CPU busy on its behalf. An `ExitStormGuard` counts the exits from
+//! each source in a fixed window of time, and when a source goes over the
+//! threshold, asks a policy supplied by the VMM what to do about it:
+//!
+//!     use bhyve_api::storm::*;
+//!     use bhyve_api::vm::*;
+//!     use std::time::Duration;
+//!
+//!     fn run(vm: &VirtualMachine, vcpu_id: i32) -> Result<(), bhyve_api::Error> {
+//!         let mut guard = ExitStormGuard::new(vcpu_id, StormOptions::default(), |storm: &Storm| {
+//!             match storm.source {
+//!                 // The debug port is noisy, but harmless
+//!                 ExitSource::Port(0x80) => StormAction::Throttle(Duration::from_micros(100)),
+//!                 _ => StormAction::Abort,
+//!             }
+//!         });
+//!         loop {
+//!             let exit = vm.run(vcpu_id)?;
+//!             // Returns Error::ExitStorm if the policy aborts
+//!             guard.check(vm, &exit)?;
+//!             // Handle the exit
+//!         }
+//!     }
+//!
+//! The policy is consulted once per source per window, and its answer holds
+//! for the source's exits until the window ends.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::log::LogLevel;
+use crate::vm::{VirtualMachine, VmExit};
+use crate::Error;
+
+/// Where a run of exits comes from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ExitSource {
+    /// Accesses to an I/O port.
+    Port(u16),
+    /// Accesses to a guest physical address that needed emulation.
+    Mmio(u64),
+}
+
+impl ExitSource {
+    /// Returns the source of 'exit', if it is an exit the guard counts.
+    pub fn of(exit: &VmExit) -> Option<ExitSource> {
+        match *exit {
+            VmExit::InOut(ref req) | VmExit::InOutStr(ref req, _) => Some(ExitSource::Port(req.port)),
+            VmExit::InstEmul(gpa, ..) => Some(ExitSource::Mmio(gpa)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ExitSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitSource::Port(port) => write!(f, "port {:#x}", port),
+            ExitSource::Mmio(gpa) => write!(f, "MMIO address {:#x}", gpa),
+        }
+    }
+}
+
+/// What to do about a source that went over the threshold.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StormAction {
+    /// Sleep for the given time before each further exit from the source is
+    /// handled, which bounds its rate.
+    Throttle(Duration),
+    /// Report the storm to the VM's log sink at `LogLevel::Warn`, and
+    /// carry on.
+    Log,
+    /// Stop the run loop: `check()` returns `Error::ExitStorm`.
+    Abort,
+}
+
+/// How many exits from one source make a storm.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StormOptions {
+    /// Exits from a single source within one window that count as a storm.
+    pub threshold: u64,
+    /// Length of the window.
+    pub window: Duration,
+}
+
+impl Default for StormOptions {
+    fn default() -> StormOptions {
+        StormOptions { threshold: 100_000, window: Duration::from_secs(1) }
+    }
+}
+
+/// A source that went over the threshold, as passed to the policy.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Storm {
+    pub vcpu_id: i32,
+    pub source: ExitSource,
+    /// Exits from the source in the current window so far.
+    pub exits: u64,
+    pub window: Duration,
+}
+
+/// Counts the exits of a VCPU by source, and applies a policy to sources
+/// that go over the threshold.
+pub struct ExitStormGuard {
+    vcpu_id: i32,
+    options: StormOptions,
+    policy: Box<dyn FnMut(&Storm) -> StormAction + Send>,
+    window_start: Option<Instant>,
+    counts: HashMap<ExitSource, u64>,
+    actions: HashMap<ExitSource, StormAction>, // sources over the threshold
+    storms: u64,
+}
+
+impl ExitStormGuard {
+    /// Creates a guard for the exits of 'vcpu_id', which calls 'policy' for
+    /// each source that goes over the threshold in 'options'.
+    pub fn new<F>(vcpu_id: i32, options: StormOptions, policy: F) -> ExitStormGuard
+        where F: FnMut(&Storm) -> StormAction + Send + 'static
+    {
+        ExitStormGuard {
+            vcpu_id: vcpu_id,
+            options: options,
+            policy: Box::new(policy),
+            window_start: None,
+            counts: HashMap::new(),
+            actions: HashMap::new(),
+            storms: 0,
+        }
+    }
+
+    /// Counts 'exit', and applies the policy's action if its source is over
+    /// the threshold. Returns `Error::ExitStorm` if the action is to abort.
+    pub fn check(&mut self, vm: &VirtualMachine, exit: &VmExit) -> Result<(), Error> {
+        let (source, action, storm) = match self.count(exit, Instant::now()) {
+            Some(result) => result,
+            None => return Ok(()),
+        };
+        match action {
+            StormAction::Throttle(duration) => thread::sleep(duration),
+            StormAction::Log => {
+                if let Some(storm) = storm {
+                    vm.log(LogLevel::Warn, || {
+                        format!("VCPU {}: {} exits from {} within {:?}",
+                                storm.vcpu_id, storm.exits, storm.source, storm.window)
+                    });
+                }
+            }
+            StormAction::Abort => {
+                return Err(Error::ExitStorm { vcpu_id: self.vcpu_id, source: source, exits: self.counts[&source] });
+            }
+        }
+        Ok(())
+    }
+
+    // Counts 'exit' at time 'now'. If its source is over the threshold,
+    // returns the source and the action for it, along with the storm if the
+    // policy was consulted just now.
+    fn count(&mut self, exit: &VmExit, now: Instant) -> Option<(ExitSource, StormAction, Option<Storm>)> {
+        let source = ExitSource::of(exit)?;
+        match self.window_start {
+            Some(start) if now.duration_since(start) < self.options.window => (),
+            _ => {
+                self.window_start = Some(now);
+                self.counts.clear();
+                self.actions.clear();
+            }
+        }
+        let exits = self.counts.entry(source).or_insert(0);
+        *exits += 1;
+        if let Some(action) = self.actions.get(&source) {
+            return Some((source, *action, None));
+        }
+        if *exits < self.options.threshold {
+            return None;
+        }
+        let storm = Storm { vcpu_id: self.vcpu_id, source: source, exits: *exits, window: self.options.window };
+        let action = (self.policy)(&storm);
+        self.actions.insert(source, action);
+        self.storms += 1;
+        Some((source, action, Some(storm)))
+    }
+
+    /// Returns the number of storms reported to the policy so far.
+    pub fn storms(&self) -> u64 {
+        self.storms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{InOutRequest, IoDirection};
+
+    fn outb(port: u16) -> VmExit {
+        VmExit::InOut(InOutRequest { port: port, bytes: 1, direction: IoDirection::Out, value: 0, string: false, rep: false })
+    }
+
+    #[test]
+    fn test_exit_storm_guard() {
+        let options = StormOptions { threshold: 3, window: Duration::from_secs(1) };
+        let mut guard = ExitStormGuard::new(0, options, |storm: &Storm| {
+            match storm.source {
+                ExitSource::Port(0x80) => StormAction::Log,
+                _ => StormAction::Abort,
+            }
+        });
+        let start = Instant::now();
+        assert!(guard.count(&outb(0x80), start).is_none());
+        assert!(guard.count(&outb(0x80), start).is_none());
+        assert!(guard.count(&VmExit::Pause, start).is_none());
+        assert!(guard.count(&outb(0x3f8), start).is_none());
+        match guard.count(&outb(0x80), start) {
+            Some((_, StormAction::Log, Some(storm))) => assert_eq!((storm.source, storm.exits), (ExitSource::Port(0x80), 3)),
+            other => panic!("unexpected result {:?}", other),
+        }
+        // The action holds without asking again until the window ends
+        assert_eq!(guard.count(&outb(0x80), start), Some((ExitSource::Port(0x80), StormAction::Log, None)));
+        assert!(guard.count(&outb(0x80), start + Duration::from_secs(1)).is_none());
+        assert_eq!(guard.storms(), 1);
+    }
+}
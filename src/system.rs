@@ -1,13 +1,27 @@
 // Copyright (C) 2020, Oxide Computer Company
 
-use libc::{ioctl, open, O_EXCL, O_RDWR, EINVAL};
+use libc::{ioctl, open, O_CLOEXEC, O_EXCL, O_RDWR, EINVAL, EACCES, EBUSY, ENOENT, ENOTTY, ENXIO, EPERM};
 use std::ffi::CString;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::process;
 use std::os::unix::io::{AsRawFd, FromRawFd};
 
-use crate::include::vmm_dev::{VMM_CREATE_VM, VMM_DESTROY_VM};
+use crate::include::vmm_dev::{VMM_CREATE_VM, VMM_DESTROY_VM, VMM_INTERFACE_VERSION};
+use crate::lifecycle::{EventStream, VmEvent};
+use crate::metadata::{MetadataStore, VmMetadata};
+use crate::vm::VirtualMachine;
 use crate::Error;
 
+/// Options controlling how `/dev/vmmctl` is opened.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemOptions {
+    /// Leave the filehandle open across exec(), so child processes inherit
+    /// the ability to create and destroy VMs. By default it is closed on exec.
+    pub inheritable: bool,
+}
+
 /// The VMMSystem module handles VMM system operations. It creates and
 /// owns the initial filehandle on `/dev/vmmctl`.
 ///
@@ -18,6 +32,7 @@ use crate::Error;
 
 pub struct VMMSystem {
     vmmctl: File,
+    events: EventStream,
 }
 
 impl VMMSystem {
@@ -27,11 +42,21 @@ impl VMMSystem {
     /// operations.
 
     pub fn new() -> Result<VMMSystem, Error> {
+        VMMSystem::with_options(SystemOptions::default())
+    }
+
+    /// Opens a filehandle to `/dev/vmmctl` as with `new()`, applying the
+    /// settings in 'options'.
+    pub fn with_options(options: SystemOptions) -> Result<VMMSystem, Error> {
         let c_path = match CString::new("/dev/vmmctl") {
             Ok(s) => s,
             Err(_) => return Err(Error::new(EINVAL))
         };
-        let raw_fd = unsafe { open(c_path.as_ptr(), O_RDWR | O_EXCL) };
+        let flags = match options.inheritable {
+            true => O_RDWR | O_EXCL,
+            false => O_RDWR | O_EXCL | O_CLOEXEC,
+        };
+        let raw_fd = unsafe { open(c_path.as_ptr(), flags) };
         if raw_fd < 0 {
             return Err(Error::last());
         }
@@ -41,9 +66,22 @@ impl VMMSystem {
         // and ownership of File struct is consumed by KVMSystem struct.
         Ok(VMMSystem {
             vmmctl: safe_handle,
+            events: EventStream::new(),
         })
     }
 
+    /// Returns the stream of events for the VMs created and destroyed
+    /// through this handle.
+    pub fn events(&self) -> &EventStream {
+        &self.events
+    }
+
+    /// Gets the VMM interface version reported by the kernel, or 'None' if
+    /// the kernel predates interface versioning and doesn't report one.
+    pub fn interface_version(&self) -> Result<Option<i32>, Error> {
+        interface_version_of(&self.vmmctl)
+    }
+
     /// Creates a device for virtual machine operation at `/dev/vmm/[name]`,
     /// and returns a `Result`. If the creation operation fails, the `Result`
     /// unwraps as an `Error`. If it succeeds, the `Result` unwraps as `i32`
@@ -58,10 +96,81 @@ impl VMMSystem {
         if result == -1 {
             return Err(Error::last());
         } else {
+            self.events.emit(VmEvent::Created(name.to_string()));
             return Ok(result);
         }
     }
 
+    /// Creates a device for virtual machine operation at `/dev/vmm/[name]`
+    /// as `create_vm()` does, and saves 'metadata' for it in 'store', so it
+    /// can be found again by its UUID with `MetadataStore::find()`. The VM is
+    /// destroyed again if the metadata can't be saved.
+    pub fn create_vm_with_metadata(&self, name: &str, metadata: &VmMetadata, store: &MetadataStore) -> Result<i32, Error> {
+        let result = self.create_vm(name)?;
+        if let Err(e) = store.save(name, metadata) {
+            let _ = self.destroy_vm(name);
+            return Err(e);
+        }
+        Ok(result)
+    }
+
+    /// Destroys the virtual machine device at `/dev/vmm/[name]`, and removes
+    /// its metadata from 'store'.
+    pub fn destroy_vm_with_metadata(&self, name: &str, store: &MetadataStore) -> Result<i32, Error> {
+        let result = self.destroy_vm(name)?;
+        store.remove(name)?;
+        Ok(result)
+    }
+
+    /// Creates a virtual machine called 'name' whose system memory is a copy
+    /// of the memory of 'source', which must be paused, as described for
+    /// `VirtualMachine::copy_memory_from()`. The new VM is destroyed again if
+    /// the copy fails.
+    ///
+    /// This copies every page the source guest has used: it takes time and
+    /// host memory in proportion to the guest's memory, since the kernel
+    /// can't share or copy-on-write a segment between VMs.
+    pub fn create_vm_copying_memory(&self, source: &VirtualMachine, name: &str) -> Result<VirtualMachine, Error> {
+        self.create_vm(name)?;
+        let result = VirtualMachine::new(name).and_then(|mut vm| {
+            vm.lowmem_limit = source.lowmem_limit;
+            vm.set_memflags(source.memflags());
+            vm.copy_memory_from(source)?;
+            Ok(vm)
+        });
+        if result.is_err() {
+            let _ = self.destroy_vm(name);
+        }
+        result
+    }
+
+    /// Creates a virtual machine called 'name' as a copy of the paused
+    /// template VM 'source', for spinning up many identical guests quickly.
+    /// The CPU topology and system memory are copied, along with the x2APIC
+    /// mode and registers of each active VCPU, which is then activated in
+    /// the clone, ready to run, and given the capabilities last set on it
+    /// through `source`. The clone fails with EINVAL if its maximum number
+    /// of CPUs differs from the source's. The new VM is destroyed again if
+    /// any step fails.
+    ///
+    /// This version of the VMM interface has no accessors for MSRs (such as
+    /// LSTAR, STAR, SFMASK, KERNEL_GS_BASE, TSC_AUX or PAT), FPU and xsave
+    /// state, or local APIC state, so these are left in their reset state,
+    /// and a template must be paused where the guest has yet to set them,
+    /// such as before it enters long mode. Device memory, and the state of
+    /// emulated devices in the kernel or the VMM, are not copied either.
+    pub fn clone_vm(&self, source: &VirtualMachine, name: &str) -> Result<VirtualMachine, Error> {
+        let vm = self.create_vm_copying_memory(source, name)?;
+        match clone_vcpus(source, &vm) {
+            Ok(()) => Ok(vm),
+            Err(e) => {
+                drop(vm);
+                let _ = self.destroy_vm(name);
+                Err(e)
+            }
+        }
+    }
+
     /// Destroys a device for virtual machine operations at `/dev/vmm/[name]`,
     /// and returns a `Result`. If the destruction operation fails, the `Result`
     /// unwraps as an `Error`. If it succeeds, the `Result` unwraps as `i32`
@@ -76,7 +185,133 @@ impl VMMSystem {
         if result == -1 {
             return Err(Error::last());
         } else {
+            self.events.emit(VmEvent::Destroyed(name.to_string()));
             return Ok(result);
         }
     }
+
+    /// Lists the names of the virtual machine devices under `/dev/vmm`,
+    /// sorted by name. This includes VMs created by other processes, and
+    /// ones left behind by processes that exited without destroying them.
+    pub fn list_vms(&self) -> Result<Vec<String>, Error> {
+        list_vms_in(Path::new("/dev/vmm"))
+    }
+
+    /// Checks that the calling process can open `/dev/vmmctl` and create
+    /// virtual machines, by creating and destroying a short-lived VM. If it
+    /// can't, the returned `Error::Privilege` describes what is missing,
+    /// rather than reporting a bare `EPERM`.
+    ///
+    /// This must not be called while another `VMMSystem` is open in the
+    /// process, since `/dev/vmmctl` is opened exclusively.
+    pub fn check_privileges() -> Result<(), Error> {
+        let system = match VMMSystem::new() {
+            Ok(system) => system,
+            Err(Error::Errno(e)) => {
+                let hint = match e.errno() {
+                    ENOENT | ENXIO => "the bhyve kernel module is not installed, or the vmm \
+                                       devices are not available in this zone",
+                    EACCES | EPERM => "opening the VMM control device requires the sys_config \
+                                       privilege, for example through pfexec or as root",
+                    EBUSY => "another process has the VMM control device open",
+                    _ => return Err(Error::Errno(e)),
+                };
+                return Err(Error::Privilege { operation: "open /dev/vmmctl", errno: e, hint: hint });
+            }
+            Err(e) => return Err(e),
+        };
+
+        let name = format!("bhyve-api-check-{}", process::id());
+        match system.create_vm(&name) {
+            Ok(_) => (),
+            Err(Error::Errno(e)) => {
+                let hint = match e.errno() {
+                    EACCES | EPERM => "creating virtual machines requires the sys_config privilege",
+                    _ => return Err(Error::Errno(e)),
+                };
+                return Err(Error::Privilege { operation: "create a virtual machine", errno: e, hint: hint });
+            }
+            Err(e) => return Err(e),
+        }
+        system.destroy_vm(&name)?;
+        Ok(())
+    }
+}
+
+// Copies the topology, and the state of each active VCPU, from 'source' to
+// 'target', activating the VCPUs in 'target'.
+fn clone_vcpus(source: &VirtualMachine, target: &VirtualMachine) -> Result<(), Error> {
+    let (sockets, cores, threads, maxcpus) = source.get_topology()?;
+    target.set_topology(sockets, cores, threads)?;
+    // The kernel fixes maxcpus, so it can only be checked, not set
+    if target.get_topology()?.3 != maxcpus {
+        return Err(Error::new(EINVAL));
+    }
+
+    let capabilities = source.recorded_capabilities();
+    for vcpu_id in source.get_active_cpus()?.iter() {
+        target.set_x2apic_state(vcpu_id, source.get_x2apic_state(vcpu_id)?)?;
+        source.dump_vcpu(vcpu_id)?.restore(target, vcpu_id)?;
+        target.activate_vcpu(vcpu_id)?;
+        // Capabilities are set on active VCPUs, as when the VM was set up
+        for &(cap_vcpu, cap, val) in capabilities.iter() {
+            if cap_vcpu == vcpu_id {
+                target.set_capability(vcpu_id, cap, val)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Reads the VMM interface version through the `/dev/vmmctl` filehandle
+// 'vmmctl'. The version is returned as the result of the ioctl rather than
+// through a struct, and kernels without the ioctl fail it with ENOTTY.
+fn interface_version_of(vmmctl: &File) -> Result<Option<i32>, Error> {
+    let result = unsafe { ioctl(vmmctl.as_raw_fd(), VMM_INTERFACE_VERSION) };
+    if result >= 0 {
+        return Ok(Some(result));
+    }
+    let err = Error::last();
+    if err.errno() == ENOTTY {
+        return Ok(None);
+    }
+    Err(err)
+}
+
+// Lists the device names in 'dir', which is missing when no VMs exist.
+fn list_vms_in(dir: &Path) -> Result<Vec<String>, Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::from(e)),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        // VM names are created from C strings, so are never expected to be
+        // anything other than UTF-8 in practice
+        if let Some(name) = entry.file_name().to_str() {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_vms_in() {
+        let dir = std::env::temp_dir().join(format!("bhyve-api-list-{}", process::id()));
+        assert_eq!(list_vms_in(&dir).unwrap(), Vec::<String>::new());
+
+        fs::create_dir(&dir).unwrap();
+        File::create(dir.join("vm2")).unwrap();
+        File::create(dir.join("vm1")).unwrap();
+        let names = list_vms_in(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(names.unwrap(), vec!["vm1", "vm2"]);
+    }
 }
@@ -0,0 +1,289 @@
+//! Host timers for emulated devices.
+//!
+//! Devices that act on their own schedule, such as an RTC raising periodic
+//! interrupts or a UART timing out its receive FIFO, share one
+//! `TimerService` rather than each spawning a timing thread. Callbacks run
+//! on the service's thread, or are posted to a VCPU's work queue, so that
+//! they run on the VCPU thread between exits:
+//!
+//!     use bhyve_api::timer::TimerService;
+//!     use bhyve_api::vcpu::VcpuKicker;
+//!     use std::time::Duration;
+//!
+//!     fn start_rtc(timers: &TimerService, kicker: VcpuKicker) {
+//!         let period = Duration::from_micros(976);
+//!         timers.schedule_on_vcpu(period, Some(period), kicker, |vm, _vcpu_id| {
+//!             vm.isa_pulse_irq(8, 8)?;
+//!             Ok(())
+//!         });
+//!     }
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::vcpu::VcpuKicker;
+use crate::vm::VirtualMachine;
+use crate::Error;
+
+/// Identifies a timer scheduled on a `TimerService`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+struct Timer {
+    deadline: Instant,
+    period: Option<Duration>,
+    // Taken while the callback runs, with the lock released
+    callback: Option<Box<dyn FnMut() + Send>>,
+}
+
+struct TimerState {
+    next_id: u64,
+    timers: HashMap<TimerId, Timer>,
+    // Deadlines of the timers, earliest first. Entries for timers that have
+    // been cancelled or rescheduled are skipped when they come up.
+    queue: BinaryHeap<Reverse<(Instant, TimerId)>>,
+    stopped: bool,
+}
+
+impl TimerState {
+    // Removes and returns the ID of the first timer due at or before 'now',
+    // or returns the deadline of the next timer.
+    fn next_due(&mut self, now: Instant) -> Result<TimerId, Option<Instant>> {
+        while let Some(Reverse((deadline, id))) = self.queue.peek().cloned() {
+            let current = match self.timers.get(&id) {
+                Some(timer) => timer.deadline == deadline,
+                None => false,
+            };
+            if !current {
+                self.queue.pop();
+                continue;
+            }
+            if deadline > now {
+                return Err(Some(deadline));
+            }
+            self.queue.pop();
+            return Ok(id);
+        }
+        Err(None)
+    }
+}
+
+struct Shared {
+    state: Mutex<TimerState>,
+    changed: Condvar,
+}
+
+/// A thread that runs timer callbacks for emulated devices.
+pub struct TimerService {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TimerService {
+    /// Starts the timer thread, named `timers`.
+    pub fn new() -> io::Result<TimerService> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(TimerState {
+                next_id: 1,
+                timers: HashMap::new(),
+                queue: BinaryHeap::new(),
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let thread_shared = Arc::clone(&shared);
+        let thread = thread::Builder::new().name("timers".to_string()).spawn(move || {
+            run_timers(&thread_shared);
+        })?;
+        Ok(TimerService { shared: shared, thread: Some(thread) })
+    }
+
+    /// Schedules 'callback' to run on the timer thread after 'delay', and
+    /// then every 'period' if one is given, until the timer is cancelled.
+    /// A periodic timer that falls behind skips the periods it missed
+    /// rather than running the callback several times in a row.
+    ///
+    /// Callbacks should be short, since they delay every other timer.
+    pub fn schedule<F>(&self, delay: Duration, period: Option<Duration>, callback: F) -> TimerId
+        where F: FnMut() + Send + 'static
+    {
+        let mut state = self.shared.state.lock().unwrap();
+        let id = TimerId(state.next_id);
+        state.next_id += 1;
+        let deadline = Instant::now() + delay;
+        state.timers.insert(id, Timer {
+            deadline: deadline,
+            period: period.filter(|period| *period > Duration::from_secs(0)),
+            callback: Some(Box::new(callback)),
+        });
+        state.queue.push(Reverse((deadline, id)));
+        self.shared.changed.notify_all();
+        id
+    }
+
+    /// Schedules 'work' to be posted to the VCPU thread of 'kicker' after
+    /// 'delay', and then every 'period' if one is given, as with
+    /// `schedule()`. The work runs on the VCPU thread between exits, where
+    /// it can safely change VCPU state.
+    pub fn schedule_on_vcpu<F>(&self, delay: Duration, period: Option<Duration>, kicker: VcpuKicker, work: F) -> TimerId
+        where F: Fn(&VirtualMachine, i32) -> Result<(), Error> + Send + Sync + 'static
+    {
+        let work = Arc::new(work);
+        self.schedule(delay, period, move || {
+            let work = Arc::clone(&work);
+            // The kick only fails if the VCPU thread has exited, in which
+            // case there is nothing left to deliver the work to.
+            let _ = kicker.post(move |vm, vcpu_id| work(vm, vcpu_id));
+        })
+    }
+
+    /// Moves the next expiry of timer 'id' to 'delay' from now. Returns
+    /// false if the timer no longer exists.
+    pub fn reschedule(&self, id: TimerId, delay: Duration) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        let deadline = Instant::now() + delay;
+        match state.timers.get_mut(&id) {
+            Some(timer) => timer.deadline = deadline,
+            None => return false,
+        }
+        state.queue.push(Reverse((deadline, id)));
+        self.shared.changed.notify_all();
+        true
+    }
+
+    /// Cancels timer 'id'. Returns false if it had already expired (for a
+    /// one-shot timer) or been cancelled. A callback that is running when
+    /// its timer is cancelled finishes, but doesn't run again.
+    pub fn cancel(&self, id: TimerId) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        state.timers.remove(&id).is_some()
+    }
+
+    /// Returns the number of timers that are scheduled.
+    pub fn pending(&self) -> usize {
+        self.shared.state.lock().unwrap().timers.len()
+    }
+}
+
+impl Drop for TimerService {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stopped = true;
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Runs timer callbacks as they expire, until the service is dropped.
+fn run_timers(shared: &Shared) {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        if state.stopped {
+            return;
+        }
+        let now = Instant::now();
+        let id = match state.next_due(now) {
+            Ok(id) => id,
+            Err(Some(deadline)) => {
+                state = shared.changed.wait_timeout(state, deadline - now).unwrap().0;
+                continue;
+            }
+            Err(None) => {
+                state = shared.changed.wait(state).unwrap();
+                continue;
+            }
+        };
+
+        let mut callback = match state.timers.get_mut(&id).and_then(|timer| timer.callback.take()) {
+            Some(callback) => callback,
+            None => continue,
+        };
+        drop(state);
+        callback();
+        state = shared.state.lock().unwrap();
+
+        // The timer may have been cancelled or rescheduled while it ran
+        let state_ref = &mut *state;
+        let timer = match state_ref.timers.get_mut(&id) {
+            Some(timer) => timer,
+            None => continue,
+        };
+        if timer.deadline <= now {
+            match timer.period {
+                Some(period) => {
+                    timer.deadline = next_deadline(timer.deadline, period, Instant::now());
+                    state_ref.queue.push(Reverse((timer.deadline, id)));
+                }
+                None => {
+                    state_ref.timers.remove(&id);
+                    continue;
+                }
+            }
+        }
+        timer.callback = Some(callback);
+    }
+}
+
+// Returns the first multiple of 'period' after 'deadline' that is later
+// than 'now', skipping any periods that were missed. If that is too far
+// off to compute, such as for a tiny period after the host was suspended
+// for a long time, the timer starts again one period after 'now'.
+fn next_deadline(deadline: Instant, period: Duration, now: Instant) -> Instant {
+    let next = deadline + period;
+    if next > now {
+        return next;
+    }
+    let period_nanos = period.as_nanos() as u64;
+    let behind = (now - deadline).as_nanos() as u64 / period_nanos;
+    match behind.checked_add(1).and_then(|periods| periods.checked_mul(period_nanos)) {
+        Some(skipped) => deadline + Duration::from_nanos(skipped),
+        None => now + period,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_next_deadline() {
+        let start = Instant::now();
+        let period = Duration::from_millis(10);
+        assert_eq!(next_deadline(start, period, start), start + period);
+        assert_eq!(next_deadline(start, period, start + Duration::from_millis(35)), start + period * 4);
+        // More periods were missed than fit in a u32
+        let period = Duration::from_nanos(1);
+        let now = start + Duration::from_secs(10);
+        assert_eq!(next_deadline(start, period, now), now + period);
+    }
+
+    #[test]
+    fn test_timer_order() {
+        let timers = TimerService::new().unwrap();
+        let (tx, rx) = mpsc::channel();
+        for (delay, name) in [(30, "c"), (10, "a"), (20, "b"), (25, "cancelled")].iter() {
+            let tx = tx.clone();
+            let id = timers.schedule(Duration::from_millis(*delay), None, move || tx.send(*name).unwrap());
+            if *name == "cancelled" {
+                assert!(timers.cancel(id));
+            }
+        }
+        let order: Vec<_> = (0..3).map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        assert_eq!(order, vec!["a", "b", "c"]);
+
+        let id = timers.schedule(Duration::from_millis(1), Some(Duration::from_millis(1)), move || {
+            let _ = tx.send("tick");
+        });
+        for _ in 0..3 {
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "tick");
+        }
+        assert!(timers.cancel(id));
+        assert_eq!(timers.pending(), 0);
+    }
+}
@@ -0,0 +1,248 @@
+//! Guest execution tracing with the monitor trap flag.
+//!
+//! With `VM_CAP_MTRAP_EXIT` enabled, the VCPU exits after every guest
+//! instruction. Feeding each exit from `run()` to an `MtrapTrace` collects
+//! the guest instruction pointers into a fixed-size ring buffer, keeping
+//! the most recent records.
+//!
+//!     use bhyve_api::vm::*;
+//!
+//!     fn trace(vm: &VirtualMachine, vcpu_id: i32) -> Result<(), bhyve_api::Error> {
+//!         let mut trace = vm.start_mtrap_trace(vcpu_id, 1024)?;
+//!         for _ in 0..100 {
+//!             let exit = vm.run(vcpu_id)?;
+//!             if !trace.record(vm, &exit)? {
+//!                 break;
+//!             }
+//!         }
+//!         for record in trace.stop(vm)? {
+//!             println!("{:>8} {:#x}", record.seq, record.rip);
+//!         }
+//!         Ok(())
+//!     }
+//!
+//! The same exits can bound how far a guest runs. An `InstructionStepper`
+//! runs a VCPU for a given number of guest instructions, stopping early at
+//! any other exit, which is the basis for record/replay style debugging:
+//! record how many instructions ran before each external event, and replay
+//! the event at the same count.
+//!
+//!     use bhyve_api::trace::StepOutcome;
+//!     use bhyve_api::vm::*;
+//!
+//!     fn run_to(vm: &VirtualMachine, vcpu_id: i32, count: u64) -> Result<u64, bhyve_api::Error> {
+//!         let mut stepper = vm.start_stepper(vcpu_id)?;
+//!         while stepper.executed() < count {
+//!             match stepper.run(count - stepper.executed())? {
+//!                 StepOutcome::Budget => break,
+//!                 StepOutcome::Exit(exit) => println!("exit: {:?}", exit),
+//!             }
+//!         }
+//!         stepper.stop()
+//!     }
+//!
+//! The counts are approximate. This interface version gives no access to
+//! the guest's performance counters, so there is no hardware instruction or
+//! branch counting, and MTRAP exits are only available with Intel VMX:
+//! elsewhere, starting a stepper fails. An instruction that exits for
+//! emulation may not be followed by an MTRAP exit, so it may go uncounted,
+//! and each iteration of a REP string instruction may count on its own.
+//! Counts are only comparable between runs on the same host.
+
+use std::collections::VecDeque;
+
+use crate::vm::{vm_cap_type, vm_reg_name, VirtualMachine, VmExit};
+use crate::Error;
+
+/// A traced guest instruction.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TraceRecord {
+    /// Position of the record in the trace, counting from zero, including
+    /// records that have since been dropped from the ring buffer.
+    pub seq: u64,
+    /// Guest instruction pointer at the exit.
+    pub rip: u64,
+}
+
+/// A ring buffer of guest instruction pointers collected from MTRAP exits.
+pub struct MtrapTrace {
+    vcpu_id: i32,
+    capacity: usize,
+    records: VecDeque<TraceRecord>,
+    total: u64,
+}
+
+impl MtrapTrace {
+    /// Enables MTRAP exits on the VCPU and returns an empty trace that keeps
+    /// at most 'capacity' records.
+    pub fn start(vm: &VirtualMachine, vcpu_id: i32, capacity: usize) -> Result<MtrapTrace, Error> {
+        vm.set_capability(vcpu_id, vm_cap_type::VM_CAP_MTRAP_EXIT, 1)?;
+        Ok(MtrapTrace {
+            vcpu_id: vcpu_id,
+            capacity: capacity,
+            records: VecDeque::with_capacity(capacity),
+            total: 0,
+        })
+    }
+
+    /// Records the guest instruction pointer if 'exit' is an MTRAP exit,
+    /// dropping the oldest record if the buffer is full. Returns true if the
+    /// exit was recorded, and false for any other kind of exit, which the
+    /// caller should handle itself.
+    pub fn record(&mut self, vm: &VirtualMachine, exit: &VmExit) -> Result<bool, Error> {
+        match exit {
+            VmExit::Mtrap => (),
+            _ => return Ok(false),
+        }
+        let rip = vm.get_register(self.vcpu_id, vm_reg_name::VM_REG_GUEST_RIP)?;
+        self.push(rip);
+        Ok(true)
+    }
+
+    fn push(&mut self, rip: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(TraceRecord { seq: self.total, rip: rip });
+        self.total += 1;
+    }
+
+    /// Iterates over the records in the buffer, oldest first.
+    pub fn records(&self) -> impl Iterator<Item = &TraceRecord> {
+        self.records.iter()
+    }
+
+    /// Returns the number of instructions traced, including records that
+    /// have been dropped from the buffer.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Disables MTRAP exits on the VCPU, and returns the records in the
+    /// buffer, oldest first.
+    pub fn stop(self, vm: &VirtualMachine) -> Result<Vec<TraceRecord>, Error> {
+        vm.set_capability(self.vcpu_id, vm_cap_type::VM_CAP_MTRAP_EXIT, 0)?;
+        Ok(self.records.into_iter().collect())
+    }
+}
+
+/// Why `InstructionStepper::run()` returned.
+#[derive(Debug)]
+pub enum StepOutcome {
+    /// The VCPU ran the number of instructions it was given.
+    Budget,
+    /// The VCPU exited for another reason, which the caller should handle
+    /// before running it again.
+    Exit(VmExit),
+}
+
+/// Runs a VCPU for bounded numbers of guest instructions, counted with
+/// MTRAP exits. MTRAP exits are disabled again when the stepper is stopped
+/// or dropped.
+pub struct InstructionStepper<'a> {
+    vm: &'a VirtualMachine,
+    vcpu_id: i32,
+    executed: u64,
+    stopped: bool,
+}
+
+impl<'a> InstructionStepper<'a> {
+    /// Enables MTRAP exits on the VCPU and returns a stepper with a count
+    /// of zero.
+    pub fn start(vm: &'a VirtualMachine, vcpu_id: i32) -> Result<InstructionStepper<'a>, Error> {
+        vm.set_capability(vcpu_id, vm_cap_type::VM_CAP_MTRAP_EXIT, 1)?;
+        Ok(InstructionStepper { vm: vm, vcpu_id: vcpu_id, executed: 0, stopped: false })
+    }
+
+    /// Runs the VCPU until it has executed 'budget' more instructions, or
+    /// exits for any other reason. Returns `StepOutcome::Budget` straight
+    /// away if 'budget' is zero.
+    pub fn run(&mut self, budget: u64) -> Result<StepOutcome, Error> {
+        let mut remaining = budget;
+        while remaining > 0 {
+            let exit = self.vm.run(self.vcpu_id)?;
+            if let Some(outcome) = account(&mut self.executed, exit, &mut remaining) {
+                return Ok(outcome);
+            }
+        }
+        Ok(StepOutcome::Budget)
+    }
+
+    /// Returns the number of instructions executed since the stepper was
+    /// started.
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// Disables MTRAP exits on the VCPU, and returns the number of
+    /// instructions executed.
+    pub fn stop(mut self) -> Result<u64, Error> {
+        self.stopped = true;
+        self.vm.set_capability(self.vcpu_id, vm_cap_type::VM_CAP_MTRAP_EXIT, 0)?;
+        Ok(self.executed)
+    }
+}
+
+impl<'a> Drop for InstructionStepper<'a> {
+    fn drop(&mut self) {
+        // Leaving MTRAP exits on would make every instruction exit
+        if !self.stopped {
+            let _ = self.vm.set_capability(self.vcpu_id, vm_cap_type::VM_CAP_MTRAP_EXIT, 0);
+        }
+    }
+}
+
+// Counts 'exit' against the 'remaining' budget, adding the instructions run
+// to 'executed', and returns the outcome if the run should stop there.
+fn account(executed: &mut u64, exit: VmExit, remaining: &mut u64) -> Option<StepOutcome> {
+    match exit {
+        VmExit::Mtrap => {
+            *executed += 1;
+            *remaining -= 1;
+            match *remaining {
+                0 => Some(StepOutcome::Budget),
+                _ => None,
+            }
+        }
+        // Nothing ran, so run again
+        VmExit::Bogus => None,
+        exit => Some(StepOutcome::Exit(exit)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut trace = MtrapTrace { vcpu_id: 0, capacity: 2, records: VecDeque::new(), total: 0 };
+        trace.push(0x1000);
+        trace.push(0x1002);
+        trace.push(0x1005);
+
+        let records: Vec<_> = trace.records().cloned().collect();
+        assert_eq!(records, vec![TraceRecord { seq: 1, rip: 0x1002 }, TraceRecord { seq: 2, rip: 0x1005 }]);
+        assert_eq!(trace.total(), 3);
+    }
+
+    #[test]
+    fn test_instruction_stepper() {
+        let mut executed = 0;
+        let mut remaining = 2;
+        assert!(account(&mut executed, VmExit::Mtrap, &mut remaining).is_none());
+        assert!(account(&mut executed, VmExit::Bogus, &mut remaining).is_none());
+        match account(&mut executed, VmExit::Pause, &mut remaining) {
+            Some(StepOutcome::Exit(VmExit::Pause)) => (),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+        match account(&mut executed, VmExit::Mtrap, &mut remaining) {
+            Some(StepOutcome::Budget) => (),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+        assert_eq!((executed, remaining), (2, 0));
+    }
+}
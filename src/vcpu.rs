@@ -0,0 +1,751 @@
+//! Helpers for driving virtual CPUs from host threads.
+//!
+//! A thread blocked in `VirtualMachine::run()` only returns to userspace on a
+//! VM exit. To interrupt it from another thread, install the kick signal
+//! handler once per process, create a `VcpuKicker` on the VCPU thread, and
+//! hand clones of it to the threads that need to interrupt the VCPU.
+//!
+//! Other threads can also post work to run on the VCPU thread, such as
+//! asserting an interrupt once a timer fires or an I/O request completes.
+//! Posting kicks the VCPU, and the run loop runs the work between exits.
+//! A kick that lands after the run loop has checked for one, but before it
+//! enters VM_RUN, makes VM_RUN return a `VmExit::Debug` exit at once:
+//!
+//!     use bhyve_api::vcpu::*;
+//!     use bhyve_api::vm::*;
+//!
+//!     fn run_loop(vm: &VirtualMachine, vcpu_id: i32, kicker: &VcpuKicker) -> Result<(), bhyve_api::Error> {
+//!         loop {
+//!             if kicker.take_pending()? {
+//!                 kicker.work().run_pending(vm, vcpu_id)?;
+//!             }
+//!             match vm.run(vcpu_id)? {
+//!                 VmExit::Interrupted | VmExit::Debug => continue,
+//!                 _ => return Ok(()),
+//!             }
+//!         }
+//!     }
+
+use libc::{c_int, pthread_t, EINVAL, ENOENT};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr::null_mut;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::capability::Capability;
+use crate::cpuset::CpuSet;
+use crate::lifecycle::VmEvent;
+use crate::timer::TimerService;
+use crate::vm::{vm_cap_type, vm_reg_name, InOutRequest, VirtualMachine, VmExit, VmExitInfo};
+use crate::Error;
+
+/// The signal used to interrupt a thread in VM_RUN.
+pub const KICK_SIGNAL: c_int = libc::SIGUSR2;
+
+extern "C" fn handle_kick(_signum: c_int) {
+    // Nothing to do, the signal only exists to interrupt the ioctl.
+}
+
+/// Installs a no-op handler for `KICK_SIGNAL`. The handler is installed
+/// without `SA_RESTART`, so a kicked thread returns from VM_RUN with `EINTR`,
+/// which `run()` reports as `VmExit::Interrupted`.
+///
+/// This replaces any existing handler for the signal in the whole process.
+pub fn install_kick_handler() -> Result<(), Error> {
+    // Struct is allocated (and owned) by Rust
+    let mut action: libc::sigaction = unsafe { mem::zeroed() };
+    action.sa_sigaction = handle_kick as extern "C" fn(c_int) as usize;
+    action.sa_flags = 0;
+    let result = unsafe {
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(KICK_SIGNAL, &action, null_mut())
+    };
+    if result == 0 {
+        return Ok(());
+    } else {
+        return Err(Error::last());
+    }
+}
+
+/// Work posted to run on a VCPU thread, which is passed the virtual machine
+/// and the VCPU ID.
+pub type Work = Box<dyn FnOnce(&VirtualMachine, i32) -> Result<(), Error> + Send>;
+
+/// A queue of work for a VCPU thread, which any thread can add to. The
+/// work runs in the order it was posted.
+#[derive(Clone, Default)]
+pub struct WorkQueue {
+    items: Arc<Mutex<VecDeque<Work>>>,
+}
+
+impl WorkQueue {
+    /// Creates an empty queue.
+    pub fn new() -> WorkQueue {
+        WorkQueue::default()
+    }
+
+    /// Adds 'work' to the end of the queue, without waking the VCPU thread.
+    /// Use `VcpuKicker::post()` to have it run promptly.
+    pub fn push<F>(&self, work: F)
+        where F: FnOnce(&VirtualMachine, i32) -> Result<(), Error> + Send + 'static
+    {
+        self.items.lock().unwrap().push_back(Box::new(work));
+    }
+
+    /// Returns the number of items waiting to run.
+    pub fn len(&self) -> usize {
+        self.items.lock().unwrap().len()
+    }
+
+    /// Returns true if no work is waiting.
+    pub fn is_empty(&self) -> bool {
+        self.items.lock().unwrap().is_empty()
+    }
+
+    /// Runs the queued work for VCPU 'vcpu_id' of 'vm', including any posted
+    /// while it runs, and returns how many items ran. The run loop calls
+    /// this between exits, when the VCPU isn't in the guest. If an item
+    /// fails, the rest stay queued and the error is returned.
+    pub fn run_pending(&self, vm: &VirtualMachine, vcpu_id: i32) -> Result<usize, Error> {
+        let mut count = 0;
+        loop {
+            // The lock isn't held while the work runs, so it can post more
+            let work = match self.items.lock().unwrap().pop_front() {
+                Some(work) => work,
+                None => return Ok(count),
+            };
+            work(vm, vcpu_id)?;
+            count += 1;
+        }
+    }
+}
+
+/// A handle for interrupting the host thread that runs a VCPU.
+///
+/// A signal that arrives while the thread is outside of VM_RUN would be
+/// lost, so every kick also sets a pending flag, which the run loop should
+/// check with `take_pending()` before entering the guest, and suspends the
+/// VCPU in the kernel, as `VirtualMachine::suspend_vcpu()` does, so a
+/// VM_RUN entered after the check returns at once with `VmExit::Debug`.
+/// `take_pending()` resumes the VCPU again, unless it was already suspended
+/// by someone else when it was kicked. A pending kick means there may
+/// be work in the VCPU's `WorkQueue` to run.
+#[derive(Clone)]
+pub struct VcpuKicker {
+    vm: Arc<VirtualMachine>,
+    vcpu_id: i32,
+    thread: pthread_t,
+    kick: Arc<Mutex<Kick>>,
+    kicked: Arc<Condvar>,
+    work: WorkQueue,
+}
+
+// The state of the latest kick. Suspending the VCPU and marking the kick as
+// pending happen under the same lock as taking it, so take_pending() only
+// resumes a VCPU that it has seen kicked.
+#[derive(Debug, Default)]
+struct Kick {
+    pending: bool,
+    suspended: bool, // the kick itself suspended the VCPU
+}
+
+impl VcpuKicker {
+    /// Creates a kicker for VCPU 'vcpu_id' of 'vm', run by the calling
+    /// thread. It must only be used while that thread is alive, since the
+    /// thread ID may be reused afterwards.
+    pub fn current(vm: Arc<VirtualMachine>, vcpu_id: i32) -> VcpuKicker {
+        VcpuKicker {
+            vm: vm,
+            vcpu_id: vcpu_id,
+            thread: unsafe { libc::pthread_self() },
+            kick: Arc::new(Mutex::new(Kick::default())),
+            kicked: Arc::new(Condvar::new()),
+            work: WorkQueue::new(),
+        }
+    }
+
+    /// Marks a kick as pending, suspends the VCPU, and signals the VCPU
+    /// thread, forcing it out of VM_RUN if it is currently in the guest.
+    /// A kick that is already pending has done all that, so further kicks
+    /// only signal the thread again. A VCPU that is already suspended, for
+    /// example by `VirtualMachine::suspend_vcpu()`, is left to whoever
+    /// suspended it, and one that hasn't been activated yet can't be
+    /// suspended, so both are only marked and signalled.
+    pub fn kick(&self) -> Result<(), Error> {
+        {
+            let mut kick = self.kick.lock().unwrap();
+            if !kick.pending && !self.vm.get_debug_cpus()?.contains(self.vcpu_id) {
+                match self.vm.suspend_vcpu(self.vcpu_id) {
+                    Ok(_) => kick.suspended = true,
+                    Err(ref e) if e.errno() == EINVAL => (),
+                    Err(e) => return Err(e),
+                }
+            }
+            kick.pending = true;
+        }
+        self.kicked.notify_all();
+        let result = unsafe { libc::pthread_kill(self.thread, KICK_SIGNAL) };
+        if result == 0 {
+            return Ok(());
+        } else {
+            // pthread_kill returns the error number rather than setting errno
+            return Err(Error::new(result));
+        }
+    }
+
+    /// Returns true if a kick was delivered since the last call, clearing
+    /// the pending flag, and resuming the VCPU if the kick suspended it.
+    pub fn take_pending(&self) -> Result<bool, Error> {
+        let mut kick = self.kick.lock().unwrap();
+        if kick.suspended {
+            self.vm.resume_vcpu(self.vcpu_id)?;
+            kick.suspended = false;
+        }
+        Ok(mem::replace(&mut kick.pending, false))
+    }
+
+    /// Blocks the calling thread until a kick is pending or 'timeout' has
+    /// passed, returning true if it was kicked. The kick stays pending for
+    /// `take_pending()`, so a VCPU thread can idle here in place of the
+    /// guest and still see the work that woke it.
+    pub fn wait_kick(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut kick = self.kick.lock().unwrap();
+        while !kick.pending {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            kick = self.kicked.wait_timeout(kick, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    /// Returns the ID of the VCPU the kicker targets.
+    pub fn vcpu_id(&self) -> i32 {
+        self.vcpu_id
+    }
+
+    // Suspends the VCPU on behalf of the caller, which resumes it itself.
+    // A suspension made by a kick still pending is handed over to the
+    // caller, so take_pending() doesn't resume the VCPU from under it.
+    fn suspend_held(&self) -> Result<(), Error> {
+        let mut kick = self.kick.lock().unwrap();
+        self.vm.suspend_vcpu(self.vcpu_id)?;
+        kick.suspended = false;
+        Ok(())
+    }
+
+    /// Returns the work queue of the VCPU thread.
+    pub fn work(&self) -> &WorkQueue {
+        &self.work
+    }
+
+    /// Queues 'work' to run on the VCPU thread, and kicks the thread so it
+    /// runs before the VCPU next enters the guest.
+    pub fn post<F>(&self, work: F) -> Result<(), Error>
+        where F: FnOnce(&VirtualMachine, i32) -> Result<(), Error> + Send + 'static
+    {
+        self.work.push(work);
+        self.kick()
+    }
+}
+
+/// A virtual CPU of a shared `VirtualMachine`, usually obtained with
+/// `VirtualMachine::vcpu()`, which checks the ID. Each VCPU-scoped operation
+/// of the VM is available on it without the VCPU ID, so a thread can own
+/// the handle of the VCPU it runs.
+pub struct Vcpu {
+    vm: Arc<VirtualMachine>,
+    id: i32,
+}
+
+/// What a VCPU run loop should do after an exit has been handled.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ExitAction {
+    /// Enter the guest again.
+    Continue,
+    /// Leave the run loop and end the VCPU thread.
+    Stop,
+}
+
+/// Reports sent to the supervisor when a VCPU thread ends.
+#[derive(Debug)]
+pub enum VcpuReport {
+    /// The run loop stopped, either because the handler asked it to or
+    /// because running the VCPU or handling an exit failed.
+    Exited(i32 /* vcpu */, Result<(), Error>),
+    /// The exit handler panicked.
+    Panicked(i32 /* vcpu */, String /* panic message */),
+}
+
+/// A running VCPU thread.
+pub struct VcpuThread {
+    /// Handle for joining the VCPU thread once it has reported its exit.
+    pub handle: JoinHandle<()>,
+    /// Kicker targeting the VCPU thread.
+    pub kicker: VcpuKicker,
+}
+
+impl Vcpu {
+    /// Creates a handle for the VCPU identified by 'id' on 'vm'.
+    pub fn new(vm: Arc<VirtualMachine>, id: i32) -> Vcpu {
+        Vcpu { vm: vm, id: id }
+    }
+
+    /// Returns the VCPU ID.
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    /// Returns the virtual machine this VCPU belongs to.
+    pub fn vm(&self) -> &VirtualMachine {
+        &self.vm
+    }
+
+    /// Runs the VCPU until its next exit, as with `VirtualMachine::run()`.
+    pub fn run(&self) -> Result<VmExit, Error> {
+        self.vm.run(self.id)
+    }
+
+    /// Runs the VCPU until its next exit, returning the exit along with the
+    /// guest RIP and instruction length, as with
+    /// `VirtualMachine::run_info()`.
+    pub fn run_info(&self) -> Result<VmExitInfo, Error> {
+        self.vm.run_info(self.id)
+    }
+
+    /// Completes an IN exit by supplying 'value' to the guest, as with
+    /// `VirtualMachine::complete_inout()`.
+    pub fn complete_inout(&self, request: &InOutRequest, value: u32) -> Result<(), Error> {
+        self.vm.complete_inout(self.id, request, value)
+    }
+
+    /// Gets the value of register 'reg'.
+    pub fn get_register(&self, reg: vm_reg_name) -> Result<u64, Error> {
+        self.vm.get_register(self.id, reg)
+    }
+
+    /// Sets register 'reg' to 'val'.
+    pub fn set_register(&self, reg: vm_reg_name, val: u64) -> Result<bool, Error> {
+        self.vm.set_register(self.id, reg, val)
+    }
+
+    /// Gets the values of the registers in 'regs', in the same order.
+    pub fn get_registers(&self, regs: &[vm_reg_name]) -> Result<Vec<u64>, Error> {
+        self.vm.get_registers(self.id, regs)
+    }
+
+    /// Sets each register in 'regs' to its paired value.
+    pub fn set_registers(&self, regs: &[(vm_reg_name, u64)]) -> Result<bool, Error> {
+        self.vm.set_registers(self.id, regs)
+    }
+
+    /// Gets the base, limit, and access rights of descriptor register 'reg'.
+    pub fn get_desc(&self, reg: vm_reg_name) -> Result<(u64, u32, u32), Error> {
+        self.vm.get_desc(self.id, reg)
+    }
+
+    /// Sets the base, limit, and access rights of descriptor register 'reg'.
+    pub fn set_desc(&self, reg: vm_reg_name, base: u64, limit: u32, access: u32) -> Result<bool, Error> {
+        self.vm.set_desc(self.id, reg, base, limit, access)
+    }
+
+    /// Gets the raw value of capability 'cap'.
+    pub fn get_capability(&self, cap: vm_cap_type) -> Result<i32, Error> {
+        self.vm.get_capability(self.id, cap)
+    }
+
+    /// Sets capability 'cap' to the raw value 'val'.
+    pub fn set_capability(&self, cap: vm_cap_type, val: i32) -> Result<bool, Error> {
+        self.vm.set_capability(self.id, cap, val)
+    }
+
+    /// Gets the typed value of capability 'C'.
+    pub fn get_cap<C: Capability>(&self) -> Result<C::Value, Error> {
+        self.vm.get_cap::<C>(self.id)
+    }
+
+    /// Sets capability 'C' to the typed 'value'.
+    pub fn set_cap<C: Capability>(&self, value: C::Value) -> Result<bool, Error> {
+        self.vm.set_cap::<C>(self.id, value)
+    }
+
+    /// Returns true if the local APIC is in x2APIC mode.
+    pub fn get_x2apic_state(&self) -> Result<bool, Error> {
+        self.vm.get_x2apic_state(self.id)
+    }
+
+    /// Enables or disables x2APIC mode of the local APIC.
+    pub fn set_x2apic_state(&self, enable: bool) -> Result<bool, Error> {
+        self.vm.set_x2apic_state(self.id, enable)
+    }
+
+    /// Activates the VCPU, so it can be run.
+    pub fn activate(&self) -> Result<bool, Error> {
+        self.vm.activate_vcpu(self.id)
+    }
+
+    /// Suspends the VCPU, forcing it out of the guest.
+    pub fn suspend(&self) -> Result<bool, Error> {
+        self.vm.suspend_vcpu(self.id)
+    }
+
+    /// Resumes the VCPU after `suspend()`.
+    pub fn resume(&self) -> Result<bool, Error> {
+        self.vm.resume_vcpu(self.id)
+    }
+
+    /// Puts the VCPU in its power-on state, as with
+    /// `VirtualMachine::vcpu_reset()`.
+    pub fn reset(&self) -> Result<bool, Error> {
+        self.vm.vcpu_reset(self.id)
+    }
+
+    /// Runs the VCPU until its next exit or until 'deadline', whichever
+    /// comes first, so that a host thread can share its time between
+    /// several VCPUs, or get back to servicing devices within a bounded
+    /// time. A run stopped by the deadline returns `VmExit::Interrupted`,
+    /// as does a call made once the deadline has passed, which doesn't
+    /// enter the guest.
+    ///
+    /// The deadline is enforced by a timer on 'timers' that suspends the
+    /// VCPU, which forces it out of VM_RUN even if the timer fires just
+    /// before it enters the guest, where a kick would be lost. The VCPU is
+    /// resumed before this returns.
+    pub fn run_until(&self, deadline: Instant, timers: &TimerService) -> Result<VmExit, Error> {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(VmExit::Interrupted);
+        }
+        let expiry = Arc::new(Mutex::new(Expiry::default()));
+        let timer_expiry = Arc::clone(&expiry);
+        let vm = Arc::clone(&self.vm);
+        let id = self.id;
+        let timer = timers.schedule(deadline - now, None, move || {
+            let mut expiry = timer_expiry.lock().unwrap();
+            // A timer that went off as it was cancelled must not suspend a
+            // later run
+            if !expiry.finished {
+                expiry.fired = vm.suspend_vcpu(id).is_ok();
+            }
+        });
+        let exit = self.vm.run(self.id);
+        timers.cancel(timer);
+        let fired = {
+            let mut expiry = expiry.lock().unwrap();
+            expiry.finished = true;
+            expiry.fired
+        };
+        if fired {
+            self.vm.resume_vcpu(self.id)?;
+        }
+        Ok(deadline_exit(exit?, fired))
+    }
+
+    /// Spawns a host thread named `vcpu-N` that runs the VCPU, passing each
+    /// exit to 'handler' until the handler returns `ExitAction::Stop` or an
+    /// error. A kick that arrives between exits is passed to the handler as
+    /// `VmExit::Interrupted` without entering the guest, after running any
+    /// work posted to the thread. Work that fails stops the thread like a
+    /// failing handler.
+    ///
+    /// When the thread ends, a `VcpuReport` is sent on 'supervisor'. Panics
+    /// in the handler are caught and reported, so the supervisor can shut
+    /// down the rest of the virtual machine in an orderly way.
+    pub fn spawn<F>(self, supervisor: Sender<VcpuReport>, handler: F) -> io::Result<VcpuThread>
+        where F: FnMut(&Vcpu, VmExit) -> Result<ExitAction, Error> + Send + 'static
+    {
+        self.spawn_gated(Arc::new(PauseGate::new()), supervisor, handler)
+    }
+
+    // Spawns the VCPU thread, with a run loop that parks at 'gate' between
+    // exits whenever the gate is closed.
+    fn spawn_gated<F>(self, gate: Arc<PauseGate>, supervisor: Sender<VcpuReport>, mut handler: F) -> io::Result<VcpuThread>
+        where F: FnMut(&Vcpu, VmExit) -> Result<ExitAction, Error> + Send + 'static
+    {
+        let (kicker_tx, kicker_rx) = mpsc::channel();
+        let thread_gate = Arc::clone(&gate);
+        gate.enter();
+        let spawned = thread::Builder::new().name(format!("vcpu-{}", self.id)).spawn(move || {
+            let kicker = VcpuKicker::current(Arc::clone(&self.vm), self.id);
+            // The receiver only goes away if spawn() has already returned
+            let _ = kicker_tx.send(kicker.clone());
+
+            let id = self.id;
+            let result = panic::catch_unwind(AssertUnwindSafe(|| -> Result<(), Error> {
+                loop {
+                    thread_gate.checkpoint();
+                    let exit = match kicker.take_pending()? {
+                        true => VmExit::Interrupted,
+                        // A kick that lands after the check suspends the
+                        // VCPU, so VM_RUN returns without entering the guest
+                        false => match self.vm.run(self.id)? {
+                            VmExit::Debug if kicker.take_pending()? => VmExit::Interrupted,
+                            exit => exit,
+                        },
+                    };
+                    if let VmExit::Interrupted = exit {
+                        kicker.work().run_pending(&self.vm, self.id)?;
+                    }
+                    if handler(&self, exit)? == ExitAction::Stop {
+                        return Ok(());
+                    }
+                }
+            }));
+            thread_gate.leave();
+
+            let report = match result {
+                Ok(status) => VcpuReport::Exited(id, status),
+                Err(payload) => VcpuReport::Panicked(id, panic_message(payload)),
+            };
+            // Nothing to report to if the supervisor has gone away
+            let _ = supervisor.send(report);
+        });
+        let handle = match spawned {
+            Ok(handle) => handle,
+            Err(e) => {
+                gate.leave();
+                return Err(e);
+            }
+        };
+
+        match kicker_rx.recv() {
+            Ok(kicker) => Ok(VcpuThread { handle: handle, kicker: kicker }),
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "VCPU thread exited during startup")),
+        }
+    }
+}
+
+// Coordinates pausing the run loops of a group of VCPU threads. Each run
+// loop calls checkpoint() before entering the guest, and parks there while
+// the gate is closed.
+struct PauseGate {
+    state: Mutex<GateState>,
+    changed: Condvar,
+}
+
+struct GateState {
+    paused: bool,
+    running: usize, // run loops that haven't ended
+    parked: usize,  // run loops waiting at the checkpoint
+}
+
+impl PauseGate {
+    fn new() -> PauseGate {
+        PauseGate {
+            state: Mutex::new(GateState { paused: false, running: 0, parked: 0 }),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn enter(&self) {
+        self.state.lock().unwrap().running += 1;
+    }
+
+    fn leave(&self) {
+        self.state.lock().unwrap().running -= 1;
+        self.changed.notify_all();
+    }
+
+    fn checkpoint(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.paused {
+            return;
+        }
+        state.parked += 1;
+        self.changed.notify_all();
+        while state.paused {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.parked -= 1;
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().paused = true;
+    }
+
+    // Blocks until every running loop is parked at its checkpoint.
+    fn wait_parked(&self) {
+        let mut state = self.state.lock().unwrap();
+        while state.parked < state.running {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    fn open(&self) {
+        self.state.lock().unwrap().paused = false;
+        self.changed.notify_all();
+    }
+}
+
+/// The VCPU threads of a virtual machine, which can be paused and resumed
+/// as a group.
+pub struct VcpuSet {
+    vm: Arc<VirtualMachine>,
+    threads: Vec<(i32, VcpuThread)>,
+    gate: Arc<PauseGate>,
+}
+
+impl VcpuSet {
+    /// Creates an empty set of VCPU threads for 'vm'.
+    pub fn new(vm: Arc<VirtualMachine>) -> VcpuSet {
+        VcpuSet {
+            vm: vm,
+            threads: Vec::new(),
+            gate: Arc::new(PauseGate::new()),
+        }
+    }
+
+    /// Spawns a thread running the VCPU identified by 'vcpu_id', as with
+    /// `Vcpu::spawn()`, and adds it to the set.
+    pub fn spawn<F>(&mut self, vcpu_id: i32, supervisor: Sender<VcpuReport>, handler: F) -> io::Result<()>
+        where F: FnMut(&Vcpu, VmExit) -> Result<ExitAction, Error> + Send + 'static
+    {
+        let vcpu = Vcpu::new(Arc::clone(&self.vm), vcpu_id);
+        let thread = vcpu.spawn_gated(Arc::clone(&self.gate), supervisor, handler)?;
+        self.threads.push((vcpu_id, thread));
+        Ok(())
+    }
+
+    /// Returns the IDs of the VCPUs in the set.
+    pub fn vcpu_ids(&self) -> Vec<i32> {
+        self.threads.iter().map(|(id, _)| *id).collect()
+    }
+
+    /// Queues 'work' to run on the thread of VCPU 'vcpu_id', as with
+    /// `VcpuKicker::post()`. Returns `ENOENT` if the VCPU isn't in the set.
+    pub fn post<F>(&self, vcpu_id: i32, work: F) -> Result<(), Error>
+        where F: FnOnce(&VirtualMachine, i32) -> Result<(), Error> + Send + 'static
+    {
+        match self.threads.iter().find(|(id, _)| *id == vcpu_id) {
+            Some((_, thread)) => thread.kicker.post(work),
+            None => Err(Error::new(ENOENT)),
+        }
+    }
+
+    /// Returns the VCPUs in the set as a `CpuSet`.
+    pub fn cpus(&self) -> CpuSet {
+        self.threads.iter().map(|(id, _)| *id).collect()
+    }
+
+    /// Suspends every VCPU in the set and waits until each of their threads
+    /// has left VM_RUN and parked. When this returns, no VCPU in the set is
+    /// executing guest code or handling an exit, and each stays suspended
+    /// in the kernel, so device state, and guest mappings with
+    /// `set_region_protection()`, can be changed safely until
+    /// `resume_all()` is called.
+    pub fn pause_all(&self) -> Result<(), Error> {
+        self.gate.close();
+        for (_, thread) in self.threads.iter() {
+            // Suspended by the set rather than the kick, so the thread
+            // leaves the VCPU suspended when it takes the kick
+            thread.kicker.suspend_held()?;
+            // Threads are only joined by join(), which consumes the set, so
+            // their thread IDs are still valid here
+            thread.kicker.kick()?;
+        }
+        self.gate.wait_parked();
+        self.vm.events().emit(VmEvent::Paused);
+        Ok(())
+    }
+
+    /// Resumes every VCPU in the set after `pause_all()`.
+    pub fn resume_all(&self) -> Result<(), Error> {
+        for (id, _) in self.threads.iter() {
+            self.vm.resume_vcpu(*id)?;
+        }
+        self.gate.open();
+        self.vm.events().emit(VmEvent::Resumed);
+        Ok(())
+    }
+
+    /// Waits for every VCPU thread in the set to end.
+    pub fn join(self) {
+        for (_, thread) in self.threads {
+            let _ = thread.handle.join();
+        }
+    }
+}
+
+// Whether the deadline timer of a `run_until()` has suspended the VCPU.
+#[derive(Debug, Default)]
+struct Expiry {
+    finished: bool,
+    fired: bool,
+}
+
+// Reports the debug exit caused by a deadline suspending the VCPU as an
+// interrupted run. Any other exit happened before the suspension took
+// effect, and is returned as it is.
+fn deadline_exit(exit: VmExit, fired: bool) -> VmExit {
+    match exit {
+        VmExit::Debug if fired => VmExit::Interrupted,
+        exit => exit,
+    }
+}
+
+// Extracts the message from a panic payload, which is a &str or a String
+// for panics raised with a message.
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        return s.to_string();
+    }
+    match payload.downcast::<String>() {
+        Ok(s) => *s,
+        Err(_) => String::from("unknown panic payload"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pause_gate() {
+        let gate = Arc::new(PauseGate::new());
+        let (tx, rx) = mpsc::channel();
+
+        // A loop started behind a closed gate parks before doing any work
+        gate.close();
+        gate.enter();
+        let thread_gate = Arc::clone(&gate);
+        let handle = thread::spawn(move || {
+            thread_gate.checkpoint();
+            tx.send(()).unwrap();
+            thread_gate.leave();
+        });
+        gate.wait_parked();
+        assert!(rx.try_recv().is_err());
+
+        gate.open();
+        rx.recv().unwrap();
+        handle.join().unwrap();
+
+        // A gate with no running loops has nothing to wait for
+        gate.close();
+        gate.wait_parked();
+    }
+
+    #[test]
+    fn test_deadline_exit() {
+        match deadline_exit(VmExit::Debug, true) {
+            VmExit::Interrupted => (),
+            other => panic!("expected an interrupted run, got {:?}", other),
+        }
+        match deadline_exit(VmExit::Debug, false) {
+            VmExit::Debug => (),
+            other => panic!("expected a debug exit, got {:?}", other),
+        }
+        match deadline_exit(VmExit::Halt(0x2, 0), true) {
+            VmExit::Halt(..) => (),
+            other => panic!("expected a halt exit, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,506 @@
+//! Virtio split virtqueues.
+//!
+//! A virtio device exchanges buffers with its driver through virtqueues in
+//! guest memory. In the split layout each queue has three parts: a table of
+//! buffer descriptors, an available ring where the driver offers chains of
+//! descriptors to the device, and a used ring where the device returns them.
+//! `Queue` holds the device side of one queue, reading and writing the
+//! rings through `QueueMemory`, and implements both ways of suppressing
+//! interrupts and notifications: the ring flags, and the event indexes of
+//! `VIRTIO_F_EVENT_IDX`. Devices normally use the VM's `GuestMemory`, which
+//! reaches the rings through the host mappings of the guest memory regions.
+//!
+//!     use bhyve_api::virtio::*;
+//!     use bhyve_api::vm::VirtualMachine;
+//!
+//!     // Completes every buffer the driver has made available, and returns
+//!     // true if the driver should be interrupted.
+//!     fn process(vm: &VirtualMachine, queue: &mut Queue) -> Result<bool, bhyve_api::Error> {
+//!         let mem = vm.memory();
+//!         while let Some(chain) = queue.pop(mem)? {
+//!             let written = chain.writable_len();
+//!             queue.add_used(mem, chain.head(), written)?;
+//!         }
+//!         queue.needs_interrupt(mem)
+//!     }
+
+use libc::EINVAL;
+use std::sync::atomic::{fence, Ordering};
+
+use crate::bytes::{self, FromBytes};
+use crate::memory::GuestMemory;
+use crate::vm::VirtualMachine;
+use crate::Error;
+
+/// Largest number of entries a split virtqueue can have.
+pub const QUEUE_MAX_SIZE: u16 = 32768;
+
+/// The descriptor continues in the descriptor at 'next'.
+pub const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+/// The buffer is written by the device, rather than read.
+pub const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+/// The buffer holds a table of indirect descriptors.
+pub const VIRTQ_DESC_F_INDIRECT: u16 = 0x4;
+
+// The driver doesn't want interrupts (available ring flags)
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 0x1;
+// The device doesn't want notifications (used ring flags)
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 0x1;
+
+const DESC_SIZE: u64 = 16;
+const USED_ELEM_SIZE: u64 = 8;
+
+/// Guest memory that virtqueues and their buffers are read from and written
+/// to.
+pub trait QueueMemory {
+    /// Reads guest physical memory at 'gpa' into 'buf'.
+    fn read_bytes(&self, gpa: u64, buf: &mut [u8]) -> Result<(), Error>;
+
+    /// Writes 'buf' to guest physical memory at 'gpa'.
+    fn write_bytes(&self, gpa: u64, buf: &[u8]) -> Result<(), Error>;
+
+    /// Reads a value of type 'T' at 'gpa', in the guest's byte order.
+    fn read_obj<T: FromBytes>(&self, gpa: u64) -> Result<T, Error> {
+        // Safe because any bytes make a valid FromBytes value
+        let mut value: T = unsafe { std::mem::zeroed() };
+        self.read_bytes(gpa, bytes::as_mut_bytes(&mut value))?;
+        Ok(value.to_native())
+    }
+
+    /// Writes 'value' at 'gpa', in the guest's byte order.
+    fn write_obj<T: FromBytes>(&self, gpa: u64, value: &T) -> Result<(), Error> {
+        let value = value.to_le();
+        self.write_bytes(gpa, bytes::as_bytes(&value))
+    }
+}
+
+impl QueueMemory for GuestMemory {
+    fn read_bytes(&self, gpa: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.read_slice(gpa, buf)
+    }
+
+    fn write_bytes(&self, gpa: u64, buf: &[u8]) -> Result<(), Error> {
+        self.write_slice(gpa, buf)
+    }
+
+    fn read_obj<T: FromBytes>(&self, gpa: u64) -> Result<T, Error> {
+        GuestMemory::read_obj(self, gpa)
+    }
+
+    fn write_obj<T: FromBytes>(&self, gpa: u64, value: &T) -> Result<(), Error> {
+        GuestMemory::write_obj(self, gpa, *value)
+    }
+}
+
+impl QueueMemory for VirtualMachine {
+    fn read_bytes(&self, gpa: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.read_guest_memory(gpa, buf)
+    }
+
+    fn write_bytes(&self, gpa: u64, buf: &[u8]) -> Result<(), Error> {
+        self.write_guest_memory(gpa, buf)
+    }
+}
+
+/// A buffer descriptor, as in the descriptor table.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Descriptor {
+    /// Guest physical address of the buffer.
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+impl Descriptor {
+    fn read<M: QueueMemory>(mem: &M, table: u64, index: u16) -> Result<Descriptor, Error> {
+        // Read the whole descriptor at once, and split it up here
+        let mut raw = [0u8; DESC_SIZE as usize];
+        mem.read_bytes(table + index as u64 * DESC_SIZE, &mut raw)?;
+        let mut addr = [0; 8];
+        addr.copy_from_slice(&raw[0..8]);
+        Ok(Descriptor {
+            addr: u64::from_le_bytes(addr),
+            len: u32::from_le_bytes([raw[8], raw[9], raw[10], raw[11]]),
+            flags: u16::from_le_bytes([raw[12], raw[13]]),
+            next: u16::from_le_bytes([raw[14], raw[15]]),
+        })
+    }
+
+    /// Returns true if the device writes the buffer.
+    pub fn is_write_only(&self) -> bool {
+        (self.flags & VIRTQ_DESC_F_WRITE) != 0
+    }
+}
+
+/// A chain of buffers made available by the driver, with any indirect
+/// descriptors already followed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DescriptorChain {
+    head: u16,
+    descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    /// Returns the index of the first descriptor, which identifies the chain
+    /// when it is returned with `Queue::add_used()`.
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    /// Returns the descriptors of the chain, in order.
+    pub fn descriptors(&self) -> &[Descriptor] {
+        &self.descriptors
+    }
+
+    /// Returns the buffers the device reads.
+    pub fn readable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors.iter().filter(|desc| !desc.is_write_only())
+    }
+
+    /// Returns the buffers the device writes.
+    pub fn writable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors.iter().filter(|desc| desc.is_write_only())
+    }
+
+    /// Returns the total length of the buffers the device reads.
+    pub fn readable_len(&self) -> u32 {
+        self.readable().fold(0u32, |len, desc| len.saturating_add(desc.len))
+    }
+
+    /// Returns the total length of the buffers the device writes.
+    pub fn writable_len(&self) -> u32 {
+        self.writable().fold(0u32, |len, desc| len.saturating_add(desc.len))
+    }
+}
+
+/// The device side of a split virtqueue.
+#[derive(Debug, Clone)]
+pub struct Queue {
+    max_size: u16,
+    size: u16,
+    desc_table: u64,
+    avail_ring: u64,
+    used_ring: u64,
+    ready: bool,
+    event_idx: bool,
+    next_avail: u16,
+    next_used: u16,
+    // The used index when the driver was last interrupted
+    signalled_used: Option<u16>,
+    // The driver made a malformed chain available
+    broken: bool,
+}
+
+impl Queue {
+    /// Creates a queue of up to 'max_size' entries, which must be a power of
+    /// two no larger than `QUEUE_MAX_SIZE`. The queue starts out at its
+    /// maximum size, and isn't ready until the driver sets it up.
+    pub fn new(max_size: u16) -> Result<Queue, Error> {
+        if !max_size.is_power_of_two() || max_size > QUEUE_MAX_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(Queue::with_max_size(max_size))
+    }
+
+    fn with_max_size(max_size: u16) -> Queue {
+        Queue {
+            max_size: max_size,
+            size: max_size,
+            desc_table: 0,
+            avail_ring: 0,
+            used_ring: 0,
+            ready: false,
+            event_idx: false,
+            next_avail: 0,
+            next_used: 0,
+            signalled_used: None,
+            broken: false,
+        }
+    }
+
+    /// Returns the largest size the driver can set.
+    pub fn max_size(&self) -> u16 {
+        self.max_size
+    }
+
+    /// Returns the number of entries in the queue.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Sets the number of entries, as chosen by the driver.
+    pub fn set_size(&mut self, size: u16) {
+        self.size = size;
+    }
+
+    /// Sets the guest physical addresses of the descriptor table, the
+    /// available ring, and the used ring.
+    pub fn set_addresses(&mut self, desc_table: u64, avail_ring: u64, used_ring: u64) {
+        self.desc_table = desc_table;
+        self.avail_ring = avail_ring;
+        self.used_ring = used_ring;
+    }
+
+    /// Enables or disables use of the event index fields, as negotiated
+    /// with the `VIRTIO_F_EVENT_IDX` feature.
+    pub fn set_event_idx(&mut self, enabled: bool) {
+        self.event_idx = enabled;
+    }
+
+    /// Marks the queue ready for use, or not. Returns `EINVAL` if the driver
+    /// set an invalid size or misaligned ring addresses.
+    pub fn set_ready(&mut self, ready: bool) -> Result<(), Error> {
+        if ready && !self.is_valid() {
+            return Err(Error::new(EINVAL));
+        }
+        self.ready = ready;
+        Ok(())
+    }
+
+    /// Returns true if the driver has set up the queue.
+    pub fn ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Returns true if the driver made a malformed chain available, after
+    /// which the queue is unusable until it is reset, and the device should
+    /// set `DEVICE_NEEDS_RESET` in its status.
+    pub fn needs_reset(&self) -> bool {
+        self.broken
+    }
+
+    /// Returns the queue to its state before the driver set it up.
+    pub fn reset(&mut self) {
+        *self = Queue::with_max_size(self.max_size);
+    }
+
+    fn is_valid(&self) -> bool {
+        self.size > 0 && self.size <= self.max_size && self.size.is_power_of_two()
+            && self.desc_table & 0xf == 0 && self.avail_ring & 0x1 == 0 && self.used_ring & 0x3 == 0
+    }
+
+    // Addresses of the fields of the rings
+    fn avail_idx_addr(&self) -> u64 {
+        self.avail_ring + 2
+    }
+
+    fn avail_entry_addr(&self, index: u16) -> u64 {
+        self.avail_ring + 4 + (index % self.size) as u64 * 2
+    }
+
+    fn used_event_addr(&self) -> u64 {
+        self.avail_ring + 4 + self.size as u64 * 2
+    }
+
+    fn used_idx_addr(&self) -> u64 {
+        self.used_ring + 2
+    }
+
+    fn used_entry_addr(&self, index: u16) -> u64 {
+        self.used_ring + 4 + (index % self.size) as u64 * USED_ELEM_SIZE
+    }
+
+    fn avail_event_addr(&self) -> u64 {
+        self.used_ring + 4 + self.size as u64 * USED_ELEM_SIZE
+    }
+
+    /// Takes the next chain of descriptors the driver has made available,
+    /// or returns 'None' if there are none, or the queue isn't ready.
+    ///
+    /// Returns `EINVAL` for a malformed chain, such as one that loops or
+    /// refers to descriptors outside the table, or the error reading a
+    /// descriptor outside guest memory. The chain is consumed, and
+    /// the queue marked as needing a reset, so it returns 'None' from then
+    /// on rather than failing on the same chain again.
+    pub fn pop<M: QueueMemory>(&mut self, mem: &M) -> Result<Option<DescriptorChain>, Error> {
+        if !self.ready || self.broken {
+            return Ok(None);
+        }
+        let avail_idx: u16 = mem.read_obj(self.avail_idx_addr())?;
+        if avail_idx == self.next_avail {
+            return Ok(None);
+        }
+        // The ring entry must be read after the index that covers it
+        fence(Ordering::Acquire);
+        let head: u16 = mem.read_obj(self.avail_entry_addr(self.next_avail))?;
+        let descriptors = self.read_chain(mem, head);
+        self.next_avail = self.next_avail.wrapping_add(1);
+        let descriptors = match descriptors {
+            Ok(descriptors) => descriptors,
+            Err(e) => {
+                self.broken = true;
+                return Err(e);
+            }
+        };
+        if self.event_idx {
+            // Ask to be notified as soon as the driver adds another chain
+            mem.write_obj(self.avail_event_addr(), &self.next_avail)?;
+        }
+        Ok(Some(DescriptorChain { head: head, descriptors: descriptors }))
+    }
+
+    fn read_chain<M: QueueMemory>(&self, mem: &M, head: u16) -> Result<Vec<Descriptor>, Error> {
+        let mut descriptors = Vec::new();
+        let (mut table, mut table_size, mut index) = (self.desc_table, self.size as u32, head);
+        let mut indirect = false;
+        loop {
+            // A chain can't be longer than its table without looping
+            if index as u32 >= table_size || descriptors.len() as u32 >= table_size + self.size as u32 {
+                return Err(Error::new(EINVAL));
+            }
+            let desc = Descriptor::read(mem, table, index)?;
+            if (desc.flags & VIRTQ_DESC_F_INDIRECT) != 0 {
+                // An indirect table replaces the rest of the chain, and
+                // can't itself be indirect
+                if indirect || desc.len == 0 || (desc.len as u64 & (DESC_SIZE - 1)) != 0 {
+                    return Err(Error::new(EINVAL));
+                }
+                indirect = true;
+                table = desc.addr;
+                table_size = (desc.len as u64 / DESC_SIZE) as u32;
+                index = 0;
+                continue;
+            }
+            descriptors.push(desc);
+            if (desc.flags & VIRTQ_DESC_F_NEXT) == 0 {
+                return Ok(descriptors);
+            }
+            index = desc.next;
+        }
+    }
+
+    /// Returns the chain with head 'head' to the driver, with 'len' bytes
+    /// written to its buffers.
+    pub fn add_used<M: QueueMemory>(&mut self, mem: &M, head: u16, len: u32) -> Result<(), Error> {
+        let entry = self.used_entry_addr(self.next_used);
+        mem.write_obj(entry, &(head as u32))?;
+        mem.write_obj(entry + 4, &len)?;
+        self.next_used = self.next_used.wrapping_add(1);
+        // The driver must see the entry before the index that covers it
+        fence(Ordering::Release);
+        mem.write_obj(self.used_idx_addr(), &self.next_used)
+    }
+
+    /// Returns true if the driver should be interrupted for the chains
+    /// returned since the last interrupt, and records that it was.
+    pub fn needs_interrupt<M: QueueMemory>(&mut self, mem: &M) -> Result<bool, Error> {
+        // The used index must be visible before the driver's request is read
+        fence(Ordering::SeqCst);
+        let old = self.signalled_used.replace(self.next_used);
+        if !self.event_idx {
+            let flags: u16 = mem.read_obj(self.avail_ring)?;
+            return Ok((flags & VIRTQ_AVAIL_F_NO_INTERRUPT) == 0);
+        }
+        let used_event: u16 = mem.read_obj(self.used_event_addr())?;
+        match old {
+            Some(old) => Ok(need_event(used_event, self.next_used, old)),
+            None => Ok(true),
+        }
+    }
+
+    /// Asks the driver to notify the device when it makes chains available,
+    /// or not to. The driver may notify anyway, so a device that disables
+    /// notifications should still check the queue on each one it gets.
+    pub fn set_notifications<M: QueueMemory>(&mut self, mem: &M, enabled: bool) -> Result<(), Error> {
+        if self.event_idx {
+            // Notifications can't be turned off with event indexes, only
+            // put off until the ring moves on
+            if enabled {
+                mem.write_obj(self.avail_event_addr(), &self.next_avail)?;
+            }
+        } else {
+            let flags: u16 = if enabled { 0 } else { VIRTQ_USED_F_NO_NOTIFY };
+            mem.write_obj(self.used_ring, &flags)?;
+        }
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+// Returns true if moving an index from 'old' to 'new' passed 'event', the
+// value the other side asked to be told about (vring_need_event()).
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    struct TestMemory(RefCell<Vec<u8>>);
+
+    impl QueueMemory for TestMemory {
+        fn read_bytes(&self, gpa: u64, buf: &mut [u8]) -> Result<(), Error> {
+            let mem = self.0.borrow();
+            buf.copy_from_slice(&mem[gpa as usize..gpa as usize + buf.len()]);
+            Ok(())
+        }
+
+        fn write_bytes(&self, gpa: u64, buf: &[u8]) -> Result<(), Error> {
+            self.0.borrow_mut()[gpa as usize..gpa as usize + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_queue() {
+        let mem = TestMemory(RefCell::new(vec![0; 0x1000]));
+        let (desc, avail, used) = (0x000, 0x400, 0x800);
+        let mut queue = Queue::new(16).unwrap();
+        queue.set_size(4);
+        queue.set_addresses(desc, avail, used);
+        queue.set_ready(true).unwrap();
+        assert_eq!(queue.pop(&mem).unwrap(), None);
+
+        // A readable buffer chained to an indirect table with one writable
+        // buffer
+        let write_desc = |table: u64, index: u64, desc: Descriptor| {
+            let gpa = table + index * DESC_SIZE;
+            mem.write_obj(gpa, &desc.addr).unwrap();
+            mem.write_obj(gpa + 8, &desc.len).unwrap();
+            mem.write_obj(gpa + 12, &desc.flags).unwrap();
+            mem.write_obj(gpa + 14, &desc.next).unwrap();
+        };
+        let readable = Descriptor { addr: 0x100, len: 8, flags: VIRTQ_DESC_F_NEXT, next: 3 };
+        let writable = Descriptor { addr: 0x200, len: 64, flags: VIRTQ_DESC_F_WRITE, next: 0 };
+        write_desc(desc, 2, readable);
+        write_desc(desc, 3, Descriptor { addr: 0xc00, len: 16, flags: VIRTQ_DESC_F_INDIRECT, next: 0 });
+        write_desc(0xc00, 0, writable);
+        mem.write_obj(avail + 4, &2u16).unwrap();
+        mem.write_obj(avail + 2, &1u16).unwrap();
+
+        let chain = queue.pop(&mem).unwrap().unwrap();
+        assert_eq!(chain.head(), 2);
+        assert_eq!(chain.descriptors(), &[readable, writable]);
+        assert_eq!((chain.readable_len(), chain.writable_len()), (8, 64));
+        assert_eq!(queue.pop(&mem).unwrap(), None);
+
+        queue.add_used(&mem, chain.head(), 5).unwrap();
+        assert_eq!(mem.read_obj::<u16>(used + 2).unwrap(), 1);
+        assert_eq!(mem.read_obj::<[u32; 2]>(used + 4).unwrap(), [2, 5]);
+        assert!(queue.needs_interrupt(&mem).unwrap());
+        mem.write_obj(avail, &VIRTQ_AVAIL_F_NO_INTERRUPT).unwrap();
+        assert!(!queue.needs_interrupt(&mem).unwrap());
+
+        // A chain starting outside the table is rejected, and consumed
+        mem.write_obj(avail + 6, &7u16).unwrap();
+        mem.write_obj(avail + 2, &2u16).unwrap();
+        assert!(queue.pop(&mem).is_err());
+        assert!(queue.needs_reset());
+        assert_eq!(queue.pop(&mem).unwrap(), None);
+        queue.reset();
+        assert!(!queue.needs_reset());
+    }
+
+    #[test]
+    fn test_need_event() {
+        // The driver asked to be interrupted once entry 5 is used
+        assert!(need_event(5, 6, 4));
+        assert!(!need_event(5, 5, 4));
+        assert!(!need_event(5, 8, 6));
+        // Across the wrap of the index
+        assert!(need_event(0xffff, 1, 0xfffe));
+    }
+}
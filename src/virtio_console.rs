@@ -0,0 +1,487 @@
+//! A virtio console on PCI.
+//!
+//! `VirtioConsole` is a minimal virtio 1.0 console with a single port, built
+//! from the `pci`, `msix`, and `virtio` modules, and serves as a reference
+//! for device models built on them. Output the guest writes to the console
+//! goes to a host `Write`, and input is passed to the guest with
+//! `receive()`.
+//!
+//! The device uses the virtio PCI transport with its registers in BAR 0,
+//! and interrupts the guest only through MSI-X, in BAR 1. It has no INTx
+//! pin, so drivers that can't use MSI-X get no interrupts.
+//!
+//!     use bhyve_api::pci::PciHostBridge;
+//!     use bhyve_api::virtio_console::VirtioConsole;
+//!     use bhyve_api::vm::VirtualMachine;
+//!     use std::sync::{Arc, Mutex};
+//!
+//!     fn add_console(vm: Arc<VirtualMachine>, bridge: &mut PciHostBridge) -> Result<Arc<Mutex<VirtioConsole>>, bhyve_api::Error> {
+//!         let console = Arc::new(Mutex::new(VirtioConsole::new(vm, Box::new(std::io::stdout()))?));
+//!         bridge.add_device(4, console.clone())?;
+//!         Ok(console)
+//!     }
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::device::{GuestDevice, IoValue};
+use crate::msix::Msix;
+use crate::pci::{Bar, BarKind, PciConfig, PciDevice};
+use crate::virtio::Queue;
+use crate::vm::VirtualMachine;
+use crate::Error;
+
+/// PCI vendor ID of virtio devices.
+pub const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+/// PCI device ID of a virtio 1.0 console.
+pub const VIRTIO_CONSOLE_DEVICE_ID: u16 = 0x1043;
+
+/// Size of the receive and transmit queues.
+pub const CONSOLE_QUEUE_SIZE: u16 = 64;
+/// Most input bytes held for the guest before `receive()` accepts no more.
+pub const CONSOLE_INPUT_MAX: usize = 4096;
+
+// Feature bits
+const VIRTIO_CONSOLE_F_SIZE: u64 = 1 << 0;
+const VIRTIO_RING_F_EVENT_IDX: u64 = 1 << 29;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const DEVICE_FEATURES: u64 = VIRTIO_CONSOLE_F_SIZE | VIRTIO_RING_F_EVENT_IDX | VIRTIO_F_VERSION_1;
+
+// Device status bits
+const STATUS_DRIVER_OK: u8 = 0x04;
+const STATUS_FEATURES_OK: u8 = 0x08;
+const STATUS_NEEDS_RESET: u8 = 0x40;
+
+// ISR status bits
+const ISR_QUEUE: u8 = 0x1;
+const ISR_CONFIG: u8 = 0x2;
+
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+const RECEIVE_QUEUE: usize = 0;
+const TRANSMIT_QUEUE: usize = 1;
+const NUM_QUEUES: usize = 2;
+// One MSI-X vector for configuration changes, and one per queue
+const NUM_VECTORS: u16 = 3;
+
+// Layout of BAR 0, one structure per page
+const REGS_BAR: u8 = 0;
+const REGS_BAR_SIZE: u64 = 0x4000;
+const COMMON_CFG: u64 = 0x0000;
+const COMMON_CFG_LEN: u64 = 0x38;
+const ISR_CFG: u64 = 0x1000;
+const DEVICE_CFG: u64 = 0x2000;
+const DEVICE_CFG_LEN: u64 = 12;
+const NOTIFY_CFG: u64 = 0x3000;
+const NOTIFY_OFF_MULTIPLIER: u32 = 4;
+
+// Layout of BAR 1
+const MSIX_BAR: u8 = 1;
+const MSIX_BAR_SIZE: u64 = 0x1000;
+const MSIX_PBA: u64 = 0x800;
+
+// Virtio PCI capability types
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+const PCI_CAP_ID_VENDOR: u8 = 0x09;
+
+// Largest buffer copied from the guest in one piece
+const MAX_BUFFER: u32 = 0x10000;
+
+/// A virtio console with one port, on PCI.
+pub struct VirtioConsole {
+    vm: Arc<VirtualMachine>,
+    config: PciConfig,
+    msix: Msix,
+    queues: Vec<Queue>,
+    // Ring addresses set by the driver, applied when a queue is enabled
+    queue_addrs: [[u64; 3]; NUM_QUEUES],
+    queue_vectors: [u16; NUM_QUEUES],
+    config_vector: u16,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    driver_features: u64,
+    status: u8,
+    queue_select: u16,
+    isr: u8,
+    size: (u16, u16),
+    input: VecDeque<u8>,
+    output: Box<dyn Write + Send>,
+}
+
+impl VirtioConsole {
+    /// Creates a console for 'vm', writing guest output to 'output'.
+    pub fn new(vm: Arc<VirtualMachine>, output: Box<dyn Write + Send>) -> Result<VirtioConsole, Error> {
+        let mut config = PciConfig::new(VIRTIO_PCI_VENDOR_ID, VIRTIO_CONSOLE_DEVICE_ID, 0x07, 0x80, 0x00);
+        config.set_u8(0x08, 1); // revision 1 for virtio 1.0 devices
+        config.set_subsystem(VIRTIO_PCI_VENDOR_ID, 0x0003);
+        config.add_bar(REGS_BAR as usize, Bar { kind: BarKind::Mmio32, size: REGS_BAR_SIZE, prefetchable: false })?;
+        config.add_bar(MSIX_BAR as usize, Bar { kind: BarKind::Mmio32, size: MSIX_BAR_SIZE, prefetchable: false })?;
+        add_virtio_cap(&mut config, VIRTIO_PCI_CAP_COMMON_CFG, COMMON_CFG, COMMON_CFG_LEN, None)?;
+        add_virtio_cap(&mut config, VIRTIO_PCI_CAP_NOTIFY_CFG, NOTIFY_CFG, NUM_QUEUES as u64 * NOTIFY_OFF_MULTIPLIER as u64,
+                       Some(NOTIFY_OFF_MULTIPLIER))?;
+        add_virtio_cap(&mut config, VIRTIO_PCI_CAP_ISR_CFG, ISR_CFG, 1, None)?;
+        add_virtio_cap(&mut config, VIRTIO_PCI_CAP_DEVICE_CFG, DEVICE_CFG, DEVICE_CFG_LEN, None)?;
+
+        let mut msix = Msix::new(Arc::clone(&vm), NUM_VECTORS)?;
+        msix.add_capability(&mut config, MSIX_BAR, 0, MSIX_BAR, MSIX_PBA as u32)?;
+
+        Ok(VirtioConsole {
+            vm: vm,
+            config: config,
+            msix: msix,
+            queues: vec![Queue::new(CONSOLE_QUEUE_SIZE)?, Queue::new(CONSOLE_QUEUE_SIZE)?],
+            queue_addrs: [[0; 3]; NUM_QUEUES],
+            queue_vectors: [VIRTIO_MSI_NO_VECTOR; NUM_QUEUES],
+            config_vector: VIRTIO_MSI_NO_VECTOR,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            driver_features: 0,
+            status: 0,
+            queue_select: 0,
+            isr: 0,
+            size: (80, 25),
+            input: VecDeque::new(),
+            output: output,
+        })
+    }
+
+    /// Passes 'data' to the guest as console input, and returns the number
+    /// of bytes accepted. Input is held until the driver provides buffers
+    /// for it, up to `CONSOLE_INPUT_MAX` bytes.
+    pub fn receive(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let accepted = std::cmp::min(data.len(), CONSOLE_INPUT_MAX - self.input.len());
+        self.input.extend(&data[..accepted]);
+        self.flush_input()?;
+        Ok(accepted)
+    }
+
+    /// Sets the console size reported to the guest, and notifies the driver
+    /// of the change.
+    pub fn set_size(&mut self, cols: u16, rows: u16) -> Result<(), Error> {
+        self.size = (cols, rows);
+        self.isr |= ISR_CONFIG;
+        self.interrupt(self.config_vector)
+    }
+
+    /// Returns true once the driver has finished setting up the device.
+    pub fn driver_ok(&self) -> bool {
+        (self.status & STATUS_DRIVER_OK) != 0
+    }
+
+    // Copies held input into the buffers the driver has made available.
+    fn flush_input(&mut self) -> Result<(), Error> {
+        let queue = &mut self.queues[RECEIVE_QUEUE];
+        let mut used = false;
+        while !self.input.is_empty() {
+            let chain = match queue.pop(self.vm.memory())? {
+                Some(chain) => chain,
+                None => break,
+            };
+            let mut written = 0;
+            for desc in chain.writable() {
+                let count = std::cmp::min(desc.len as usize, self.input.len());
+                let bytes: Vec<u8> = self.input.drain(..count).collect();
+                self.vm.memory().write_slice(desc.addr, &bytes)?;
+                written += count as u32;
+            }
+            queue.add_used(self.vm.memory(), chain.head(), written)?;
+            used = true;
+        }
+        if used && queue.needs_interrupt(self.vm.memory())? {
+            return self.queue_interrupt(RECEIVE_QUEUE);
+        }
+        Ok(())
+    }
+
+    // Writes out the buffers the driver has queued for transmission.
+    fn transmit(&mut self) -> Result<(), Error> {
+        let queue = &mut self.queues[TRANSMIT_QUEUE];
+        let mut used = false;
+        while let Some(chain) = queue.pop(self.vm.memory())? {
+            for desc in chain.readable() {
+                let mut buf = vec![0; std::cmp::min(desc.len, MAX_BUFFER) as usize];
+                self.vm.memory().read_slice(desc.addr, &mut buf)?;
+                // Output that can't be written is dropped, as on a
+                // disconnected serial line
+                let _ = self.output.write_all(&buf);
+            }
+            queue.add_used(self.vm.memory(), chain.head(), 0)?;
+            used = true;
+        }
+        let _ = self.output.flush();
+        if used && queue.needs_interrupt(self.vm.memory())? {
+            return self.queue_interrupt(TRANSMIT_QUEUE);
+        }
+        Ok(())
+    }
+
+    fn queue_interrupt(&mut self, queue: usize) -> Result<(), Error> {
+        self.isr |= ISR_QUEUE;
+        self.interrupt(self.queue_vectors[queue])
+    }
+
+    fn interrupt(&mut self, vector: u16) -> Result<(), Error> {
+        if vector == VIRTIO_MSI_NO_VECTOR {
+            return Ok(());
+        }
+        self.msix.notify(vector).map(|_| ())
+    }
+
+    fn reset_device(&mut self) {
+        for queue in self.queues.iter_mut() {
+            queue.reset();
+        }
+        self.queue_addrs = [[0; 3]; NUM_QUEUES];
+        self.queue_vectors = [VIRTIO_MSI_NO_VECTOR; NUM_QUEUES];
+        self.config_vector = VIRTIO_MSI_NO_VECTOR;
+        self.device_feature_select = 0;
+        self.driver_feature_select = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.queue_select = 0;
+        self.isr = 0;
+        self.input.clear();
+    }
+
+    fn selected_queue(&self) -> Option<usize> {
+        let index = self.queue_select as usize;
+        if index < NUM_QUEUES {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    // The common configuration structure, as the driver sees it.
+    fn common_cfg(&self) -> Vec<u8> {
+        let mut cfg = Vec::with_capacity(COMMON_CFG_LEN as usize);
+        let features = match self.device_feature_select {
+            0 => DEVICE_FEATURES as u32,
+            1 => (DEVICE_FEATURES >> 32) as u32,
+            _ => 0,
+        };
+        let driver_features = match self.driver_feature_select {
+            0 => self.driver_features as u32,
+            1 => (self.driver_features >> 32) as u32,
+            _ => 0,
+        };
+        cfg.extend_from_slice(&self.device_feature_select.to_le_bytes());
+        cfg.extend_from_slice(&features.to_le_bytes());
+        cfg.extend_from_slice(&self.driver_feature_select.to_le_bytes());
+        cfg.extend_from_slice(&driver_features.to_le_bytes());
+        cfg.extend_from_slice(&self.config_vector.to_le_bytes());
+        cfg.extend_from_slice(&(NUM_QUEUES as u16).to_le_bytes());
+        cfg.push(self.status);
+        cfg.push(0); // config generation
+        cfg.extend_from_slice(&self.queue_select.to_le_bytes());
+        match self.queues.get(self.queue_select as usize) {
+            Some(queue) => {
+                let index = self.queue_select as usize;
+                cfg.extend_from_slice(&queue.size().to_le_bytes());
+                cfg.extend_from_slice(&self.queue_vectors[index].to_le_bytes());
+                cfg.extend_from_slice(&(queue.ready() as u16).to_le_bytes());
+                cfg.extend_from_slice(&(index as u16).to_le_bytes()); // notify offset
+                for addr in self.queue_addrs[index].iter() {
+                    cfg.extend_from_slice(&addr.to_le_bytes());
+                }
+            }
+            None => cfg.resize(COMMON_CFG_LEN as usize, 0),
+        }
+        cfg
+    }
+
+    fn common_write(&mut self, offset: u64, size: u8, value: u64) {
+        match offset {
+            0x00 => self.device_feature_select = value as u32,
+            0x08 => self.driver_feature_select = value as u32,
+            0x0c => {
+                match self.driver_feature_select {
+                    0 => self.driver_features = (self.driver_features & !0xffff_ffff) | (value & 0xffff_ffff),
+                    1 => self.driver_features = (self.driver_features & 0xffff_ffff) | (value << 32),
+                    _ => {}
+                }
+            }
+            0x10 => self.config_vector = self.checked_vector(value as u16),
+            0x14 => self.write_status(value as u8),
+            0x16 => self.queue_select = value as u16,
+            0x18 => {
+                if let Some(index) = self.selected_queue() {
+                    if !self.queues[index].ready() {
+                        self.queues[index].set_size(value as u16);
+                    }
+                }
+            }
+            0x1a => {
+                let vector = self.checked_vector(value as u16);
+                if let Some(index) = self.selected_queue() {
+                    self.queue_vectors[index] = vector;
+                }
+            }
+            0x1c => {
+                if let Some(index) = self.selected_queue() {
+                    if value == 1 {
+                        let [desc, driver, device] = self.queue_addrs[index];
+                        self.queues[index].set_addresses(desc, driver, device);
+                        self.queues[index].set_event_idx((self.driver_features & VIRTIO_RING_F_EVENT_IDX) != 0);
+                        if self.queues[index].set_ready(true).is_err() {
+                            self.status |= STATUS_NEEDS_RESET;
+                        }
+                    }
+                }
+            }
+            0x20..=0x37 => {
+                if let Some(index) = self.selected_queue() {
+                    let addr = &mut self.queue_addrs[index][((offset - 0x20) / 8) as usize];
+                    match (size, offset % 8) {
+                        (8, 0) => *addr = value,
+                        (4, 0) => *addr = (*addr & !0xffff_ffff) | (value & 0xffff_ffff),
+                        (4, 4) => *addr = (*addr & 0xffff_ffff) | (value << 32),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Returns 'vector' if it is a valid MSI-X vector, and the value that
+    // tells the driver it isn't otherwise.
+    fn checked_vector(&self, vector: u16) -> u16 {
+        if vector < self.msix.vectors() {
+            vector
+        } else {
+            VIRTIO_MSI_NO_VECTOR
+        }
+    }
+
+    fn write_status(&mut self, status: u8) {
+        if status == 0 {
+            self.reset_device();
+            return;
+        }
+        let mut status = status;
+        if (status & STATUS_FEATURES_OK) != 0 && (self.status & STATUS_FEATURES_OK) == 0 {
+            // Only features the device offered, including virtio 1.0, can be
+            // accepted
+            let features = self.driver_features;
+            if (features & !DEVICE_FEATURES) != 0 || (features & VIRTIO_F_VERSION_1) == 0 {
+                status &= !STATUS_FEATURES_OK;
+            }
+        }
+        self.status = status | (self.status & STATUS_NEEDS_RESET);
+    }
+
+    fn device_cfg(&self) -> Vec<u8> {
+        let mut cfg = Vec::with_capacity(DEVICE_CFG_LEN as usize);
+        cfg.extend_from_slice(&self.size.0.to_le_bytes());
+        cfg.extend_from_slice(&self.size.1.to_le_bytes());
+        cfg.extend_from_slice(&1u32.to_le_bytes()); // max_nr_ports
+        cfg.extend_from_slice(&0u32.to_le_bytes()); // emerg_wr
+        cfg
+    }
+
+    fn regs_read(&mut self, offset: u64, size: u8) -> u64 {
+        let (bytes, base) = match offset {
+            COMMON_CFG..=0x0fff => (self.common_cfg(), COMMON_CFG),
+            ISR_CFG => {
+                // Reading the ISR status acknowledges it
+                let isr = self.isr;
+                self.isr = 0;
+                return isr as u64;
+            }
+            DEVICE_CFG..=0x2fff => (self.device_cfg(), DEVICE_CFG),
+            _ => return 0,
+        };
+        read_le(&bytes, (offset - base) as usize, size)
+    }
+
+    fn regs_write(&mut self, offset: u64, size: u8, value: u64) {
+        match offset {
+            COMMON_CFG..=0x0fff => self.common_write(offset - COMMON_CFG, size, value),
+            NOTIFY_CFG..=0x3fff => {
+                // Errors accessing guest memory leave the device needing a
+                // reset, since the driver has corrupted the queue
+                let result = match (offset - NOTIFY_CFG) / NOTIFY_OFF_MULTIPLIER as u64 {
+                    0 => self.flush_input(),
+                    1 => self.transmit(),
+                    _ => Ok(()),
+                };
+                if result.is_err() {
+                    self.status |= STATUS_NEEDS_RESET;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Adds a virtio PCI capability describing the structure at 'offset' in BAR
+// 0, with the notify offset multiplier for the notification structure.
+fn add_virtio_cap(config: &mut PciConfig, cfg_type: u8, offset: u64, len: u64, multiplier: Option<u32>) -> Result<(), Error> {
+    let cap_len = if multiplier.is_some() { 20 } else { 16 };
+    let cap = config.add_capability(PCI_CAP_ID_VENDOR, cap_len)?;
+    config.set_u8(cap + 2, cap_len as u8);
+    config.set_u8(cap + 3, cfg_type);
+    config.set_u8(cap + 4, REGS_BAR);
+    config.set_u32(cap + 8, offset as u32);
+    config.set_u32(cap + 12, len as u32);
+    if let Some(multiplier) = multiplier {
+        config.set_u32(cap + 16, multiplier);
+    }
+    Ok(())
+}
+
+// Reads a little-endian value of 'size' bytes at 'offset' in 'bytes', with
+// bytes past the end reading as zero.
+fn read_le(bytes: &[u8], offset: usize, size: u8) -> u64 {
+    let mut value = 0;
+    for i in (0..size as usize).rev() {
+        value = (value << 8) | bytes.get(offset + i).cloned().unwrap_or(0) as u64;
+    }
+    value
+}
+
+impl GuestDevice for VirtioConsole {
+    fn reset(&mut self) {
+        self.reset_device();
+        self.msix.reset();
+    }
+}
+
+impl PciDevice for VirtioConsole {
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+
+    fn config_write(&mut self, offset: u16, value: IoValue) {
+        self.config.write(offset, value);
+        let _ = self.msix.config_updated(&self.config);
+    }
+
+    fn bar_read(&mut self, bar: usize, offset: u64, size: u8) -> u64 {
+        match bar as u8 {
+            REGS_BAR => self.regs_read(offset, size),
+            MSIX_BAR if offset < MSIX_PBA => self.msix.table_read(offset, size),
+            MSIX_BAR => self.msix.pba_read(offset - MSIX_PBA, size),
+            _ => 0,
+        }
+    }
+
+    fn bar_write(&mut self, bar: usize, offset: u64, size: u8, value: u64) {
+        match bar as u8 {
+            REGS_BAR => self.regs_write(offset, size, value),
+            MSIX_BAR if offset < MSIX_PBA => {
+                let _ = self.msix.table_write(offset, size, value);
+            }
+            _ => {}
+        }
+    }
+}
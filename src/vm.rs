@@ -1,14 +1,43 @@
 //! Bhyve virtual machine operations.
 
-use libc::{ioctl, open, O_RDWR, c_void, sysconf, _SC_PAGESIZE, EINVAL, EFAULT};
-use std::ffi::{CString, CStr};
+use libc::{ioctl, open, O_RDWR, O_CLOEXEC, c_void, sysconf, _SC_PAGESIZE, EBUSY, EINVAL, EFAULT, ENOENT, EINTR, EAGAIN, ENOTSUP};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::ffi::CString;
 use std::fs::File;
-use std::os::unix::io::{AsRawFd, FromRawFd};
-
-pub use crate::include::vmm::{vm_cap_type, vm_reg_name};
-use crate::include::vmm::{vm_suspend_how, vm_exitcode, x2apic_state, seg_desc};
+use std::io::Read;
+use std::mem::size_of;
+use std::ops::BitOr;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+pub use crate::include::vmm::{vm_cap_type, vm_reg_name, vm_exitcode};
+pub use crate::include::vmm::{vm_cpu_mode, vm_paging_mode, vm_guest_paging, task_switch_reason};
+use crate::include::vmm::{vm_suspend_how, x2apic_state, vm_intr_trigger, seg_desc, vm_exit, vm_inout, VM_MAXCPU, VM_MAX_MEMMAPS};
 use crate::include::vmm_dev::*;
-use crate::include::specialreg::{CR0_NE};
+use crate::include::cstring;
+use crate::include::specialreg::{CR0_NE, CR0_PE, CR0_PG, CR4_PAE, EFER_LMA, EFER_LME};
+use crate::balloon::BalloonState;
+use crate::bytes::{self, FromBytes};
+use crate::capability::{CapType, CapValue, Capability};
+use crate::cpuset::{CpuSet, CPUSET_WORDS};
+use crate::dump::VcpuDump;
+use crate::features::KernelFeatures;
+use crate::hpet::HpetConfig;
+use crate::lifecycle::{EventStream, VmEvent};
+use crate::log::{LogLevel, LogSink};
+use crate::memory::{GuestMemory, HostMapping};
+use crate::pci_passthru::PptLimits;
+use crate::policy::{PauseExits, PausePolicy};
+use crate::portio::merge_rax;
+use crate::scatter::{self, GuestSegment};
+use crate::stats::BalloonStats;
+use crate::trace::{InstructionStepper, MtrapTrace};
+use crate::vcpu::Vcpu;
+use crate::volatile::VolatileSlice;
 use crate::Error;
 
 const MB: u64 = 1024 * 1024;
@@ -16,10 +45,59 @@ const GB: u64 = 1024 * MB;
 
 const MAX_BOOTROM_SIZE: usize = 16 * MB as usize;
 
+// Direction flag in RFLAGS, from machine/psl.h
+const PSL_D: u64 = 0x00000400;
+
+// Bits of a segment's access rights, as reported by the kernel.
+const SEG_ACCESS_EXPAND_DOWN: u32 = 1 << 2;
+const SEG_ACCESS_CODE: u32 = 1 << 3;
+const SEG_ACCESS_DB: u32 = 1 << 14;
+
+// Stack fault and general protection exception vectors.
+const IDT_SS: i32 = 12;
+const IDT_GP: i32 = 13;
+
+// Guest physical address ranges claimed by the in-kernel interrupt
+// controllers and timers, which must not be shadowed by guest mappings.
+const RESERVED_MMIO: [(&str, u64, u64); 3] = [
+    ("I/O APIC", 0xfec00000, 0x1000),
+    ("HPET", 0xfed00000, 0x400),
+    ("local APIC", 0xfee00000, MB),
+];
+
 // Size of the guard region before and after the virtual address space
 // mapping the guest physical memory. This must be a multiple of the
 // superpage size for performance reasons.
-//const VM_MMAP_GUARD_SIZE: usize = 4 * MB as usize;
+pub(crate) const VM_MMAP_GUARD_SIZE: usize = 4 * MB as usize;
+
+/// Options controlling how a virtual machine device is opened.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct VmOptions {
+    /// The kernel's VMM interface version, as reported by
+    /// `VMMSystem::interface_version()`. The device isn't opened if the
+    /// version doesn't match the one this library was written for. 'None'
+    /// skips the check, as for kernels that predate interface versioning.
+    pub interface_version: Option<i32>,
+    /// Leave the filehandle open across exec(), so child processes inherit
+    /// access to the virtual machine. By default it is closed on exec.
+    pub inheritable: bool,
+}
+
+/// Instrumentation callbacks invoked by `VirtualMachine::run()` around each
+/// entry into the guest, for profilers and schedulers that need to account
+/// for VCPU time without wrapping every call to `run()`.
+///
+/// The callbacks run on the VCPU thread in the hot path, so they should be
+/// cheap and must not block.
+pub trait RunHooks: Send + Sync {
+    /// Called just before the VCPU identified by 'vcpu_id' enters the guest.
+    fn before_entry(&self, _vcpu_id: i32) {}
+
+    /// Called when VM_RUN returns, with the exit code ('None' if VM_RUN
+    /// failed or was interrupted without an exit), the time VM_RUN was
+    /// entered, and the time spent in it.
+    fn after_exit(&self, _vcpu_id: i32, _exitcode: Option<vm_exitcode>, _entered: Instant, _elapsed: Duration) {}
+}
 
 /// The VirtualMachine module handles Bhyve virtual machine operations.
 /// It owns the filehandle for these operations.
@@ -27,7 +105,19 @@ pub struct VirtualMachine {
     vm: File,
     pub name: String,
     pub lowmem_limit: usize,
-    pub memflags: i32,
+    memflags: AtomicI32, // MemFlags bits applied to new mappings
+    features: KernelFeatures,
+    exit_counts: Vec<AtomicU64>, // VM_MAXCPU rows of NUM_EXITCODES counters
+    exit_times: Vec<AtomicI64>, // per VCPU, gethrtime() at the last exit
+    clocks: Vec<RunClock>, // per VCPU, time spent in and out of VM_RUN
+    run_hooks: RwLock<Option<Arc<dyn RunHooks>>>,
+    log_sink: RwLock<Option<Arc<dyn LogSink>>>,
+    memory: Arc<GuestMemory>,
+    active_vcpus: Mutex<BTreeSet<i32>>, // VCPUs activated through this handle
+    capabilities: Mutex<Vec<(i32, vm_cap_type, i32)>>, // last value set, per VCPU and capability
+    events: EventStream,
+    suspend_reported: AtomicBool, // a Suspended event was sent since the last reinit
+    balloon: Mutex<BalloonState>,
 }
 
 impl VirtualMachine {
@@ -37,70 +127,178 @@ impl VirtualMachine {
     /// `VirtualMachine`.
 
     pub fn new(name: &str) -> Result<VirtualMachine, Error> {
+        VirtualMachine::with_options(name, VmOptions::default())
+    }
+
+    /// Opens a filehandle to an existing virtual machine device by name,
+    /// applying the checks requested in 'options'.
+    ///
+    /// With 'interface_version' set, a kernel using a different interface
+    /// version is rejected with `Error::AbiMismatch` before the device is
+    /// opened. The version comes from the caller's `VMMSystem`, since
+    /// `/dev/vmmctl` can't be opened again while that handle is open:
+    ///
+    ///     use bhyve_api::system::VMMSystem;
+    ///     use bhyve_api::vm::{VirtualMachine, VmOptions};
+    ///
+    ///     fn open(system: &VMMSystem, name: &str) -> Result<VirtualMachine, bhyve_api::Error> {
+    ///         let options = VmOptions { interface_version: system.interface_version()?, ..VmOptions::default() };
+    ///         VirtualMachine::with_options(name, options)
+    ///     }
+    pub fn with_options(name: &str, options: VmOptions) -> Result<VirtualMachine, Error> {
+        check_interface_version(options.interface_version)?;
         let path = format!("/dev/vmm/{}", name);
         let c_path = match CString::new(path) {
             Ok(s) => s,
             Err(_) => return Err(Error::new(EINVAL))
         };
-        let raw_fd = unsafe { open(c_path.as_ptr(), O_RDWR) };
+        let flags = match options.inheritable {
+            true => O_RDWR,
+            false => O_RDWR | O_CLOEXEC,
+        };
+        let raw_fd = unsafe { open(c_path.as_ptr(), flags) };
         if raw_fd < 0 {
             return Err(Error::last());
         }
         let safe_handle = unsafe { File::from_raw_fd(raw_fd) };
 
+        let features = KernelFeatures::probe(safe_handle.as_raw_fd());
+
         // Return value is safe because raw file descriptor result is checked
         // and ownership of File struct is consumed by VirtualMachine struct.
         Ok(VirtualMachine {
             vm: safe_handle,
             name: name.to_string(),
             lowmem_limit: 3 * GB as usize,
-            memflags: 0,
+            memflags: AtomicI32::new(0),
+            features: features,
+            exit_counts: (0..VM_MAXCPU * NUM_EXITCODES).map(|_| AtomicU64::new(0)).collect(),
+            exit_times: (0..VM_MAXCPU).map(|_| AtomicI64::new(0)).collect(),
+            clocks: (0..VM_MAXCPU).map(|_| RunClock::new()).collect(),
+            run_hooks: RwLock::new(None),
+            log_sink: RwLock::new(None),
+            memory: Arc::new(GuestMemory::new()),
+            active_vcpus: Mutex::new(BTreeSet::new()),
+            capabilities: Mutex::new(Vec::new()),
+            events: EventStream::new(),
+            suspend_reported: AtomicBool::new(false),
+            balloon: Mutex::new(BalloonState::default()),
         })
     }
 
+    /// Returns the optional kernel interfaces detected when the virtual
+    /// machine device was opened.
+    pub fn features(&self) -> &KernelFeatures {
+        &self.features
+    }
+
+    /// Returns the stream of lifecycle events of the virtual machine: VCPU
+    /// activation, suspension seen by `run()`, panics reported through a
+    /// `PvPanic` device, and the pauses of `VcpuSet`s running it.
+    pub fn events(&self) -> &EventStream {
+        &self.events
+    }
+
+    /// Sets the flags applied to guest memory mappings created from now on,
+    /// by `mmap_memseg()` and the functions that set up guest memory with
+    /// it. Existing mappings keep the flags they were created with, as
+    /// reported by `memory_residency()`, including when they are removed
+    /// and created again by `set_region_protection()` or a copy of the VM.
+    pub fn set_memflags(&self, flags: MemFlags) {
+        self.memflags.store(flags.bits(), Ordering::SeqCst);
+    }
+
+    /// Returns the flags applied to new guest memory mappings.
+    pub fn memflags(&self) -> MemFlags {
+        MemFlags(self.memflags.load(Ordering::SeqCst))
+    }
+
     /// Map the memory segment identified by 'segid' into the guest address space
     /// at [gpa,gpa+len) with protection 'prot'.
     pub fn mmap_memseg(&self, gpa: u64, segid: i32, off: i64, len: usize, prot: i32) -> Result<bool, Error> {
-        let mut flags = 0;
-        if (self.memflags & VM_MEM_F_WIRED) != 0 {
-            flags = VM_MEMMAP_F_WIRED;
-        }
+        self.map_memseg(&self.new_mapping(gpa, segid, off, len, prot), true)
+    }
 
-        let mem_data = vm_memmap {
+    /// Maps [gpa,gpa+len) in the guest physical address space to the
+    /// memory segment 'segid' at offset 'off', as with `mmap_memseg()`, but
+    /// fails with `Error::AlreadyMapped` if any part of the range is already
+    /// mapped, even by an identical mapping.
+    pub fn mmap_memseg_exclusive(&self, gpa: u64, segid: i32, off: i64, len: usize, prot: i32) -> Result<bool, Error> {
+        self.map_memseg(&self.new_mapping(gpa, segid, off, len, prot), false)
+    }
+
+    // Returns a mapping with the flags set by set_memflags().
+    fn new_mapping(&self, gpa: u64, segid: i32, off: i64, len: usize, prot: i32) -> MemMap {
+        MemMap {
             gpa: gpa,
             segid: segid,
             segoff: off,
             len: len,
             prot: prot,
-            flags: flags,
-        };
+            flags: self.memflags().memmap_flags(),
+        }
+    }
 
-	// If this mapping already exists then don't create it again. This
-	// is the common case for SYSMEM mappings created by bhyveload(8).
-        match self.mmap_getnext(gpa) {
-            Ok(exists) => if exists.gpa == mem_data.gpa {
-                // A memory segment already exists at the same guest physical address
-                // we are trying to create.
-                if exists.segid == mem_data.segid && exists.segoff == mem_data.segoff &&
-                   exists.prot == mem_data.prot && exists.flags == mem_data.flags {
-                    // The existing memory segment is identical to the one we want to
-                    // create, so do nothing, and return a success value.
-                    return Ok(true);
-                } else {
-                    // The existing memory segment is not identical to the one we want
-                    // to create, so return an error value.
-                    return Err(Error::new(EFAULT));
-                }
-            }
-            Err(_) => (), // The memory segment wasn't found, so we should create it
+    // Creates 'map', keeping its own flags, so mappings that are removed and
+    // created again don't pick up flags set since. If 'reuse' is set, an
+    // existing mapping identical to the requested one is accepted as success.
+    fn map_memseg(&self, map: &MemMap, reuse: bool) -> Result<bool, Error> {
+        let mem_data = vm_memmap {
+            gpa: map.gpa,
+            segid: map.segid,
+            segoff: map.segoff,
+            len: map.len,
+            prot: map.prot,
+            flags: map.flags,
         };
 
+        // The kernel refuses to map over an existing mapping, so attempt the
+        // mapping first and only look for a conflict if it fails. Checking
+        // first would race with other processes mapping the same range.
         let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_MMAP_MEMSEG, &mem_data) };
         if result == 0 {
             return Ok(true);
-        } else {
-            return Err(Error::last());
         }
+        let err = Error::ioctl("VM_MMAP_MEMSEG", size_of::<vm_memmap>());
+
+        let existing = match self.find_mapping(map.gpa, map.len as u64)? {
+            Some(existing) => existing,
+            None => return Err(err),
+        };
+        // If this mapping already exists then don't create it again. This
+        // is the common case for SYSMEM mappings created by bhyveload(8).
+        if reuse && existing.gpa == mem_data.gpa && existing.len == mem_data.len &&
+           existing.segid == mem_data.segid && existing.segoff == mem_data.segoff &&
+           existing.prot == mem_data.prot && existing.flags == mem_data.flags {
+            return Ok(true);
+        }
+        Err(Error::AlreadyMapped(existing))
+    }
+
+    // Finds the first mapping that overlaps [gpa,gpa+len), if any.
+    fn find_mapping(&self, gpa: u64, len: u64) -> Result<Option<MemMap>, Error> {
+        // Mappings are returned in order of guest physical address, and one
+        // starting below 'gpa' may extend into the range, so start from 0.
+        for map in self.memory_maps() {
+            let map = map?;
+            if map.gpa >= gpa + len {
+                return Ok(None);
+            }
+            if map.overlaps(gpa, len) {
+                return Ok(Some(map));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns an iterator over the mappings of memory segments into the
+    /// guest physical address space, in order of address. This includes
+    /// mappings made by other processes, such as bhyveload(8), so tools can
+    /// see what is already mapped before adding to it. The mappings are
+    /// read from the kernel as the iterator advances; it stops after
+    /// yielding an error.
+    pub fn memory_maps(&self) -> MemMaps<'_> {
+        MemMaps { vm: self, next: Some(0) }
     }
 
     /// Iterate over the guest address space. This function finds an address range
@@ -119,12 +317,20 @@ impl VirtualMachine {
         if result == 0 {
             return Ok(memseg_data);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_MMAP_GETNEXT", size_of::<vm_memmap>()));
         }
     }
 
     /// Unmap the memory segment at the guest physical address range [gpa,gpa+len)
+    ///
+    /// Fails with `EBUSY` if part of the range is borrowed for DMA.
     pub fn munmap_memseg(&self, gpa: u64, len: usize) -> Result<bool, Error> {
+        let _dma = self.memory.lock_dma_free(gpa, len as u64)?;
+        self.unmap_memseg(gpa, len)
+    }
+
+    // Removes a guest mapping, for callers already holding the DMA lock.
+    fn unmap_memseg(&self, gpa: u64, len: usize) -> Result<bool, Error> {
         // Struct is allocated (and owned) by Rust
         let mem_data = vm_munmap {
             gpa: gpa,
@@ -135,16 +341,11 @@ impl VirtualMachine {
         if result == 0 {
             return Ok(true);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_MUNMAP_MEMSEG", size_of::<vm_munmap>()));
         }
     }
 
     pub fn alloc_memseg(&self, segid: i32, len: usize, name: &str) -> Result<bool, Error> {
-        let c_name = match CString::new(name) {
-            Ok(s) => s,
-            Err(_) => return Err(Error::new(EINVAL))
-        };
-
         // If the memory segment has already been created then just return.
         // This is the usual case for the SYSMEM segment created by userspace
         // loaders like bhyveload(8).
@@ -152,9 +353,7 @@ impl VirtualMachine {
             Ok(exists) => if exists.len != 0 {
                 // A memory segment already exists with the same segment ID as the one
                 // we are trying to allocate.
-                let r_name = unsafe { CStr::from_ptr(exists.name.as_ptr()) };
-                let exists_name = r_name.to_owned();
-                if exists.len == len && exists_name == c_name {
+                if exists.len == len && cstring::field_eq(&exists.name, name) {
                     // The existing memory segment is identical to the one we want to
                     // allocate, so do nothing, and return a success value.
                     return Ok(true);
@@ -173,26 +372,14 @@ impl VirtualMachine {
             len: len,
             ..Default::default()
         };
-
-        let name_length = name.len();
-        if name_length > 0 {
-            // Don't copy the name if the string is empty (zero length)
-            if name_length >= memseg_data.name.len() {
-                // name is too long for vm_memseg struct
-                return Err(Error::new(EINVAL));
-            } else {
-                // Copy each character from the CString to the char array
-                for (to, from) in memseg_data.name.iter_mut().zip(c_name.as_bytes_with_nul()) {
-                    *to = *from as i8;
-                }
-            }
-        }
+        // An empty name leaves the name field zeroed
+        cstring::copy_to_field(&mut memseg_data.name, name)?;
 
         let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_ALLOC_MEMSEG, &memseg_data) };
         if result == 0 {
             return Ok(true);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_ALLOC_MEMSEG", size_of::<vm_memseg>()));
         }
     }
 
@@ -207,7 +394,7 @@ impl VirtualMachine {
         if result == 0 {
             return Ok(memseg_data);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_GET_MEMSEG", size_of::<vm_memseg>()));
         }
     }
 
@@ -215,20 +402,8 @@ impl VirtualMachine {
         self.alloc_memseg(segid, len, name)?;
         let mapoff = self.get_devmem_offset(segid)?;
 
-//        let len2 = VM_MMAP_GUARD_SIZE + len + VM_MMAP_GUARD_SIZE;
-//        let base: *mut u8 = unsafe {
-//            libc::mmap(
-//                null_mut(),
-//                len2,
-//                libc::PROT_NONE,
-//                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
-//                -1,
-//                0,
-//            ) as *mut u8
-//        };
-
         // mmap the devmem region in the host address space
-        let _ptr: *mut u8 = unsafe {
+        let ptr = unsafe {
             libc::mmap(
                 base as *mut c_void,
                 len,
@@ -236,8 +411,11 @@ impl VirtualMachine {
                 libc::MAP_SHARED | libc::MAP_FIXED,
                 self.vm.as_raw_fd(),
                 mapoff,
-            ) as *mut u8
+            )
         };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::last());
+        }
         return Ok(true);
 
     }
@@ -285,805 +463,3159 @@ impl VirtualMachine {
         if result == 0 {
             return Ok(memseg_data.offset);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_DEVMEM_GETOFFSET", size_of::<vm_devmem_offset>()));
         }
     }
 
-    /// Sets up a memory segment for the bootrom
+    /// Sets up a memory segment for the bootrom, mapped so that it ends at
+    /// the 4GB boundary in the guest physical address space.
+    ///
+    /// Images that are not a multiple of the page size are padded up to the
+    /// next page boundary, so the host region at 'base' must be large enough
+    /// to hold the padded length.
     ///
     /// Returns Ok if successful, and an Error otherwise.
     pub fn setup_bootrom(&self, base: u64, len: usize) -> Result<bool, Error> {
+        self.setup_rom_parts(base, &BootromLayout::single(len))?;
+        Ok(true)
+    }
 
+    /// Sets up the bootrom with the parts and guest physical address given
+    /// by 'layout', such as a read-only firmware image below a writable
+    /// UEFI variable store, over a host mapping reserved by the library
+    /// between guard pages. Each part is padded to the page size, and
+    /// becomes a region in `regions()` with the part's name. The images can
+    /// be loaded through the returned `Bootrom`, which must be kept as long
+    /// as the VM is in use.
+    pub fn setup_bootrom_layout(&self, layout: &BootromLayout) -> Result<Bootrom, Error> {
         let page_size: usize = unsafe { sysconf(_SC_PAGESIZE) as usize };
-        // Limit bootrom size to 16MB so it doesn't encroach into reserved
-        // MMIO space (e.g. APIC, HPET, MSI).
-        if len > MAX_BOOTROM_SIZE || len < page_size {
-            return Err(Error::new(EINVAL));
+        let (_, len) = layout.placement(page_size)?;
+        let mut mapping = HostMapping::reserve(len)?;
+        let regions = self.setup_rom_parts(mapping.addr(), layout)?;
+        mapping.attach(&self.memory);
+        Ok(Bootrom { mapping: mapping, regions: regions })
+    }
+
+    // Sets up the bootrom segment with the parts of 'layout', backed by the
+    // host region at 'base', and returns the region of each part.
+    fn setup_rom_parts(&self, base: u64, layout: &BootromLayout) -> Result<Vec<GuestRegion>, Error> {
+        let page_size: usize = unsafe { sysconf(_SC_PAGESIZE) as usize };
+        let (gpa, len) = layout.placement(page_size)?;
+        check_reserved(gpa, len as u64)?;
+        let mut regions = Vec::with_capacity(layout.parts.len());
+        let mut offset = 0;
+        for part in layout.parts.iter() {
+            let part_len = (part.len + page_size - 1) & !(page_size - 1);
+            regions.push(GuestRegion {
+                name: part.name,
+                segid: MemSegId::VM_BOOTROM as i32,
+                segoff: offset as i64,
+                gpa: gpa + offset as u64,
+                len: part_len as u64,
+                prot: part.prot.prot(),
+                host_addr: base + offset as u64,
+            });
+            offset += part_len;
         }
-        // Map the bootrom into the host address space
-        self.add_devmem(MemSegId::VM_BOOTROM as i32, "bootrom", base, len)?;
 
-        // Map the bootrom into the guest address space
-	let prot = libc::PROT_READ | libc::PROT_EXEC;
-	let gpa: u64 = (1 << 32) - len as u64;
-	self.mmap_memseg(gpa, MemSegId::VM_BOOTROM as i32, 0, len, prot)?;
+        self.with_regions(&regions, || {
+            // Map the bootrom into the host address space
+            self.add_devmem(MemSegId::VM_BOOTROM as i32, "bootrom", base, len)?;
 
-        Ok(true)
+            // Map each part into the guest address space
+            for region in regions.iter() {
+                self.mmap_memseg(region.gpa, region.segid, region.segoff, region.len as usize, region.prot)?;
+            }
+            Ok(true)
+        })?;
+        Ok(regions)
     }
 
+    /// Sets up the guest memory below 4GB, mapped at guest physical address
+    /// 0 and backed by the host region at 'base'. Returns `EINVAL` if 'len'
+    /// is over `lowmem_limit`, and `Error::ReservedRange` if a raised limit
+    /// lets it reach the ranges reserved for in-kernel devices.
     pub fn setup_lowmem(&self, base: u64, len: usize) -> Result<bool, Error> {
         if len > self.lowmem_limit {
             return Err(Error::new(EINVAL));
         }
+        // A 'lowmem_limit' raised past the PCI hole reaches the I/O APIC
+        check_reserved(0, len as u64)?;
 
 	let gpa: u64 = 0;
         let readonly = false;
+        let region = GuestRegion {
+            name: "lowmem",
+            segid: MemSegId::VM_LOWMEM as i32,
+            segoff: 0,
+            gpa: gpa,
+            len: len as u64,
+            prot: libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+            host_addr: base,
+        };
         // Map the guest memory into the host address space
-        self.add_guest_memory(MemSegId::VM_LOWMEM as i32, gpa, base, len, readonly)?;
-
-        Ok(true)
+        self.with_region(region, || {
+            self.add_guest_memory(MemSegId::VM_LOWMEM as i32, gpa, base, len, readonly)
+        })
     }
 
+    /// Sets up the guest memory above 4GB, backed by the host region at
+    /// 'base'.
     pub fn setup_highmem(&self, base: u64, len: usize) -> Result<bool, Error> {
 	let gpa: u64 = 4 * GB;
+        check_reserved(gpa, len as u64)?;
         let readonly = false;
+        let region = GuestRegion {
+            name: "highmem",
+            segid: MemSegId::VM_HIGHMEM as i32,
+            segoff: 0,
+            gpa: gpa,
+            len: len as u64,
+            prot: libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+            host_addr: base,
+        };
         // Map the guest memory into the host address space
-        self.add_guest_memory(MemSegId::VM_HIGHMEM as i32, gpa, base, len, readonly)?;
-
-        Ok(true)
+        self.with_region(region, || {
+            self.add_guest_memory(MemSegId::VM_HIGHMEM as i32, gpa, base, len, readonly)
+        })
     }
 
-    /// Set the base, limit, and access values of a descriptor register on the VCPU
-    pub fn set_desc(&self, vcpu_id: i32, reg: vm_reg_name, base: u64, limit: u32, access: u32) -> Result<bool, Error> {
-        // Struct is allocated (and owned) by Rust
-        let seg_data = vm_seg_desc {
-            cpuid: vcpu_id,
-            regnum: reg as i32,
-            desc: seg_desc {base: base, limit: limit, access: access},
-        };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SET_SEGMENT_DESCRIPTOR, &seg_data) };
-        if result == 0 {
-            return Ok(true);
-        } else {
-            return Err(Error::last());
+    /// Sets up a framebuffer memory segment of 'len' bytes, mapped at 'gpa'
+    /// in the guest physical address space, usually the address of a PCI
+    /// BAR, and at 'base' in the host address space.
+    ///
+    /// Returns Ok if successful, and an Error otherwise.
+    pub fn setup_framebuffer(&self, gpa: u64, base: u64, len: usize) -> Result<bool, Error> {
+        if len == 0 {
+            return Err(Error::new(EINVAL));
         }
+        check_reserved(gpa, len as u64)?;
+        let region = GuestRegion {
+            name: "framebuffer",
+            segid: MemSegId::VM_FRAMEBUFFER as i32,
+            segoff: 0,
+            gpa: gpa,
+            len: len as u64,
+            prot: libc::PROT_READ | libc::PROT_WRITE,
+            host_addr: base,
+        };
+
+        self.with_region(region, || {
+            self.add_devmem(MemSegId::VM_FRAMEBUFFER as i32, "framebuffer", base, len)?;
+            self.mmap_memseg(gpa, MemSegId::VM_FRAMEBUFFER as i32, 0, len, region.prot)
+        })
     }
 
-    /// Get the base, limit, and access values of a descriptor register on the VCPU
-    pub fn get_desc(&self, vcpu_id: i32, reg: vm_reg_name) -> Result<(u64, u32, u32), Error> {
-        // Struct is allocated (and owned) by Rust, but modified by C
-        let mut seg_data = vm_seg_desc {
-            cpuid: vcpu_id,
-            regnum: reg as i32,
-            ..Default::default()
-        };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_SEGMENT_DESCRIPTOR, &mut seg_data) };
-        if result == 0 {
-            return Ok((seg_data.desc.base, seg_data.desc.limit, seg_data.desc.access));
-        } else {
-            return Err(Error::last());
+    /// Sets up the bootrom as `setup_bootrom()` does, over a host mapping
+    /// reserved by the library between guard pages, rather than at an
+    /// address chosen by the caller. The image can be copied in through the
+    /// returned mapping, which must be kept as long as the VM is in use;
+    /// dropping it removes the bootrom from `regions()`.
+    pub fn setup_bootrom_mapped(&self, len: usize) -> Result<HostMapping, Error> {
+        let page_size: usize = unsafe { sysconf(_SC_PAGESIZE) as usize };
+        let padded_len = (len + page_size - 1) & !(page_size - 1);
+        if len == 0 || padded_len > MAX_BOOTROM_SIZE {
+            return Err(Error::BootromSize { len: len, max: MAX_BOOTROM_SIZE });
         }
+        let mut mapping = HostMapping::reserve(padded_len)?;
+        self.setup_bootrom(mapping.addr(), len)?;
+        mapping.attach(&self.memory);
+        Ok(mapping)
     }
 
-    /// Set the value of a single register on the VCPU
-    pub fn set_register(&self, vcpu_id: i32, reg: vm_reg_name, val: u64) -> Result<bool, Error> {
-        // Struct is allocated (and owned) by Rust
-        let reg_data = vm_register {
-            cpuid: vcpu_id,
-            regnum: reg as i32,
-            regval: val,
-        };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SET_REGISTER, &reg_data) };
-        if result == 0 {
-            return Ok(true);
-        } else {
-            return Err(Error::last());
+    /// Sets up the bootrom from the firmware image at 'path', such as an
+    /// OVMF-style `.fd` file, as `setup_bootrom_mapped()` does, and copies
+    /// the image into it. Images must be a whole number of pages, or
+    /// `EINVAL` is returned, and no larger than the bootrom limit of 16MB,
+    /// or `Error::BootromSize`. The returned mapping must be kept as long as
+    /// the VM is in use.
+    pub fn load_bootrom_file(&self, path: &Path) -> Result<HostMapping, Error> {
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as u64 };
+        let file = File::open(path)?;
+        let len = check_bootrom_file_len(file.metadata()?.len(), page_size)?;
+        let mut image = Vec::with_capacity(len);
+        file.take(len as u64).read_to_end(&mut image)?;
+        // The file was truncated after it was checked
+        if image.len() != len {
+            return Err(Error::new(EINVAL));
         }
+        let bootrom = self.setup_bootrom_mapped(len)?;
+        bootrom.slice().copy_from(&image);
+        Ok(bootrom)
     }
 
-    /// Get the value of a single register on the VCPU
-    pub fn get_register(&self, vcpu_id: i32, reg: vm_reg_name) -> Result<u64, Error> {
-        // Struct is allocated (and owned) by Rust, but modified by C
-        let mut reg_data = vm_register {
-            cpuid: vcpu_id,
-            regnum: reg as i32,
-            ..Default::default()
-        };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_REGISTER, &mut reg_data) };
-        if result == 0 {
-            return Ok(reg_data.regval);
-        } else {
-            return Err(Error::last());
-        }
+    /// Sets up the guest memory below 4GB as `setup_lowmem()` does, over a
+    /// host mapping reserved by the library between guard pages. The
+    /// returned mapping must be kept as long as the VM is in use; dropping
+    /// it removes lowmem from `regions()`.
+    pub fn setup_lowmem_mapped(&self, len: usize) -> Result<HostMapping, Error> {
+        let mut mapping = HostMapping::reserve(len)?;
+        self.setup_lowmem(mapping.addr(), len)?;
+        mapping.attach(&self.memory);
+        Ok(mapping)
     }
 
-    pub fn rtc_write(&self, offset: i32, value: u8) -> Result<bool, Error> {
-        // Struct is allocated (and owned) by Rust
-        let rtc_data = vm_rtc_data {
-            offset: offset,
-            value: value,
-        };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_RTC_WRITE, &rtc_data) };
-        if result == 0 {
-            return Ok(true);
-        } else {
-            return Err(Error::last());
-        }
+    /// Sets up the guest memory above 4GB as `setup_highmem()` does, over a
+    /// host mapping reserved by the library between guard pages. The
+    /// returned mapping must be kept as long as the VM is in use; dropping
+    /// it removes highmem from `regions()`.
+    pub fn setup_highmem_mapped(&self, len: usize) -> Result<HostMapping, Error> {
+        let mut mapping = HostMapping::reserve(len)?;
+        self.setup_highmem(mapping.addr(), len)?;
+        mapping.attach(&self.memory);
+        Ok(mapping)
     }
 
-    pub fn rtc_read(&self, offset: i32) -> Result<u8, Error> {
-        // Struct is allocated (and owned) by Rust, but modified by C
-        let mut rtc_data = vm_rtc_data {
-            offset: offset,
-            ..Default::default()
-        };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_RTC_READ, &mut rtc_data) };
-        if result == 0 {
-            return Ok(rtc_data.value);
-        } else {
-            return Err(Error::last());
+    /// Returns the guest memory regions set up through this handle, in the
+    /// order they were set up.
+    pub fn regions(&self) -> Vec<GuestRegion> {
+        self.memory.regions()
+    }
+
+    /// Returns the guest memory of the virtual machine, for device emulation.
+    pub fn memory(&self) -> &GuestMemory {
+        &self.memory
+    }
+
+    /// Tears down the guest memory set up through this handle, for control
+    /// planes that keep running after a VM is gone. The host mapping of
+    /// each region in a `HostMapping` from `setup_lowmem_mapped()` or its
+    /// siblings is replaced by an inaccessible reservation, releasing the
+    /// VM's memory, while the address range stays reserved for the
+    /// `HostMapping` to unmap. Host memory supplied by the caller, such as
+    /// a `GuestBacking`, is left alone, for the caller to unmap. The regions
+    /// are then forgotten, so they can be set up again.
+    ///
+    /// With 'unmap_guest' set, the guest mappings of the regions, and any
+    /// mappings they were split into, are removed as well, which needs
+    /// `KernelFeatures::munmap_memseg`, or `ENOTSUP` is returned.
+    ///
+    /// Fails with `EBUSY` if any guest memory is borrowed for DMA. This is
+    /// done on drop, without unmapping the guest.
+    pub fn teardown_memory(&self, unmap_guest: bool) -> Result<(), Error> {
+        if unmap_guest {
+            if !self.features.munmap_memseg {
+                return Err(Error::new(ENOTSUP));
+            }
+            // The region list is read before the DMA lock is taken, as
+            // borrow_dma() takes them in that order. The lock is held
+            // across the unmaps, so no borrow can start in the meantime.
+            let regions = self.regions();
+            let _dma = self.memory.lock_dma_free(0, !0)?;
+            for region in regions.iter().rev() {
+                while let Some(map) = self.find_mapping(region.gpa, region.len)? {
+                    self.unmap_memseg(map.gpa, map.len)?;
+                }
+            }
+            // The ballooned ranges went with the rest of the mappings
+            self.balloon.lock().unwrap().take();
         }
+        self.memory.teardown()
     }
 
-    pub fn rtc_settime(&self, secs: i64) -> Result<bool, Error> {
-        // Struct is allocated (and owned) by Rust
-        let rtc_data = vm_rtc_time {
-            secs: secs,
+    // Claims the guest physical range of 'region', failing if it overlaps
+    // a region that was already set up, then runs 'setup'. The claim is
+    // released again if 'setup' fails. Setting up an identical region again
+    // is allowed, so setup can be retried.
+    fn with_region<F>(&self, region: GuestRegion, setup: F) -> Result<bool, Error>
+        where F: FnOnce() -> Result<bool, Error>
+    {
+        self.with_regions(&[region], setup)
+    }
+
+    // Claims the guest physical ranges of several regions at once, as
+    // `with_region()` does for one. Either all of them are claimed, or none.
+    fn with_regions<F>(&self, new_regions: &[GuestRegion], setup: F) -> Result<bool, Error>
+        where F: FnOnce() -> Result<bool, Error>
+    {
+        let claimed: Vec<GuestRegion> = {
+            let mut regions = self.memory.lock_regions();
+            let mut claimed = Vec::new();
+            for region in new_regions.iter() {
+                match find_overlap(&regions, region) {
+                    Some(existing) if existing == *region => (),
+                    Some(existing) => {
+                        return Err(Error::RegionOverlap { gpa: region.gpa, len: region.len, region: existing });
+                    }
+                    None => claimed.push(*region),
+                }
+            }
+            regions.extend(claimed.iter().cloned());
+            claimed
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_RTC_SETTIME, &rtc_data) };
-        if result == 0 {
-            return Ok(true);
-        } else {
-            return Err(Error::last());
+
+        let result = setup();
+        if result.is_err() && !claimed.is_empty() {
+            self.memory.lock_regions().retain(|r| !claimed.contains(r));
         }
+        result
     }
 
-    pub fn rtc_gettime(&self) -> Result<i64, Error> {
-        // Struct is allocated (and owned) by Rust, but modified by C
-        let mut rtc_data = vm_rtc_time::default();
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_RTC_GETTIME, &mut rtc_data) };
-        if result == 0 {
-            return Ok(rtc_data.secs);
-        } else {
-            return Err(Error::last());
+    /// Reads guest physical memory starting at 'gpa' into 'buf'. A range
+    /// within one of the regions in `regions()` is read through its host
+    /// mapping; anything else through a temporary read-only mapping of the
+    /// guest's system memory, which fails with `ENXIO` if the range isn't
+    /// backed by guest memory.
+    pub fn read_guest_memory(&self, gpa: u64, buf: &mut [u8]) -> Result<(), Error> {
+        if self.memory.covers(gpa, buf.len() as u64, libc::PROT_READ) {
+            return self.memory.read_slice(gpa, buf);
         }
+        self.with_guest_mapping(gpa, buf.len(), libc::PROT_READ, |src| {
+            // Safe because the mapping covers [gpa,gpa+buf.len())
+            unsafe { std::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        })
     }
 
-    /// Sets basic attributes of CPUs on the VirtualMachine: sockets, cores,
-    /// and threads.
-    pub fn set_topology(&self, sockets: u16, cores: u16, threads: u16) -> Result<bool, Error> {
-        // Struct is allocated (and owned) by Rust
-        let top_data = vm_cpu_topology {
-            sockets: sockets,
-            cores: cores,
-            threads: threads,
-            maxcpus: 0, // any other value is invalid
-        };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SET_TOPOLOGY, &top_data) };
-        if result == 0 {
-            return Ok(true);
-        } else {
-            return Err(Error::last());
+    /// Writes 'buf' to guest physical memory starting at 'gpa', through the
+    /// host mapping of the region holding it, or a temporary mapping of the
+    /// guest's system memory, as `read_guest_memory()` reads it.
+    pub fn write_guest_memory(&self, gpa: u64, buf: &[u8]) -> Result<(), Error> {
+        if self.memory.covers(gpa, buf.len() as u64, libc::PROT_WRITE) {
+            return self.memory.write_slice(gpa, buf);
         }
+        self.with_guest_mapping(gpa, buf.len(), libc::PROT_READ | libc::PROT_WRITE, |dst| {
+            // Safe because the mapping covers [gpa,gpa+buf.len()), and is
+            // writable.
+            unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), dst, buf.len()) };
+        })
     }
 
-    /// Gets current settings for CPUs on the VirtualMachine: sockets, cores,
-    /// threads, and maximum number of CPUs.
-    pub fn get_topology(&self) -> Result<(u16, u16, u16, u16), Error> {
+    /// Gets the paging state of the VCPU from its control registers, for
+    /// translating guest linear addresses.
+    pub fn guest_paging(&self, vcpu_id: i32) -> Result<vm_guest_paging, Error> {
+        let cr0 = self.get_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CR0)?;
+        let cr3 = self.get_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CR3)?;
+        let cr4 = self.get_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CR4)?;
+        let efer = self.get_register(vcpu_id, vm_reg_name::VM_REG_GUEST_EFER)?;
+        let (_, _, cs_access) = self.get_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_CS)?;
+        let (_, _, ss_access) = self.get_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_SS)?;
+        Ok(paging_state(cr0, cr3, cr4, efer, cs_access, ss_access))
+    }
+
+    /// Translates the guest linear address 'gla' to a guest physical address
+    /// using the page tables described by 'paging', checking for access
+    /// 'prot' (`PROT_READ` or `PROT_WRITE`). Returns 'None' if the guest
+    /// would fault, in which case the kernel has injected the fault into the
+    /// VCPU and the exit should not be completed.
+    pub fn gla2gpa(&self, vcpu_id: i32, paging: &vm_guest_paging, gla: u64, prot: i32) -> Result<Option<u64>, Error> {
         // Struct is allocated (and owned) by Rust, but modified by C
-        let mut top = vm_cpu_topology::default();
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_TOPOLOGY, &mut top) };
+        let mut gla_data = vm_gla2gpa {
+            vcpuid: vcpu_id,
+            prot: prot,
+            gla: gla,
+            paging: *paging,
+            fault: 0,
+            gpa: 0,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GLA2GPA, &mut gla_data) };
         if result == 0 {
-            return Ok((top.sockets, top.cores, top.threads, top.maxcpus));
+            match gla_data.fault {
+                0 => return Ok(Some(gla_data.gpa)),
+                _ => return Ok(None),
+            }
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_GLA2GPA", size_of::<vm_gla2gpa>()));
         }
     }
 
-    /// Gets current stats for a CPUs on the VirtualMachine.
-    pub fn get_stats(&self, vcpu_id: i32) -> Result<i32, Error> {
+    /// Translates 'gla' like `gla2gpa()`, but without injecting a fault into
+    /// the VCPU when the translation fails, so the guest's address space can
+    /// be inspected without disturbing it, as a debugger does. Returns
+    /// 'None' if the access would fault.
+    pub fn gla2gpa_nofault(&self, vcpu_id: i32, paging: &vm_guest_paging, gla: u64, prot: i32) -> Result<Option<u64>, Error> {
         // Struct is allocated (and owned) by Rust, but modified by C
-        let mut stats_data = vm_stats {
-            cpuid: vcpu_id,
-            ..Default::default()
+        let mut gla_data = vm_gla2gpa {
+            vcpuid: vcpu_id,
+            prot: prot,
+            gla: gla,
+            paging: *paging,
+            fault: 0,
+            gpa: 0,
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_STATS_IOC, &mut stats_data) };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GLA2GPA_NOFAULT, &mut gla_data) };
         if result == 0 {
-            return Ok(stats_data.num_entries);
+            match gla_data.fault {
+                0 => return Ok(Some(gla_data.gpa)),
+                _ => return Ok(None),
+            }
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_GLA2GPA_NOFAULT", size_of::<vm_gla2gpa>()));
         }
     }
 
-    /// Activates a Virtual CPU on the VirtualMachine.
-    pub fn activate_vcpu(&self, vcpu_id: i32) -> Result<bool, Error> {
-        // Struct is allocated (and owned) by Rust
-        let cpu_data = vm_activate_cpu { vcpuid: vcpu_id };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_ACTIVATE_CPU, &cpu_data) };
-        if result == 0 {
-            return Ok(true);
-        } else {
-            return Err(Error::last());
+    /// Translates [gla,gla+len) in the guest linear address space into the
+    /// guest physical and host virtual address ranges backing it, one per
+    /// run of physically contiguous guest pages, for string I/O and DMA
+    /// emulation across page boundaries. Returns 'None' if the guest would
+    /// fault on any page of the range, as for `gla2gpa()`.
+    ///
+    /// Every page must be backed by one of the regions in `regions()`, or
+    /// `EFAULT` is returned.
+    pub fn gla_segments(&self, vcpu_id: i32, paging: &vm_guest_paging, gla: u64, len: usize, prot: i32) -> Result<Option<Vec<GuestSegment>>, Error> {
+        scatter::gla_segments(self, vcpu_id, paging, gla, len, prot)
+    }
+
+    /// Reads a value of type 'T' from guest physical memory at 'gpa', which
+    /// doesn't need to be aligned, converting it from the guest's byte order.
+    /// Within the regions in `regions()`, this is `GuestMemory::read_obj()`.
+    pub fn read_obj<T: FromBytes>(&self, gpa: u64) -> Result<T, Error> {
+        if self.memory.covers(gpa, size_of::<T>() as u64, libc::PROT_READ) {
+            return self.memory.read_obj(gpa);
         }
+        // Safe because any bytes make a valid FromBytes value
+        let mut value: T = unsafe { std::mem::zeroed() };
+        self.read_guest_memory(gpa, bytes::as_mut_bytes(&mut value))?;
+        Ok(value.to_native())
     }
 
-    pub fn set_x2apic_state(&self, vcpu_id: i32, enable: bool) -> Result<bool, Error> {
-        let state = match enable {
-            true => x2apic_state::X2APIC_ENABLED,
-            false => x2apic_state::X2APIC_DISABLED
-        };
+    /// Writes 'value' to guest physical memory at 'gpa', which doesn't need
+    /// to be aligned, in the guest's byte order. Within the regions in
+    /// `regions()`, this is `GuestMemory::write_obj()`.
+    pub fn write_obj<T: FromBytes>(&self, gpa: u64, value: &T) -> Result<(), Error> {
+        if self.memory.covers(gpa, size_of::<T>() as u64, libc::PROT_WRITE) {
+            return self.memory.write_obj(gpa, *value);
+        }
+        let value = value.to_le();
+        self.write_guest_memory(gpa, bytes::as_bytes(&value))
+    }
 
-        // Struct is allocated (and owned) by Rust
-        let x2apic_data = vm_x2apic {
-            cpuid: vcpu_id,
-            state: state,
+    // Maps [gpa,gpa+len) of guest system memory into the host address space
+    // with protection 'prot', and calls 'f' with a pointer to 'gpa' in the
+    // mapping, which is removed once 'f' returns.
+    fn with_guest_mapping<F>(&self, gpa: u64, len: usize, prot: i32, f: F) -> Result<(), Error>
+        where F: FnOnce(*mut u8)
+    {
+        if len == 0 {
+            return Ok(());
+        }
+        // The mapping offset must be page aligned
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as u64 };
+        let map_gpa = gpa & !(page_size - 1);
+        let map_len = (gpa - map_gpa) as usize + len;
+
+        // Guest system memory is mapped at offsets equal to its guest
+        // physical address in the VM device
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                map_len,
+                prot,
+                libc::MAP_SHARED,
+                self.vm.as_raw_fd(),
+                map_gpa as libc::off_t,
+            )
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SET_X2APIC_STATE, &x2apic_data) };
-        if result == 0 {
-            return Ok(true);
-        } else {
+        if ptr == libc::MAP_FAILED {
             return Err(Error::last());
         }
+        // Safe because the offset is within the mapping, which is unmapped
+        // only after 'f' returns.
+        unsafe {
+            f((ptr as *mut u8).add((gpa - map_gpa) as usize));
+            libc::munmap(ptr, map_len);
+        }
+        Ok(())
     }
 
-    pub fn get_x2apic_state(&self, vcpu_id: i32) -> Result<bool, Error> {
-        // Struct is allocated (and owned) by Rust, but modified by C
-        let mut x2apic_data = vm_x2apic {
-            cpuid: vcpu_id,
-            ..Default::default()
+    /// Changes the guest's access to [gpa,gpa+len) to 'prot', for example to
+    /// make the bootrom writable while emulating a flash update, or to
+    /// write-protect memory to catch the guest's writes to it. The mapping
+    /// holding the range is split around it, and the range is merged again
+    /// with neighbouring parts of the same segment that have the same
+    /// protection, so restoring the old protection restores the original
+    /// mapping. Regions in `regions()` that lie within the range take on
+    /// the new protection, which `reinit_full()` maps them with.
+    ///
+    /// The mappings are briefly removed while they are replaced, so every
+    /// active VCPU must be suspended, with `suspend_vcpu()` or by
+    /// suspending the VM, or `EBUSY` is returned.
+    ///
+    /// The range must be page aligned and lie within a single mapping, or
+    /// `EINVAL` is returned. Returns `EFAULT` if the range isn't mapped,
+    /// `EBUSY` if part of the range is borrowed for DMA, and
+    /// `Error::TooManyMappings` if the split would take more mappings than
+    /// the kernel allows, in which case nothing is changed.
+    pub fn set_region_protection(&self, gpa: u64, len: usize, prot: MemProt) -> Result<(), Error> {
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as u64 };
+        let len = len as u64;
+        check_page_range(gpa, len, page_size)?;
+        self.check_vcpus_stopped()?;
+        let map = match self.find_mapping(gpa, len)? {
+            Some(map) => map,
+            None => return Err(Error::new(EFAULT)),
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_X2APIC_STATE, &mut x2apic_data) };
-        if result == 0 {
-            match x2apic_data.state {
-                x2apic_state::X2APIC_ENABLED => return Ok(true),
-                x2apic_state::X2APIC_DISABLED => return Ok(false),
-                x2apic_state::X2APIC_STATE_LAST => return Err(Error::new(EINVAL)),
+        if gpa < map.gpa || gpa + len > map.gpa + map.len as u64 {
+            return Err(Error::new(EINVAL));
+        }
+        let prev = match map.gpa {
+            0 => None,
+            _ => self.find_mapping(map.gpa - 1, 1)?,
+        };
+        let next = self.find_mapping(map.gpa + map.len as u64, 1)?;
+        let (from, to) = plan_protection(&map, prev, next, gpa, len, prot.prot());
+        self.check_mapping_count(&from, &to)?;
+        self.change_mappings(&from, &to)?;
+        for region in self.memory.lock_regions().iter_mut() {
+            if gpa <= region.gpa && region.gpa + region.len <= gpa + len {
+                region.prot = prot.prot();
             }
-        } else {
-            return Err(Error::last());
         }
+        Ok(())
     }
 
-    /// From Intel Vol 3a:
-    /// Table 9-1. IA-32 Processor States Following Power-up, Reset or INIT
-    pub fn vcpu_reset(&self, vcpu_id: i32) -> Result<bool, Error> {
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RFLAGS, 0x2)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RIP, 0xfff0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CR0, CR0_NE)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CR3, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CR4, 0)?;
-
-        // CS: present, r/w, accessed, 16-bit, byte granularity, usable
-	let cs_base = 0xffff0000;
-	let cs_limit = 0xffff;
-	let cs_access = 0x0093;
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_CS, cs_base, cs_limit, cs_access)?;
-
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CS, 0xf000)?;
-
-
-        // SS,DS,ES,FS,GS: present, r/w, accessed, 16-bit, byte granularity
-	let desc_base = 0;
-	let desc_limit = 0xffff;
-	let desc_access = 0x0093;
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_SS, desc_base, desc_limit, desc_access)?;
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_DS, desc_base, desc_limit, desc_access)?;
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_ES, desc_base, desc_limit, desc_access)?;
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_FS, desc_base, desc_limit, desc_access)?;
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_GS, desc_base, desc_limit, desc_access)?;
-
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_SS, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_DS, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_ES, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_FS, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_GS, 0)?;
-
-        // General purpose registers
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RAX, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RBX, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RCX, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RDX, 0xf00)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RSI, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RDI, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RBP, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RSP, 0)?;
+    /// Takes [gpa,gpa+len) of system memory from the guest, for a balloon
+    /// device whose guest driver has given the pages up. The range is
+    /// unmapped from the guest with `munmap_memseg()`, splitting the system
+    /// memory mapping that holds it, so this needs
+    /// `KernelFeatures::munmap_memseg`, or `ENOTSUP` is returned. The memory
+    /// segment behind the range is left as it is.
+    ///
+    /// The mappings are briefly removed while they are replaced, so every
+    /// active VCPU must be suspended, or `EBUSY` is returned, as for
+    /// `set_region_protection()`.
+    ///
+    /// The range must be page aligned and lie within a single system memory
+    /// mapping, or `EINVAL` is returned. Returns `EFAULT` if the range isn't
+    /// mapped, which includes ranges already in the balloon, and
+    /// `Error::TooManyMappings` if the split would take more mappings than
+    /// the kernel allows.
+    pub fn inflate_balloon(&self, gpa: u64, len: usize) -> Result<(), Error> {
+        if !self.features.munmap_memseg {
+            return Err(Error::new(ENOTSUP));
+        }
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as u64 };
+        let len = len as u64;
+        check_page_range(gpa, len, page_size)?;
+        self.check_vcpus_stopped()?;
+        let mut balloon = self.balloon.lock().unwrap();
+        let map = self.sysmem_mapping(gpa, len)?;
+        let to = plan_hole(&map, gpa, len);
+        self.check_mapping_count(&[map], &to)?;
+        self.change_mappings(&[map], &to)?;
+        balloon.inflate(map.slice(gpa, len))?;
+        balloon.count(true);
+        Ok(())
+    }
 
+    /// Returns [gpa,gpa+len), which must have been added to the balloon with
+    /// `inflate_balloon()`, to the guest, by mapping it again with the
+    /// protection and flags it had. The range is merged with the mappings
+    /// either side of it where they carry on in the same segment, so
+    /// deflating the whole balloon restores the original mappings. The
+    /// guest finds the range as the memory segment holds it.
+    ///
+    /// As for `inflate_balloon()`, every active VCPU must be suspended, or
+    /// `EBUSY` is returned. Returns `ENOENT` unless the whole range is in
+    /// the balloon.
+    pub fn deflate_balloon(&self, gpa: u64, len: usize) -> Result<(), Error> {
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as u64 };
+        check_page_range(gpa, len as u64, page_size)?;
+        self.check_vcpus_stopped()?;
+        let mut balloon = self.balloon.lock().unwrap();
+        let piece = balloon.deflate(gpa, len as u64)?;
+        if let Err(e) = self.remap_ballooned(&piece) {
+            let _ = balloon.inflate(piece);
+            return Err(e);
+        }
+        balloon.count(false);
+        Ok(())
+    }
 
-        // GDTR, IDTR
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_GDTR, 0, 0xffff, 0)?;
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_IDTR, 0, 0xffff, 0)?;
+    /// Returns the ranges of guest memory in the balloon, as (gpa, len)
+    /// pairs in order of guest physical address.
+    pub fn ballooned_ranges(&self) -> Vec<(u64, u64)> {
+        self.balloon.lock().unwrap().ranges()
+    }
 
-        // TR
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_TR, 0, 0, 0x0000008b)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_TR, 0)?;
+    /// Returns the accounting of the balloon: the memory currently in it,
+    /// and the inflations and deflations through this handle.
+    pub fn balloon_stats(&self) -> BalloonStats {
+        self.balloon.lock().unwrap().stats()
+    }
 
-        // LDTR
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_LDTR, 0, 0xffff, 0x00000082)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_LDTR, 0)?;
+    // Maps the ballooned 'piece' into the guest again, merged with the
+    // mappings either side of it that carry on in the same segment with the
+    // same protection.
+    fn remap_ballooned(&self, piece: &MemMap) -> Result<(), Error> {
+        let prev = match piece.gpa {
+            0 => None,
+            _ => self.find_mapping(piece.gpa - 1, 1)?,
+        };
+        let prev = prev.filter(|prev| prev.prot == piece.prot && continues(prev, piece));
+        let next = self.find_mapping(piece.gpa + piece.len as u64, 1)?;
+        let next = next.filter(|next| next.prot == piece.prot && continues(piece, next));
+        let (from, to) = plan_fill(piece, prev, next);
+        self.check_mapping_count(&from, &to)?;
+        self.change_mappings(&from, &to)
+    }
 
-        Ok(true)
+    // Maps every ballooned range into the guest again, for when the guest
+    // is reset along with its balloon driver.
+    fn restore_balloon(&self) -> Result<(), Error> {
+        let pieces = self.balloon.lock().unwrap().take();
+        for piece in pieces.iter() {
+            self.remap_ballooned(piece)?;
+        }
+        Ok(())
     }
 
-    /// Suspends a Virtual CPU on the VirtualMachine.
-    pub fn suspend_vcpu(&self, vcpu_id: i32) -> Result<bool, Error> {
-        // Struct is allocated (and owned) by Rust
-        let cpu_data = vm_activate_cpu { vcpuid: vcpu_id };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SUSPEND_CPU, &cpu_data) };
-        if result == 0 {
-            return Ok(true);
-        } else {
-            return Err(Error::last());
+    // Returns EBUSY unless every active VCPU is suspended or stopped for
+    // debugging, so guest mappings can be replaced.
+    fn check_vcpus_stopped(&self) -> Result<(), Error> {
+        let suspended = self.get_suspended_cpus()?;
+        let debug = self.get_debug_cpus()?;
+        if self.get_active_cpus()?.iter().any(|vcpu_id| !suspended.contains(vcpu_id) && !debug.contains(vcpu_id)) {
+            return Err(Error::new(EBUSY));
         }
+        Ok(())
     }
 
-    /// Resumes a Virtual CPU on the VirtualMachine.
-    pub fn resume_vcpu(&self, vcpu_id: i32) -> Result<bool, Error> {
-        // Struct is allocated (and owned) by Rust
-        let cpu_data = vm_activate_cpu { vcpuid: vcpu_id };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_RESUME_CPU, &cpu_data) };
-        if result == 0 {
-            return Ok(true);
-        } else {
-            return Err(Error::last());
+    // Fails with Error::TooManyMappings if replacing the mappings in 'from'
+    // with those in 'to' would take more mappings than the kernel allows.
+    fn check_mapping_count(&self, from: &[MemMap], to: &[MemMap]) -> Result<(), Error> {
+        let mappings = self.memory_maps().collect::<Result<Vec<MemMap>, Error>>()?;
+        let needed = mappings.len() + to.len() - from.len();
+        if needed > VM_MAX_MEMMAPS {
+            return Err(Error::TooManyMappings { needed: needed, max: VM_MAX_MEMMAPS });
         }
+        Ok(())
     }
 
-    /// Runs the VirtualMachine, and returns an exit reason.
-    pub fn run(&self, vcpu_id: i32) -> Result<VmExit, Error> {
-        // Struct is allocated (and owned) by Rust, but modified by C
-        let mut run_data = vm_run {
-            cpuid: vcpu_id,
-            ..Default::default()
+    // Finds the system memory mapping holding all of [gpa,gpa+len).
+    fn sysmem_mapping(&self, gpa: u64, len: u64) -> Result<MemMap, Error> {
+        let map = match self.find_mapping(gpa, len)? {
+            Some(map) => map,
+            None => return Err(Error::new(EFAULT)),
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_RUN, &mut run_data) };
-        if result == 0 {
-            let rip = run_data.vm_exit.rip;
-            println!("RIP after run is {}", rip);
-            let cid = run_data.cpuid;
-            println!("VCPU ID is {}", cid);
-            match run_data.vm_exit.exitcode {
-                vm_exitcode::VM_EXITCODE_INOUT => {
-                    // Safe because the exit code told us which union field to use.
-                    let io = unsafe { run_data.vm_exit.u.inout };
-                    let port = io.port;
-                    let value = io.eax;
-                    let bytes = io.bytes();
-
-                    if io.is_in() {
-                        return Ok(VmExit::IoIn(port, bytes));
-                    } else {
-                        return Ok(VmExit::IoOut(port, bytes, value));
-                    }
-                }
-                vm_exitcode::VM_EXITCODE_INOUT_STR => {
-                    // Safe because the exit code told us which union field to use.
-                    let vis = unsafe { run_data.vm_exit.u.inout_str };
-                    let io = vis.inout;
-                    let port = io.port;
-
-                    if !io.is_string() {
-                        return Err(Error::new(EINVAL));
-                    }
+        if gpa < map.gpa || gpa + len > map.gpa + map.len as u64 {
+            return Err(Error::new(EINVAL));
+        }
+        // System memory segments are the unnamed ones
+        if self.get_memseg(map.segid)?.name[0] != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(map)
+    }
 
-                    let mask: u64 = match vis.addrsize {
-                        2 => 0xffff,
-                        4 => 0xffffffff,
-                        8 => 0xffffffffffffffff,
-                        _ => return Err(Error::new(EINVAL))
-                    };
-
-                    let index: u64 = vis.index & mask;
-                    let count: u64 = vis.count & mask;
-
-                    let bytes = io.bytes();
-                    let repeat = io.is_repeat();
-                    if io.is_in() {
-                        return Ok(VmExit::IoInStr(port, bytes, index, count, repeat));
-                    } else {
-                        return Ok(VmExit::IoOutStr(port, bytes, index, count, repeat));
-                    }
-                }
-                vm_exitcode::VM_EXITCODE_VMX => {
-                    let status = unsafe { run_data.vm_exit.u.vmx.status };
-                    let reason = unsafe { run_data.vm_exit.u.vmx.exit_reason };
-                    let qual = unsafe { run_data.vm_exit.u.vmx.exit_qualification };
-                    let inst_type = unsafe { run_data.vm_exit.u.vmx.inst_type };
-                    let inst_error = unsafe { run_data.vm_exit.u.vmx.inst_error };
-                    return Ok(VmExit::Vmx(status, reason, qual, inst_type, inst_error));
-                }
-                vm_exitcode::VM_EXITCODE_BOGUS => {
-                    return Ok(VmExit::Bogus);
-                }
-                vm_exitcode::VM_EXITCODE_RDMSR => {
-                    return Ok(VmExit::RdMsr);
-                }
-                vm_exitcode::VM_EXITCODE_WRMSR => {
-                    return Ok(VmExit::WrMsr);
-                }
-                vm_exitcode::VM_EXITCODE_HLT => {
-                    return Ok(VmExit::Halt);
-                }
-                vm_exitcode::VM_EXITCODE_MTRAP => {
-                    return Ok(VmExit::Mtrap);
-                }
-                vm_exitcode::VM_EXITCODE_PAUSE => {
-                    return Ok(VmExit::Pause);
-                }
-                vm_exitcode::VM_EXITCODE_PAGING => {
-                    return Ok(VmExit::Paging);
-                }
-                vm_exitcode::VM_EXITCODE_INST_EMUL => {
-                    return Ok(VmExit::InstEmul);
-                }
-                vm_exitcode::VM_EXITCODE_SPINUP_AP => {
-                    return Ok(VmExit::SpinupAp);
-                }
-                vm_exitcode::VM_EXITCODE_DEPRECATED1 => {
-                    return Ok(VmExit::Deprecated);
-                }
-                vm_exitcode::VM_EXITCODE_RUNBLOCK => {
-                    return Ok(VmExit::RunBlock);
-                }
-                vm_exitcode::VM_EXITCODE_IOAPIC_EOI => {
-                    let ioapic = unsafe { run_data.vm_exit.u.ioapic_eoi };
-                    return Ok(VmExit::IoapicEoi(ioapic.vector));
-                }
-                vm_exitcode::VM_EXITCODE_SUSPENDED => {
-                    return Ok(VmExit::Suspended);
-                }
-                vm_exitcode::VM_EXITCODE_TASK_SWITCH => {
-                    return Ok(VmExit::TaskSwitch);
-                }
-                vm_exitcode::VM_EXITCODE_MONITOR => {
-                    return Ok(VmExit::Monitor);
-                }
-                vm_exitcode::VM_EXITCODE_MWAIT => {
-                    return Ok(VmExit::Mwait);
-                }
-                vm_exitcode::VM_EXITCODE_SVM => {
-                    let svm = unsafe { run_data.vm_exit.u.svm };
-                    return Ok(VmExit::Svm(svm.exitcode, svm.exitinfo1, svm.exitinfo2));
-                }
-                vm_exitcode::VM_EXITCODE_REQIDLE => {
-                    return Ok(VmExit::ReqIdle);
-                }
-                vm_exitcode::VM_EXITCODE_DEBUG => {
-                    return Ok(VmExit::Debug);
-                }
-                vm_exitcode::VM_EXITCODE_VMINSN => {
-                    return Ok(VmExit::VmInsn);
-                }
-                vm_exitcode::VM_EXITCODE_HT => {
-                    return Ok(VmExit::Ht);
-                }
-                vm_exitcode::VM_EXITCODE_MAX => {
-                    return Ok(VmExit::Max);
-                }
+    // Replaces the guest mappings in 'from' with those in 'to', leaving the
+    // mappings both share alone. If a change fails, the mappings in 'from'
+    // are restored as far as possible.
+    fn change_mappings(&self, from: &[MemMap], to: &[MemMap]) -> Result<(), Error> {
+        let result = self.apply_mappings(from, to);
+        if result.is_err() {
+            for map in to.iter().filter(|map| !from.contains(map)) {
+                let _ = self.munmap_memseg(map.gpa, map.len);
+            }
+            for map in from.iter().filter(|map| !to.contains(map)) {
+                let _ = self.map_memseg(map, true);
             }
-        } else {
-            return Err(Error::last());
         }
+        result
     }
 
-    /// Resets the VirtualMachine.
-    pub fn reset(&self) -> Result<i32, Error> {
-        let suspend_data = vm_suspend { how: vm_suspend_how::VM_SUSPEND_RESET };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SUSPEND, &suspend_data) };
-        if result == 0 {
-            return Ok(result);
-        } else {
-            return Err(Error::last());
+    fn apply_mappings(&self, from: &[MemMap], to: &[MemMap]) -> Result<(), Error> {
+        for map in from.iter().filter(|map| !to.contains(map)) {
+            self.munmap_memseg(map.gpa, map.len)?;
         }
+        for map in to.iter().filter(|map| !from.contains(map)) {
+            self.map_memseg(map, false)?;
+        }
+        Ok(())
     }
 
-    /// Halts the VirtualMachine.
-    pub fn halt(&self) -> Result<i32, Error> {
-        let suspend_data = vm_suspend { how: vm_suspend_how::VM_SUSPEND_HALT };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SUSPEND, &suspend_data) };
-        if result == 0 {
-            return Ok(result);
-        } else {
-            return Err(Error::last());
+    /// Reports how much of each guest memory mapping is resident in host
+    /// memory, in order of guest physical address. Memory that is not
+    /// resident has either never been touched by the guest, or has been
+    /// paged out by the host, so comparing the totals with the guest memory
+    /// size shows how far the host is overcommitted.
+    pub fn memory_residency(&self) -> Result<Vec<MemResidency>, Error> {
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as usize };
+        let mut residency = Vec::new();
+        for map in self.memory_maps() {
+            let map = map?;
+
+            // System memory is mapped at offsets equal to its guest physical
+            // address in the VM device, and device memory at its own offset.
+            let seg = self.get_memseg(map.segid)?;
+            let offset = match seg.name[0] {
+                0 => map.gpa as i64,
+                _ => self.get_devmem_offset(map.segid)? + map.segoff,
+            };
+            residency.push(MemResidency {
+                gpa: map.gpa,
+                len: map.len,
+                segid: map.segid,
+                resident: self.resident_bytes(offset, map.len, page_size)?,
+                wired: (map.flags & VM_MEMMAP_F_WIRED) != 0,
+            });
         }
+        Ok(residency)
     }
 
-    /// Suspends the VirtualMachine with power off.
-    pub fn poweroff(&self) -> Result<i32, Error> {
-        let suspend_data = vm_suspend { how: vm_suspend_how::VM_SUSPEND_POWEROFF };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SUSPEND, &suspend_data) };
-        if result == 0 {
-            return Ok(result);
-        } else {
+    // Counts the resident bytes in [offset,offset+len) of the VM device,
+    // through a temporary mapping that doesn't fault any pages in.
+    fn resident_bytes(&self, offset: i64, len: usize, page_size: usize) -> Result<usize, Error> {
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, self.vm.as_raw_fd(), offset as libc::off_t)
+        };
+        if ptr == libc::MAP_FAILED {
             return Err(Error::last());
         }
+        // Mappings are a whole number of pages long
+        let mut pages = vec![0; len / page_size];
+        let result = unsafe { libc::mincore(ptr, len, pages.as_mut_ptr()) };
+        let err = Error::last();
+        unsafe {
+            libc::munmap(ptr, len);
+        }
+        if result != 0 {
+            return Err(err);
+        }
+        Ok(count_resident(&pages, page_size).min(len))
     }
 
-    /// Suspends the VirtualMachine with triple fault.
-    pub fn triplefault(&self) -> Result<i32, Error> {
-        let suspend_data = vm_suspend { how: vm_suspend_how::VM_SUSPEND_TRIPLEFAULT };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SUSPEND, &suspend_data) };
-        if result == 0 {
-            return Ok(result);
-        } else {
-            return Err(Error::last());
+    /// Populates the system memory of this virtual machine from 'source',
+    /// for forking a VM on the same host. Every system memory segment
+    /// mapped in 'source' is allocated and mapped here at the same guest
+    /// physical address, then filled from the source's pages. Every active
+    /// VCPU of 'source' must be suspended, so the guest can't change its
+    /// memory during the copy, or `EBUSY` is returned.
+    ///
+    /// Pages are copied directly between mappings of the two VM devices,
+    /// with no buffer in between, and pages that read as zero are skipped,
+    /// since new segments start out zeroed. The clone therefore only
+    /// allocates the memory the source guest has used. This interface
+    /// version has no way to share a segment between VMs, so the memory is
+    /// always copied: a full memcpy of every page in use, whose size
+    /// `MemCopyStats` reports. Device memory, such as the bootrom, is not
+    /// copied.
+    ///
+    /// The segments are not mapped into the host address space. Calling
+    /// `setup_lowmem()` or `setup_highmem()` afterwards with the same
+    /// length maps them at a host address.
+    pub fn copy_memory_from(&self, source: &VirtualMachine) -> Result<MemCopyStats, Error> {
+        // Two mappings of the same memory would alias
+        if self.name == source.name {
+            return Err(Error::new(EINVAL));
+        }
+        let active = source.get_active_cpus()?;
+        let suspended = source.get_suspended_cpus()?;
+        if active.iter().any(|vcpu_id| !suspended.contains(vcpu_id)) {
+            return Err(Error::new(EBUSY));
         }
-    }
 
-    /// Reinitializes the VirtualMachine.
-    pub fn reinit(&self) -> Result<i32, Error> {
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_REINIT) };
-        if result == 0 {
-            return Ok(result);
-        } else {
-            return Err(Error::last());
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as usize };
+        let mut stats = MemCopyStats::default();
+        for map in source.memory_maps() {
+            let map = map?;
+
+            // System memory segments are the unnamed ones
+            let seg = source.get_memseg(map.segid)?;
+            if seg.name[0] != 0 {
+                continue;
+            }
+            self.alloc_memseg(map.segid, seg.len, "")?;
+            self.map_memseg(&map, true)?;
+            let copied = self.copy_mapping(source, map.gpa, map.len, page_size)?;
+            stats.mappings += 1;
+            stats.copied += copied;
+            stats.skipped += map.len - copied;
         }
+        Ok(stats)
     }
 
-    /// Get the value of an optional capability on the VCPU
-    pub fn get_capability(&self, vcpu_id: i32, cap: vm_cap_type) -> Result<i32, Error> {
+    // Copies the pages of [gpa,gpa+len) of system memory that aren't zero
+    // from 'source', returning the number of bytes copied.
+    fn copy_mapping(&self, source: &VirtualMachine, gpa: u64, len: usize, page_size: usize) -> Result<usize, Error> {
+        let mut copied = 0;
+        let mut result = Ok(());
+        source.with_guest_mapping(gpa, len, libc::PROT_READ, |src| {
+            result = self.with_guest_mapping(gpa, len, libc::PROT_READ | libc::PROT_WRITE, |dst| {
+                // Safe because both mappings cover [gpa,gpa+len), and map
+                // the memory of different VMs.
+                let (src, dst) = unsafe {
+                    (std::slice::from_raw_parts(src, len), std::slice::from_raw_parts_mut(dst, len))
+                };
+                copied = copy_nonzero_pages(src, dst, page_size);
+            });
+        })?;
+        result?;
+        Ok(copied)
+    }
+
+    /// Gets the chain of nested page table entries that map the guest
+    /// physical address 'gpa', from the top level down, for debugging
+    /// EPT/NPT mappings. The chain stops early at an entry that isn't
+    /// present, or at a large page.
+    pub fn get_gpa_pmap(&self, gpa: u64) -> Result<Vec<u64>, Error> {
         // Struct is allocated (and owned) by Rust, but modified by C
-        let mut cap_data = vm_capability {
-            cpuid: vcpu_id,
-            captype: cap,
+        let mut pte_data = vm_gpa_pte {
+            gpa: gpa,
             ..Default::default()
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_CAPABILITY, &mut cap_data) };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_GPA_PMAP, &mut pte_data) };
         if result == 0 {
-            return Ok(cap_data.capval);
+            let count = (pte_data.ptenum.max(0) as usize).min(pte_data.pte.len());
+            return Ok(pte_data.pte[..count].to_vec());
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_GET_GPA_PMAP", size_of::<vm_gpa_pte>()));
         }
     }
 
-    /// Set the value of an optional capability on the VCPU
-    pub fn set_capability(&self, vcpu_id: i32, cap: vm_cap_type, val: i32) -> Result<bool, Error> {
+    /// Set the base, limit, and access values of a descriptor register on the VCPU
+    pub fn set_desc(&self, vcpu_id: i32, reg: vm_reg_name, base: u64, limit: u32, access: u32) -> Result<bool, Error> {
         // Struct is allocated (and owned) by Rust
-        let cap_data = vm_capability {
+        let seg_data = vm_seg_desc {
             cpuid: vcpu_id,
-            captype: cap,
-            capval: val,
-            ..Default::default()
+            regnum: reg as i32,
+            desc: seg_desc {base: base, limit: limit, access: access},
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SET_CAPABILITY, &cap_data) };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SET_SEGMENT_DESCRIPTOR, &seg_data) };
         if result == 0 {
             return Ok(true);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_SET_SEGMENT_DESCRIPTOR", size_of::<vm_seg_desc>()));
         }
     }
 
-    /// Set interrupt info on the VCPU
-    pub fn set_intinfo(&self, vcpu_id: i32, info1: u64) -> Result<bool, Error> {
-        // Struct is allocated (and owned) by Rust
-        let intinfo_data = vm_intinfo {
-            vcpuid: vcpu_id,
-            info1: info1,
+    /// Get the base, limit, and access values of a descriptor register on the VCPU
+    pub fn get_desc(&self, vcpu_id: i32, reg: vm_reg_name) -> Result<(u64, u32, u32), Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut seg_data = vm_seg_desc {
+            cpuid: vcpu_id,
+            regnum: reg as i32,
             ..Default::default()
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SET_INTINFO, &intinfo_data) };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_SEGMENT_DESCRIPTOR, &mut seg_data) };
+        if result == 0 {
+            return Ok((seg_data.desc.base, seg_data.desc.limit, seg_data.desc.access));
+        } else {
+            return Err(Error::ioctl("VM_GET_SEGMENT_DESCRIPTOR", size_of::<vm_seg_desc>()));
+        }
+    }
+
+    /// Set the value of a single register on the VCPU
+    pub fn set_register(&self, vcpu_id: i32, reg: vm_reg_name, val: u64) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let reg_data = vm_register {
+            cpuid: vcpu_id,
+            regnum: reg as i32,
+            regval: val,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SET_REGISTER, &reg_data) };
         if result == 0 {
             return Ok(true);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_SET_REGISTER", size_of::<vm_register>()));
         }
     }
 
-    /// Get the interrupt info on the VCPU
-    pub fn get_intinfo(&self, vcpu_id: i32) -> Result<(u64, u64), Error> {
+    /// Get the value of a single register on the VCPU
+    pub fn get_register(&self, vcpu_id: i32, reg: vm_reg_name) -> Result<u64, Error> {
         // Struct is allocated (and owned) by Rust, but modified by C
-        let mut intinfo_data = vm_intinfo {
-            vcpuid: vcpu_id,
+        let mut reg_data = vm_register {
+            cpuid: vcpu_id,
+            regnum: reg as i32,
             ..Default::default()
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_INTINFO, &mut intinfo_data) };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_REGISTER, &mut reg_data) };
         if result == 0 {
-            return Ok((intinfo_data.info1, intinfo_data.info2));
+            return Ok(reg_data.regval);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_GET_REGISTER", size_of::<vm_register>()));
         }
     }
 
-    /// Inject an exception on the VCPU
-    pub fn inject_exception(&self, vcpu_id: i32, vector: i32, valid: i32, errcode: u32, restart: i32) -> Result<bool, Error> {
-        // Struct is allocated (and owned) by Rust
-        let exc_data = vm_exception {
+    /// Gets the values of several registers on the VCPU with one ioctl, in
+    /// the order of 'regs'. Saving the full state of a VCPU this way takes
+    /// one system call rather than one per register.
+    pub fn get_registers(&self, vcpu_id: i32, regs: &[vm_reg_name]) -> Result<Vec<u64>, Error> {
+        let regnums: Vec<i32> = regs.iter().map(|reg| *reg as i32).collect();
+        let mut regvals = vec![0u64; regs.len()];
+        // Struct is allocated (and owned) by Rust, but the values it points
+        // to are modified by C
+        let set_data = vm_register_set {
             cpuid: vcpu_id,
-            vector: vector,
-            error_code: errcode,
-            error_code_valid: valid,
-            restart_instruction: restart,
+            count: regs.len() as u32,
+            regnums: regnums.as_ptr(),
+            regvals: regvals.as_mut_ptr(),
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_INJECT_EXCEPTION, &exc_data) };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_REGISTER_SET, &set_data) };
         if result == 0 {
-            return Ok(true);
+            return Ok(regvals);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_GET_REGISTER_SET", size_of::<vm_register_set>()));
         }
     }
 
-    /// Inject non-maskable interrupt (NMI) on the VCPU
-    pub fn inject_nmi(&self, vcpu_id: i32) -> Result<bool, Error> {
+    /// Sets several registers on the VCPU with one ioctl, in the order
+    /// given.
+    pub fn set_registers(&self, vcpu_id: i32, regs: &[(vm_reg_name, u64)]) -> Result<bool, Error> {
+        let regnums: Vec<i32> = regs.iter().map(|(reg, _)| *reg as i32).collect();
+        let mut regvals: Vec<u64> = regs.iter().map(|(_, val)| *val).collect();
         // Struct is allocated (and owned) by Rust
-        let nmi_data = vm_nmi {
+        let set_data = vm_register_set {
             cpuid: vcpu_id,
+            count: regs.len() as u32,
+            regnums: regnums.as_ptr(),
+            regvals: regvals.as_mut_ptr(),
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_INJECT_NMI, &nmi_data) };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SET_REGISTER_SET, &set_data) };
         if result == 0 {
             return Ok(true);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_SET_REGISTER_SET", size_of::<vm_register_set>()));
         }
     }
 
-    /// Signal to the Local Advanced Programmable Interrupt Controller (LAPIC)
-    /// that an interrupt request (IRQ) at 'vector' needs to be sent to the VCPU
-    /// identified by 'vcpu_id'. The state of the interrupt request is recorded in
-    /// the LAPIC interrupt request register (IRR).
-    pub fn lapic_irq(&self, vcpu_id: i32, vector: i32) -> Result<bool, Error> {
+    pub fn rtc_write(&self, offset: i32, value: u8) -> Result<bool, Error> {
         // Struct is allocated (and owned) by Rust
-        let irq_data = vm_lapic_irq {
-            cpuid: vcpu_id,
-            vector: vector,
+        let rtc_data = vm_rtc_data {
+            offset: offset,
+            value: value,
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_LAPIC_IRQ, &irq_data) };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_RTC_WRITE, &rtc_data) };
         if result == 0 {
             return Ok(true);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_RTC_WRITE", size_of::<vm_rtc_data>()));
         }
     }
 
-    /// Trigger an interrupt request (IRQ) according to the local vector table
-    /// (LVT) on the Local Advanced Programmable Interrupt Controller (LAPIC)
-    /// for the VCPU identified by 'vcpu_id'. The 'vcpu_id' can be set to -1 to
-    /// trigger the interrupt on all VCPUs.
-    pub fn lapic_local_irq(&self, vcpu_id: i32, vector: i32) -> Result<bool, Error> {
-        // Struct is allocated (and owned) by Rust
-        let irq_data = vm_lapic_irq {
-            cpuid: vcpu_id,
-            vector: vector,
+    pub fn rtc_read(&self, offset: i32) -> Result<u8, Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut rtc_data = vm_rtc_data {
+            offset: offset,
+            ..Default::default()
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_LAPIC_LOCAL_IRQ, &irq_data) };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_RTC_READ, &mut rtc_data) };
         if result == 0 {
-            return Ok(true);
+            return Ok(rtc_data.value);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_RTC_READ", size_of::<vm_rtc_data>()));
         }
     }
 
-    /// Signal an interrupt using Message Signaled Interrupts (MSI)
-    pub fn lapic_msi(&self, addr: u64, msg: u64) -> Result<bool, Error> {
+    pub fn rtc_settime(&self, secs: i64) -> Result<bool, Error> {
         // Struct is allocated (and owned) by Rust
-        let msi_data = vm_lapic_msi {
-            msg: msg,
-            addr: addr,
+        let rtc_data = vm_rtc_time {
+            secs: secs,
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_LAPIC_MSI, &msi_data) };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_RTC_SETTIME, &rtc_data) };
         if result == 0 {
             return Ok(true);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_RTC_SETTIME", size_of::<vm_rtc_time>()));
         }
     }
 
-    /// Set the I/O APIC pin state for an interrupt request (IRQ) on the VM to true.
-    pub fn ioapic_assert_irq(&self, irq: i32) -> Result<bool, Error> {
+    pub fn rtc_gettime(&self) -> Result<i64, Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut rtc_data = vm_rtc_time::default();
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_RTC_GETTIME, &mut rtc_data) };
+        if result == 0 {
+            return Ok(rtc_data.secs);
+        } else {
+            return Err(Error::ioctl("VM_RTC_GETTIME", size_of::<vm_rtc_time>()));
+        }
+    }
+
+    /// Sets basic attributes of CPUs on the VirtualMachine: sockets, cores,
+    /// and threads.
+    pub fn set_topology(&self, sockets: u16, cores: u16, threads: u16) -> Result<bool, Error> {
         // Struct is allocated (and owned) by Rust
-        let irq_data = vm_ioapic_irq {
-            irq: irq,
+        let top_data = vm_cpu_topology {
+            sockets: sockets,
+            cores: cores,
+            threads: threads,
+            maxcpus: 0, // any other value is invalid
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_IOAPIC_ASSERT_IRQ, &irq_data) };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SET_TOPOLOGY, &top_data) };
         if result == 0 {
             return Ok(true);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_SET_TOPOLOGY", size_of::<vm_cpu_topology>()));
         }
     }
 
-    /// Set the I/O APIC pin state for an interrupt request (IRQ) on the VM to false.
-    pub fn ioapic_deassert_irq(&self, irq: i32) -> Result<bool, Error> {
-        // Struct is allocated (and owned) by Rust
-        let irq_data = vm_ioapic_irq {
-            irq: irq,
+    /// Gets current settings for CPUs on the VirtualMachine: sockets, cores,
+    /// threads, and maximum number of CPUs.
+    pub fn get_topology(&self) -> Result<(u16, u16, u16, u16), Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut top = vm_cpu_topology::default();
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_TOPOLOGY, &mut top) };
+        if result == 0 {
+            return Ok((top.sockets, top.cores, top.threads, top.maxcpus));
+        } else {
+            return Err(Error::ioctl("VM_GET_TOPOLOGY", size_of::<vm_cpu_topology>()));
+        }
+    }
+
+    /// Gets current stats for a CPUs on the VirtualMachine.
+    pub fn get_stats(&self, vcpu_id: i32) -> Result<i32, Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut stats_data = vm_stats {
+            cpuid: vcpu_id,
+            ..Default::default()
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_IOAPIC_DEASSERT_IRQ, &irq_data) };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_STATS_IOC, &mut stats_data) };
         if result == 0 {
-            return Ok(true);
+            return Ok(stats_data.num_entries);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_STATS_IOC", size_of::<vm_stats>()));
         }
     }
 
-    /// Set the I/O APIC pin state for an interrupt request (IRQ) on the VM to
-    /// true and then false (a "pulse").
-    pub fn ioapic_pulse_irq(&self, irq: i32) -> Result<bool, Error> {
-        // Struct is allocated (and owned) by Rust
-        let irq_data = vm_ioapic_irq {
-            irq: irq,
+    /// Gets the values of the statistics for a CPU on the VirtualMachine, in
+    /// index order, along with the time they were sampled, as a duration
+    /// since the Unix epoch. Use `get_stat_desc()` or `VmStats` to name them.
+    pub fn get_stat_values(&self, vcpu_id: i32) -> Result<(Duration, Vec<u64>), Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut stats_data = vm_stats {
+            cpuid: vcpu_id,
+            ..Default::default()
         };
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_IOAPIC_PULSE_IRQ, &irq_data) };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_STATS_IOC, &mut stats_data) };
         if result == 0 {
-            return Ok(true);
+            let count = (stats_data.num_entries.max(0) as usize).min(MAX_VM_STATS);
+            let sampled = Duration::from_secs(stats_data.tv.tv_sec.max(0) as u64)
+                + Duration::from_micros(stats_data.tv.tv_usec.max(0) as u64);
+            return Ok((sampled, stats_data.statbuf[..count].to_vec()));
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_STATS_IOC", size_of::<vm_stats>()));
         }
     }
 
-    /// Get the I/O APIC pincount for the VM
-    pub fn ioapic_pincount(&self) -> Result<i32, Error> {
-        // Integer is allocated (and owned) by Rust, but modified by C
-        let mut pincount: i32 = 0;
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_IOAPIC_PINCOUNT, &mut pincount) };
+    /// Gets the description of the statistic at 'index'. Returns `EINVAL`
+    /// past the last statistic.
+    pub fn get_stat_desc(&self, index: i32) -> Result<String, Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut desc_data = vm_stat_desc {
+            index: index,
+            ..Default::default()
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_STAT_DESC, &mut desc_data) };
         if result == 0 {
-            return Ok(pincount);
+            return Ok(String::from_utf8_lossy(&cstring::field_bytes(&desc_data.desc)).into_owned());
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_STAT_DESC", size_of::<vm_stat_desc>()));
         }
     }
 
-    /// Restart the current instruction on the VCPU
-    pub fn restart_instruction(&self, vcpu_id: i32) -> Result<bool, Error> {
-        // Integer is allocated (and owned) by Rust
-        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_RESTART_INSTRUCTION, &vcpu_id) };
+    /// Returns a handle for the VCPU identified by 'vcpu_id', which carries
+    /// the virtual machine along with the ID, so the per-VCPU operations can
+    /// be called on it without mixing up IDs between VMs. Returns `EINVAL`
+    /// if the ID is outside the VM's topology.
+    pub fn vcpu(self: &Arc<Self>, vcpu_id: i32) -> Result<Vcpu, Error> {
+        // Kernels without topology support allow any ID below VM_MAXCPU
+        let maxcpus = self.get_topology().ok().map(|(_, _, _, maxcpus)| maxcpus);
+        if !valid_vcpu_id(vcpu_id, maxcpus) {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(Vcpu::new(Arc::clone(self), vcpu_id))
+    }
+
+    /// Activates a Virtual CPU on the VirtualMachine.
+    pub fn activate_vcpu(&self, vcpu_id: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let cpu_data = vm_activate_cpu { vcpuid: vcpu_id };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_ACTIVATE_CPU, &cpu_data) };
         if result == 0 {
+            self.active_vcpus.lock().unwrap().insert(vcpu_id);
+            self.events.emit(VmEvent::VcpuActivated(vcpu_id));
             return Ok(true);
         } else {
-            return Err(Error::last());
+            return Err(Error::ioctl("VM_ACTIVATE_CPU", size_of::<vm_activate_cpu>()));
         }
     }
-}
 
-// Different styles of mapping the memory assigned to a VM into the address
-// space of the controlling process.
-#[repr(C)]
-#[allow(non_camel_case_types, unused)]
-#[derive(Debug, Copy, Clone)]
-enum vm_mmap_style {
-	VM_MMAP_NONE,		/* no mapping */
-	VM_MMAP_ALL,		/* fully and statically mapped */
-	VM_MMAP_SPARSE,		/* mappings created on-demand */
-}
+    pub fn set_x2apic_state(&self, vcpu_id: i32, enable: bool) -> Result<bool, Error> {
+        let state = match enable {
+            true => x2apic_state::X2APIC_ENABLED,
+            false => x2apic_state::X2APIC_DISABLED
+        };
 
-// 'flags' value passed to 'vm_set_memflags()'.
-//const VM_MEM_F_INCORE: i32 = 0x01;    // include guest memory in core file
-const VM_MEM_F_WIRED: i32 = 0x02;	// guest memory is wired
+        // Struct is allocated (and owned) by Rust
+        let x2apic_data = vm_x2apic {
+            cpuid: vcpu_id,
+            state: state,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SET_X2APIC_STATE, &x2apic_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_SET_X2APIC_STATE", size_of::<vm_x2apic>()));
+        }
+    }
 
-/// Identifiers for memory segments, both system memory and devmem segments.
-#[repr(C)]
-#[allow(non_camel_case_types, unused)]
-#[derive(Debug, Copy, Clone)]
-pub enum MemSegId{
-        VM_LOWMEM = 0,
-        VM_HIGHMEM = 1,
-        VM_BOOTROM = 2,
-        VM_FRAMEBUFFER = 3,
-}
+    pub fn get_x2apic_state(&self, vcpu_id: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut x2apic_data = vm_x2apic {
+            cpuid: vcpu_id,
+            ..Default::default()
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_X2APIC_STATE, &mut x2apic_data) };
+        if result == 0 {
+            match x2apic_data.state {
+                x2apic_state::X2APIC_ENABLED => return Ok(true),
+                x2apic_state::X2APIC_DISABLED => return Ok(false),
+                x2apic_state::X2APIC_STATE_LAST => return Err(Error::new(EINVAL)),
+            }
+        } else {
+            return Err(Error::ioctl("VM_GET_X2APIC_STATE", size_of::<vm_x2apic>()));
+        }
+    }
 
-/// Reasons for virtual machine exits.
-///
-/// The exit reasons are mapped to the `VM_EXIT_*` defines in `machine/vmm.h`.
-///
-#[derive(Debug)]
-pub enum VmExit {
-    IoIn(u16 /* port */, u16 /* bytes */),
-    IoOut(u16 /* port */, u16 /* bytes */, u32 /* value */),
-    IoInStr(u16 /* port */, u16 /* bytes */, u64 /* index */, u64 /* count */, bool /* repeat */),
-    IoOutStr(u16 /* port */, u16 /* bytes */, u64 /* index */, u64 /* count */, bool /* repeat */),
-    Vmx(i32 /* status */, u32 /* exit reason */, u64 /* exit qualification */, i32 /* instruction type */, i32 /* instruction error */),
-    Bogus,
-    RdMsr,
-    WrMsr,
+    /// Gets the set of VCPUs that have been activated.
+    pub fn get_active_cpus(&self) -> Result<CpuSet, Error> {
+        self.get_cpus(VM_ACTIVE_CPUS)
+    }
+
+    /// Gets the set of VCPUs that are suspended, either individually with
+    /// `suspend_vcpu()` or because the VM is suspended.
+    pub fn get_suspended_cpus(&self) -> Result<CpuSet, Error> {
+        self.get_cpus(VM_SUSPENDED_CPUS)
+    }
+
+    /// Gets the set of VCPUs that are suspended for debugging.
+    pub fn get_debug_cpus(&self) -> Result<CpuSet, Error> {
+        self.get_cpus(VM_DEBUG_CPUS)
+    }
+
+    fn get_cpus(&self, which: i32) -> Result<CpuSet, Error> {
+        // Buffer is allocated (and owned) by Rust, but modified by C
+        let mut bits = [0u64; CPUSET_WORDS];
+        let cpuset_data = vm_cpuset {
+            which: which,
+            cpusetsize: size_of::<[u64; CPUSET_WORDS]>() as i32,
+            cpus: bits.as_mut_ptr() as *mut c_void,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_CPUS, &cpuset_data) };
+        if result == 0 {
+            return Ok(CpuSet::from_bits(bits));
+        } else {
+            return Err(Error::ioctl("VM_GET_CPUS", size_of::<vm_cpuset>()));
+        }
+    }
+
+    /// From Intel Vol 3a:
+    /// Table 9-1. IA-32 Processor States Following Power-up, Reset or INIT
+    pub fn vcpu_reset(&self, vcpu_id: i32) -> Result<bool, Error> {
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RFLAGS, 0x2)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RIP, 0xfff0)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CR0, CR0_NE)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CR3, 0)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CR4, 0)?;
+
+        // CS: present, r/w, accessed, 16-bit, byte granularity, usable
+	let cs_base = 0xffff0000;
+	let cs_limit = 0xffff;
+	let cs_access = 0x0093;
+        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_CS, cs_base, cs_limit, cs_access)?;
+
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CS, 0xf000)?;
+
+
+        // SS,DS,ES,FS,GS: present, r/w, accessed, 16-bit, byte granularity
+	let desc_base = 0;
+	let desc_limit = 0xffff;
+	let desc_access = 0x0093;
+        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_SS, desc_base, desc_limit, desc_access)?;
+        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_DS, desc_base, desc_limit, desc_access)?;
+        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_ES, desc_base, desc_limit, desc_access)?;
+        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_FS, desc_base, desc_limit, desc_access)?;
+        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_GS, desc_base, desc_limit, desc_access)?;
+
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_SS, 0)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_DS, 0)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_ES, 0)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_FS, 0)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_GS, 0)?;
+
+        // General purpose registers
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RAX, 0)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RBX, 0)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RCX, 0)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RDX, 0xf00)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RSI, 0)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RDI, 0)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RBP, 0)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RSP, 0)?;
+
+
+        // GDTR, IDTR
+        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_GDTR, 0, 0xffff, 0)?;
+        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_IDTR, 0, 0xffff, 0)?;
+
+        // TR
+        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_TR, 0, 0, 0x0000008b)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_TR, 0)?;
+
+        // LDTR
+        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_LDTR, 0, 0xffff, 0x00000082)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_LDTR, 0)?;
+
+        Ok(true)
+    }
+
+    /// Suspends a Virtual CPU on the VirtualMachine.
+    pub fn suspend_vcpu(&self, vcpu_id: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let cpu_data = vm_activate_cpu { vcpuid: vcpu_id };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SUSPEND_CPU, &cpu_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_SUSPEND_CPU", size_of::<vm_activate_cpu>()));
+        }
+    }
+
+    /// Resumes a Virtual CPU on the VirtualMachine.
+    pub fn resume_vcpu(&self, vcpu_id: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let cpu_data = vm_activate_cpu { vcpuid: vcpu_id };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_RESUME_CPU, &cpu_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_RESUME_CPU", size_of::<vm_activate_cpu>()));
+        }
+    }
+
+    /// Runs the VirtualMachine, and returns an exit reason.
+    ///
+    /// If the run is interrupted before the VCPU exits, for example by a
+    /// signal delivered to the calling thread, `VmExit::Interrupted` is
+    /// returned so the caller can check for pending work and run again.
+    pub fn run(&self, vcpu_id: i32) -> Result<VmExit, Error> {
+        self.run_info(vcpu_id).map(|info| info.exit)
+    }
+
+    /// Runs the VCPU as `run()` does, and returns the exit reason along with
+    /// the guest RIP and instruction length at the exit, for run loops that
+    /// need to advance past or report the exiting instruction. The exit is
+    /// also logged at `LogLevel::Debug` to the sink set with
+    /// `set_log_sink()`, if any.
+    ///
+    /// An interrupted run reports a RIP and instruction length of zero.
+    pub fn run_info(&self, vcpu_id: i32) -> Result<VmExitInfo, Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut run_data = vm_run {
+            cpuid: vcpu_id,
+            ..Default::default()
+        };
+        let hooks = self.run_hooks.read().unwrap().clone();
+        if let Some(ref hooks) = hooks {
+            hooks.before_entry(vcpu_id);
+        }
+        let entered = Instant::now();
+        // Safe because gethrtime() takes no arguments and cannot fail
+        let entry_time = unsafe { gethrtime() };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_RUN, &mut run_data) };
+        let exit_time = unsafe { gethrtime() };
+        if let Some(clock) = self.clock(vcpu_id) {
+            let exitcode = match result {
+                0 => Some(run_data.vm_exit.exitcode),
+                _ => None,
+            };
+            clock.account(entry_time, exit_time, exitcode);
+        }
+        // Capture errno before the hooks have a chance to change it
+        let run_error = match result {
+            0 => None,
+            _ => Some(Error::ioctl("VM_RUN", size_of::<vm_run>())),
+        };
+        if let Some(ref hooks) = hooks {
+            let exitcode = match result {
+                0 => Some(run_data.vm_exit.exitcode),
+                _ => None,
+            };
+            hooks.after_exit(vcpu_id, exitcode, entered, entered.elapsed());
+        }
+        if let Some(err) = run_error {
+            match err.errno() {
+                EINTR | EAGAIN => return Ok(VmExitInfo { rip: 0, inst_length: 0, exit: VmExit::Interrupted }),
+                _ => return Err(err),
+            }
+        } else {
+            self.count_exit(vcpu_id, run_data.vm_exit.exitcode, exit_time);
+            self.log(LogLevel::Debug, || {
+                format!("vcpu {} exited with {:?} at rip {:#x}", run_data.cpuid,
+                        run_data.vm_exit.exitcode, run_data.vm_exit.rip)
+            });
+            let exit = decode_exit(&run_data.vm_exit)?;
+            self.emit_exit_event(&exit);
+            return Ok(VmExitInfo {
+                rip: run_data.vm_exit.rip,
+                inst_length: run_data.vm_exit.inst_length,
+                exit: exit,
+            });
+        }
+    }
+
+    /// Installs instrumentation callbacks to be invoked by `run()` on every
+    /// VCPU, replacing any previously installed hooks. Passing 'None'
+    /// removes the hooks.
+    pub fn set_run_hooks(&self, hooks: Option<Arc<dyn RunHooks>>) {
+        *self.run_hooks.write().unwrap() = hooks;
+    }
+
+    /// Installs the destination for diagnostic messages from this
+    /// VirtualMachine, replacing any previous one. Passing 'None', the
+    /// default, discards them.
+    pub fn set_log_sink(&self, sink: Option<Arc<dyn LogSink>>) {
+        *self.log_sink.write().unwrap() = sink;
+    }
+
+    // Sends the message built by 'message' to the log sink, if one is
+    // installed and wants messages at 'level'.
+    pub(crate) fn log<F>(&self, level: LogLevel, message: F) where F: FnOnce() -> String {
+        if let Some(ref sink) = *self.log_sink.read().unwrap() {
+            if sink.enabled(level) {
+                sink.log(level, &message());
+            }
+        }
+    }
+
+    // Sends the lifecycle event for 'exit', if it has one. Every VCPU exits
+    // when the VM is suspended, but the event is only sent once.
+    fn emit_exit_event(&self, exit: &VmExit) {
+        if let VmExit::Suspended(reason) = *exit {
+            if !self.suspend_reported.swap(true, Ordering::SeqCst) {
+                self.events.emit(VmEvent::Suspended(reason));
+            }
+        }
+    }
+
+    // Records an exit in the per-VCPU exit counters, along with the time
+    // VM_RUN returned.
+    fn count_exit(&self, vcpu_id: i32, code: vm_exitcode, exit_time: hrtime_t) {
+        if vcpu_id >= 0 && (vcpu_id as usize) < VM_MAXCPU {
+            let index = vcpu_id as usize * NUM_EXITCODES + code as usize;
+            self.exit_counts[index].fetch_add(1, Ordering::Relaxed);
+            self.exit_times[vcpu_id as usize].store(exit_time, Ordering::Relaxed);
+        }
+    }
+
+    /// Gets the high-resolution host time, as returned by `gethrtime()`, at
+    /// which VM_RUN last returned with an exit on the VCPU, or 0 if it has
+    /// not exited yet. Device emulation that is sensitive to latency, such
+    /// as timers, can compare this with the current `gethrtime()` to
+    /// compensate for the time taken to handle the exit, using `hrtime()`.
+    pub fn last_exit_time(&self, vcpu_id: i32) -> Result<i64, Error> {
+        if vcpu_id < 0 || vcpu_id as usize >= VM_MAXCPU {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(self.exit_times[vcpu_id as usize].load(Ordering::Relaxed))
+    }
+
+    /// Gets the number of exits of each type seen by `run()` on the VCPU
+    /// since the VirtualMachine was opened. These are kept by the library,
+    /// so only exits from runs through this VirtualMachine are counted.
+    pub fn exit_counters(&self, vcpu_id: i32) -> Result<ExitCounters, Error> {
+        if vcpu_id < 0 || vcpu_id as usize >= VM_MAXCPU {
+            return Err(Error::new(EINVAL));
+        }
+        let row = vcpu_id as usize * NUM_EXITCODES;
+        let mut counts = [0; NUM_EXITCODES];
+        for (count, counter) in counts.iter_mut().zip(&self.exit_counts[row..row + NUM_EXITCODES]) {
+            *count = counter.load(Ordering::Relaxed);
+        }
+        Ok(ExitCounters { counts: counts })
+    }
+
+    /// Returns how the time of the VCPU identified by 'vcpu_id' has been
+    /// split between running in VM_RUN and handling exits in userspace,
+    /// since the virtual machine was opened.
+    pub fn run_times(&self, vcpu_id: i32) -> Result<RunTimes, Error> {
+        match self.clock(vcpu_id) {
+            Some(clock) => Ok(clock.times()),
+            None => Err(Error::new(EINVAL)),
+        }
+    }
+
+    fn clock(&self, vcpu_id: i32) -> Option<&RunClock> {
+        if vcpu_id < 0 {
+            return None;
+        }
+        self.clocks.get(vcpu_id as usize)
+    }
+
+    /// Resets the VirtualMachine.
+    pub fn reset(&self) -> Result<i32, Error> {
+        let suspend_data = vm_suspend { how: vm_suspend_how::VM_SUSPEND_RESET };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SUSPEND, &suspend_data) };
+        if result == 0 {
+            return Ok(result);
+        } else {
+            return Err(Error::ioctl("VM_SUSPEND", size_of::<vm_suspend>()));
+        }
+    }
+
+    /// Halts the VirtualMachine.
+    pub fn halt(&self) -> Result<i32, Error> {
+        let suspend_data = vm_suspend { how: vm_suspend_how::VM_SUSPEND_HALT };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SUSPEND, &suspend_data) };
+        if result == 0 {
+            return Ok(result);
+        } else {
+            return Err(Error::ioctl("VM_SUSPEND", size_of::<vm_suspend>()));
+        }
+    }
+
+    /// Suspends the VirtualMachine with power off.
+    pub fn poweroff(&self) -> Result<i32, Error> {
+        let suspend_data = vm_suspend { how: vm_suspend_how::VM_SUSPEND_POWEROFF };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SUSPEND, &suspend_data) };
+        if result == 0 {
+            return Ok(result);
+        } else {
+            return Err(Error::ioctl("VM_SUSPEND", size_of::<vm_suspend>()));
+        }
+    }
+
+    /// Suspends the VirtualMachine with triple fault.
+    pub fn triplefault(&self) -> Result<i32, Error> {
+        let suspend_data = vm_suspend { how: vm_suspend_how::VM_SUSPEND_TRIPLEFAULT };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SUSPEND, &suspend_data) };
+        if result == 0 {
+            return Ok(result);
+        } else {
+            return Err(Error::ioctl("VM_SUSPEND", size_of::<vm_suspend>()));
+        }
+    }
+
+    /// Reinitializes the VirtualMachine.
+    ///
+    /// Fails with `EBUSY` if any guest memory is borrowed for DMA, since
+    /// reinitializing removes devmem mappings such as the bootrom.
+    pub fn reinit(&self) -> Result<i32, Error> {
+        let _dma = self.memory.lock_dma_free(0, !0)?;
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_REINIT) };
+        if result == 0 {
+            self.suspend_reported.store(false, Ordering::SeqCst);
+            return Ok(result);
+        } else {
+            return Err(Error::ioctl("VM_REINIT", 0));
+        }
+    }
+
+    /// Reinitializes the VirtualMachine, as with `reinit()`, and restores
+    /// the state set up through this handle that the kernel discards:
+    /// the guest mappings of the regions in `regions()` that the kernel
+    /// removed are reissued (system memory stays mapped across a reset, but
+    /// devmem mappings such as the bootrom are dropped), VCPUs activated
+    /// with `activate_vcpu()` are activated again, and capabilities set with
+    /// `set_capability()` are then set to their last values.
+    ///
+    /// Memory in the balloon is mapped into the guest again, as with
+    /// `deflate_balloon()`, since the guest's balloon driver starts again
+    /// from nothing.
+    ///
+    /// VCPU registers are left in their reset state, so the caller only has
+    /// to load the boot state before running the guest again.
+    pub fn reinit_full(&self) -> Result<i32, Error> {
+        let result = self.reinit()?;
+        self.restore_balloon()?;
+
+        for region in self.regions() {
+            if self.find_mapping(region.gpa, region.len)?.is_none() {
+                self.mmap_memseg(region.gpa, region.segid, region.segoff, region.len as usize, region.prot)?;
+            }
+        }
+        let active_vcpus = self.active_vcpus.lock().unwrap().clone();
+        for vcpu_id in active_vcpus {
+            self.activate_vcpu(vcpu_id)?;
+        }
+        // Capabilities are set on active VCPUs, as when the VM was set up
+        for (vcpu_id, cap, val) in self.recorded_capabilities() {
+            self.set_capability(vcpu_id, cap, val)?;
+        }
+        Ok(result)
+    }
+
+    // The last value set with `set_capability()`, per VCPU and capability.
+    pub(crate) fn recorded_capabilities(&self) -> Vec<(i32, vm_cap_type, i32)> {
+        self.capabilities.lock().unwrap().clone()
+    }
+
+    /// Get the value of an optional capability on the VCPU
+    pub fn get_capability(&self, vcpu_id: i32, cap: vm_cap_type) -> Result<i32, Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut cap_data = vm_capability {
+            cpuid: vcpu_id,
+            captype: cap,
+            ..Default::default()
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_CAPABILITY, &mut cap_data) };
+        if result == 0 {
+            return Ok(cap_data.capval);
+        } else {
+            return Err(Error::ioctl("VM_GET_CAPABILITY", size_of::<vm_capability>()));
+        }
+    }
+
+    /// Set the value of an optional capability on the VCPU
+    pub fn set_capability(&self, vcpu_id: i32, cap: vm_cap_type, val: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let cap_data = vm_capability {
+            cpuid: vcpu_id,
+            captype: cap,
+            capval: val,
+            ..Default::default()
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SET_CAPABILITY, &cap_data) };
+        if result == 0 {
+            record_capability(&mut self.capabilities.lock().unwrap(), vcpu_id, cap, val);
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_SET_CAPABILITY", size_of::<vm_capability>()));
+        }
+    }
+
+    /// Sets the capability 'C' on the VCPU to a value of its type.
+    pub fn set_cap<C: Capability>(&self, vcpu_id: i32, value: C::Value) -> Result<bool, Error> {
+        self.set_capability(vcpu_id, C::cap(), value.to_raw())
+    }
+
+    /// Gets the value of the capability 'C' on the VCPU.
+    pub fn get_cap<C: Capability>(&self, vcpu_id: i32) -> Result<C::Value, Error> {
+        let raw = self.get_capability(vcpu_id, C::cap())?;
+        Ok(C::Value::from_raw(raw))
+    }
+
+    /// Sets the capability 'cap' on the VCPU to 'value', returning `EINVAL`
+    /// if it isn't the kind of value the capability takes.
+    pub fn set_capability_value(&self, vcpu_id: i32, cap: vm_cap_type, value: CapValue) -> Result<bool, Error> {
+        let raw = value.to_raw(cap)?;
+        self.set_capability(vcpu_id, cap, raw)
+    }
+
+    /// Gets the value of the capability 'cap' on the VCPU.
+    pub fn get_capability_value(&self, vcpu_id: i32, cap: vm_cap_type) -> Result<CapValue, Error> {
+        let raw = self.get_capability(vcpu_id, cap)?;
+        Ok(CapValue::from_raw(cap, raw))
+    }
+
+    /// Enables MTRAP exits on the VCPU, returning a trace that collects the
+    /// guest instruction pointer from each MTRAP exit into a ring buffer of
+    /// 'capacity' records. See the `trace` module.
+    pub fn start_mtrap_trace(&self, vcpu_id: i32, capacity: usize) -> Result<MtrapTrace, Error> {
+        MtrapTrace::start(self, vcpu_id, capacity)
+    }
+
+    /// Enables MTRAP exits on the VCPU, returning a stepper that runs it for
+    /// bounded numbers of guest instructions. See the `trace` module for how
+    /// exact the counts are.
+    pub fn start_stepper(&self, vcpu_id: i32) -> Result<InstructionStepper<'_>, Error> {
+        InstructionStepper::start(self, vcpu_id)
+    }
+
+    /// Enables PAUSE exits on the VCPU, returning a handler that applies
+    /// 'policy' to each one. See the `policy` module.
+    pub fn enable_pause_exits(&self, vcpu_id: i32, policy: PausePolicy) -> Result<PauseExits, Error> {
+        PauseExits::enable(self, vcpu_id, policy)
+    }
+
+    /// Gathers the general purpose, control, debug, and segment registers,
+    /// EFER, and pending event injection of the VCPU into one structure,
+    /// which displays in the style of `bhyvectl --get-all`.
+    pub fn dump_vcpu(&self, vcpu_id: i32) -> Result<VcpuDump, Error> {
+        VcpuDump::capture(self, vcpu_id)
+    }
+
+    /// Set interrupt info on the VCPU
+    pub fn set_intinfo(&self, vcpu_id: i32, info1: u64) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let intinfo_data = vm_intinfo {
+            vcpuid: vcpu_id,
+            info1: info1,
+            ..Default::default()
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SET_INTINFO, &intinfo_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_SET_INTINFO", size_of::<vm_intinfo>()));
+        }
+    }
+
+    /// Get the interrupt info on the VCPU
+    pub fn get_intinfo(&self, vcpu_id: i32) -> Result<(u64, u64), Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut intinfo_data = vm_intinfo {
+            vcpuid: vcpu_id,
+            ..Default::default()
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_INTINFO, &mut intinfo_data) };
+        if result == 0 {
+            return Ok((intinfo_data.info1, intinfo_data.info2));
+        } else {
+            return Err(Error::ioctl("VM_GET_INTINFO", size_of::<vm_intinfo>()));
+        }
+    }
+
+    /// Inject an exception on the VCPU
+    pub fn inject_exception(&self, vcpu_id: i32, vector: i32, valid: i32, errcode: u32, restart: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let exc_data = vm_exception {
+            cpuid: vcpu_id,
+            vector: vector,
+            error_code: errcode,
+            error_code_valid: valid,
+            restart_instruction: restart,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_INJECT_EXCEPTION, &exc_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_INJECT_EXCEPTION", size_of::<vm_exception>()));
+        }
+    }
+
+    /// Inject non-maskable interrupt (NMI) on the VCPU
+    pub fn inject_nmi(&self, vcpu_id: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let nmi_data = vm_nmi {
+            cpuid: vcpu_id,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_INJECT_NMI, &nmi_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_INJECT_NMI", size_of::<vm_nmi>()));
+        }
+    }
+
+    /// Signal to the Local Advanced Programmable Interrupt Controller (LAPIC)
+    /// that an interrupt request (IRQ) at 'vector' needs to be sent to the VCPU
+    /// identified by 'vcpu_id'. The state of the interrupt request is recorded in
+    /// the LAPIC interrupt request register (IRR).
+    pub fn lapic_irq(&self, vcpu_id: i32, vector: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let irq_data = vm_lapic_irq {
+            cpuid: vcpu_id,
+            vector: vector,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_LAPIC_IRQ, &irq_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_LAPIC_IRQ", size_of::<vm_lapic_irq>()));
+        }
+    }
+
+    /// Trigger an interrupt request (IRQ) according to the local vector table
+    /// (LVT) on the Local Advanced Programmable Interrupt Controller (LAPIC)
+    /// for the VCPU identified by 'vcpu_id'. The 'vcpu_id' can be set to -1 to
+    /// trigger the interrupt on all VCPUs.
+    pub fn lapic_local_irq(&self, vcpu_id: i32, vector: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let irq_data = vm_lapic_irq {
+            cpuid: vcpu_id,
+            vector: vector,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_LAPIC_LOCAL_IRQ, &irq_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_LAPIC_LOCAL_IRQ", size_of::<vm_lapic_irq>()));
+        }
+    }
+
+    /// Signal an interrupt using Message Signaled Interrupts (MSI)
+    pub fn lapic_msi(&self, addr: u64, msg: u64) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let msi_data = vm_lapic_msi {
+            msg: msg,
+            addr: addr,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_LAPIC_MSI, &msi_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_LAPIC_MSI", size_of::<vm_lapic_msi>()));
+        }
+    }
+
+    /// Set the I/O APIC pin state for an interrupt request (IRQ) on the VM to true.
+    pub fn ioapic_assert_irq(&self, irq: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let irq_data = vm_ioapic_irq {
+            irq: irq,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_IOAPIC_ASSERT_IRQ, &irq_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_IOAPIC_ASSERT_IRQ", size_of::<vm_ioapic_irq>()));
+        }
+    }
+
+    /// Set the I/O APIC pin state for an interrupt request (IRQ) on the VM to false.
+    pub fn ioapic_deassert_irq(&self, irq: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let irq_data = vm_ioapic_irq {
+            irq: irq,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_IOAPIC_DEASSERT_IRQ, &irq_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_IOAPIC_DEASSERT_IRQ", size_of::<vm_ioapic_irq>()));
+        }
+    }
+
+    /// Set the I/O APIC pin state for an interrupt request (IRQ) on the VM to
+    /// true and then false (a "pulse").
+    pub fn ioapic_pulse_irq(&self, irq: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let irq_data = vm_ioapic_irq {
+            irq: irq,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_IOAPIC_PULSE_IRQ, &irq_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_IOAPIC_PULSE_IRQ", size_of::<vm_ioapic_irq>()));
+        }
+    }
+
+    /// Set the state of a legacy ISA interrupt request (IRQ) on the VM to true.
+    /// The IRQ is raised on both the 8259 PIC line 'atpic_irq' and the I/O
+    /// APIC pin 'ioapic_irq'. Either can be set to -1 to skip that controller.
+    pub fn isa_assert_irq(&self, atpic_irq: i32, ioapic_irq: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let irq_data = vm_isa_irq {
+            atpic_irq: atpic_irq,
+            ioapic_irq: ioapic_irq,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_ISA_ASSERT_IRQ, &irq_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_ISA_ASSERT_IRQ", size_of::<vm_isa_irq>()));
+        }
+    }
+
+    /// Set the state of a legacy ISA interrupt request (IRQ) on the VM to false,
+    /// on the 8259 PIC line 'atpic_irq' and the I/O APIC pin 'ioapic_irq'.
+    pub fn isa_deassert_irq(&self, atpic_irq: i32, ioapic_irq: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let irq_data = vm_isa_irq {
+            atpic_irq: atpic_irq,
+            ioapic_irq: ioapic_irq,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_ISA_DEASSERT_IRQ, &irq_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_ISA_DEASSERT_IRQ", size_of::<vm_isa_irq>()));
+        }
+    }
+
+    /// Set the state of a legacy ISA interrupt request (IRQ) on the VM to true
+    /// and then false (a "pulse"), on the 8259 PIC line 'atpic_irq' and the
+    /// I/O APIC pin 'ioapic_irq'.
+    pub fn isa_pulse_irq(&self, atpic_irq: i32, ioapic_irq: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let irq_data = vm_isa_irq {
+            atpic_irq: atpic_irq,
+            ioapic_irq: ioapic_irq,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_ISA_PULSE_IRQ, &irq_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_ISA_PULSE_IRQ", size_of::<vm_isa_irq>()));
+        }
+    }
+
+    /// Set the trigger mode of the 8259 PIC line 'atpic_irq' to level
+    /// triggered if 'level_triggered' is true, or to edge triggered,
+    /// through the PIC's edge/level control registers (ELCR).
+    pub fn isa_set_irq_trigger(&self, atpic_irq: i32, level_triggered: bool) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let trigger_data = vm_isa_irq_trigger {
+            atpic_irq: atpic_irq,
+            trigger: match level_triggered {
+                true => vm_intr_trigger::LEVEL_TRIGGER,
+                false => vm_intr_trigger::EDGE_TRIGGER,
+            },
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_ISA_SET_IRQ_TRIGGER, &trigger_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_ISA_SET_IRQ_TRIGGER", size_of::<vm_isa_irq_trigger>()));
+        }
+    }
+
+    /// Get the capabilities of the in-kernel HPET, as the lower 32 bits of
+    /// its General Capabilities and ID register.
+    pub fn get_hpet_capabilities(&self) -> Result<u32, Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut hpet_data = vm_hpet_cap::default();
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_HPET_CAPABILITIES, &mut hpet_data) };
+        if result == 0 {
+            return Ok(hpet_data.capabilities);
+        } else {
+            return Err(Error::ioctl("VM_GET_HPET_CAPABILITIES", size_of::<vm_hpet_cap>()));
+        }
+    }
+
+    /// Queries the in-kernel HPET and decides whether to advertise it to the
+    /// guest. Returns the HPET configuration, including a matching ACPI HPET
+    /// table, or 'None' if the kernel doesn't emulate a usable HPET. See the
+    /// `hpet` module.
+    pub fn configure_hpet(&self) -> Result<Option<HpetConfig>, Error> {
+        HpetConfig::probe(self)
+    }
+
+    /// Get the I/O APIC pincount for the VM
+    pub fn ioapic_pincount(&self) -> Result<i32, Error> {
+        // Integer is allocated (and owned) by Rust, but modified by C
+        let mut pincount: i32 = 0;
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_IOAPIC_PINCOUNT, &mut pincount) };
+        if result == 0 {
+            return Ok(pincount);
+        } else {
+            return Err(Error::ioctl("VM_IOAPIC_PINCOUNT", size_of::<i32>()));
+        }
+    }
+
+    /// Completes the IN instruction of 'request', an `InOut` exit on the
+    /// VCPU, by placing 'value' in the low bytes of RAX as the instruction
+    /// would. The guest sees the value when the VCPU is next run. Returns
+    /// `EINVAL` for OUT and string requests, which have nothing to complete.
+    pub fn complete_inout(&self, vcpu_id: i32, request: &InOutRequest, value: u32) -> Result<(), Error> {
+        if !request.is_in() || request.string {
+            return Err(Error::new(EINVAL));
+        }
+        let rax = self.get_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RAX)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RAX, merge_rax(rax, request.bytes, value))?;
+        Ok(())
+    }
+
+    /// Translates the guest buffer of the next 'count' elements of the
+    /// string I/O instruction described by 'request' and 'operands', as
+    /// `gla_segments()` does, checking for write access for INS and read
+    /// access for OUTS. Returns 'None' if the guest would fault, in which
+    /// case the fault has been injected and the exit must not be completed;
+    /// this includes a buffer outside the segment limit, which raises #GP,
+    /// or #SS for the stack segment. Returns `EINVAL` if the buffer wraps
+    /// around the address size.
+    pub fn string_io_segments(&self, vcpu_id: i32, request: &InOutRequest, operands: &StringIo, count: u64) -> Result<Option<Vec<GuestSegment>>, Error> {
+        if !operands.in_segment_limit(request.bytes, count) {
+            let vector = match operands.segment {
+                vm_reg_name::VM_REG_GUEST_SS => IDT_SS,
+                _ => IDT_GP,
+            };
+            self.inject_exception(vcpu_id, vector, 1, 0, 1)?;
+            return Ok(None);
+        }
+        let (gla, len) = match operands.linear_range(request.bytes, count) {
+            Some(range) => range,
+            None => return Err(Error::new(EINVAL)),
+        };
+        let prot = match request.direction {
+            IoDirection::In => libc::PROT_WRITE,
+            IoDirection::Out => libc::PROT_READ,
+        };
+        self.gla_segments(vcpu_id, &operands.paging, gla, len, prot)
+    }
+
+    /// Completes 'done' elements of the string I/O instruction described by
+    /// 'request' and 'operands', once the data has been moved, by stepping
+    /// RDI or RSI past them, and counting them off RCX for REP. If elements
+    /// are left, the instruction is restarted so the VCPU exits again for
+    /// the rest. Returns true if the instruction is complete.
+    pub fn complete_inout_str(&self, vcpu_id: i32, request: &InOutRequest, operands: &StringIo, done: u64) -> Result<bool, Error> {
+        if !request.string || done > operands.count {
+            return Err(Error::new(EINVAL));
+        }
+        let index_reg = match request.direction {
+            IoDirection::In => vm_reg_name::VM_REG_GUEST_RDI,
+            IoDirection::Out => vm_reg_name::VM_REG_GUEST_RSI,
+        };
+        let mut step = (done * request.bytes as u64) as i64;
+        if operands.is_reverse() {
+            step = -step;
+        }
+        let index = self.get_register(vcpu_id, index_reg)?;
+        self.set_register(vcpu_id, index_reg, step_register(index, step, operands.addrsize))?;
+
+        let remaining = operands.count - done;
+        if request.rep {
+            let rcx = self.get_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RCX)?;
+            self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RCX, step_register(rcx, -(done as i64), operands.addrsize))?;
+        }
+        if remaining > 0 {
+            self.restart_instruction(vcpu_id)?;
+        }
+        Ok(remaining == 0)
+    }
+
+    /// Restart the current instruction on the VCPU
+    pub fn restart_instruction(&self, vcpu_id: i32) -> Result<bool, Error> {
+        // Integer is allocated (and owned) by Rust
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_RESTART_INSTRUCTION, &vcpu_id) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_RESTART_INSTRUCTION", size_of::<i32>()));
+        }
+    }
+
+    /// Binds the PCI passthrough device open as 'pptfd' to the virtual
+    /// machine, so its MMIO ranges and interrupts can be routed to the
+    /// guest. The device must have been assigned to the ppt driver on the
+    /// host, and can only be bound to one virtual machine at a time.
+    pub fn bind_pptdev(&self, pptfd: RawFd) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let ppt_data = vm_pptdev {
+            pptfd: pptfd,
+        };
+
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_BIND_PPTDEV, &ppt_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_BIND_PPTDEV", size_of::<vm_pptdev>()));
+        }
+    }
+
+    /// Unbinds the PCI passthrough device open as 'pptfd' from the virtual
+    /// machine, removing its MMIO mappings and disabling its interrupts.
+    pub fn unbind_pptdev(&self, pptfd: RawFd) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let ppt_data = vm_pptdev {
+            pptfd: pptfd,
+        };
+
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_UNBIND_PPTDEV, &ppt_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_UNBIND_PPTDEV", size_of::<vm_pptdev>()));
+        }
+    }
+
+    /// Maps [hpa,hpa+len) of a BAR of the bound passthrough device 'pptfd'
+    /// into the guest physical address space at 'gpa', so the guest reaches
+    /// the device registers without exits. The addresses and length must be
+    /// page aligned.
+    pub fn map_pptdev_mmio(&self, pptfd: RawFd, gpa: u64, hpa: u64, len: usize) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let mmio_data = vm_pptdev_mmio {
+            pptfd: pptfd,
+            gpa: gpa,
+            hpa: hpa,
+            len: len,
+        };
+
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_MAP_PPTDEV_MMIO, &mmio_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_MAP_PPTDEV_MMIO", size_of::<vm_pptdev_mmio>()));
+        }
+    }
+
+    /// Sets up 'numvec' MSI vectors of the bound passthrough device
+    /// 'pptfd', delivering them to the guest as messages 'msg' to 'addr',
+    /// as programmed by the guest in the device's MSI capability. A
+    /// 'numvec' of 0 disables MSI on the device.
+    pub fn pptdev_msi(&self, vcpu_id: i32, pptfd: RawFd, addr: u64, msg: u64, numvec: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let msi_data = vm_pptdev_msi {
+            vcpu: vcpu_id,
+            pptfd: pptfd,
+            numvec: numvec,
+            msg: msg,
+            addr: addr,
+        };
+
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_PPTDEV_MSI, &msi_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_PPTDEV_MSI", size_of::<vm_pptdev_msi>()));
+        }
+    }
+
+    /// Sets up entry 'idx' of the MSI-X table of the bound passthrough
+    /// device 'pptfd', delivering it to the guest as message 'msg' to
+    /// 'addr'. The entry is masked while bit 0 of 'vector_control' is set.
+    pub fn pptdev_msix(&self, vcpu_id: i32, pptfd: RawFd, idx: i32, addr: u64, msg: u64, vector_control: u32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let msix_data = vm_pptdev_msix {
+            vcpu: vcpu_id,
+            pptfd: pptfd,
+            idx: idx,
+            msg: msg,
+            vector_control: vector_control,
+            addr: addr,
+        };
+
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_PPTDEV_MSIX, &msix_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::ioctl("VM_PPTDEV_MSIX", size_of::<vm_pptdev_msix>()));
+        }
+    }
+
+    /// Gets the number of MSI and MSI-X vectors the host can allocate for
+    /// the passthrough device 'pptfd', which bounds what the emulated
+    /// capabilities should advertise to the guest.
+    pub fn get_pptdev_limits(&self, pptfd: RawFd) -> Result<PptLimits, Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut limits_data = vm_pptdev_limits {
+            pptfd: pptfd,
+            ..Default::default()
+        };
+
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_PPTDEV_LIMITS, &mut limits_data) };
+        if result == 0 {
+            return Ok(PptLimits { msi: limits_data.msi_limit, msix: limits_data.msix_limit });
+        } else {
+            return Err(Error::ioctl("VM_GET_PPTDEV_LIMITS", size_of::<vm_pptdev_limits>()));
+        }
+    }
+}
+
+impl Drop for VirtualMachine {
+    fn drop(&mut self) {
+        // Release the library's own host mappings of guest memory, which
+        // would otherwise keep the VM's memory alive after the handle is
+        // closed. Host memory supplied by the caller is theirs. Nothing can
+        // be borrowing guest memory for DMA once the VM is being dropped.
+        let _ = self.memory.teardown();
+    }
+}
+
+// Checks the interface version 'found' against the one this library's ioctl
+// structs were written for. Those are the structs of kernels that predate
+// interface versioning, so a kernel that doesn't report a version matches.
+fn check_interface_version(found: Option<i32>) -> Result<(), Error> {
+    match found {
+        Some(found) if found != VMM_LEGACY_INTERFACE_VERSION => {
+            Err(Error::AbiMismatch { expected: VMM_LEGACY_INTERFACE_VERSION, found: found })
+        }
+        _ => Ok(()),
+    }
+}
+
+// Counts the bytes in pages marked as resident in a mincore() vector, in
+// which the low bit of each entry is set for resident pages.
+fn count_resident<T: Copy + Into<i32>>(pages: &[T], page_size: usize) -> usize {
+    pages.iter().filter(|page| ((**page).into() & 1) != 0).count() * page_size
+}
+
+// Decodes the exit reported by VM_RUN, using the payload that goes with
+// its exit code.
+fn decode_exit(vm_exit: &vm_exit) -> Result<VmExit, Error> {
+    match vm_exit.exitcode {
+        vm_exitcode::VM_EXITCODE_INOUT => {
+            // Safe because the exit code told us which union field to use.
+            let io = unsafe { vm_exit.u.inout };
+            return Ok(VmExit::InOut(InOutRequest::from_inout(&io)));
+        }
+        vm_exitcode::VM_EXITCODE_INOUT_STR => {
+            // Safe because the exit code told us which union field to use.
+            let vis = unsafe { vm_exit.u.inout_str };
+            let io = vis.inout;
+
+            if !io.is_string() {
+                return Err(Error::new(EINVAL));
+            }
+
+            let mask: u64 = match vis.addrsize {
+                2 => 0xffff,
+                4 => 0xffffffff,
+                8 => 0xffffffffffffffff,
+                _ => return Err(Error::new(EINVAL))
+            };
+
+            let operands = StringIo {
+                paging: vis.paging,
+                rflags: vis.rflags,
+                cr0: vis.cr0,
+                index: vis.index & mask,
+                count: vis.count & mask,
+                addrsize: vis.addrsize as u8,
+                segment: vis.segname,
+                seg_base: vis.seg_desc.base,
+                seg_limit: vis.seg_desc.limit,
+                seg_access: vis.seg_desc.access,
+            };
+            return Ok(VmExit::InOutStr(InOutRequest::from_inout(&io), operands));
+        }
+        vm_exitcode::VM_EXITCODE_VMX => {
+            let status = unsafe { vm_exit.u.vmx.status };
+            let reason = unsafe { vm_exit.u.vmx.exit_reason };
+            let qual = unsafe { vm_exit.u.vmx.exit_qualification };
+            let inst_type = unsafe { vm_exit.u.vmx.inst_type };
+            let inst_error = unsafe { vm_exit.u.vmx.inst_error };
+            return Ok(VmExit::Vmx(status, reason, qual, inst_type, inst_error));
+        }
+        vm_exitcode::VM_EXITCODE_BOGUS => {
+            return Ok(VmExit::Bogus);
+        }
+        vm_exitcode::VM_EXITCODE_RDMSR => {
+            // Safe because the exit code told us which union field to use.
+            let msr = unsafe { vm_exit.u.msr };
+            return Ok(VmExit::RdMsr(msr.code));
+        }
+        vm_exitcode::VM_EXITCODE_WRMSR => {
+            // Safe because the exit code told us which union field to use.
+            let msr = unsafe { vm_exit.u.msr };
+            return Ok(VmExit::WrMsr(msr.code, msr.wval));
+        }
+        vm_exitcode::VM_EXITCODE_HLT => {
+            // Safe because the exit code told us which union field to use.
+            let hlt = unsafe { vm_exit.u.hlt };
+            return Ok(VmExit::Halt(hlt.rflags, hlt.intr_status));
+        }
+        vm_exitcode::VM_EXITCODE_MTRAP => {
+            return Ok(VmExit::Mtrap);
+        }
+        vm_exitcode::VM_EXITCODE_PAUSE => {
+            return Ok(VmExit::Pause);
+        }
+        vm_exitcode::VM_EXITCODE_PAGING => {
+            // Safe because the exit code told us which union field to use.
+            let paging = unsafe { vm_exit.u.paging };
+            return Ok(VmExit::Paging(paging.gpa, paging.fault_type));
+        }
+        vm_exitcode::VM_EXITCODE_INST_EMUL => {
+            let emul = unsafe { vm_exit.u.inst_emul };
+            let (bytes, len) = emul.inst_bytes();
+            let bitness = match emul.paging.cpu_mode {
+                vm_cpu_mode::CPU_MODE_64BIT => 64,
+                vm_cpu_mode::CPU_MODE_REAL => 16,
+                _ => if emul.cs_d != 0 { 32 } else { 16 },
+            };
+            let inst = FaultingInst {
+                rip: vm_exit.rip,
+                bitness: bitness,
+                bytes: bytes,
+                len: len as u8,
+            };
+            return Ok(VmExit::InstEmul(emul.gpa, emul.gla, emul.paging, inst));
+        }
+        vm_exitcode::VM_EXITCODE_SPINUP_AP => {
+            // Safe because the exit code told us which union field to use.
+            let spinup = unsafe { vm_exit.u.spinup_ap };
+            return Ok(VmExit::SpinupAp(spinup.vcpu, spinup.rip));
+        }
+        vm_exitcode::VM_EXITCODE_DEPRECATED1 => {
+            return Ok(VmExit::Deprecated);
+        }
+        vm_exitcode::VM_EXITCODE_RUNBLOCK => {
+            return Ok(VmExit::RunBlock);
+        }
+        vm_exitcode::VM_EXITCODE_IOAPIC_EOI => {
+            let ioapic = unsafe { vm_exit.u.ioapic_eoi };
+            return Ok(VmExit::IoapicEoi(ioapic.vector));
+        }
+        vm_exitcode::VM_EXITCODE_SUSPENDED => {
+            // Safe because the exit code told us which union field to use.
+            let suspended = unsafe { vm_exit.u.suspended };
+            let how = suspended.how;
+            return Ok(VmExit::Suspended(SuspendReason::from(how)));
+        }
+        vm_exitcode::VM_EXITCODE_TASK_SWITCH => {
+            // Safe because the exit code told us which union field to use.
+            let ts = unsafe { vm_exit.u.task_switch };
+            let errcode = match ts.errcode_valid {
+                0 => None,
+                _ => Some(ts.errcode),
+            };
+            return Ok(VmExit::TaskSwitch(ts.tsssel, TaskSwitchReason::from(ts.reason), errcode, ts.ext != 0, ts.paging));
+        }
+        vm_exitcode::VM_EXITCODE_MONITOR => {
+            return Ok(VmExit::Monitor);
+        }
+        vm_exitcode::VM_EXITCODE_MWAIT => {
+            return Ok(VmExit::Mwait);
+        }
+        vm_exitcode::VM_EXITCODE_SVM => {
+            let svm = unsafe { vm_exit.u.svm };
+            return Ok(VmExit::Svm(svm.exitcode, svm.exitinfo1, svm.exitinfo2));
+        }
+        vm_exitcode::VM_EXITCODE_REQIDLE => {
+            return Ok(VmExit::ReqIdle);
+        }
+        vm_exitcode::VM_EXITCODE_DEBUG => {
+            return Ok(VmExit::Debug);
+        }
+        vm_exitcode::VM_EXITCODE_VMINSN => {
+            return Ok(VmExit::VmInsn);
+        }
+        vm_exitcode::VM_EXITCODE_HT => {
+            return Ok(VmExit::Ht);
+        }
+        vm_exitcode::VM_EXITCODE_MAX => {
+            return Ok(VmExit::Max);
+        }
+    }
+}
+
+/// Checks that 'vcpu_id' is below VM_MAXCPU, and below 'maxcpus' if the
+/// topology of the VM reports a limit.
+fn valid_vcpu_id(vcpu_id: i32, maxcpus: Option<u16>) -> bool {
+    let limit = match maxcpus {
+        Some(maxcpus) if maxcpus != 0 => (maxcpus as usize).min(VM_MAXCPU),
+        _ => VM_MAXCPU,
+    };
+    vcpu_id >= 0 && (vcpu_id as usize) < limit
+}
+
+/// Copies the pages of 'src' that aren't all zero to 'dst', which starts
+/// out zeroed, returning the number of bytes copied.
+fn copy_nonzero_pages(src: &[u8], dst: &mut [u8], page_size: usize) -> usize {
+    let mut copied = 0;
+    for (from, to) in src.chunks(page_size).zip(dst.chunks_mut(page_size)) {
+        if from.iter().any(|byte| *byte != 0) {
+            to.copy_from_slice(from);
+            copied += from.len();
+        }
+    }
+    copied
+}
+
+/// Records 'val' as the value of capability 'cap' on the VCPU, replacing
+/// any value recorded before, for replay by `reinit_full()`.
+fn record_capability(capabilities: &mut Vec<(i32, vm_cap_type, i32)>, vcpu_id: i32, cap: vm_cap_type, val: i32) {
+    match capabilities.iter_mut().find(|(id, c, _)| *id == vcpu_id && *c as i32 == cap as i32) {
+        Some(entry) => entry.2 = val,
+        None => capabilities.push((vcpu_id, cap, val)),
+    }
+}
+
+/// Derives the paging state of a VCPU from its control registers, and the
+/// access rights of its code and stack segments.
+fn paging_state(cr0: u64, cr3: u64, cr4: u64, efer: u64, cs_access: u32, ss_access: u32) -> vm_guest_paging {
+    // CS.L, the 64-bit code segment flag
+    let cs_long = (cs_access & 0x2000) != 0;
+    let cpu_mode = if (efer & EFER_LMA) != 0 {
+        match cs_long {
+            true => vm_cpu_mode::CPU_MODE_64BIT,
+            false => vm_cpu_mode::CPU_MODE_COMPATIBILITY,
+        }
+    } else if (cr0 & CR0_PE) != 0 {
+        vm_cpu_mode::CPU_MODE_PROTECTED
+    } else {
+        vm_cpu_mode::CPU_MODE_REAL
+    };
+    let paging_mode = if (cr0 & CR0_PG) == 0 {
+        vm_paging_mode::PAGING_MODE_FLAT
+    } else if (cr4 & CR4_PAE) == 0 {
+        vm_paging_mode::PAGING_MODE_32
+    } else if (efer & EFER_LME) != 0 {
+        vm_paging_mode::PAGING_MODE_64
+    } else {
+        vm_paging_mode::PAGING_MODE_PAE
+    };
+    vm_guest_paging {
+        cr3: cr3,
+        // The current privilege level is the DPL of the stack segment
+        cpl: ((ss_access >> 5) & 0x3) as i32,
+        cpu_mode: cpu_mode,
+        paging_mode: paging_mode,
+    }
+}
+
+/// Returns the first region in 'regions' that overlaps 'region'.
+fn find_overlap(regions: &[GuestRegion], region: &GuestRegion) -> Option<GuestRegion> {
+    regions.iter().find(|r| r.overlaps(region.gpa, region.len)).cloned()
+}
+
+// Returns true if 'next' carries on from 'prev' in both the guest physical
+// address space and the same memory segment.
+pub(crate) fn continues(prev: &MemMap, next: &MemMap) -> bool {
+    prev.segid == next.segid && prev.flags == next.flags &&
+        prev.gpa + prev.len as u64 == next.gpa && prev.segoff + prev.len as i64 == next.segoff
+}
+
+// Plans the change of [gpa,gpa+len), which lies within 'map', to protection
+// 'prot', returning the mappings to replace and their replacements. The
+// mappings either side of 'map', 'prev' and 'next', are merged with the
+// changed range if it reaches them and they have the same protection.
+fn plan_protection(map: &MemMap, prev: Option<MemMap>, next: Option<MemMap>, gpa: u64, len: u64, prot: i32) -> (Vec<MemMap>, Vec<MemMap>) {
+    let end = map.gpa + map.len as u64;
+    let piece = |start: u64, size: u64, prot: i32| MemMap {
+        gpa: start,
+        segoff: map.segoff + (start - map.gpa) as i64,
+        len: size as usize,
+        prot: prot,
+        ..*map
+    };
+    let mut from = vec![*map];
+    let mut mid = piece(gpa, len, prot);
+    let mut to = Vec::with_capacity(3);
+    if gpa > map.gpa {
+        to.push(piece(map.gpa, gpa - map.gpa, map.prot));
+    } else if let Some(prev) = prev.filter(|prev| prev.prot == prot && continues(prev, map)) {
+        from.insert(0, prev);
+        mid = MemMap { gpa: prev.gpa, segoff: prev.segoff, len: prev.len + mid.len, ..mid };
+    }
+    let post = match next.filter(|next| next.prot == prot && continues(map, next)) {
+        Some(next) if gpa + len == end => {
+            from.push(next);
+            mid.len += next.len;
+            None
+        }
+        _ if gpa + len < end => Some(piece(gpa + len, end - gpa - len, map.prot)),
+        _ => None,
+    };
+    to.push(mid);
+    to.extend(post);
+    (from, to)
+}
+
+// Returns the mappings that replace 'map' when [gpa,gpa+len), which lies
+// within it, is unmapped.
+fn plan_hole(map: &MemMap, gpa: u64, len: u64) -> Vec<MemMap> {
+    let end = map.gpa + map.len as u64;
+    let mut to = Vec::with_capacity(2);
+    if gpa > map.gpa {
+        to.push(map.slice(map.gpa, gpa - map.gpa));
+    }
+    if gpa + len < end {
+        to.push(map.slice(gpa + len, end - gpa - len));
+    }
+    to
+}
+
+// Plans mapping 'piece' again, merged with the mappings either side of it,
+// 'prev' and 'next', which the caller has checked carry on from it. Returns
+// the mappings to replace and their replacement.
+fn plan_fill(piece: &MemMap, prev: Option<MemMap>, next: Option<MemMap>) -> (Vec<MemMap>, Vec<MemMap>) {
+    let mut merged = *piece;
+    let mut from = Vec::with_capacity(2);
+    if let Some(prev) = prev {
+        merged = MemMap { gpa: prev.gpa, segoff: prev.segoff, len: prev.len + merged.len, ..merged };
+        from.push(prev);
+    }
+    if let Some(next) = next {
+        merged.len += next.len;
+        from.push(next);
+    }
+    (from, vec![merged])
+}
+
+// Checks that [gpa,gpa+len) is non-empty and page aligned.
+fn check_page_range(gpa: u64, len: u64, page_size: u64) -> Result<(), Error> {
+    let mask = page_size - 1;
+    if len == 0 || (gpa & mask) != 0 || (len & mask) != 0 || gpa.checked_add(len).is_none() {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(())
+}
+
+// Checks the length of a bootrom image file, returning it as a usize.
+fn check_bootrom_file_len(len: u64, page_size: u64) -> Result<usize, Error> {
+    if len == 0 || len > MAX_BOOTROM_SIZE as u64 {
+        return Err(Error::BootromSize { len: len as usize, max: MAX_BOOTROM_SIZE });
+    }
+    if (len & (page_size - 1)) != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(len as usize)
+}
+
+/// Checks that the guest physical range [gpa,gpa+len) doesn't overlap any of
+/// the regions reserved for in-kernel device emulation.
+fn check_reserved(gpa: u64, len: u64) -> Result<(), Error> {
+    for (region, start, size) in RESERVED_MMIO.iter() {
+        if gpa < start + size && *start < gpa + len {
+            return Err(Error::ReservedRange { gpa: gpa, len: len, region: region });
+        }
+    }
+    Ok(())
+}
+
+// Different styles of mapping the memory assigned to a VM into the address
+// space of the controlling process.
+#[repr(C)]
+#[allow(non_camel_case_types, unused)]
+#[derive(Debug, Copy, Clone)]
+enum vm_mmap_style {
+	VM_MMAP_NONE,		/* no mapping */
+	VM_MMAP_ALL,		/* fully and statically mapped */
+	VM_MMAP_SPARSE,		/* mappings created on-demand */
+}
+
+// 'flags' value passed to 'vm_set_memflags()'. VM_MEM_F_INCORE (0x01),
+// which includes guest memory in core files, has no illumos equivalent:
+// coreadm(8) decides whether shared mappings such as guest memory are dumped.
+const VM_MEM_F_WIRED: i32 = 0x02;	// guest memory is wired
+
+/// Flags applied to guest memory mappings, set with `set_memflags()`.
+/// Flags are combined with `|`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct MemFlags(i32);
+
+impl MemFlags {
+    /// No flags: guest memory is pageable, which is the default.
+    pub const NONE: MemFlags = MemFlags(0);
+    /// Wire guest memory, so it is never paged out, as DMA through an IOMMU
+    /// by passthrough devices requires.
+    pub const WIRED: MemFlags = MemFlags(VM_MEM_F_WIRED);
+
+    /// Converts `VM_MEM_F_*` flags, as passed to `vm_set_memflags()` in
+    /// libvmmapi. Returns 'None' if any unknown flag is set, including
+    /// `VM_MEM_F_INCORE`, which illumos can't honour.
+    pub fn from_bits(bits: i32) -> Option<MemFlags> {
+        match bits & !VM_MEM_F_WIRED {
+            0 => Some(MemFlags(bits)),
+            _ => None,
+        }
+    }
+
+    /// Returns the flags as `VM_MEM_F_*` flags.
+    pub fn bits(&self) -> i32 {
+        self.0
+    }
+
+    /// Returns true if every flag in 'other' is set.
+    pub fn contains(&self, other: MemFlags) -> bool {
+        (self.0 & other.0) == other.0
+    }
+
+    // Returns the VM_MEMMAP_F_* flags for a mapping created with these flags.
+    fn memmap_flags(&self) -> i32 {
+        match self.contains(MemFlags::WIRED) {
+            true => VM_MEMMAP_F_WIRED,
+            false => 0,
+        }
+    }
+}
+
+impl BitOr for MemFlags {
+    type Output = MemFlags;
+
+    fn bitor(self, other: MemFlags) -> MemFlags {
+        MemFlags(self.0 | other.0)
+    }
+}
+
+/// Identifiers for memory segments, both system memory and devmem segments.
+#[repr(C)]
+#[allow(non_camel_case_types, unused)]
+#[derive(Debug, Copy, Clone)]
+pub enum MemSegId{
+        VM_LOWMEM = 0,
+        VM_HIGHMEM = 1,
+        VM_BOOTROM = 2,
+        VM_FRAMEBUFFER = 3,
+}
+
+/// A mapping of a memory segment into the guest physical address space.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MemMap {
+    /// Guest physical address of the mapping.
+    pub gpa: u64,
+    /// Memory segment mapped.
+    pub segid: i32,
+    /// Offset into the memory segment.
+    pub segoff: i64,
+    /// Length of the mapping in bytes.
+    pub len: usize,
+    /// Guest access permissions, as `PROT_*` flags.
+    pub prot: i32,
+    /// `VM_MEMMAP_F_*` flags.
+    pub flags: i32,
+}
+
+impl MemMap {
+    /// Returns true if the mapping covers any part of [gpa,gpa+len).
+    pub fn overlaps(&self, gpa: u64, len: u64) -> bool {
+        len != 0 && self.gpa < gpa + len && gpa < self.gpa + self.len as u64
+    }
+
+    // Returns the part of the mapping covering [gpa,gpa+len), which must lie
+    // within it.
+    pub(crate) fn slice(&self, gpa: u64, len: u64) -> MemMap {
+        MemMap {
+            gpa: gpa,
+            segoff: self.segoff + (gpa - self.gpa) as i64,
+            len: len as usize,
+            ..*self
+        }
+    }
+}
+
+/// Guest access permissions of a memory mapping.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MemProt {
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
+}
+
+impl MemProt {
+    pub const NONE: MemProt = MemProt { read: false, write: false, exec: false };
+    pub const READ_ONLY: MemProt = MemProt { read: true, write: false, exec: false };
+    pub const READ_EXEC: MemProt = MemProt { read: true, write: false, exec: true };
+    pub const READ_WRITE: MemProt = MemProt { read: true, write: true, exec: false };
+    pub const ALL: MemProt = MemProt { read: true, write: true, exec: true };
+
+    /// Converts `PROT_*` flags, ignoring any others.
+    pub fn from_prot(prot: i32) -> MemProt {
+        MemProt {
+            read: (prot & libc::PROT_READ) != 0,
+            write: (prot & libc::PROT_WRITE) != 0,
+            exec: (prot & libc::PROT_EXEC) != 0,
+        }
+    }
+
+    /// Returns the permissions as `PROT_*` flags.
+    pub fn prot(&self) -> i32 {
+        let mut prot = libc::PROT_NONE;
+        if self.read {
+            prot |= libc::PROT_READ;
+        }
+        if self.write {
+            prot |= libc::PROT_WRITE;
+        }
+        if self.exec {
+            prot |= libc::PROT_EXEC;
+        }
+        prot
+    }
+}
+
+impl From<vm_memmap> for MemMap {
+    fn from(map: vm_memmap) -> MemMap {
+        MemMap {
+            gpa: map.gpa,
+            segid: map.segid,
+            segoff: map.segoff,
+            len: map.len,
+            prot: map.prot,
+            flags: map.flags,
+        }
+    }
+}
+
+/// Iterator over the guest memory mappings of a virtual machine, returned by
+/// `VirtualMachine::memory_maps()`.
+pub struct MemMaps<'a> {
+    vm: &'a VirtualMachine,
+    next: Option<u64>, // address to look for the next mapping from
+}
+
+impl<'a> Iterator for MemMaps<'a> {
+    type Item = Result<MemMap, Error>;
+
+    fn next(&mut self) -> Option<Result<MemMap, Error>> {
+        let gpa = self.next?;
+        match self.vm.mmap_getnext(gpa) {
+            Ok(map) if map.len != 0 => {
+                self.next = map.gpa.checked_add(map.len as u64);
+                Some(Ok(MemMap::from(map)))
+            }
+            Ok(_) => {
+                self.next = None;
+                None
+            }
+            Err(ref e) if e.errno() == ENOENT => {
+                self.next = None;
+                None
+            }
+            Err(e) => {
+                self.next = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// A guest memory region set up by `setup_lowmem()`, `setup_highmem()`,
+/// `setup_bootrom()`, or `setup_framebuffer()`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GuestRegion {
+    /// Name of the region, such as "lowmem".
+    pub name: &'static str,
+    /// Memory segment backing the region.
+    pub segid: i32,
+    /// Offset of the region in the memory segment.
+    pub segoff: i64,
+    /// Guest physical address of the region.
+    pub gpa: u64,
+    /// Length of the region in bytes.
+    pub len: u64,
+    /// Guest access permissions, as `PROT_*` flags.
+    pub prot: i32,
+    /// Host virtual address the region is mapped at.
+    pub host_addr: u64,
+}
+
+impl GuestRegion {
+    /// Returns true if the region covers any part of [gpa,gpa+len).
+    pub fn overlaps(&self, gpa: u64, len: u64) -> bool {
+        len != 0 && self.gpa < gpa + len && gpa < self.gpa + self.len
+    }
+}
+
+/// A part of the bootrom, such as a firmware image or the variable store
+/// that UEFI firmware keeps its settings in.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RomPart {
+    /// Name of the part's region in `regions()`.
+    pub name: &'static str,
+    /// Length of the part in bytes, before padding to the page size.
+    pub len: usize,
+    /// Guest access permissions. Parts the guest writes to, such as a
+    /// variable store, need `MemProt::READ_WRITE`.
+    pub prot: MemProt,
+}
+
+/// The layout of the bootrom in the guest physical address space, for
+/// `VirtualMachine::setup_bootrom_layout()`. The parts are mapped one after
+/// another from a single bootrom segment, which is limited to 16MB.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootromLayout {
+    /// Guest physical address of the start of the bootrom, which must be
+    /// page aligned, or 'None' to end it at 4GB, where the processor starts
+    /// executing after reset.
+    pub gpa: Option<u64>,
+    /// The parts of the bootrom, in order of guest physical address.
+    pub parts: Vec<RomPart>,
+}
+
+impl BootromLayout {
+    /// A read-only bootrom image of 'len' bytes, ending at 4GB, as set up by
+    /// `setup_bootrom()`.
+    pub fn single(len: usize) -> BootromLayout {
+        BootromLayout {
+            gpa: None,
+            parts: vec![RomPart { name: "bootrom", len: len, prot: MemProt::READ_EXEC }],
+        }
+    }
+
+    /// Split UEFI firmware, such as OVMF_CODE.fd with its OVMF_VARS.fd: a
+    /// read-only code image of 'code_len' bytes ending at 4GB, and below it
+    /// a writable variable store of 'vars_len' bytes, called "varstore".
+    pub fn split(code_len: usize, vars_len: usize) -> BootromLayout {
+        BootromLayout {
+            gpa: None,
+            parts: vec![
+                RomPart { name: "varstore", len: vars_len, prot: MemProt::READ_WRITE },
+                RomPart { name: "bootrom", len: code_len, prot: MemProt::READ_EXEC },
+            ],
+        }
+    }
+
+    // Returns the guest physical address of the bootrom and its length once
+    // every part is padded to 'page_size'.
+    fn placement(&self, page_size: usize) -> Result<(u64, usize), Error> {
+        let mut len: usize = 0;
+        let mut image_len: usize = 0;
+        for part in self.parts.iter() {
+            if part.len == 0 {
+                return Err(Error::BootromSize { len: 0, max: MAX_BOOTROM_SIZE });
+            }
+            image_len = image_len.saturating_add(part.len);
+            len = len.saturating_add(part.len.saturating_add(page_size - 1) & !(page_size - 1));
+        }
+        // Limit bootrom size to 16MB so it doesn't encroach into reserved
+        // MMIO space (e.g. APIC, HPET, MSI).
+        if len == 0 || len > MAX_BOOTROM_SIZE {
+            return Err(Error::BootromSize { len: image_len, max: MAX_BOOTROM_SIZE });
+        }
+        let gpa = match self.gpa {
+            Some(gpa) if (gpa & (page_size as u64 - 1)) != 0 => return Err(Error::new(EINVAL)),
+            Some(gpa) if gpa.checked_add(len as u64).is_none() => return Err(Error::new(EINVAL)),
+            Some(gpa) => gpa,
+            None => (1 << 32) - len as u64,
+        };
+        Ok((gpa, len))
+    }
+}
+
+/// A bootrom set up by `VirtualMachine::setup_bootrom_layout()`, with the
+/// host mapping it was set up over, which is unmapped when this is dropped.
+#[derive(Debug)]
+pub struct Bootrom {
+    mapping: HostMapping,
+    regions: Vec<GuestRegion>,
+}
+
+impl Bootrom {
+    /// Returns the region of each part, in order of guest physical address.
+    pub fn regions(&self) -> &[GuestRegion] {
+        &self.regions
+    }
+
+    /// Returns the host memory of the part called 'name', padding included,
+    /// for loading its image.
+    pub fn part(&self, name: &str) -> Option<VolatileSlice<'_>> {
+        let region = self.regions.iter().find(|region| region.name == name)?;
+        let offset = (region.host_addr - self.mapping.addr()) as usize;
+        self.mapping.slice().subslice(offset, region.len as usize).ok()
+    }
+
+    /// Returns the host mapping of the whole bootrom.
+    pub fn mapping(&self) -> &HostMapping {
+        &self.mapping
+    }
+}
+
+/// Why the virtual machine was suspended, reported with every
+/// `VmExit::Suspended` exit until the VM is reinitialized.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SuspendReason {
+    /// The guest, or `reset()`, requested a reset.
+    Reset,
+    /// The guest, or `poweroff()`, requested a power off.
+    Poweroff,
+    /// The guest halted every VCPU with interrupts disabled, or `halt()` was
+    /// called.
     Halt,
+    /// A VCPU triple faulted, or `triplefault()` was called.
+    TripleFault,
+    /// The kernel reported a reason this library doesn't know about, with
+    /// its raw value.
+    Unknown(i32),
+}
+
+impl From<i32> for SuspendReason {
+    fn from(how: i32) -> SuspendReason {
+        match vm_suspend_how::try_from(how) {
+            Ok(vm_suspend_how::VM_SUSPEND_RESET) => SuspendReason::Reset,
+            Ok(vm_suspend_how::VM_SUSPEND_POWEROFF) => SuspendReason::Poweroff,
+            Ok(vm_suspend_how::VM_SUSPEND_HALT) => SuspendReason::Halt,
+            Ok(vm_suspend_how::VM_SUSPEND_TRIPLEFAULT) => SuspendReason::TripleFault,
+            _ => SuspendReason::Unknown(how),
+        }
+    }
+}
+
+/// What caused a task switch, reported with `VmExit::TaskSwitch`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TaskSwitchReason {
+    /// A CALL to a TSS or task gate.
+    Call,
+    /// An IRET with the NT flag set.
+    Iret,
+    /// A JMP to a TSS or task gate.
+    Jmp,
+    /// An interrupt or exception through a task gate in the IDT.
+    IdtGate,
+    /// The kernel reported a reason this library doesn't know about, with
+    /// its raw value.
+    Unknown(i32),
+}
+
+impl From<i32> for TaskSwitchReason {
+    fn from(reason: i32) -> TaskSwitchReason {
+        match task_switch_reason::try_from(reason) {
+            Ok(task_switch_reason::TSR_CALL) => TaskSwitchReason::Call,
+            Ok(task_switch_reason::TSR_IRET) => TaskSwitchReason::Iret,
+            Ok(task_switch_reason::TSR_JMP) => TaskSwitchReason::Jmp,
+            Ok(task_switch_reason::TSR_IDT_GATE) => TaskSwitchReason::IdtGate,
+            Err(_) => TaskSwitchReason::Unknown(reason),
+        }
+    }
+}
+
+/// Host memory residency of a guest memory mapping.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MemResidency {
+    /// Guest physical address of the mapping.
+    pub gpa: u64,
+    /// Length of the mapping in bytes.
+    pub len: usize,
+    /// Memory segment mapped.
+    pub segid: i32,
+    /// Bytes of the mapping resident in host memory.
+    pub resident: usize,
+    /// The mapping is wired, so it stays resident once touched.
+    pub wired: bool,
+}
+
+/// The memory copied by `VirtualMachine::copy_memory_from()`.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct MemCopyStats {
+    /// System memory mappings recreated.
+    pub mappings: usize,
+    /// Bytes copied from the source.
+    pub copied: usize,
+    /// Bytes skipped because they were zero in the source.
+    pub skipped: usize,
+}
+
+const NUM_EXITCODES: usize = vm_exitcode::ALL.len();
+
+/// Counts of VM exits on a single VCPU, by exit code.
+#[derive(Debug, Copy, Clone)]
+pub struct ExitCounters {
+    counts: [u64; NUM_EXITCODES],
+}
+
+impl ExitCounters {
+    /// Returns the number of exits with exit code 'code'.
+    pub fn get(&self, code: vm_exitcode) -> u64 {
+        self.counts[code as usize]
+    }
+
+    /// Returns the number of exits of all types.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Iterates over the exit codes that have been seen, with their counts.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (vm_exitcode, u64)> + 'a {
+        vm_exitcode::ALL.iter().zip(self.counts.iter())
+            .filter(|(_, count)| **count > 0)
+            .map(|(code, count)| (*code, *count))
+    }
+}
+
+// Accounting of the time a VCPU spends in VM_RUN, and between returning
+// from VM_RUN and entering it again, which is attributed to handling the
+// exit that VM_RUN returned.
+struct RunClock {
+    in_run: AtomicU64,
+    in_host: AtomicU64,
+    per_exit: Vec<AtomicU64>, // host time after each exit code
+    returned: AtomicI64, // gethrtime() when VM_RUN last returned, or 0
+    last_code: AtomicUsize, // exit code VM_RUN last returned, or NUM_EXITCODES
+}
+
+impl RunClock {
+    fn new() -> RunClock {
+        RunClock {
+            in_run: AtomicU64::new(0),
+            in_host: AtomicU64::new(0),
+            per_exit: (0..NUM_EXITCODES).map(|_| AtomicU64::new(0)).collect(),
+            returned: AtomicI64::new(0),
+            last_code: AtomicUsize::new(NUM_EXITCODES),
+        }
+    }
+
+    // Accounts for one call to VM_RUN, entered at 'entry' and returning at
+    // 'exit', with 'exitcode' if it returned an exit.
+    fn account(&self, entry: i64, exit: i64, exitcode: Option<vm_exitcode>) {
+        let returned = self.returned.swap(exit, Ordering::Relaxed);
+        if returned != 0 && entry > returned {
+            let host = (entry - returned) as u64;
+            self.in_host.fetch_add(host, Ordering::Relaxed);
+            if let Some(counter) = self.per_exit.get(self.last_code.load(Ordering::Relaxed)) {
+                counter.fetch_add(host, Ordering::Relaxed);
+            }
+        }
+        if exit > entry {
+            self.in_run.fetch_add((exit - entry) as u64, Ordering::Relaxed);
+        }
+        let code = match exitcode {
+            Some(code) => code as usize,
+            None => NUM_EXITCODES,
+        };
+        self.last_code.store(code, Ordering::Relaxed);
+    }
+
+    fn times(&self) -> RunTimes {
+        let mut per_exit = [0; NUM_EXITCODES];
+        for (time, counter) in per_exit.iter_mut().zip(self.per_exit.iter()) {
+            *time = counter.load(Ordering::Relaxed);
+        }
+        RunTimes {
+            in_run: Duration::from_nanos(self.in_run.load(Ordering::Relaxed)),
+            in_host: Duration::from_nanos(self.in_host.load(Ordering::Relaxed)),
+            per_exit: per_exit,
+        }
+    }
+}
+
+/// The split of a VCPU's time between VM_RUN and userspace.
+///
+/// Time in VM_RUN includes time the VCPU was halted in the kernel, or
+/// waiting for a host CPU, so it is an upper bound on guest execution time.
+/// Host time is measured from VM_RUN returning to it being entered again,
+/// and is attributed to the exit that VM_RUN returned. It includes any time
+/// the VCPU thread spent paused or descheduled between runs.
+#[derive(Debug, Copy, Clone)]
+pub struct RunTimes {
+    /// Total time spent in VM_RUN.
+    pub in_run: Duration,
+    /// Total time spent between returning from VM_RUN and entering it again.
+    pub in_host: Duration,
+    per_exit: [u64; NUM_EXITCODES],
+}
+
+impl RunTimes {
+    /// Returns the host time spent handling exits with exit code 'code'.
+    pub fn handling(&self, code: vm_exitcode) -> Duration {
+        Duration::from_nanos(self.per_exit[code as usize])
+    }
+
+    /// Returns the fraction of the accounted time spent outside VM_RUN,
+    /// between 0 and 1.
+    pub fn host_fraction(&self) -> f64 {
+        let total = self.in_run + self.in_host;
+        if total == Duration::from_secs(0) {
+            return 0.0;
+        }
+        self.in_host.as_nanos() as f64 / total.as_nanos() as f64
+    }
+
+    /// Iterates over the exit codes that have been handled, with the host
+    /// time spent handling them.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (vm_exitcode, Duration)> + 'a {
+        vm_exitcode::ALL.iter().zip(self.per_exit.iter())
+            .filter(|(_, time)| **time > 0)
+            .map(|(code, time)| (*code, Duration::from_nanos(*time)))
+    }
+}
+
+/// Returns the current high-resolution host time in nanoseconds, on the
+/// same clock as `VirtualMachine::last_exit_time()`.
+pub fn hrtime() -> i64 {
+    // Safe because gethrtime() takes no arguments and cannot fail
+    unsafe { gethrtime() }
+}
+
+/// The instruction that caused an instruction emulation exit, as fetched by
+/// the kernel.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FaultingInst {
+    /// Guest instruction pointer of the instruction.
+    pub rip: u64,
+    /// Operand size of the code segment: 16, 32, or 64 bits.
+    pub bitness: u32,
+    bytes: [u8; 15],
+    len: u8,
+}
+
+impl FaultingInst {
+    /// Creates a faulting instruction record, keeping at most 15 bytes.
+    pub fn new(rip: u64, bitness: u32, inst: &[u8]) -> FaultingInst {
+        let mut bytes = [0; 15];
+        let len = inst.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&inst[..len]);
+        FaultingInst { rip: rip, bitness: bitness, bytes: bytes, len: len as u8 }
+    }
+
+    /// Returns the instruction bytes, which are empty if the kernel didn't
+    /// fetch the instruction.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+/// The direction of a port I/O access.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum IoDirection {
+    /// IN, from the port to the guest.
+    In,
+    /// OUT, from the guest to the port.
+    Out,
+}
+
+/// A port I/O access by the guest, from an `InOut` or `InOutStr` exit.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct InOutRequest {
+    pub port: u16,
+    /// Access width: 1, 2 or 4 bytes.
+    pub bytes: u16,
+    pub direction: IoDirection,
+    /// The value written by an OUT, in the low 'bytes' bytes. Not
+    /// meaningful for IN, or for string instructions, whose data is in
+    /// guest memory.
+    pub value: u32,
+    /// Set for the string instructions, INS and OUTS.
+    pub string: bool,
+    /// Set if the string instruction has a REP prefix.
+    pub rep: bool,
+}
+
+impl InOutRequest {
+    fn from_inout(io: &vm_inout) -> InOutRequest {
+        InOutRequest {
+            port: io.port,
+            bytes: io.bytes(),
+            direction: if io.is_in() { IoDirection::In } else { IoDirection::Out },
+            value: io.eax,
+            string: io.is_string(),
+            rep: io.is_repeat(),
+        }
+    }
+
+    /// Returns true for IN and INS.
+    pub fn is_in(&self) -> bool {
+        self.direction == IoDirection::In
+    }
+}
+
+/// The operands of a string I/O instruction, INS or OUTS, from an
+/// `InOutStr` exit.
+#[derive(Debug, Copy, Clone)]
+pub struct StringIo {
+    /// Paging state of the VCPU, for translating the buffer's addresses.
+    pub paging: vm_guest_paging,
+    pub rflags: u64,
+    pub cr0: u64,
+    /// Offset of the next element in its segment: RDI for INS, RSI for
+    /// OUTS, truncated to the address size.
+    pub index: u64,
+    /// Elements left to transfer: RCX with a REP prefix, otherwise 1.
+    pub count: u64,
+    /// Address size of the instruction in bytes: 2, 4 or 8.
+    pub addrsize: u8,
+    /// Segment holding the buffer: ES for INS, DS or an override for OUTS.
+    pub segment: vm_reg_name,
+    pub seg_base: u64,
+    pub seg_limit: u32,
+    pub seg_access: u32,
+}
+
+impl StringIo {
+    /// Returns true if the direction flag is set, so the instruction steps
+    /// down through memory.
+    pub fn is_reverse(&self) -> bool {
+        (self.rflags & PSL_D) != 0
+    }
+
+    /// Returns the lowest guest linear address and the length of the memory
+    /// accessed by the next 'count' elements of 'bytes' bytes. Element 0 is
+    /// at the start of the range, or at the end of it if `is_reverse()`.
+    /// Returns 'None' if the range wraps around the address size, which
+    /// the caller has to split, or lies outside the segment limit.
+    pub fn linear_range(&self, bytes: u16, count: u64) -> Option<(u64, usize)> {
+        let len = (bytes as u64).checked_mul(count)?;
+        if len == 0 {
+            return Some((0, 0));
+        }
+        let (start, end) = self.offset_range(len, bytes)?;
+        if !self.within_limit(start, end) {
+            return None;
+        }
+        // Segment bases other than FS and GS are ignored in 64-bit mode
+        let base = match self.paging.cpu_mode {
+            vm_cpu_mode::CPU_MODE_64BIT => match self.segment {
+                vm_reg_name::VM_REG_GUEST_FS | vm_reg_name::VM_REG_GUEST_GS => self.seg_base,
+                _ => 0,
+            },
+            _ => self.seg_base & 0xffffffff,
+        };
+        Some((base.wrapping_add(start), len as usize))
+    }
+
+    /// Returns true if the next 'count' elements of 'bytes' bytes lie within
+    /// the segment limit, as the processor checks outside 64-bit mode. An
+    /// access past the limit raises #GP, or #SS for the stack segment.
+    pub fn in_segment_limit(&self, bytes: u16, count: u64) -> bool {
+        let len = match (bytes as u64).checked_mul(count) {
+            Some(0) => return true,
+            Some(len) => len,
+            None => return false,
+        };
+        match self.offset_range(len, bytes) {
+            Some((start, end)) => self.within_limit(start, end),
+            // Wrapping around the address size is not a limit violation
+            None => true,
+        }
+    }
+
+    // Returns the first and last segment offsets of 'len' bytes of
+    // elements of 'bytes' bytes, or 'None' if they wrap around the address
+    // size.
+    fn offset_range(&self, len: u64, bytes: u16) -> Option<(u64, u64)> {
+        let start = match self.is_reverse() {
+            true => self.index.checked_sub(len - bytes as u64)?,
+            false => self.index,
+        };
+        let end = start.checked_add(len - 1)?;
+        if end > addrsize_mask(self.addrsize) {
+            return None;
+        }
+        Some((start, end))
+    }
+
+    // Checks segment offsets [start,end] against the limit. Expand-down
+    // data segments hold the offsets above the limit, up to 64K or 4GB
+    // depending on their B flag.
+    fn within_limit(&self, start: u64, end: u64) -> bool {
+        if self.paging.cpu_mode == vm_cpu_mode::CPU_MODE_64BIT {
+            return true;
+        }
+        let limit = self.seg_limit as u64;
+        let code = (self.seg_access & SEG_ACCESS_CODE) != 0;
+        if !code && (self.seg_access & SEG_ACCESS_EXPAND_DOWN) != 0 {
+            let upper = match self.seg_access & SEG_ACCESS_DB {
+                0 => 0xffff,
+                _ => 0xffffffff,
+            };
+            start > limit && end <= upper
+        } else {
+            end <= limit
+        }
+    }
+}
+
+// Returns the mask of an address of 'addrsize' bytes.
+fn addrsize_mask(addrsize: u8) -> u64 {
+    match addrsize {
+        2 => 0xffff,
+        4 => 0xffffffff,
+        _ => 0xffffffffffffffff,
+    }
+}
+
+// Adds 'delta' to the index or count register 'reg' of an instruction with
+// 'addrsize' bytes of address, as the instruction would: 16-bit updates
+// leave the rest of the register alone, and 32-bit updates clear the upper
+// half.
+fn step_register(reg: u64, delta: i64, addrsize: u8) -> u64 {
+    let next = reg.wrapping_add(delta as u64);
+    match addrsize {
+        2 => (reg & !0xffff) | (next & 0xffff),
+        4 => next & 0xffffffff,
+        _ => next,
+    }
+}
+
+/// A VM exit, with the state of the VCPU at the exit, as returned by
+/// `VirtualMachine::run_info()`.
+#[derive(Debug)]
+pub struct VmExitInfo {
+    /// Guest RIP of the instruction that caused the exit.
+    pub rip: u64,
+    /// Length of that instruction in bytes, or 0 if unknown.
+    pub inst_length: i32,
+    /// The exit reason and its payload.
+    pub exit: VmExit,
+}
+
+/// Reasons for virtual machine exits.
+///
+/// The exit reasons are mapped to the `VM_EXIT_*` defines in `machine/vmm.h`.
+///
+#[derive(Debug)]
+pub enum VmExit {
+    InOut(InOutRequest),
+    InOutStr(InOutRequest, StringIo),
+    Vmx(i32 /* status */, u32 /* exit reason */, u64 /* exit qualification */, i32 /* instruction type */, i32 /* instruction error */),
+    Bogus,
+    RdMsr(u32 /* MSR */),
+    WrMsr(u32 /* MSR */, u64 /* value */),
+    Halt(u64 /* rflags */, u64 /* interrupt status */),
     Mtrap,
     Pause,
-    Paging,
-    InstEmul,
-    SpinupAp,
+    Paging(u64 /* gpa */, i32 /* fault type */),
+    InstEmul(u64 /* gpa */, u64 /* gla */, vm_guest_paging, FaultingInst),
+    SpinupAp(i32 /* vcpu */, u64 /* rip */),
     Deprecated,
     RunBlock,
     IoapicEoi(i32 /* vector */),
-    Suspended,
-    TaskSwitch,
+    Suspended(SuspendReason),
+    TaskSwitch(u16 /* new TSS selector */, TaskSwitchReason, Option<u32> /* error code */, bool /* external event */, vm_guest_paging),
     Monitor,
     Mwait,
     Svm(u64 /* exitcode */, u64 /* exitinfo1 */, u64 /* exitinfo2 */),
@@ -1092,4 +3624,309 @@ pub enum VmExit {
     VmInsn,
     Ht,
     Max,
+    /// VM_RUN returned early with `EINTR` or `EAGAIN`, without a VM exit.
+    Interrupted,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suspend_reason() {
+        assert_eq!(SuspendReason::from(vm_suspend_how::VM_SUSPEND_RESET as i32), SuspendReason::Reset);
+        assert_eq!(SuspendReason::from(vm_suspend_how::VM_SUSPEND_TRIPLEFAULT as i32), SuspendReason::TripleFault);
+        assert_eq!(SuspendReason::from(vm_suspend_how::VM_SUSPEND_NONE as i32), SuspendReason::Unknown(0));
+        // A reason added by a newer kernel is passed through
+        assert_eq!(SuspendReason::from(42), SuspendReason::Unknown(42));
+    }
+
+    #[test]
+    fn test_task_switch_reason() {
+        assert_eq!(TaskSwitchReason::from(task_switch_reason::TSR_IRET as i32), TaskSwitchReason::Iret);
+        assert_eq!(TaskSwitchReason::from(task_switch_reason::TSR_IDT_GATE as i32), TaskSwitchReason::IdtGate);
+        assert_eq!(TaskSwitchReason::from(9), TaskSwitchReason::Unknown(9));
+    }
+
+    #[test]
+    fn test_check_reserved() {
+        // The largest bootrom ends at 4GB without touching the local APIC
+        let gpa = (1 << 32) - MAX_BOOTROM_SIZE as u64;
+        assert!(check_reserved(gpa, MAX_BOOTROM_SIZE as u64).is_ok());
+
+        match check_reserved(0xfed00000 - 0x1000, 0x2000) {
+            Err(Error::ReservedRange { region, .. }) => assert_eq!(region, "HPET"),
+            other => panic!("expected HPET overlap, got {:?}", other),
+        }
+        assert!(check_reserved(0xfee00000, 1).is_err());
+        assert!(check_reserved(0xfef00000, 0x1000).is_ok());
+    }
+
+    #[test]
+    fn test_bootrom_placement() {
+        let layout = BootromLayout::split(0x1ff800, 0x20000);
+        assert_eq!(layout.placement(0x1000).unwrap(), ((1 << 32) - 0x220000, 0x220000));
+        assert_eq!(BootromLayout::single(1).placement(0x1000).unwrap(), ((1 << 32) - 0x1000, 0x1000));
+
+        let layout = BootromLayout { gpa: Some(0xffc00000), ..layout };
+        assert_eq!(layout.placement(0x1000).unwrap(), (0xffc00000, 0x220000));
+        let layout = BootromLayout { gpa: Some(0xffc00800), ..layout };
+        assert!(layout.placement(0x1000).is_err());
+        match BootromLayout::split(MAX_BOOTROM_SIZE, 1).placement(0x1000) {
+            Err(Error::BootromSize { len, .. }) => assert_eq!(len, MAX_BOOTROM_SIZE + 1),
+            other => panic!("expected a size error, got {:?}", other),
+        }
+        assert!(BootromLayout::single(0).placement(0x1000).is_err());
+    }
+
+    #[test]
+    fn test_check_bootrom_file_len() {
+        assert_eq!(check_bootrom_file_len(0x200000, 0x1000).unwrap(), 0x200000);
+        assert_eq!(check_bootrom_file_len(0x200800, 0x1000).unwrap_err().errno(), EINVAL);
+        match check_bootrom_file_len(MAX_BOOTROM_SIZE as u64 + 0x1000, 0x1000) {
+            Err(Error::BootromSize { len, .. }) => assert_eq!(len, MAX_BOOTROM_SIZE + 0x1000),
+            other => panic!("expected a size error, got {:?}", other),
+        }
+        assert!(check_bootrom_file_len(0, 0x1000).is_err());
+    }
+
+    #[test]
+    fn test_count_resident() {
+        let pages: [u8; 5] = [1, 0, 3, 2, 1];
+        assert_eq!(count_resident(&pages, 4096), 3 * 4096);
+        let pages: [i8; 2] = [-1, 0];
+        assert_eq!(count_resident(&pages, 4096), 4096);
+    }
+
+    #[test]
+    fn test_valid_vcpu_id() {
+        assert!(valid_vcpu_id(0, None));
+        assert!(valid_vcpu_id(VM_MAXCPU as i32 - 1, None));
+        assert!(!valid_vcpu_id(VM_MAXCPU as i32, None));
+        assert!(!valid_vcpu_id(-1, None));
+        assert!(valid_vcpu_id(3, Some(4)));
+        assert!(!valid_vcpu_id(4, Some(4)));
+        assert!(valid_vcpu_id(4, Some(0)));
+    }
+
+    #[test]
+    fn test_copy_nonzero_pages() {
+        let mut src = vec![0u8; 4 * 16];
+        src[17] = 1;
+        src[63] = 2;
+        let mut dst = vec![0u8; src.len()];
+        assert_eq!(copy_nonzero_pages(&src, &mut dst, 16), 2 * 16);
+        assert_eq!(dst, src);
+    }
+
+    #[test]
+    fn test_check_page_range() {
+        assert!(check_page_range(0x10000, 0x2000, 0x1000).is_ok());
+        assert!(check_page_range(0x10000, 0, 0x1000).is_err());
+        assert!(check_page_range(0x10800, 0x1000, 0x1000).is_err());
+        assert!(check_page_range(0x10000, 0x1800, 0x1000).is_err());
+    }
+
+    #[test]
+    fn test_plan_protection() {
+        let rx = libc::PROT_READ | libc::PROT_EXEC;
+        let rw = MemProt::READ_WRITE.prot();
+        let bootrom = MemMap { gpa: 0xfff00000, segid: 2, segoff: 0, len: 0x100000, prot: rx, flags: 0 };
+        let spans = |maps: &[MemMap]| maps.iter().map(|m| (m.gpa, m.segoff, m.len, m.prot)).collect::<Vec<_>>();
+
+        // Making part of the bootrom writable splits it in three
+        let (from, to) = plan_protection(&bootrom, None, None, 0xfff10000, 0x10000, rw);
+        assert_eq!(from, vec![bootrom]);
+        assert_eq!(spans(&to), vec![(0xfff00000, 0, 0x10000, rx), (0xfff10000, 0x10000, 0x10000, rw), (0xfff20000, 0x20000, 0xe0000, rx)]);
+
+        // Restoring it merges the pieces back together
+        let (from, to) = plan_protection(&to[1], Some(to[0]), Some(to[2]), 0xfff10000, 0x10000, rx);
+        assert_eq!(from.len(), 3);
+        assert_eq!(to, vec![bootrom]);
+
+        // Neighbours in other segments are left alone
+        let lowmem = MemMap { gpa: 0, segid: 0, segoff: 0, len: 0x10000, prot: rx, flags: 0 };
+        let (from, to) = plan_protection(&bootrom, Some(lowmem), None, 0xfff00000, 0x100000, rx);
+        assert_eq!((from, to), (vec![bootrom], vec![bootrom]));
+    }
+
+    #[test]
+    fn test_plan_balloon() {
+        let highmem = MemMap { gpa: 4 * GB, segid: 1, segoff: 0, len: 0x100000, prot: 3, flags: 0 };
+
+        // Ballooning the middle of the mapping leaves the pieces either side
+        let to = plan_hole(&highmem, 4 * GB + 0x10000, 0x10000);
+        assert_eq!(to, vec![highmem.slice(4 * GB, 0x10000), highmem.slice(4 * GB + 0x20000, 0xe0000)]);
+        assert_eq!(plan_hole(&highmem, 4 * GB, 0x100000), vec![]);
+
+        // Deflating it merges the pieces back together
+        let piece = highmem.slice(4 * GB + 0x10000, 0x10000);
+        assert_eq!(piece.segoff, 0x10000);
+        let (from, to) = plan_fill(&piece, Some(to[0]), Some(to[1]));
+        assert_eq!((from.len(), to), (2, vec![highmem]));
+        assert_eq!(plan_fill(&piece, None, None), (vec![], vec![piece]));
+    }
+
+    #[test]
+    fn test_memmap_overlaps() {
+        let map = MemMap { gpa: 0x1000, segid: 0, segoff: 0, len: 0x2000, prot: 0, flags: 0 };
+        assert!(map.overlaps(0x1000, 0x2000));
+        assert!(map.overlaps(0, 0x1001));
+        assert!(map.overlaps(0x2fff, 0x1000));
+        assert!(!map.overlaps(0, 0x1000));
+        assert!(!map.overlaps(0x3000, 0x1000));
+        assert!(!map.overlaps(0x1000, 0));
+    }
+
+    #[test]
+    fn test_find_overlap() {
+        let lowmem = GuestRegion { name: "lowmem", segid: 0, segoff: 0, gpa: 0, len: 3 * GB, prot: 0, host_addr: 0x1000_0000 };
+        let bootrom = GuestRegion { name: "bootrom", segid: 2, segoff: 0, gpa: 4 * GB - MB, len: MB, prot: 0, host_addr: 0 };
+        let regions = [lowmem, bootrom];
+
+        let fb = GuestRegion { name: "framebuffer", segid: 3, segoff: 0, gpa: 0xc000_0000 - 0x1000, len: 0x10_0000, prot: 0, host_addr: 0 };
+        assert_eq!(find_overlap(&regions, &fb), Some(lowmem));
+        let fb = GuestRegion { gpa: 0xc000_0000, ..fb };
+        assert_eq!(find_overlap(&regions, &fb), None);
+        let fb = GuestRegion { gpa: 4 * GB - 0x2000, len: 0x4000, ..fb };
+        assert_eq!(find_overlap(&regions, &fb), Some(bootrom));
+    }
+
+    #[test]
+    fn test_record_capability() {
+        let mut capabilities = Vec::new();
+        record_capability(&mut capabilities, 0, vm_cap_type::VM_CAP_HALT_EXIT, 1);
+        record_capability(&mut capabilities, 1, vm_cap_type::VM_CAP_HALT_EXIT, 1);
+        record_capability(&mut capabilities, 0, vm_cap_type::VM_CAP_PAUSE_EXIT, 1);
+        record_capability(&mut capabilities, 0, vm_cap_type::VM_CAP_HALT_EXIT, 0);
+
+        let recorded: Vec<_> = capabilities.iter().map(|(id, cap, val)| (*id, *cap as i32, *val)).collect();
+        assert_eq!(recorded, vec![(0, vm_cap_type::VM_CAP_HALT_EXIT as i32, 0),
+                                  (1, vm_cap_type::VM_CAP_HALT_EXIT as i32, 1),
+                                  (0, vm_cap_type::VM_CAP_PAUSE_EXIT as i32, 1)]);
+    }
+
+    #[test]
+    fn test_run_clock() {
+        let clock = RunClock::new();
+        // Time before the first entry isn't attributed to anything
+        clock.account(100, 400, Some(vm_exitcode::VM_EXITCODE_INOUT));
+        clock.account(450, 1000, Some(vm_exitcode::VM_EXITCODE_HLT));
+        clock.account(1010, 1100, None);
+        clock.account(1200, 1300, Some(vm_exitcode::VM_EXITCODE_INOUT));
+
+        let times = clock.times();
+        assert_eq!(times.in_run, Duration::from_nanos(300 + 550 + 90 + 100));
+        assert_eq!(times.in_host, Duration::from_nanos(50 + 10 + 100));
+        assert_eq!(times.handling(vm_exitcode::VM_EXITCODE_INOUT), Duration::from_nanos(50));
+        assert_eq!(times.handling(vm_exitcode::VM_EXITCODE_HLT), Duration::from_nanos(10));
+        assert_eq!(times.iter().count(), 2);
+        assert!((times.host_fraction() - 160.0 / 1200.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_paging_state() {
+        let paging = paging_state(0, 0, 0, 0, 0x93, 0x93);
+        assert_eq!(paging.cpu_mode, vm_cpu_mode::CPU_MODE_REAL);
+        assert_eq!(paging.paging_mode, vm_paging_mode::PAGING_MODE_FLAT);
+
+        // Long mode, 64-bit code segment, user mode stack
+        let paging = paging_state(CR0_PE | CR0_PG, 0x1000, CR4_PAE, EFER_LME | EFER_LMA, 0x209b, 0xf3);
+        assert_eq!(paging.cpu_mode, vm_cpu_mode::CPU_MODE_64BIT);
+        assert_eq!(paging.paging_mode, vm_paging_mode::PAGING_MODE_64);
+        assert_eq!(paging.cpl, 3);
+        assert_eq!(paging.cr3, 0x1000);
+
+        let paging = paging_state(CR0_PE | CR0_PG, 0, CR4_PAE, 0, 0x9b, 0x93);
+        assert_eq!(paging.cpu_mode, vm_cpu_mode::CPU_MODE_PROTECTED);
+        assert_eq!(paging.paging_mode, vm_paging_mode::PAGING_MODE_PAE);
+    }
+
+    #[test]
+    fn test_string_io() {
+        // REP OUTSW from DS:SI in real mode
+        let mut operands = StringIo {
+            paging: paging_state(0, 0, 0, 0, 0x93, 0x93),
+            rflags: 0x2,
+            cr0: 0,
+            index: 0x100,
+            count: 8,
+            addrsize: 2,
+            segment: vm_reg_name::VM_REG_GUEST_DS,
+            seg_base: 0x7000 << 4,
+            seg_limit: 0xffff,
+            seg_access: 0x93,
+        };
+        assert_eq!(operands.linear_range(2, 8), Some((0x70100, 16)));
+        assert_eq!(operands.linear_range(2, 0x8000), None);
+
+        // Stepping down through memory, element 0 is at the end
+        operands.rflags |= PSL_D;
+        assert_eq!(operands.linear_range(2, 8), Some((0x700f2, 16)));
+        assert_eq!(operands.linear_range(2, 0x100), None);
+
+        // Accesses past the limit fault, and expand-down segments hold the
+        // offsets above it
+        operands.rflags &= !PSL_D;
+        operands.seg_limit = 0x10f;
+        assert!(operands.in_segment_limit(2, 8));
+        assert!(!operands.in_segment_limit(2, 9));
+        assert_eq!(operands.linear_range(2, 9), None);
+        operands.seg_access = 0x97;
+        operands.seg_limit = 0xff;
+        assert_eq!(operands.linear_range(2, 8), Some((0x70100, 16)));
+        operands.seg_limit = 0x100;
+        assert!(!operands.in_segment_limit(2, 8));
+        operands.seg_limit = 0xffff;
+        operands.seg_access = 0x93;
+
+        // Only FS and GS have a base in 64-bit mode
+        operands.paging = paging_state(CR0_PE | CR0_PG, 0x1000, CR4_PAE, EFER_LME | EFER_LMA, 0x209b, 0x93);
+        operands.addrsize = 8;
+        assert_eq!(operands.linear_range(2, 1), Some((0x100, 2)));
+        operands.segment = vm_reg_name::VM_REG_GUEST_FS;
+        assert_eq!(operands.linear_range(2, 1), Some((0x70100, 2)));
+
+        assert_eq!(step_register(0x1234_5678_0000_fffe, 4, 2), 0x1234_5678_0000_0002);
+        assert_eq!(step_register(0x1234_5678_0000_fffe, 4, 4), 0x0000_0000_0001_0002);
+        assert_eq!(step_register(0x10, -4, 8), 0xc);
+    }
+
+    #[test]
+    fn test_exit_counters() {
+        let mut counters = ExitCounters { counts: [0; NUM_EXITCODES] };
+        counters.counts[vm_exitcode::VM_EXITCODE_INOUT as usize] = 5;
+        counters.counts[vm_exitcode::VM_EXITCODE_HLT as usize] = 2;
+
+        assert_eq!(counters.get(vm_exitcode::VM_EXITCODE_INOUT), 5);
+        assert_eq!(counters.get(vm_exitcode::VM_EXITCODE_PAUSE), 0);
+        assert_eq!(counters.total(), 7);
+        let seen: Vec<_> = counters.iter().collect();
+        assert_eq!(seen, vec![(vm_exitcode::VM_EXITCODE_INOUT, 5), (vm_exitcode::VM_EXITCODE_HLT, 2)]);
+    }
+
+    #[test]
+    fn test_mem_flags() {
+        let flags = MemFlags::WIRED | MemFlags::NONE;
+        assert_eq!(flags.bits(), 0x02);
+        assert!(flags.contains(MemFlags::WIRED));
+        assert!(!MemFlags::NONE.contains(MemFlags::WIRED));
+        assert_eq!(MemFlags::from_bits(0x02), Some(MemFlags::WIRED));
+        assert_eq!(MemFlags::from_bits(0x04), None);
+        // VM_MEM_F_INCORE can't be honoured, so it is refused
+        assert_eq!(MemFlags::from_bits(0x03), None);
+        assert_eq!(flags.memmap_flags(), VM_MEMMAP_F_WIRED);
+        assert_eq!(MemFlags::NONE.memmap_flags(), 0);
+        assert_eq!(MemFlags::default(), MemFlags::NONE);
+    }
+
+    #[test]
+    fn test_check_interface_version() {
+        assert!(check_interface_version(None).is_ok());
+        assert!(check_interface_version(Some(VMM_LEGACY_INTERFACE_VERSION)).is_ok());
+        match check_interface_version(Some(15)) {
+            Err(Error::AbiMismatch { expected, found }) => assert_eq!((expected, found), (VMM_LEGACY_INTERFACE_VERSION, 15)),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
 }
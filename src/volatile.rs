@@ -0,0 +1,176 @@
+//! Volatile access to memory shared with the guest.
+//!
+//! Guest VCPUs can change guest memory at any time while the VMM process is
+//! reading or writing it, so Rust references to that memory (`&[u8]` or
+//! `&mut [u8]`) are undefined behavior. A `VolatileSlice` refers to a range
+//! of a guest memory mapping, and only accesses it with volatile reads and
+//! writes, which the compiler won't elide, merge, or assume unchanged.
+//!
+//!     use bhyve_api::vm::VirtualMachine;
+//!
+//!     // Reads a descriptor index, then the descriptor, from a device ring
+//!     fn next_desc(vm: &VirtualMachine, ring_gpa: u64) -> Result<u64, bhyve_api::Error> {
+//!         let dma = vm.memory().borrow_dma(ring_gpa, 4096)?;
+//!         let ring = dma.slice();
+//!         let index: u16 = ring.read(2)?;
+//!         ring.read(16 + 16 * (index as usize % 256))
+//!     }
+
+use libc::EFAULT;
+use std::marker::PhantomData;
+use std::mem::{align_of, size_of};
+
+use crate::bytes::{self, FromBytes};
+use crate::Error;
+
+/// A range of memory shared with the guest, accessed only with volatile
+/// reads and writes.
+#[derive(Debug, Copy, Clone)]
+pub struct VolatileSlice<'a> {
+    addr: *mut u8,
+    len: usize,
+    _mapping: PhantomData<&'a [u8]>,
+}
+
+impl<'a> VolatileSlice<'a> {
+    /// Creates a slice of 'len' bytes at 'addr'.
+    ///
+    /// # Safety
+    ///
+    /// [addr,addr+len) must stay mapped and writable for the lifetime 'a,
+    /// and must not be accessed through references in that time.
+    pub unsafe fn new(addr: *mut u8, len: usize) -> VolatileSlice<'a> {
+        VolatileSlice {
+            addr: addr,
+            len: len,
+            _mapping: PhantomData,
+        }
+    }
+
+    /// Returns the length of the slice in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the slice is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns a raw pointer to the start of the slice.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.addr
+    }
+
+    /// Returns the part of the slice of 'len' bytes at 'offset', or `EFAULT`
+    /// if it extends past the end of the slice.
+    pub fn subslice(&self, offset: usize, len: usize) -> Result<VolatileSlice<'a>, Error> {
+        self.check(offset, len)?;
+        // Safe because the subslice is within this slice
+        Ok(unsafe { VolatileSlice::new(self.addr.add(offset), len) })
+    }
+
+    /// Reads a value of type 'T' at 'offset', converting it from the guest's
+    /// byte order. Aligned values are read with a single access, so a value
+    /// the guest updates atomically is never seen half-written.
+    pub fn read<T: FromBytes>(&self, offset: usize) -> Result<T, Error> {
+        self.check(offset, size_of::<T>())?;
+        // Safe because the value is within the slice, and any bytes make a
+        // valid FromBytes value.
+        let value = unsafe {
+            let ptr = self.addr.add(offset);
+            if (ptr as usize) & (align_of::<T>() - 1) == 0 {
+                (ptr as *const T).read_volatile()
+            } else {
+                let mut value: T = std::mem::zeroed();
+                for (i, byte) in bytes::as_mut_bytes(&mut value).iter_mut().enumerate() {
+                    *byte = ptr.add(i).read_volatile();
+                }
+                value
+            }
+        };
+        Ok(value.to_native())
+    }
+
+    /// Writes 'value' at 'offset' in the guest's byte order. Aligned values
+    /// are written with a single access.
+    pub fn write<T: FromBytes>(&self, offset: usize, value: T) -> Result<(), Error> {
+        self.check(offset, size_of::<T>())?;
+        let value = value.to_le();
+        // Safe because the value is within the slice
+        unsafe {
+            let ptr = self.addr.add(offset);
+            if (ptr as usize) & (align_of::<T>() - 1) == 0 {
+                (ptr as *mut T).write_volatile(value);
+            } else {
+                for (i, byte) in bytes::as_bytes(&value).iter().enumerate() {
+                    ptr.add(i).write_volatile(*byte);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies bytes from the start of the slice into 'buf', returning the
+    /// number copied, which is the smaller of the two lengths.
+    pub fn copy_to(&self, buf: &mut [u8]) -> usize {
+        let count = std::cmp::min(self.len, buf.len());
+        for (i, byte) in buf[..count].iter_mut().enumerate() {
+            // Safe because 'i' is within the slice
+            *byte = unsafe { self.addr.add(i).read_volatile() };
+        }
+        count
+    }
+
+    /// Copies 'buf' to the start of the slice, returning the number of bytes
+    /// copied, which is the smaller of the two lengths.
+    pub fn copy_from(&self, buf: &[u8]) -> usize {
+        let count = std::cmp::min(self.len, buf.len());
+        for (i, byte) in buf[..count].iter().enumerate() {
+            // Safe because 'i' is within the slice
+            unsafe { self.addr.add(i).write_volatile(*byte) };
+        }
+        count
+    }
+
+    /// Sets every byte of the slice to 'value'.
+    pub fn fill(&self, value: u8) {
+        for i in 0..self.len {
+            // Safe because 'i' is within the slice
+            unsafe { self.addr.add(i).write_volatile(value) };
+        }
+    }
+
+    // Checks that [offset,offset+len) is within the slice.
+    fn check(&self, offset: usize, len: usize) -> Result<(), Error> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(Error::new(EFAULT)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_volatile_slice() {
+        let mut buf = [0u8; 16];
+        let slice = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+        slice.write(1, 0x1234_5678u32).unwrap();
+        slice.write(8, 0xaabbu16).unwrap();
+        assert_eq!(slice.read::<u32>(1).unwrap(), 0x1234_5678);
+        assert!(slice.read::<u64>(9).is_err());
+        assert!(slice.subslice(8, 9).is_err());
+
+        let sub = slice.subslice(8, 8).unwrap();
+        assert_eq!(sub.read::<u16>(0).unwrap(), 0xaabb);
+        sub.fill(0xff);
+        let mut out = [0u8; 20];
+        assert_eq!(slice.copy_to(&mut out), 16);
+        assert_eq!(&out[..10], &[0, 0x78, 0x56, 0x34, 0x12, 0, 0, 0, 0xff, 0xff]);
+        assert_eq!(slice.copy_from(&[1, 2]), 2);
+        assert_eq!(buf[..3], [1, 2, 0x56]);
+    }
+}
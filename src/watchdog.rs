@@ -0,0 +1,139 @@
+//! Detection of VCPUs that stop exiting to userspace.
+//!
+//! A `Watchdog` is a set of `RunHooks` that records when each VCPU enters
+//! the guest, and a monitor thread that reports any VCPU that has stayed in
+//! VM_RUN for longer than a configured interval. This catches guests that
+//! hang with interrupts disabled, and host scheduling problems that keep a
+//! VCPU thread from getting back to its run loop.
+//!
+//!     use bhyve_api::watchdog::Watchdog;
+//!     use std::time::Duration;
+//!
+//!     let watchdog = Watchdog::start(Duration::from_secs(5), |vcpu_id, stuck_for| {
+//!         eprintln!("vcpu {} has not exited for {:?}", vcpu_id, stuck_for);
+//!     });
+//!     // vm.set_run_hooks(Some(watchdog.clone()));
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::include::vmm::VM_MAXCPU;
+use crate::vm::{vm_exitcode, RunHooks};
+
+/// Reports VCPUs that have been in the guest for too long.
+pub struct Watchdog {
+    epoch: Instant,
+    // Per VCPU, nanoseconds since 'epoch' at which the VCPU entered the
+    // guest, plus one, or zero while it is outside of VM_RUN.
+    entered: Arc<Vec<AtomicU64>>,
+    stop: Mutex<Option<Sender<()>>>,
+}
+
+impl Watchdog {
+    /// Starts a monitor thread that calls 'callback' with the VCPU ID and
+    /// the time spent in the guest, once per entry, for any VCPU that has
+    /// not exited within 'interval'. The returned watchdog only sees VCPUs
+    /// once it is installed with `VirtualMachine::set_run_hooks()`.
+    pub fn start<F>(interval: Duration, callback: F) -> Arc<Watchdog>
+        where F: Fn(i32, Duration) + Send + 'static
+    {
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let watchdog = Arc::new(Watchdog {
+            epoch: Instant::now(),
+            entered: Arc::new((0..VM_MAXCPU).map(|_| AtomicU64::new(0)).collect()),
+            stop: Mutex::new(Some(stop_tx)),
+        });
+
+        let epoch = watchdog.epoch;
+        let entered = Arc::clone(&watchdog.entered);
+        let period = interval / 4;
+        thread::spawn(move || {
+            // The entry time of the last report for each VCPU, so a long stay
+            // in the guest is reported once rather than on every check.
+            let mut reported = vec![0; VM_MAXCPU];
+            loop {
+                match stop_rx.recv_timeout(period) {
+                    Err(RecvTimeoutError::Timeout) => (),
+                    _ => return,
+                }
+                let now = epoch.elapsed();
+                for (vcpu_id, slot) in entered.iter().enumerate() {
+                    let stamp = slot.load(Ordering::SeqCst);
+                    if stamp == 0 || stamp == reported[vcpu_id] {
+                        continue;
+                    }
+                    let in_guest = now.checked_sub(Duration::from_nanos(stamp - 1)).unwrap_or_default();
+                    if in_guest > interval {
+                        reported[vcpu_id] = stamp;
+                        callback(vcpu_id as i32, in_guest);
+                    }
+                }
+            }
+        });
+
+        watchdog
+    }
+
+    /// Stops the monitor thread. No further callbacks are made once this
+    /// returns, apart from one that may already be running.
+    pub fn stop(&self) {
+        // Dropping the sender wakes the monitor thread
+        self.stop.lock().unwrap().take();
+    }
+
+    fn slot(&self, vcpu_id: i32) -> Option<&AtomicU64> {
+        if vcpu_id < 0 {
+            return None;
+        }
+        self.entered.get(vcpu_id as usize)
+    }
+}
+
+impl RunHooks for Watchdog {
+    fn before_entry(&self, vcpu_id: i32) {
+        if let Some(slot) = self.slot(vcpu_id) {
+            let stamp = self.epoch.elapsed().as_nanos() as u64 + 1;
+            slot.store(stamp, Ordering::SeqCst);
+        }
+    }
+
+    fn after_exit(&self, vcpu_id: i32, _exitcode: Option<vm_exitcode>, _entered: Instant, _elapsed: Duration) {
+        if let Some(slot) = self.slot(vcpu_id) {
+            slot.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_reports_once() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let watchdog = Watchdog::start(Duration::from_millis(20), move |vcpu_id, _| {
+            tx.lock().unwrap().send(vcpu_id).unwrap();
+        });
+
+        watchdog.before_entry(3);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(3));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // A VCPU that exits in time is not reported
+        watchdog.after_exit(3, None, Instant::now(), Duration::from_millis(0));
+        watchdog.before_entry(1);
+        watchdog.after_exit(1, None, Instant::now(), Duration::from_millis(0));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        watchdog.stop();
+    }
+}
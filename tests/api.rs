@@ -2,6 +2,7 @@ extern crate bhyve_api;
 
 use bhyve_api::system::*;
 use bhyve_api::vm::*;
+use bhyve_api::Error;
 
 #[test]
 fn test_create_vm() {
@@ -12,3 +13,42 @@ fn test_create_vm() {
     assert_eq!(vm.name, "testname");
     vmmctl.destroy_vm(vm_name).expect("failed to destroy VM");
 }
+
+#[test]
+fn test_check_privileges() {
+    VMMSystem::check_privileges().expect("missing privileges for VM creation");
+}
+
+#[test]
+fn test_setup_reserved_range() {
+    let vm_name = "test-reserved";
+    let vmmctl = VMMSystem::new().expect("failed to create VMM system ioctl handle");
+    vmmctl.create_vm(vm_name).expect("failed to create VM device");
+    let mut vm = VirtualMachine::new(vm_name).expect("failed to open filehandle to VM device");
+
+    // A bootrom placed explicitly over the HPET
+    let layout = BootromLayout {
+        gpa: Some(0xfed00000),
+        parts: vec![RomPart { name: "bootrom", len: 0x1000, prot: MemProt::READ_EXEC }],
+    };
+    match vm.setup_bootrom_layout(&layout) {
+        Err(Error::ReservedRange { region, .. }) => assert_eq!(region, "HPET"),
+        other => panic!("expected HPET overlap, got {:?}", other),
+    }
+
+    // Lowmem past the PCI hole, with the limit raised to allow it
+    vm.lowmem_limit = 4 << 30;
+    match vm.setup_lowmem(0, 0xfec01000) {
+        Err(Error::ReservedRange { region, .. }) => assert_eq!(region, "I/O APIC"),
+        other => panic!("expected I/O APIC overlap, got {:?}", other),
+    }
+
+    // A framebuffer over the local APIC
+    match vm.setup_framebuffer(0xfee00000, 0, 0x1000) {
+        Err(Error::ReservedRange { region, .. }) => assert_eq!(region, "local APIC"),
+        other => panic!("expected local APIC overlap, got {:?}", other),
+    }
+    assert!(vm.regions().is_empty());
+    drop(vm);
+    vmmctl.destroy_vm(vm_name).expect("failed to destroy VM");
+}
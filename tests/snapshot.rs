@@ -0,0 +1,189 @@
+extern crate bhyve_api;
+
+use bhyve_api::dump::VcpuDump;
+use bhyve_api::rtc::*;
+use bhyve_api::system::*;
+use bhyve_api::vm::*;
+
+use std::sync::Arc;
+
+const TEST_CPUID: i32 = 0;
+
+// A valid hardware exception for set_intinfo(): VM_INTINFO_VALID, with
+// VM_INTINFO_HWEXCEPTION as the type.
+const INTINFO_HWEXCEPTION: u64 = (1 << 31) | (3 << 8);
+
+fn setup_vm(vm_name: &str) -> VirtualMachine {
+    let vmmctl = VMMSystem::new().expect("failed to create VMM system ioctl handle");
+    vmmctl.create_vm(vm_name).expect("failed to create VM device");
+    let vm = VirtualMachine::new(vm_name).expect("failed to open filehandle to VM device");
+    vm.vcpu_reset(TEST_CPUID).expect("failed to set initial state of registers");
+    return vm;
+}
+
+fn teardown_vm(vm_name: &str) {
+    let vmmctl = VMMSystem::new().expect("failed to create VMM system ioctl handle");
+    vmmctl.destroy_vm(vm_name).expect("failed to destroy VM");
+}
+
+// General purpose and debug registers that accept any value, each set to a
+// distinctive value by fill_registers() and changed by scramble_registers().
+const FREE_REGS: [vm_reg_name; 20] = [
+    vm_reg_name::VM_REG_GUEST_RAX,
+    vm_reg_name::VM_REG_GUEST_RBX,
+    vm_reg_name::VM_REG_GUEST_RCX,
+    vm_reg_name::VM_REG_GUEST_RDX,
+    vm_reg_name::VM_REG_GUEST_RSI,
+    vm_reg_name::VM_REG_GUEST_RDI,
+    vm_reg_name::VM_REG_GUEST_RBP,
+    vm_reg_name::VM_REG_GUEST_RSP,
+    vm_reg_name::VM_REG_GUEST_R8,
+    vm_reg_name::VM_REG_GUEST_R9,
+    vm_reg_name::VM_REG_GUEST_R10,
+    vm_reg_name::VM_REG_GUEST_R11,
+    vm_reg_name::VM_REG_GUEST_R12,
+    vm_reg_name::VM_REG_GUEST_R13,
+    vm_reg_name::VM_REG_GUEST_R14,
+    vm_reg_name::VM_REG_GUEST_R15,
+    vm_reg_name::VM_REG_GUEST_DR0,
+    vm_reg_name::VM_REG_GUEST_DR1,
+    vm_reg_name::VM_REG_GUEST_DR2,
+    vm_reg_name::VM_REG_GUEST_DR3,
+];
+
+// Segment registers with a selector. The VCPU never runs, so the kernel
+// doesn't check that the selectors and descriptors are consistent.
+const SEGMENTS: [vm_reg_name; 8] = [
+    vm_reg_name::VM_REG_GUEST_CS,
+    vm_reg_name::VM_REG_GUEST_DS,
+    vm_reg_name::VM_REG_GUEST_ES,
+    vm_reg_name::VM_REG_GUEST_FS,
+    vm_reg_name::VM_REG_GUEST_GS,
+    vm_reg_name::VM_REG_GUEST_SS,
+    vm_reg_name::VM_REG_GUEST_LDTR,
+    vm_reg_name::VM_REG_GUEST_TR,
+];
+
+const TABLES: [vm_reg_name; 2] = [
+    vm_reg_name::VM_REG_GUEST_GDTR,
+    vm_reg_name::VM_REG_GUEST_IDTR,
+];
+
+// Registers that only accept some values, and two valid values for each,
+// chosen by bit 32 of the seed passed to fill_registers().
+const CHOICE_REGS: [(vm_reg_name, u64, u64); 5] = [
+    // CD, NW and ET, as after reset; then ET, WP and AM
+    (vm_reg_name::VM_REG_GUEST_CR0, 0x6000_0010, 0x0005_0010),
+    // DE and OSFXSR
+    (vm_reg_name::VM_REG_GUEST_CR4, 0, 0x208),
+    // SCE and NXE
+    (vm_reg_name::VM_REG_GUEST_EFER, 0, 0x801),
+    // B0, with the reserved bits that read as one
+    (vm_reg_name::VM_REG_GUEST_DR6, 0xffff_0ff0, 0xffff_0ff1),
+    // L0 and G0, with the reserved bit that reads as one
+    (vm_reg_name::VM_REG_GUEST_DR7, 0x400, 0x403),
+];
+
+// Sets every register that a VcpuDump holds, except RFLAGS and the
+// interrupt shadow, to a value derived from 'seed', along with the pending
+// event information.
+fn fill_registers(vm: &VirtualMachine, seed: u64) {
+    for (i, reg) in FREE_REGS.iter().enumerate() {
+        vm.set_register(TEST_CPUID, *reg, seed + i as u64).expect("failed to set register");
+    }
+    vm.set_register(TEST_CPUID, vm_reg_name::VM_REG_GUEST_RIP, (seed >> 16) & 0xffff).expect("failed to set RIP register");
+    vm.set_register(TEST_CPUID, vm_reg_name::VM_REG_GUEST_CR2, seed + 0x100).expect("failed to set CR2");
+    vm.set_register(TEST_CPUID, vm_reg_name::VM_REG_GUEST_CR3, seed & !0xfff).expect("failed to set CR3");
+    let second = (seed >> 32) & 1 != 0;
+    for (reg, first_value, second_value) in CHOICE_REGS.iter() {
+        let value = if second { *second_value } else { *first_value };
+        vm.set_register(TEST_CPUID, *reg, value).expect("failed to set register");
+    }
+
+    for (i, reg) in SEGMENTS.iter().chain(TABLES.iter()).enumerate() {
+        let (_base, _limit, access) = vm.get_desc(TEST_CPUID, *reg).expect("failed to get descriptor");
+        let base = (seed & 0xffff_0000) + 0x1000 * i as u64;
+        let limit = ((seed >> 16) as u32 & 0xfff) + i as u32;
+        vm.set_desc(TEST_CPUID, *reg, base, limit, access).expect("failed to set descriptor");
+    }
+    for (i, reg) in SEGMENTS.iter().enumerate() {
+        let selector = ((seed >> 16) & 0xff00) + 8 * i as u64;
+        vm.set_register(TEST_CPUID, *reg, selector).expect("failed to set selector");
+    }
+
+    let vector = if second { 13 } else { 6 };
+    vm.set_intinfo(TEST_CPUID, INTINFO_HWEXCEPTION | vector).expect("failed to set intinfo");
+}
+
+// Checks that every register set by fill_registers() differs between the
+// dumps, so a restore that dropped any of them would be caught.
+fn assert_all_differ(a: &VcpuDump, b: &VcpuDump) {
+    for ((name, value_a), (_, value_b)) in a.regs.iter().zip(b.regs.iter()) {
+        if *name != "rflags" && *name != "intr_shadow" {
+            assert_ne!(value_a, value_b, "{} is the same in both dumps", name);
+        }
+    }
+    for (seg_a, seg_b) in a.segments.iter().zip(b.segments.iter()) {
+        assert_ne!((seg_a.selector, seg_a.base, seg_a.limit), (seg_b.selector, seg_b.base, seg_b.limit),
+                   "{} is the same in both dumps", seg_a.name);
+    }
+    assert_ne!(a.intinfo.0, b.intinfo.0);
+}
+
+// A distinctive value for each byte of NVRAM.
+fn nvram_pattern(offset: usize) -> u8 {
+    (offset as u8).wrapping_mul(37) ^ 0x5a
+}
+
+#[test]
+fn test_vcpu_snapshot_round_trip() {
+    let testname = "test_vcpu_snapshot_round_trip";
+    let vm = setup_vm(testname);
+
+    fill_registers(&vm, 0x1234_5678_0000);
+    let snapshot = vm.dump_vcpu(TEST_CPUID).expect("failed to snapshot VCPU");
+    assert_eq!(snapshot.reg("rax"), Some(0x1234_5678_0000));
+    assert_eq!(snapshot.reg("dr3"), Some(0x1234_5678_0013));
+
+    fill_registers(&vm, 0x0bad_0000_0000);
+    let scrambled = vm.dump_vcpu(TEST_CPUID).expect("failed to snapshot VCPU");
+    assert_all_differ(&scrambled, &snapshot);
+
+    snapshot.restore(&vm, TEST_CPUID).expect("failed to restore VCPU");
+    let restored = vm.dump_vcpu(TEST_CPUID).expect("failed to snapshot VCPU");
+    assert_eq!(restored, snapshot);
+
+    teardown_vm(testname);
+}
+
+#[test]
+fn test_rtc_snapshot_round_trip() {
+    let testname = "test_rtc_snapshot_round_trip";
+    let vm = Arc::new(setup_vm(testname));
+    let mut rtc = RtcShadow::new(Arc::clone(&vm)).expect("failed to read RTC");
+
+    // The century byte can't be written, and is left alone
+    let writable = (RTC_NVRAM_START..RTC_NVRAM_LEN).filter(|offset| *offset != RTC_CENTURY);
+    for offset in writable.clone() {
+        rtc.write_nvram(offset, nvram_pattern(offset)).expect("failed to write RTC");
+    }
+    vm.rtc_settime(1_000_000_000).expect("failed to set RTC time");
+    let snapshot = rtc.snapshot().expect("failed to snapshot RTC");
+    for offset in writable.clone() {
+        assert_eq!(snapshot.nvram[offset], nvram_pattern(offset));
+    }
+
+    for offset in writable {
+        rtc.write_nvram(offset, !nvram_pattern(offset)).expect("failed to write RTC");
+    }
+    vm.rtc_settime(0).expect("failed to set RTC time");
+    assert_ne!(rtc.snapshot().expect("failed to snapshot RTC").nvram, snapshot.nvram);
+
+    rtc.restore(&snapshot).expect("failed to restore RTC");
+    let restored = rtc.snapshot().expect("failed to snapshot RTC");
+    assert_eq!(&restored.nvram[RTC_NVRAM_START..], &snapshot.nvram[RTC_NVRAM_START..]);
+    // The clock keeps running, so allow for the time taken by the test
+    assert!(restored.time >= snapshot.time && restored.time - snapshot.time < 5);
+
+    teardown_vm(testname);
+}
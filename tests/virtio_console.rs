@@ -0,0 +1,90 @@
+extern crate bhyve_api;
+
+use bhyve_api::device::*;
+use bhyve_api::memory::*;
+use bhyve_api::pci::*;
+use bhyve_api::system::*;
+use bhyve_api::virtio_console::*;
+use bhyve_api::vm::*;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+const MEM_SIZE: usize = 16 * 1024 * 1024;
+const CONSOLE_SLOT: u8 = 3;
+
+// Guest physical addresses of the transmit queue and its buffer
+const DESC_TABLE: u64 = 0x10000;
+const AVAIL_RING: u64 = 0x11000;
+const USED_RING: u64 = 0x12000;
+const BUFFER: u64 = 0x20000;
+
+// Collects the console output.
+#[derive(Clone)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn config_read(bridge: &mut PciHostBridge, offset: u32) -> u32 {
+    bridge.pio_write(0, IoValue::new(IoWidth::Dword, 0x8000_0000 | (CONSOLE_SLOT as u32) << 11 | offset));
+    bridge.pio_read(4, IoWidth::Dword)
+}
+
+#[test]
+fn test_virtio_console_transmit() {
+    let vm_name = "test_virtio_console_transmit";
+    let vmmctl = VMMSystem::new().expect("failed to create VMM system ioctl handle");
+    vmmctl.create_vm(vm_name).expect("failed to create VM device");
+    let vm = Arc::new(VirtualMachine::new(vm_name).expect("failed to open filehandle to VM device"));
+    let lowmem = alloc_guest_backing(MEM_SIZE, BackingOptions::default()).expect("failed to allocate guest memory");
+    vm.setup_lowmem(lowmem.addr(), lowmem.len()).expect("failed to set up guest memory");
+
+    let mmio = Arc::new(Mutex::new(MmioBus::new()));
+    let allocator = BarAllocator::new(0xc000_0000..PCI_HOLE_END, 0..0);
+    let mut bridge = PciHostBridge::new(Arc::clone(&mmio), allocator);
+    let output = Output(Arc::new(Mutex::new(Vec::new())));
+    let console = VirtioConsole::new(Arc::clone(&vm), Box::new(output.clone())).expect("failed to create console");
+    bridge.add_device(CONSOLE_SLOT, Arc::new(Mutex::new(console))).expect("failed to add console");
+
+    assert_eq!(config_read(&mut bridge, 0), (VIRTIO_CONSOLE_DEVICE_ID as u32) << 16 | VIRTIO_PCI_VENDOR_ID as u32);
+    let bar = (config_read(&mut bridge, 0x10) & !0xf) as u64;
+    let mmio = mmio.lock().unwrap();
+    let write = |offset: u64, size: u8, value: u64| assert!(mmio.write(bar + offset, size, value));
+
+    // Acknowledge the device, and accept virtio 1.0
+    write(0x14, 1, 0x03);
+    write(0x08, 4, 1);
+    write(0x0c, 4, 1);
+    write(0x14, 1, 0x0b);
+    assert_eq!(mmio.read(bar + 0x14, 1), Some(0x0b));
+
+    // Set up the transmit queue, with a single buffer
+    write(0x16, 2, 1);
+    write(0x18, 2, 8);
+    write(0x20, 8, DESC_TABLE);
+    write(0x28, 8, AVAIL_RING);
+    write(0x30, 8, USED_RING);
+    write(0x1c, 2, 1);
+    write(0x14, 1, 0x0f);
+
+    vm.write_guest_memory(BUFFER, b"hello").expect("failed to write buffer");
+    vm.write_obj(DESC_TABLE, &BUFFER).expect("failed to write descriptor");
+    vm.write_obj(DESC_TABLE + 8, &5u32).expect("failed to write descriptor");
+    vm.write_obj(AVAIL_RING + 4, &0u16).expect("failed to write available ring");
+    vm.write_obj(AVAIL_RING + 2, &1u16).expect("failed to write available ring");
+
+    // Notify the transmit queue
+    write(0x3004, 2, 1);
+    assert_eq!(&output.0.lock().unwrap()[..], b"hello");
+    assert_eq!(vm.read_obj::<u16>(USED_RING + 2).expect("failed to read used ring"), 1);
+
+    vmmctl.destroy_vm(vm_name).expect("failed to destroy VM");
+}